@@ -0,0 +1,57 @@
+/*
+generated by bindgen and fixed up to use jni_sys types, with the anonymous version enum as
+jint constants to not clash with sys.rs
+
+bindgen jvmticmlr.h -o src/cmlr.rs --use-core --raw-line "use jni_sys::*;"
+ --disable-name-namespacing --default-enum-style rust --no-layout-tests
+ --whitelist-type "jvmti.*" --whitelist-type "PCStackInfo" --whitelist-var "JVMTI_CMLR.*"
+ */
+
+#![allow(non_snake_case, non_camel_case_types, clippy::all)]
+
+use jni_sys::*;
+
+pub const JVMTI_CMLR_MAJOR_VERSION_1: jint = 1;
+pub const JVMTI_CMLR_MINOR_VERSION_0: jint = 0;
+pub const JVMTI_CMLR_MAJOR_VERSION: jint = JVMTI_CMLR_MAJOR_VERSION_1;
+pub const JVMTI_CMLR_MINOR_VERSION: jint = JVMTI_CMLR_MINOR_VERSION_0;
+
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum jvmtiCMLRKind {
+    JVMTI_CMLR_DUMMY = 1,
+    JVMTI_CMLR_INLINE_INFO = 2,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct jvmtiCompiledMethodLoadRecordHeader {
+    pub kind: jvmtiCMLRKind,
+    pub majorinfoversion: jint,
+    pub minorinfoversion: jint,
+    pub next: *mut jvmtiCompiledMethodLoadRecordHeader,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct PCStackInfo {
+    pub pc: *mut ::core::ffi::c_void,
+    pub numstackframes: jint,
+    pub methods: *mut jmethodID,
+    pub bcis: *mut jint,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct jvmtiCompiledMethodLoadInlineRecord {
+    pub header: jvmtiCompiledMethodLoadRecordHeader,
+    pub numpcs: jint,
+    pub pcinfo: *mut PCStackInfo,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct jvmtiCompiledMethodLoadDummyRecord {
+    pub header: jvmtiCompiledMethodLoadRecordHeader,
+    pub message: [::std::os::raw::c_char; 50usize],
+}
@@ -1,2 +1,4 @@
+mod cmlr;
 mod sys;
+pub use cmlr::*;
 pub use sys::*;
@@ -33,7 +33,7 @@ function do_test() {
 
 rm -rf --one-file-system $results_dir
 mkdir -p $results_dir
-for path in tests/*; do
+for path in tests/*.rs; do
 	name=$(basename $path .rs)
 	if [[ $name = "common" ]]; then continue; fi
 	do_test $name &
@@ -0,0 +1,257 @@
+use crate::capability::Capability;
+use crate::raw_monitor::RawMonitor;
+use crate::util::*;
+use crate::JvmtiEnv;
+use core::ffi::c_void;
+use jni::objects::{GlobalRef, JObject, JValue};
+use jni::sys::jint;
+use jni::JNIEnv;
+use jni_jvmti_sys::{
+    jvmtiEnv, JVMTI_THREAD_MAX_PRIORITY, JVMTI_THREAD_MIN_PRIORITY, JVMTI_THREAD_NORM_PRIORITY,
+};
+use std::ops::{Deref, DerefMut};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::time::Duration;
+
+type AgentThreadFn = dyn FnOnce(JvmtiEnv, JNIEnv) + Send;
+
+/// Spawns a JVM-visible daemon thread with `RunAgentThread`, which shows up in `GetAllThreads`
+/// and needs no manual attaching.
+pub struct AgentThreadBuilder {
+    name: String,
+    priority: jint,
+}
+
+/// Handle to a started agent thread
+pub struct AgentThread {
+    name: String,
+    thread: GlobalRef,
+}
+
+/// State shared with an agent thread started by [AgentThreadBuilder::spawn_stoppable], along
+/// with whether the thread was asked to stop and whether it has ended
+#[derive(Default)]
+pub(crate) struct Stoppable<T> {
+    stopped: bool,
+    finished: bool,
+    state: T,
+}
+
+/// The env lifetime is erased for the agent thread, which is stopped before the monitor is dropped
+struct SharedState<T>(*const RawMonitor<'static, Stoppable<T>>);
+
+unsafe impl<T: Send> Send for SharedState<T> {}
+
+/// Marks an agent thread's shared state as finished and notifies its monitor on drop, so that
+/// whoever waits for the thread to end is woken even if the closure panics
+struct FinishGuard<'m, 'a, T> {
+    state: &'m RawMonitor<'a, Stoppable<T>>,
+}
+
+impl AgentThreadBuilder {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            priority: JVMTI_THREAD_NORM_PRIORITY as jint,
+        }
+    }
+
+    /// Between `JVMTI_THREAD_MIN_PRIORITY` and `JVMTI_THREAD_MAX_PRIORITY`, defaults to
+    /// `JVMTI_THREAD_NORM_PRIORITY`
+    pub fn with_priority(mut self, priority: jint) -> Self {
+        assert!(
+            (JVMTI_THREAD_MIN_PRIORITY as jint..=JVMTI_THREAD_MAX_PRIORITY as jint)
+                .contains(&priority),
+            "bad thread priority {}",
+            priority
+        );
+        self.priority = priority;
+        self
+    }
+
+    /// Allocates a new `java.lang.Thread` with the configured name and runs the closure on it.
+    /// The thread ends when the closure returns
+    pub fn spawn(
+        self,
+        jvmti: &JvmtiEnv,
+        jni: JNIEnv,
+        f: impl FnOnce(JvmtiEnv, JNIEnv) + Send + 'static,
+    ) -> JvmtiResult<AgentThread> {
+        let name = jni.new_string(&self.name)?;
+        let thread = jni.new_object(
+            "java/lang/Thread",
+            "(Ljava/lang/String;)V",
+            &[JValue::Object(name.into())],
+        );
+        delete_local_ref(jni, name.into());
+        let thread = thread?;
+        let thread_ref = match jni.new_global_ref(thread) {
+            Ok(thread_ref) => thread_ref,
+            Err(err) => {
+                delete_local_ref(jni, thread);
+                return Err(err.into());
+            }
+        };
+
+        let closure: Box<Box<AgentThreadFn>> = Box::new(Box::new(f));
+        let arg = Box::into_raw(closure);
+
+        let result = unsafe {
+            jvmti.run_agent_thread(
+                thread.into(),
+                Some(agent_thread_main),
+                arg as *const c_void,
+                self.priority,
+            )
+        };
+        if let Err(err) = result {
+            // thread never started, so the closure is still ours
+            drop(unsafe { Box::from_raw(arg) });
+            delete_local_ref(jni, thread);
+            return Err(err);
+        }
+        delete_local_ref(jni, thread);
+
+        debug!("spawned agent thread {:?}", self.name);
+        Ok(AgentThread {
+            name: self.name,
+            thread: thread_ref,
+        })
+    }
+
+    /// Requests the capabilities, to fail early rather than on the agent thread, and runs the
+    /// closure with the shared state. The state is marked finished when the closure ends, even
+    /// by panicking, which [stop] waits for.
+    ///
+    /// # Safety
+    /// The monitor must not be moved or dropped until [stop] has returned, as the thread keeps a
+    /// pointer to it
+    pub(crate) unsafe fn spawn_stoppable<T: Send + 'static>(
+        self,
+        jvmti: &JvmtiEnv,
+        jni: JNIEnv,
+        capabilities: &[Capability],
+        state: &RawMonitor<Stoppable<T>>,
+        f: impl FnOnce(JvmtiEnv, JNIEnv, &RawMonitor<Stoppable<T>>) + Send + 'static,
+    ) -> JvmtiResult<AgentThread> {
+        jvmti.require_capabilities(capabilities)?;
+
+        let shared = SharedState(state as *const RawMonitor<Stoppable<T>> as *const _);
+        self.spawn(jvmti, jni, move |jvmti, jni| {
+            // outlives this thread, as stop waits for it to finish
+            let state = unsafe { &*shared.0 };
+            let _finished = FinishGuard { state };
+            f(jvmti, jni, state)
+        })
+    }
+}
+
+impl AgentThread {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The `java.lang.Thread`, e.g. to exclude it from samples
+    pub fn thread(&self) -> &GlobalRef {
+        &self.thread
+    }
+}
+
+impl<T> Stoppable<T> {
+    pub(crate) fn new(state: T) -> Self {
+        Self {
+            stopped: false,
+            finished: false,
+            state,
+        }
+    }
+
+    pub(crate) fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+}
+
+impl<T> Deref for Stoppable<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.state
+    }
+}
+
+impl<T> DerefMut for Stoppable<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.state
+    }
+}
+
+/// Wakes the agent thread sharing the state and waits for it to end. Must not be called from the
+/// agent thread itself
+pub(crate) fn stop<T>(state: &RawMonitor<Stoppable<T>>) -> JvmtiResult<()> {
+    let mut state = state.lock()?;
+    state.stopped = true;
+    state.notify_all()?;
+    while !state.finished {
+        state.wait(None)?;
+    }
+    Ok(())
+}
+
+/// Waits on the monitor for up to `timeout` unless already stopped, for agent threads that work
+/// periodically. Returns false once the thread should end, including if the monitor fails
+pub(crate) fn wait_unless_stopped<T>(state: &RawMonitor<Stoppable<T>>, timeout: Duration) -> bool {
+    match state.lock() {
+        Ok(mut state) => {
+            if !state.stopped {
+                let _ = state.wait(Some(timeout));
+            }
+            !state.stopped
+        }
+        Err(err) => {
+            error!("agent thread failed to lock monitor: {}", err);
+            false
+        }
+    }
+}
+
+impl<T> Drop for FinishGuard<'_, '_, T> {
+    fn drop(&mut self) {
+        match self.state.lock() {
+            Ok(mut state) => {
+                state.finished = true;
+                if let Err(err) = state.notify_all() {
+                    error!("agent thread failed to notify that it finished: {}", err);
+                }
+            }
+            Err(err) => error!("agent thread failed to lock monitor to finish: {}", err),
+        }
+    }
+}
+
+/// Only logs failures, as the outcome of spawning matters more than a leaked local ref
+fn delete_local_ref(jni: JNIEnv, obj: JObject) {
+    if let Err(err) = jni.delete_local_ref(obj) {
+        warn!("failed to delete agent thread local ref: {}", err);
+    }
+}
+
+unsafe extern "C" fn agent_thread_main(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut jni::sys::JNIEnv,
+    arg: *mut c_void,
+) {
+    let closure = Box::from_raw(arg as *mut Box<AgentThreadFn>);
+    let jvmti = JvmtiEnv::from_raw(jvmti_env);
+    let jni = match JNIEnv::from_raw(jni_env) {
+        Ok(jni) => jni,
+        Err(err) => {
+            error!("agent thread has no JNIEnv: {}", err);
+            return;
+        }
+    };
+
+    // unwinding into the jvm would abort the whole process
+    if catch_unwind(AssertUnwindSafe(move || closure(jvmti, jni))).is_err() {
+        error!("agent thread panicked");
+    }
+}
@@ -0,0 +1,363 @@
+use crate::callback_state::CallbackState;
+use crate::capability::Capability;
+use crate::env::DEFAULT_HEAP_SAMPLING_INTERVAL;
+use crate::event::{EventCallbacksBuilder, EventScope, EventType};
+use crate::handles::{Class, Thread};
+use crate::heap::NonZeroJlong;
+use crate::raw_monitor::RawMonitor;
+use crate::stack::{self, FrameResolver, RawFrame, StackFrame};
+use crate::util::*;
+use crate::JvmtiEnv;
+use jni::objects::JObject;
+use jni::sys::{jint, jlong};
+use jni::{JNIEnv, JavaVM};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+const DEFAULT_MAX_FRAMES: usize = 64;
+
+pub struct AllocationProfilerBuilder {
+    sampling_interval: jint,
+    max_frames: usize,
+}
+
+/// Samples allocations through `SampledObjectAlloc` from a dedicated JVMTI environment, recording
+/// the stack of each sample. Sampled objects are tagged so `ObjectFree` tells which are still
+/// live, to find the sites that allocate what is never collected.
+///
+/// The sampling interval is global to the VM, so it also applies to any other sampling agent
+/// while the profiler runs. The previous interval is restored when the profiler is dropped.
+pub struct AllocationProfiler<'a> {
+    state: CallbackState<'a, ProfilerState<'a>>,
+    sampling_interval: jint,
+    /// Restored on drop, None until the interval is set
+    previous_interval: Option<jint>,
+}
+
+struct ProfilerState<'a> {
+    max_frames: usize,
+    samples: RawMonitor<'a, Samples>,
+}
+
+#[derive(Default)]
+struct Samples {
+    site_indices: HashMap<SiteKey, usize>,
+    sites: Vec<(SiteKey, SiteCounts)>,
+    /// Site index and size of each live sample, indexed by its tag - 1
+    live: Vec<Option<(usize, u64)>>,
+    free_slots: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+struct SiteKey {
+    class_name: String,
+    /// From the top of the stack
+    frames: Vec<RawFrame>,
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+struct SiteCounts {
+    samples: u64,
+    sampled_bytes: u64,
+    live_samples: u64,
+    live_bytes: u64,
+}
+
+/// Where objects of a class were allocated, and how many of the samples taken there are live
+#[derive(Debug, Clone)]
+pub struct AllocationSite {
+    /// e.g. `byte[]`
+    pub class_name: String,
+    /// From the top of the stack. Frames of unloaded methods are omitted
+    pub frames: Vec<StackFrame>,
+    pub samples: u64,
+    pub sampled_bytes: u64,
+    /// Samples not yet reported freed by `ObjectFree`
+    pub live_samples: u64,
+    pub live_bytes: u64,
+}
+
+/// Allocation sites seen since the profiler started
+#[derive(Debug, Clone)]
+pub struct AllocationProfile {
+    pub sampling_interval: jint,
+    /// Sorted by descending sampled bytes
+    pub sites: Vec<AllocationSite>,
+}
+
+impl Default for AllocationProfilerBuilder {
+    fn default() -> Self {
+        Self {
+            sampling_interval: DEFAULT_HEAP_SAMPLING_INTERVAL,
+            max_frames: DEFAULT_MAX_FRAMES,
+        }
+    }
+}
+
+impl AllocationProfilerBuilder {
+    /// Mean bytes allocated by a thread between samples, or 0 to sample every allocation.
+    /// Defaults to 512KiB. Set for the whole VM while the profiler runs
+    pub fn with_sampling_interval(mut self, bytes: jint) -> Self {
+        assert!(bytes >= 0, "sampling interval must not be negative");
+        self.sampling_interval = bytes;
+        self
+    }
+
+    /// Deepest stack recorded for a sample, defaults to 64
+    pub fn with_max_frames(mut self, max_frames: usize) -> Self {
+        assert_ne!(max_frames, 0, "max frames must be non-zero");
+        self.max_frames = max_frames;
+        self
+    }
+
+    /// Creates a new JVMTI environment and starts sampling
+    pub fn start<'a>(self, jvm: &JavaVM) -> JvmtiResult<AllocationProfiler<'a>> {
+        let jvmti = JvmtiEnv::from_jvm(jvm)?;
+        jvmti.require_capabilities(&[
+            Capability::GenerateSampledObjectAllocEvents,
+            Capability::GenerateObjectFreeEvents,
+            Capability::TagObjects,
+        ])?;
+
+        let state = ProfilerState {
+            max_frames: self.max_frames,
+            samples: RawMonitor::new(&jvmti, "allocation profiler", Samples::default())?,
+        };
+        let mut profiler = AllocationProfiler {
+            state: CallbackState::new(jvmti, state)?,
+            sampling_interval: self.sampling_interval,
+            previous_interval: None,
+        };
+
+        let jvmti = profiler.state.jvmti();
+        let callbacks = EventCallbacksBuilder::default()
+            .with_sampled_object_alloc(Some(sampled_object_alloc))
+            .with_object_free(Some(object_free))
+            .build();
+        jvmti.install_event_callbacks(&callbacks)?;
+        profiler.previous_interval =
+            Some(jvmti.set_heap_sampling_interval(self.sampling_interval)?);
+        let jvmti = profiler.state.jvmti();
+        jvmti.enable_event(EventType::ObjectFree, EventScope::Global)?;
+        jvmti.enable_event(EventType::SampledObjectAlloc, EventScope::Global)?;
+
+        debug!(
+            "started allocation profiler sampling every {} bytes",
+            self.sampling_interval
+        );
+        Ok(profiler)
+    }
+}
+
+impl<'a> AllocationProfiler<'a> {
+    pub fn builder() -> AllocationProfilerBuilder {
+        AllocationProfilerBuilder::default()
+    }
+
+    /// Resolves the sites sampled so far
+    pub fn profile(&self, jni: JNIEnv) -> JvmtiResult<AllocationProfile> {
+        let snapshot = self.state.samples.lock()?.sites.clone();
+
+        let mut resolver = FrameResolver::new(self.state.jvmti(), jni);
+        let mut sites = Vec::with_capacity(snapshot.len());
+        for (key, counts) in snapshot {
+            sites.push(AllocationSite {
+                class_name: key.class_name,
+                frames: key
+                    .frames
+                    .iter()
+                    .filter_map(|&frame| resolver.resolve(frame))
+                    .collect(),
+                samples: counts.samples,
+                sampled_bytes: counts.sampled_bytes,
+                live_samples: counts.live_samples,
+                live_bytes: counts.live_bytes,
+            });
+        }
+
+        sites.sort_by_key(|site| Reverse(site.sampled_bytes));
+        Ok(AllocationProfile {
+            sampling_interval: self.sampling_interval,
+            sites,
+        })
+    }
+}
+
+impl Drop for AllocationProfiler<'_> {
+    fn drop(&mut self) {
+        for event in [EventType::SampledObjectAlloc, EventType::ObjectFree] {
+            if let Err(err) = self.state.jvmti().disable_event(event, EventScope::Global) {
+                error!("failed to disable {:?}: {}", event, err);
+            }
+        }
+
+        if let Some(previous) = self.previous_interval {
+            if let Err(err) = self.state.jvmti().set_heap_sampling_interval(previous) {
+                error!("failed to restore heap sampling interval: {}", err);
+            }
+        }
+    }
+}
+
+impl Samples {
+    /// Returns the tag for the sampled object
+    fn record(&mut self, key: SiteKey, size: u64) -> jlong {
+        let site = match self.site_indices.get(&key) {
+            Some(&site) => site,
+            None => {
+                let site = self.sites.len();
+                self.site_indices.insert(key.clone(), site);
+                self.sites.push((key, SiteCounts::default()));
+                site
+            }
+        };
+
+        let counts = &mut self.sites[site].1;
+        counts.samples += 1;
+        counts.sampled_bytes += size;
+        counts.live_samples += 1;
+        counts.live_bytes += size;
+
+        let slot = match self.free_slots.pop() {
+            Some(slot) => {
+                self.live[slot] = Some((site, size));
+                slot
+            }
+            None => {
+                self.live.push(Some((site, size)));
+                self.live.len() - 1
+            }
+        };
+        slot as jlong + 1
+    }
+
+    fn free(&mut self, tag: jlong) {
+        let slot = tag as usize - 1;
+        if let Some((site, size)) = self.live.get_mut(slot).and_then(Option::take) {
+            let counts = &mut self.sites[site].1;
+            counts.live_samples -= 1;
+            counts.live_bytes -= size;
+            self.free_slots.push(slot);
+        }
+    }
+}
+
+impl SiteKey {
+    fn collect(
+        jvmti: &JvmtiEnv,
+        thread: Thread,
+        class: Class,
+        max_frames: usize,
+    ) -> JvmtiResult<Self> {
+        let signature = jvmti.get_class_signature(class)?;
+        let frames = jvmti
+            .get_stack_trace(thread, 0, max_frames)?
+            .iter()
+            .map(|&location| stack::raw_frame(location))
+            .collect();
+
+        Ok(Self {
+            class_name: java_class_name(&mutf8_to_string(signature.as_bytes())),
+            frames,
+        })
+    }
+}
+
+unsafe extern "C" fn sampled_object_alloc(
+    jvmti_env: JvmtiEnv,
+    _jni_env: JNIEnv,
+    thread: Thread,
+    object: JObject,
+    object_class: Class,
+    size: jlong,
+) {
+    CallbackState::<ProfilerState>::with(&jvmti_env, |state| {
+        let key = match SiteKey::collect(&jvmti_env, thread, object_class, state.max_frames) {
+            Ok(key) => key,
+            Err(err) => {
+                warn!("failed to collect allocation site: {}", err);
+                return;
+            }
+        };
+
+        let tag = match state.samples.lock() {
+            Ok(mut samples) => samples.record(key, size as u64),
+            Err(err) => {
+                error!("failed to record allocation sample: {}", err);
+                return;
+            }
+        };
+
+        if let Err(err) = jvmti_env.set_tag(object, NonZeroJlong::new(tag)) {
+            warn!("failed to tag sampled object: {}", err);
+            if let Ok(mut samples) = state.samples.lock() {
+                samples.free(tag);
+            }
+        }
+    });
+}
+
+/// Only raw monitor and environment local storage functions may be called from here
+unsafe extern "C" fn object_free(jvmti_env: JvmtiEnv, tag: jlong) {
+    CallbackState::<ProfilerState>::with(&jvmti_env, |state| match state.samples.lock() {
+        Ok(mut samples) => samples.free(tag),
+        Err(err) => error!("failed to record freed sample: {}", err),
+    });
+}
+
+impl AllocationProfile {
+    pub fn total_sampled_bytes(&self) -> u64 {
+        self.sites.iter().map(|site| site.sampled_bytes).sum()
+    }
+
+    pub fn total_live_bytes(&self) -> u64 {
+        self.sites.iter().map(|site| site.live_bytes).sum()
+    }
+
+    /// Sites with live samples, by descending live bytes
+    pub fn live_sites(&self) -> Vec<&AllocationSite> {
+        let mut sites: Vec<&AllocationSite> = self
+            .sites
+            .iter()
+            .filter(|site| site.live_samples > 0)
+            .collect();
+        sites.sort_by_key(|site| Reverse(site.live_bytes));
+        sites
+    }
+}
+
+fn write_site(
+    f: &mut Formatter<'_>,
+    bytes: u64,
+    samples: u64,
+    site: &AllocationSite,
+) -> std::fmt::Result {
+    writeln!(f, "{:14} {:10}  {}", bytes, samples, site.class_name)?;
+    for frame in &site.frames {
+        writeln!(f, "\tat {}", frame)?;
+    }
+    Ok(())
+}
+
+impl Display for AllocationProfile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Allocation sites by sampled bytes, sampling every {} bytes:",
+            self.sampling_interval
+        )?;
+        writeln!(f, "        #bytes   #samples  class")?;
+        for site in &self.sites {
+            write_site(f, site.sampled_bytes, site.samples, site)?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "Allocation sites by live bytes:")?;
+        writeln!(f, "        #bytes   #samples  class")?;
+        for site in self.live_sites() {
+            write_site(f, site.live_bytes, site.live_samples, site)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+use crate::util::*;
+use crate::JvmtiEnv;
+use core::ffi::c_void;
+use std::ops::Deref;
+use std::ptr::{null, NonNull};
+use std::sync::atomic::{fence, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Callbacks inside [CallbackState::with], by the parity of the epoch they entered in
+static ACTIVE_CALLBACKS: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+static EPOCH: AtomicUsize = AtomicUsize::new(0);
+/// Releases take turns, so each only waits for the callbacks of one epoch
+static RELEASING: Mutex<()> = Mutex::new(());
+
+/// A dedicated JVMTI environment, and the state its event callbacks find through its environment
+/// local storage.
+///
+/// Disabling an event does not wait for callbacks that are already running, so one may still be
+/// about to use the state afterwards. Callbacks reach the state only through
+/// [with](Self::with), and dropping this clears the local storage and waits for callbacks that
+/// may have seen the state before freeing it and disposing the environment. Events must be
+/// disabled first, and the state must not be used by anything else that outlives this, e.g. an
+/// agent thread.
+pub(crate) struct CallbackState<'a, T> {
+    jvmti: JvmtiEnv<'a>,
+    state: NonNull<T>,
+}
+
+unsafe impl<T: Send + Sync> Send for CallbackState<'_, T> {}
+unsafe impl<T: Sync> Sync for CallbackState<'_, T> {}
+
+/// Counts a callback out of its epoch, even if it panics
+struct ActiveCallback(&'static AtomicUsize);
+
+impl<'a, T: Sync> CallbackState<'a, T> {
+    /// Takes ownership of the environment, which is disposed with the state
+    pub(crate) fn new(jvmti: JvmtiEnv<'a>, state: T) -> JvmtiResult<Self> {
+        let this = Self {
+            jvmti,
+            state: NonNull::from(Box::leak(Box::new(state))),
+        };
+        // only ever read as T by with
+        unsafe {
+            this.jvmti
+                .set_environment_local_storage(this.state.as_ptr() as *const c_void)?;
+        }
+        Ok(this)
+    }
+
+    pub(crate) fn jvmti(&self) -> &JvmtiEnv<'a> {
+        &self.jvmti
+    }
+
+    /// Runs `f` on the state of the callback's environment, or returns None if it is being
+    /// released. Only uses environment local storage, so may be called from any callback
+    ///
+    /// # Safety
+    /// The environment's local storage must only ever have been set by a `CallbackState<T>`
+    pub(crate) unsafe fn with<R>(jvmti: &JvmtiEnv, f: impl FnOnce(&T) -> R) -> Option<R> {
+        let active = &ACTIVE_CALLBACKS[EPOCH.load(Ordering::SeqCst) % 2];
+        active.fetch_add(1, Ordering::SeqCst);
+        let _active = ActiveCallback(active);
+
+        // pairs with the fence in wait_for_callbacks, so either the release sees this callback or this
+        // callback sees the cleared storage
+        fence(Ordering::SeqCst);
+        match jvmti.get_environment_local_storage() {
+            Ok(state) if !state.is_null() => Some(f(&*(state as *const T))),
+            _ => None,
+        }
+    }
+}
+
+impl<T> CallbackState<'_, T> {
+    /// Waits for every callback that entered [with](Self::with) before the storage was cleared
+    fn wait_for_callbacks() {
+        let _releasing = RELEASING
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        fence(Ordering::SeqCst);
+
+        // callbacks entering from now on see the cleared storage
+        let epoch = EPOCH.fetch_add(1, Ordering::SeqCst);
+        let active = &ACTIVE_CALLBACKS[epoch % 2];
+        while active.load(Ordering::SeqCst) != 0 {
+            std::thread::yield_now();
+        }
+    }
+}
+
+impl<T> Deref for CallbackState<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { self.state.as_ref() }
+    }
+}
+
+impl Drop for ActiveCallback {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl<T> Drop for CallbackState<'_, T> {
+    fn drop(&mut self) {
+        if let Err(err) = unsafe { self.jvmti.set_environment_local_storage(null()) } {
+            // a callback may still find the state, so it must not be freed
+            error!("failed to clear callback state, leaking it: {}", err);
+        } else {
+            Self::wait_for_callbacks();
+
+            // destroys any raw monitors in the state while the environment is still valid
+            drop(unsafe { Box::from_raw(self.state.as_ptr()) });
+        }
+
+        if let Err(err) = self.jvmti.clone().dispose() {
+            error!("failed to dispose environment: {}", err);
+        }
+    }
+}
@@ -1 +1,72 @@
+use jni_jvmti_sys::jvmtiCapabilities;
 
+macro_rules! capabilities {
+    ($($variant:ident => $get:ident, $set:ident;)*) => {
+        /// A single JVMTI capability, for checking and requesting individually
+        #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+        pub enum Capability {
+            $($variant),*
+        }
+
+        impl Capability {
+            pub fn is_set(self, capabilities: &jvmtiCapabilities) -> bool {
+                match self {
+                    $(Capability::$variant => capabilities.$get() != 0),*
+                }
+            }
+
+            pub fn set(self, capabilities: &mut jvmtiCapabilities) {
+                match self {
+                    $(Capability::$variant => capabilities.$set(1)),*
+                }
+            }
+        }
+    };
+}
+
+capabilities! {
+    TagObjects => can_tag_objects, set_can_tag_objects;
+    GenerateFieldModificationEvents => can_generate_field_modification_events, set_can_generate_field_modification_events;
+    GenerateFieldAccessEvents => can_generate_field_access_events, set_can_generate_field_access_events;
+    GetBytecodes => can_get_bytecodes, set_can_get_bytecodes;
+    GetSyntheticAttribute => can_get_synthetic_attribute, set_can_get_synthetic_attribute;
+    GetOwnedMonitorInfo => can_get_owned_monitor_info, set_can_get_owned_monitor_info;
+    GetCurrentContendedMonitor => can_get_current_contended_monitor, set_can_get_current_contended_monitor;
+    GetMonitorInfo => can_get_monitor_info, set_can_get_monitor_info;
+    PopFrame => can_pop_frame, set_can_pop_frame;
+    RedefineClasses => can_redefine_classes, set_can_redefine_classes;
+    SignalThread => can_signal_thread, set_can_signal_thread;
+    GetSourceFileName => can_get_source_file_name, set_can_get_source_file_name;
+    GetLineNumbers => can_get_line_numbers, set_can_get_line_numbers;
+    GetSourceDebugExtension => can_get_source_debug_extension, set_can_get_source_debug_extension;
+    AccessLocalVariables => can_access_local_variables, set_can_access_local_variables;
+    MaintainOriginalMethodOrder => can_maintain_original_method_order, set_can_maintain_original_method_order;
+    GenerateSingleStepEvents => can_generate_single_step_events, set_can_generate_single_step_events;
+    GenerateExceptionEvents => can_generate_exception_events, set_can_generate_exception_events;
+    GenerateFramePopEvents => can_generate_frame_pop_events, set_can_generate_frame_pop_events;
+    GenerateBreakpointEvents => can_generate_breakpoint_events, set_can_generate_breakpoint_events;
+    Suspend => can_suspend, set_can_suspend;
+    RedefineAnyClass => can_redefine_any_class, set_can_redefine_any_class;
+    GetCurrentThreadCpuTime => can_get_current_thread_cpu_time, set_can_get_current_thread_cpu_time;
+    GetThreadCpuTime => can_get_thread_cpu_time, set_can_get_thread_cpu_time;
+    GenerateMethodEntryEvents => can_generate_method_entry_events, set_can_generate_method_entry_events;
+    GenerateMethodExitEvents => can_generate_method_exit_events, set_can_generate_method_exit_events;
+    GenerateAllClassHookEvents => can_generate_all_class_hook_events, set_can_generate_all_class_hook_events;
+    GenerateCompiledMethodLoadEvents => can_generate_compiled_method_load_events, set_can_generate_compiled_method_load_events;
+    GenerateMonitorEvents => can_generate_monitor_events, set_can_generate_monitor_events;
+    GenerateVmObjectAllocEvents => can_generate_vm_object_alloc_events, set_can_generate_vm_object_alloc_events;
+    GenerateNativeMethodBindEvents => can_generate_native_method_bind_events, set_can_generate_native_method_bind_events;
+    GenerateGarbageCollectionEvents => can_generate_garbage_collection_events, set_can_generate_garbage_collection_events;
+    GenerateObjectFreeEvents => can_generate_object_free_events, set_can_generate_object_free_events;
+    ForceEarlyReturn => can_force_early_return, set_can_force_early_return;
+    GetOwnedMonitorStackDepthInfo => can_get_owned_monitor_stack_depth_info, set_can_get_owned_monitor_stack_depth_info;
+    GetConstantPool => can_get_constant_pool, set_can_get_constant_pool;
+    SetNativeMethodPrefix => can_set_native_method_prefix, set_can_set_native_method_prefix;
+    RetransformClasses => can_retransform_classes, set_can_retransform_classes;
+    RetransformAnyClass => can_retransform_any_class, set_can_retransform_any_class;
+    GenerateResourceExhaustionHeapEvents => can_generate_resource_exhaustion_heap_events, set_can_generate_resource_exhaustion_heap_events;
+    GenerateResourceExhaustionThreadsEvents => can_generate_resource_exhaustion_threads_events, set_can_generate_resource_exhaustion_threads_events;
+    GenerateEarlyVmStart => can_generate_early_vmstart, set_can_generate_early_vmstart;
+    GenerateEarlyClassHookEvents => can_generate_early_class_hook_events, set_can_generate_early_class_hook_events;
+    GenerateSampledObjectAllocEvents => can_generate_sampled_object_alloc_events, set_can_generate_sampled_object_alloc_events;
+}
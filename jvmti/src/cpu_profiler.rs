@@ -0,0 +1,611 @@
+use crate::agent_thread::{self, AgentThread, AgentThreadBuilder, Stoppable};
+use crate::capability::Capability;
+use crate::handles::{Location, Method, Thread};
+use crate::raw_monitor::RawMonitor;
+use crate::stack::{line_number, StackInfo};
+use crate::thread::ThreadState;
+use crate::util::*;
+use crate::JvmtiEnv;
+use jni::objects::JObject;
+use jni::sys::jint;
+use jni::JNIEnv;
+use jni_jvmti_sys::jvmtiLineNumberEntry;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_INTERVAL: Duration = Duration::from_millis(10);
+const DEFAULT_MAX_FRAMES: usize = 128;
+
+pub struct CpuProfilerBuilder {
+    interval: Duration,
+    max_frames: usize,
+    runnable_only: bool,
+}
+
+/// Samples the stacks of Java threads periodically on an agent thread, for flame graphs or pprof.
+///
+/// Samples are taken at safepoints, so are biased towards them as with any JVMTI-based profiler.
+pub struct CpuProfiler<'a> {
+    thread: AgentThread,
+    state: Box<RawMonitor<'a, Stoppable<ProfilerState>>>,
+    interval: Duration,
+    runnable_only: bool,
+    start_time: SystemTime,
+    started: Instant,
+}
+
+#[derive(Default)]
+struct ProfilerState {
+    ticks: u64,
+    methods: Vec<ProfiledMethod>,
+    stacks: HashMap<Vec<ProfiledFrame>, u64>,
+}
+
+/// Owned by the agent thread, which resolves each method once
+struct Sampler {
+    max_frames: usize,
+    runnable_only: bool,
+    /// None for methods that failed to resolve
+    method_indices: HashMap<usize, Option<usize>>,
+    /// Indexed like the methods in the profile, empty for native methods
+    line_tables: Vec<Vec<jvmtiLineNumberEntry>>,
+}
+
+/// The stacks of one tick, and the methods first seen in them
+#[derive(Default)]
+struct Sample {
+    stacks: Vec<Vec<ProfiledFrame>>,
+    new_methods: Vec<ProfiledMethod>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProfiledMethod {
+    /// e.g. `java.lang.Object`
+    pub class_name: String,
+    pub method_name: String,
+    /// None if the class has no `SourceFile` attribute
+    pub source_file: Option<String>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct ProfiledFrame {
+    /// Index into [CpuProfile::methods]
+    pub method: usize,
+    /// None for native methods or if the class has no line number table
+    pub line: Option<jint>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SampledStack {
+    /// From the bottom of the stack. Frames of methods that could not be resolved are omitted
+    pub frames: Vec<ProfiledFrame>,
+    /// How many times a thread was seen with this stack
+    pub samples: u64,
+}
+
+/// The samples taken since the profiler started
+#[derive(Debug, Clone)]
+pub struct CpuProfile {
+    pub interval: Duration,
+    /// Whether only runnable threads were sampled, otherwise this is a wall clock profile
+    pub runnable_only: bool,
+    pub start_time: SystemTime,
+    pub duration: Duration,
+    /// How many times threads were sampled
+    pub ticks: u64,
+    pub methods: Vec<ProfiledMethod>,
+    /// By descending samples
+    pub stacks: Vec<SampledStack>,
+}
+
+impl Default for CpuProfilerBuilder {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_INTERVAL,
+            max_frames: DEFAULT_MAX_FRAMES,
+            runnable_only: true,
+        }
+    }
+}
+
+impl CpuProfilerBuilder {
+    /// Defaults to 10ms
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        assert!(!interval.is_zero(), "interval must be non-zero");
+        self.interval = interval;
+        self
+    }
+
+    /// Deepest stack recorded for a sample, defaults to 128. Deeper stacks lose their bottom
+    /// frames
+    pub fn with_max_frames(mut self, max_frames: usize) -> Self {
+        assert_ne!(max_frames, 0, "max frames must be non-zero");
+        self.max_frames = max_frames;
+        self
+    }
+
+    /// Whether to only sample threads in the `RUNNABLE` state with `GetThreadListStackTraces`,
+    /// or all threads with `GetAllStackTraces` for a wall clock profile. Defaults to true.
+    ///
+    /// Threads blocked in native code, e.g. reading a socket, are runnable as far as the VM knows
+    pub fn with_runnable_only(mut self, runnable_only: bool) -> Self {
+        self.runnable_only = runnable_only;
+        self
+    }
+
+    /// Requests `can_get_line_numbers` and `can_get_source_file_name` if not already possessed,
+    /// and starts sampling on a new agent thread
+    pub fn spawn<'a>(self, jvmti: &JvmtiEnv<'a>, jni: JNIEnv) -> JvmtiResult<CpuProfiler<'a>> {
+        let state = Box::new(RawMonitor::new(
+            jvmti,
+            "cpu profiler",
+            Stoppable::default(),
+        )?);
+
+        let interval = self.interval;
+        let mut sampler = Sampler {
+            max_frames: self.max_frames,
+            runnable_only: self.runnable_only,
+            method_indices: HashMap::new(),
+            line_tables: Vec::new(),
+        };
+        let run =
+            move |jvmti: JvmtiEnv, jni: JNIEnv, state: &RawMonitor<Stoppable<ProfilerState>>| {
+                match jvmti.get_current_thread(jni) {
+                    Ok(own_thread) => sampler.run(&jvmti, jni, own_thread, state, interval),
+                    Err(err) => error!("cpu profiler failed to get its thread: {}", err),
+                }
+                debug!("cpu profiler stopped");
+            };
+
+        // boxed and stopped on drop, so outlives the thread
+        let thread = unsafe {
+            AgentThreadBuilder::new("cpu profiler").spawn_stoppable(
+                jvmti,
+                jni,
+                &[Capability::GetLineNumbers, Capability::GetSourceFileName],
+                &state,
+                run,
+            )?
+        };
+
+        Ok(CpuProfiler {
+            thread,
+            state,
+            interval: self.interval,
+            runnable_only: self.runnable_only,
+            start_time: SystemTime::now(),
+            started: Instant::now(),
+        })
+    }
+}
+
+impl CpuProfiler<'_> {
+    pub fn builder() -> CpuProfilerBuilder {
+        CpuProfilerBuilder::default()
+    }
+
+    pub fn thread(&self) -> &AgentThread {
+        &self.thread
+    }
+
+    /// The samples taken so far
+    pub fn profile(&self) -> JvmtiResult<CpuProfile> {
+        let state = self.state.lock()?;
+        let mut stacks: Vec<SampledStack> = state
+            .stacks
+            .iter()
+            .map(|(frames, &samples)| SampledStack {
+                frames: frames.clone(),
+                samples,
+            })
+            .collect();
+        stacks.sort_by_key(|stack| Reverse(stack.samples));
+
+        Ok(CpuProfile {
+            interval: self.interval,
+            runnable_only: self.runnable_only,
+            start_time: self.start_time,
+            duration: self.started.elapsed(),
+            ticks: state.ticks,
+            methods: state.methods.clone(),
+            stacks,
+        })
+    }
+
+    /// Wakes the profiler and waits for its thread to end. Also called on drop
+    pub fn stop(&self) -> JvmtiResult<()> {
+        agent_thread::stop(&self.state)
+    }
+}
+
+impl Drop for CpuProfiler<'_> {
+    fn drop(&mut self) {
+        if let Err(err) = self.stop() {
+            error!("failed to stop cpu profiler: {}", err);
+        }
+    }
+}
+
+impl ProfilerState {
+    fn add(&mut self, sample: Sample) {
+        self.ticks += 1;
+        self.methods.extend(sample.new_methods);
+        for stack in sample.stacks {
+            *self.stacks.entry(stack).or_default() += 1;
+        }
+    }
+}
+
+impl Sampler {
+    /// Samples every `interval` until stopped
+    fn run(
+        &mut self,
+        jvmti: &JvmtiEnv,
+        jni: JNIEnv,
+        own_thread: Thread,
+        state: &RawMonitor<Stoppable<ProfilerState>>,
+        interval: Duration,
+    ) {
+        while agent_thread::wait_unless_stopped(state, interval) {
+            if let Err(err) = jni.push_local_frame(64) {
+                error!("cpu profiler failed to push local frame: {}", err);
+                break;
+            }
+
+            match self.sample(jvmti, jni, own_thread) {
+                Ok(sample) => match state.lock() {
+                    Ok(mut state) => state.add(sample),
+                    Err(err) => error!("cpu profiler failed to lock monitor: {}", err),
+                },
+                Err(err) => warn!("failed to sample threads: {}", err),
+            }
+
+            let _ = jni.pop_local_frame(JObject::null());
+        }
+    }
+
+    fn sample(&mut self, jvmti: &JvmtiEnv, jni: JNIEnv, own_thread: Thread) -> JvmtiResult<Sample> {
+        if !self.runnable_only {
+            let infos = jvmti.get_all_stack_traces(jni, self.max_frames)?;
+            return self.record(jvmti, jni, own_thread, &infos);
+        }
+
+        let threads = jvmti.get_all_threads(jni)?;
+        let mut runnable = Vec::with_capacity(threads.len());
+        for &thread in threads.iter() {
+            // may have terminated since
+            if let Ok(state) = jvmti.get_thread_state(thread) {
+                if state.contains(ThreadState::RUNNABLE)
+                    && !jni.is_same_object(*thread, *own_thread)?
+                {
+                    runnable.push(thread);
+                }
+            }
+        }
+
+        let infos = jvmti.get_thread_list_stack_traces(&runnable, self.max_frames)?;
+        self.record(jvmti, jni, own_thread, &infos)
+    }
+
+    fn record(
+        &mut self,
+        jvmti: &JvmtiEnv,
+        jni: JNIEnv,
+        own_thread: Thread,
+        infos: &[StackInfo],
+    ) -> JvmtiResult<Sample> {
+        let mut sample = Sample::default();
+        for info in infos {
+            // threads not running java code have no frames
+            if info.frames.is_empty()
+                || (self.runnable_only && !info.state.contains(ThreadState::RUNNABLE))
+                || jni.is_same_object(*info.thread, *own_thread)?
+            {
+                continue;
+            }
+
+            let stack = info
+                .frames
+                .iter()
+                .rev()
+                .filter_map(|&location| self.resolve(jvmti, jni, location, &mut sample))
+                .collect();
+            sample.stacks.push(stack);
+        }
+        Ok(sample)
+    }
+
+    fn resolve(
+        &mut self,
+        jvmti: &JvmtiEnv,
+        jni: JNIEnv,
+        location: Location,
+        sample: &mut Sample,
+    ) -> Option<ProfiledFrame> {
+        let id = location.method.into_inner() as usize;
+        let method = match self.method_indices.get(&id) {
+            Some(&method) => method,
+            None => {
+                let method = match resolve_method(jvmti, jni, location.method) {
+                    Ok((method, line_table)) => {
+                        sample.new_methods.push(method);
+                        self.line_tables.push(line_table);
+                        Some(self.line_tables.len() - 1)
+                    }
+                    Err(err) => {
+                        debug!("failed to resolve sampled method: {}", err);
+                        None
+                    }
+                };
+                self.method_indices.insert(id, method);
+                method
+            }
+        }?;
+
+        let line = match location.bci {
+            -1 => None,
+            bci => line_number(&self.line_tables[method], bci),
+        };
+        Some(ProfiledFrame { method, line })
+    }
+}
+
+/// The method and its line number table, which is empty for native methods or classes compiled
+/// without line numbers
+fn resolve_method(
+    jvmti: &JvmtiEnv,
+    jni: JNIEnv,
+    method: Method,
+) -> JvmtiResult<(ProfiledMethod, Vec<jvmtiLineNumberEntry>)> {
+    let method_name = mutf8_to_string(jvmti.get_method_name(method)?.0.as_bytes());
+
+    let class = jvmti.get_method_declaring_class(jni, method)?;
+    let class_info = (|| -> JvmtiResult<_> {
+        let signature = jvmti.get_class_signature(class)?;
+        let source_file = jvmti
+            .get_source_file_name(class)?
+            .map(|name| mutf8_to_string(name.as_bytes()));
+        Ok((
+            java_class_name(&mutf8_to_string(signature.as_bytes())),
+            source_file,
+        ))
+    })();
+    jni.delete_local_ref(*class)?;
+    let (class_name, source_file) = class_info?;
+
+    // fails for native methods
+    let line_table = jvmti.get_line_number_table(method).unwrap_or_default();
+    Ok((
+        ProfiledMethod {
+            class_name,
+            method_name,
+            source_file,
+        },
+        line_table,
+    ))
+}
+
+/// e.g. `java.lang.Thread.run`
+impl Display for ProfiledMethod {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.class_name, self.method_name)
+    }
+}
+
+impl CpuProfile {
+    /// Total thread samples
+    pub fn samples(&self) -> u64 {
+        self.stacks.iter().map(|stack| stack.samples).sum()
+    }
+
+    /// Writes Brendan Gregg's collapsed stack format for `flamegraph.pl`, a line per distinct
+    /// stack of methods from the bottom, e.g. `java.lang.Thread.run;Foo.bar 42`
+    pub fn write_collapsed<W: Write>(&self, mut out: W) -> JvmtiResult<()> {
+        // lines are ignored, so stacks differing only in lines are merged
+        let mut collapsed: BTreeMap<String, u64> = BTreeMap::new();
+        for stack in &self.stacks {
+            let methods: Vec<String> = stack
+                .frames
+                .iter()
+                .map(|frame| self.methods[frame.method].to_string())
+                .collect();
+            *collapsed.entry(methods.join(";")).or_default() += stack.samples;
+        }
+
+        for (stack, samples) in collapsed {
+            writeln!(out, "{} {}", stack, samples)?;
+        }
+        Ok(())
+    }
+
+    /// Writes an uncompressed pprof `profile.proto` message, with sample counts and the
+    /// time they stand for, a location per distinct line and a function per method
+    pub fn write_pprof<W: Write>(&self, mut out: W) -> JvmtiResult<()> {
+        let mut strings = StringTable::default();
+        let mut profile = Message::default();
+        let value_type = if self.runnable_only { "cpu" } else { "wall" };
+        let interval = self.interval.as_nanos() as u64;
+
+        for (ty, unit) in [("samples", "count"), (value_type, "nanoseconds")] {
+            profile.message(PROFILE_SAMPLE_TYPE, strings.value_type(ty, unit));
+        }
+
+        let mut locations: HashMap<ProfiledFrame, u64> = HashMap::new();
+        for stack in &self.stacks {
+            let mut location_ids = Vec::with_capacity(stack.frames.len());
+            // leaf first
+            for frame in stack.frames.iter().rev() {
+                let next_id = locations.len() as u64 + 1;
+                location_ids.push(*locations.entry(*frame).or_insert(next_id));
+            }
+
+            let mut sample = Message::default();
+            sample.packed(SAMPLE_LOCATION_ID, location_ids);
+            sample.packed(
+                SAMPLE_VALUE,
+                [stack.samples, stack.samples * interval].iter().copied(),
+            );
+            profile.message(PROFILE_SAMPLE, sample);
+        }
+
+        let mut locations: Vec<(ProfiledFrame, u64)> = locations.into_iter().collect();
+        locations.sort_by_key(|&(_, id)| id);
+        for (frame, id) in locations {
+            let mut line = Message::default();
+            line.uint(LINE_FUNCTION_ID, frame.method as u64 + 1);
+            line.int(LINE_LINE, frame.line.unwrap_or(0) as i64);
+
+            let mut location = Message::default();
+            location.uint(LOCATION_ID, id);
+            location.message(LOCATION_LINE, line);
+            profile.message(PROFILE_LOCATION, location);
+        }
+
+        for (index, method) in self.methods.iter().enumerate() {
+            let name = strings.intern(&method.to_string());
+            let mut function = Message::default();
+            function.uint(FUNCTION_ID, index as u64 + 1);
+            function.int(FUNCTION_NAME, name);
+            function.int(FUNCTION_SYSTEM_NAME, name);
+            if let Some(file) = &method.source_file {
+                function.int(FUNCTION_FILENAME, strings.intern(file));
+            }
+            profile.message(PROFILE_FUNCTION, function);
+        }
+
+        let time_nanos = self
+            .start_time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        profile.int(PROFILE_TIME_NANOS, time_nanos as i64);
+        profile.int(PROFILE_DURATION_NANOS, self.duration.as_nanos() as i64);
+        profile.message(
+            PROFILE_PERIOD_TYPE,
+            strings.value_type(value_type, "nanoseconds"),
+        );
+        profile.int(PROFILE_PERIOD, interval as i64);
+
+        // last, as everything else adds to it
+        for string in &strings.strings {
+            profile.bytes(PROFILE_STRING_TABLE, string.as_bytes());
+        }
+
+        out.write_all(&profile.0)?;
+        Ok(())
+    }
+}
+
+// field numbers from profile.proto
+const PROFILE_SAMPLE_TYPE: u32 = 1;
+const PROFILE_SAMPLE: u32 = 2;
+const PROFILE_LOCATION: u32 = 4;
+const PROFILE_FUNCTION: u32 = 5;
+const PROFILE_STRING_TABLE: u32 = 6;
+const PROFILE_TIME_NANOS: u32 = 9;
+const PROFILE_DURATION_NANOS: u32 = 10;
+const PROFILE_PERIOD_TYPE: u32 = 11;
+const PROFILE_PERIOD: u32 = 12;
+const VALUE_TYPE_TYPE: u32 = 1;
+const VALUE_TYPE_UNIT: u32 = 2;
+const SAMPLE_LOCATION_ID: u32 = 1;
+const SAMPLE_VALUE: u32 = 2;
+const LOCATION_ID: u32 = 1;
+const LOCATION_LINE: u32 = 4;
+const LINE_FUNCTION_ID: u32 = 1;
+const LINE_LINE: u32 = 2;
+const FUNCTION_ID: u32 = 1;
+const FUNCTION_NAME: u32 = 2;
+const FUNCTION_SYSTEM_NAME: u32 = 3;
+const FUNCTION_FILENAME: u32 = 4;
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_LENGTH_DELIMITED: u64 = 2;
+
+/// Just enough of a protobuf encoder for pprof. Zero scalars are omitted as proto3 defaults
+#[derive(Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u64) {
+        self.varint((field as u64) << 3 | wire_type);
+    }
+
+    fn uint(&mut self, field: u32, value: u64) {
+        if value != 0 {
+            self.key(field, WIRE_VARINT);
+            self.varint(value);
+        }
+    }
+
+    fn int(&mut self, field: u32, value: i64) {
+        self.uint(field, value as u64);
+    }
+
+    fn bytes(&mut self, field: u32, bytes: &[u8]) {
+        self.key(field, WIRE_LENGTH_DELIMITED);
+        self.varint(bytes.len() as u64);
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn message(&mut self, field: u32, message: Message) {
+        self.bytes(field, &message.0);
+    }
+
+    fn packed(&mut self, field: u32, values: impl IntoIterator<Item = u64>) {
+        let mut packed = Message::default();
+        for value in values {
+            packed.varint(value);
+        }
+        self.bytes(field, &packed.0);
+    }
+}
+
+/// pprof refers to strings by index, where index 0 must be the empty string
+struct StringTable {
+    indices: HashMap<String, i64>,
+    strings: Vec<String>,
+}
+
+impl Default for StringTable {
+    fn default() -> Self {
+        let mut table = Self {
+            indices: HashMap::new(),
+            strings: Vec::new(),
+        };
+        table.intern("");
+        table
+    }
+}
+
+impl StringTable {
+    fn intern(&mut self, string: &str) -> i64 {
+        if let Some(&index) = self.indices.get(string) {
+            return index;
+        }
+
+        let index = self.strings.len() as i64;
+        self.indices.insert(string.to_owned(), index);
+        self.strings.push(string.to_owned());
+        index
+    }
+
+    fn value_type(&mut self, ty: &str, unit: &str) -> Message {
+        let mut value_type = Message::default();
+        value_type.int(VALUE_TYPE_TYPE, self.intern(ty));
+        value_type.int(VALUE_TYPE_UNIT, self.intern(unit));
+        value_type
+    }
+}
@@ -0,0 +1,310 @@
+use crate::agent_thread::{self, AgentThread, AgentThreadBuilder, Stoppable};
+use crate::capability::Capability;
+use crate::handles::Thread;
+use crate::monitor::MonitorObject;
+use crate::raw_monitor::RawMonitor;
+use crate::stack::{get_stack_trace, StackFrame};
+use crate::thread::ThreadState;
+use crate::util::*;
+use crate::JvmtiEnv;
+use jni::objects::{GlobalRef, JObject};
+use jni::sys::jint;
+use jni::JNIEnv;
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+const MAX_FRAMES: usize = 1024;
+
+/// A thread in a deadlock, blocked on a monitor owned by the next thread in the cycle
+pub struct DeadlockedThread {
+    pub thread: GlobalRef,
+    pub name: String,
+    /// The monitor being waited for
+    pub monitor: MonitorObject,
+    /// Name of the thread that owns the monitor
+    pub owner_name: String,
+    pub stack: Vec<StackFrame>,
+}
+
+/// A cycle in the wait-for graph of threads blocked on monitor entry
+pub struct Deadlock {
+    pub threads: Vec<DeadlockedThread>,
+}
+
+/// Runs [find_deadlocks] periodically on an agent thread, reporting each deadlock once
+pub struct DeadlockWatcher<'a> {
+    thread: AgentThread,
+    state: Box<RawMonitor<'a, Stoppable<()>>>,
+}
+
+/// A thread blocked on a monitor, and the index of its owner in the list of all threads
+struct WaitsFor<'a> {
+    owner: usize,
+    monitor: JObject<'a>,
+}
+
+/// Finds all Java-level deadlocks between threads blocked on entering monitors. Requests
+/// `can_get_current_contended_monitor` and `can_get_monitor_info` if not already possessed
+pub fn find_deadlocks(jvmti: &JvmtiEnv, jni: JNIEnv) -> JvmtiResult<Vec<Deadlock>> {
+    jvmti.require_capabilities(&[
+        Capability::GetCurrentContendedMonitor,
+        Capability::GetMonitorInfo,
+    ])?;
+
+    let threads = jvmti.get_all_threads(jni)?;
+    let mut edges = Vec::with_capacity(threads.len());
+    let mut result = Ok(());
+    for &thread in threads.iter() {
+        match waits_for(jvmti, jni, thread, &threads) {
+            Ok(edge) => edges.push(edge),
+            Err(err) => {
+                result = Err(err);
+                break;
+            }
+        }
+    }
+
+    let result = result.and_then(|_| {
+        let graph: Vec<_> = edges
+            .iter()
+            .map(|edge| edge.as_ref().map(|edge| edge.owner))
+            .collect();
+        find_cycles(&graph)
+            .into_iter()
+            .map(|cycle| describe(jvmti, jni, &threads, &cycle, &edges))
+            .collect::<JvmtiResult<Vec<_>>>()
+    });
+
+    for edge in edges.into_iter().flatten() {
+        jni.delete_local_ref(edge.monitor)?;
+    }
+
+    let deadlocks = result?;
+    if !deadlocks.is_empty() {
+        debug!("found {} deadlocks", deadlocks.len());
+    }
+    Ok(deadlocks)
+}
+
+/// None if the thread is not blocked, or the monitor has no owner among the given threads
+fn waits_for<'a>(
+    jvmti: &JvmtiEnv,
+    jni: JNIEnv<'a>,
+    thread: Thread,
+    threads: &[Thread],
+) -> JvmtiResult<Option<WaitsFor<'a>>> {
+    let state = jvmti.get_thread_state(thread)?;
+    if !state.contains(ThreadState::BLOCKED_ON_MONITOR_ENTER) {
+        return Ok(None);
+    }
+
+    // may have been granted the monitor or terminated in the meantime
+    let monitor = match jvmti.get_current_contended_monitor(jni, thread) {
+        Ok(Some(monitor)) => monitor,
+        Ok(None) | Err(Error::Jvmti(JvmtiError::ThreadNotAlive)) => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    let usage = match jvmti.get_object_monitor_usage(jni, monitor) {
+        Ok(usage) => usage,
+        Err(err) => {
+            jni.delete_local_ref(monitor)?;
+            return Err(err);
+        }
+    };
+    let owner = usage.owner.and_then(|owner| {
+        threads
+            .iter()
+            .position(|t| jni.is_same_object(*owner, **t).unwrap_or(false))
+    });
+    usage.delete_local_refs(jni)?;
+
+    match owner {
+        Some(owner) => Ok(Some(WaitsFor { owner, monitor })),
+        None => {
+            jni.delete_local_ref(monitor)?;
+            Ok(None)
+        }
+    }
+}
+
+/// Each node waits for at most one other, so every cycle is found by following edges from each
+/// node until reaching a node visited before
+fn find_cycles(waits_for: &[Option<usize>]) -> Vec<Vec<usize>> {
+    let mut visited_from = vec![None; waits_for.len()];
+    let mut cycles = Vec::new();
+
+    for start in 0..waits_for.len() {
+        let mut node = start;
+        loop {
+            match visited_from[node] {
+                Some(walk) if walk == start => {
+                    let mut cycle = vec![node];
+                    let mut next = waits_for[node].expect("cycle has an edge");
+                    while next != node {
+                        cycle.push(next);
+                        next = waits_for[next].expect("cycle has an edge");
+                    }
+                    cycles.push(cycle);
+                    break;
+                }
+                // joined an earlier walk, whose cycle is already found
+                Some(_) => break,
+                None => visited_from[node] = Some(start),
+            }
+
+            match waits_for[node] {
+                Some(next) => node = next,
+                None => break,
+            }
+        }
+    }
+
+    cycles
+}
+
+fn describe(
+    jvmti: &JvmtiEnv,
+    jni: JNIEnv,
+    threads: &[Thread],
+    cycle: &[usize],
+    edges: &[Option<WaitsFor>],
+) -> JvmtiResult<Deadlock> {
+    let names = cycle
+        .iter()
+        .map(|&i| {
+            let info = jvmti.get_thread_info(jni, threads[i])?;
+            info.delete_local_refs(jni)?;
+            Ok(info.name)
+        })
+        .collect::<JvmtiResult<Vec<_>>>()?;
+
+    let mut deadlocked = Vec::with_capacity(cycle.len());
+    for (pos, &i) in cycle.iter().enumerate() {
+        let edge = edges[i].as_ref().expect("thread in cycle is blocked");
+        deadlocked.push(DeadlockedThread {
+            thread: jni.new_global_ref(*threads[i])?,
+            name: names[pos].clone(),
+            monitor: MonitorObject::describe(jvmti, jni, edge.monitor)?,
+            owner_name: names[(pos + 1) % names.len()].clone(),
+            stack: get_stack_trace(jvmti, jni, threads[i], MAX_FRAMES)?,
+        });
+    }
+
+    Ok(Deadlock {
+        threads: deadlocked,
+    })
+}
+
+impl Deadlock {
+    /// Identity hash codes of the contended monitors, the same wherever the cycle was entered
+    fn key(&self) -> Vec<jint> {
+        let mut key: Vec<_> = self.threads.iter().map(|t| t.monitor.hash_code).collect();
+        key.sort_unstable();
+        key
+    }
+}
+
+impl<'a> DeadlockWatcher<'a> {
+    /// Checks for deadlocks every `interval`, passing newly found ones to `on_deadlock`
+    pub fn spawn(
+        jvmti: &JvmtiEnv<'a>,
+        jni: JNIEnv,
+        interval: Duration,
+        mut on_deadlock: impl FnMut(&[Deadlock]) + Send + 'static,
+    ) -> JvmtiResult<Self> {
+        let state = Box::new(RawMonitor::new(
+            jvmti,
+            "deadlock watcher",
+            Stoppable::default(),
+        )?);
+
+        let run = move |jvmti: JvmtiEnv, jni: JNIEnv, state: &RawMonitor<Stoppable<()>>| {
+            let mut reported = HashSet::new();
+            while agent_thread::wait_unless_stopped(state, interval) {
+                if let Err(err) = jni.push_local_frame(64) {
+                    error!("deadlock watcher failed to push local frame: {}", err);
+                    break;
+                }
+
+                match find_deadlocks(&jvmti, jni) {
+                    Ok(deadlocks) => {
+                        let new: Vec<_> = deadlocks
+                            .into_iter()
+                            .filter(|deadlock| reported.insert(deadlock.key()))
+                            .collect();
+                        if !new.is_empty() {
+                            on_deadlock(&new);
+                        }
+                    }
+                    Err(err) => warn!("failed to check for deadlocks: {}", err),
+                }
+
+                let _ = jni.pop_local_frame(JObject::null());
+            }
+            debug!("deadlock watcher stopped");
+        };
+
+        // boxed and stopped on drop, so outlives the thread
+        let thread = unsafe {
+            AgentThreadBuilder::new("deadlock watcher").spawn_stoppable(
+                jvmti,
+                jni,
+                &[
+                    Capability::GetCurrentContendedMonitor,
+                    Capability::GetMonitorInfo,
+                ],
+                &state,
+                run,
+            )?
+        };
+
+        Ok(Self { thread, state })
+    }
+
+    pub fn thread(&self) -> &AgentThread {
+        &self.thread
+    }
+
+    /// Wakes the watcher and waits for its thread to end. Also called on drop, and must not be
+    /// called from the `on_deadlock` callback
+    pub fn stop(&self) -> JvmtiResult<()> {
+        agent_thread::stop(&self.state)
+    }
+}
+
+impl Drop for DeadlockWatcher<'_> {
+    fn drop(&mut self) {
+        if let Err(err) = self.stop() {
+            error!("failed to stop deadlock watcher: {}", err);
+        }
+    }
+}
+
+/// In the format of the deadlock section of a jstack thread dump
+impl Display for Deadlock {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Found one Java-level deadlock:")?;
+        writeln!(f, "=============================")?;
+        for thread in &self.threads {
+            writeln!(f, "\"{}\":", thread.name)?;
+            writeln!(f, "  waiting to lock {},", thread.monitor)?;
+            writeln!(f, "  which is held by \"{}\"", thread.owner_name)?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "Java stack information for the threads listed above:")?;
+        writeln!(f, "===================================================")?;
+        for thread in &self.threads {
+            writeln!(f, "\"{}\":", thread.name)?;
+            for (depth, frame) in thread.stack.iter().enumerate() {
+                writeln!(f, "\tat {}", frame)?;
+                if depth == 0 {
+                    writeln!(f, "\t- waiting to lock {}", thread.monitor)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
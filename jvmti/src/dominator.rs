@@ -0,0 +1,592 @@
+use crate::capability::Capability;
+use crate::heap::{HeapFilterFlags, HeapReference, HeapVisitControlFlags, NonZeroJlong};
+use crate::util::*;
+use crate::JvmtiEnv;
+use jni::sys::jlong;
+use jni::JNIEnv;
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+
+/// Absent node, parent or class
+const NONE: u32 = u32::MAX;
+/// The virtual root referencing every heap root, as node and DFS number
+const ROOT: u32 = 0;
+
+/// The dominator tree of the heap's reference graph, from which the retained size of every object
+/// follows: the bytes that would be freed if the object were collected.
+///
+/// Objects are numbered in depth first order from a virtual root referencing every heap root, so
+/// each object's immediate dominator has a lower number than the object itself.
+pub struct DominatorTree {
+    /// Indexed by DFS number, as are the other per object vectors
+    nodes: Vec<u32>,
+    /// Index into `class_names` of each object's class
+    classes: Vec<u32>,
+    shallow_sizes: Vec<u64>,
+    retained_sizes: Vec<u64>,
+    dominators: Vec<u32>,
+    /// Parent on a shortest path from the virtual root
+    path_parents: Vec<u32>,
+    root_kinds: HashMap<u32, RootKind>,
+    /// Of loaded classes, whose mirrors are nodes 1 to `class_names.len()`
+    class_names: Vec<String>,
+}
+
+/// An object in a [DominatorTree]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct ObjectId(u32);
+
+/// How a heap root references an object
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RootKind {
+    JniGlobal,
+    SystemClass,
+    Monitor,
+    StackLocal,
+    JniLocal,
+    Thread,
+    Other,
+}
+
+#[derive(Debug, Clone)]
+pub struct RetainedObject {
+    pub id: ObjectId,
+    /// Class name, or `class <name>` for a class itself
+    pub description: String,
+    pub shallow_size: u64,
+    pub retained_size: u64,
+    pub path_to_root: PathToRoot,
+}
+
+/// Retained size of a class is that of all its instances, except those already retained by
+/// another instance of the same class
+#[derive(Debug, Clone)]
+pub struct RetainedClass {
+    pub class_name: String,
+    pub instances: u64,
+    pub shallow_size: u64,
+    pub retained_size: u64,
+}
+
+/// A shortest chain of references from a heap root to an object
+#[derive(Debug, Clone)]
+pub struct PathToRoot {
+    pub root: RootKind,
+    /// Descriptions of the objects from the one referenced by the root to the object itself
+    pub objects: Vec<String>,
+}
+
+/// The objects and classes with the largest retained sizes
+#[derive(Debug, Clone)]
+pub struct LeakReport {
+    pub total_size: u64,
+    pub objects: Vec<RetainedObject>,
+    pub classes: Vec<RetainedClass>,
+}
+
+/// The reference graph in compressed sparse row form, by node
+struct Graph {
+    offsets: Vec<u32>,
+    targets: Vec<u32>,
+}
+
+/// Walks the whole reference graph and builds its dominator tree. Only objects reachable from the
+/// heap roots are included.
+///
+/// Objects are tagged in a private environment with `can_tag_objects`, created for the walk and
+/// disposed after, so no tags of the caller's environments are touched. The graph is held as
+/// 32-bit indices, taking a few words per object and reference rather than a heap dump's bytes.
+pub fn dominator_tree(jni: JNIEnv) -> JvmtiResult<DominatorTree> {
+    let jvm = jni.get_java_vm()?;
+    let jvmti = JvmtiEnv::from_jvm(&jvm)?;
+    let result = (|| {
+        jvmti.require_capabilities(&[Capability::TagObjects])?;
+        build(&jvmti, jni)
+    })();
+
+    if let Err(err) = jvmti.dispose() {
+        warn!("failed to dispose dominator tree environment: {}", err);
+    }
+    result
+}
+
+fn build(jvmti: &JvmtiEnv, jni: JNIEnv) -> JvmtiResult<DominatorTree> {
+    // tag classes first so every object's class tag is known
+    let classes = jvmti.get_loaded_classes(jni)?;
+    let mut class_names = Vec::with_capacity(classes.len());
+    for (index, &class) in classes.iter().enumerate() {
+        jvmti.set_tag(*class, NonZeroJlong::new(index as jlong + 1))?;
+        let signature = jvmti.get_class_signature(class)?;
+        class_names.push(java_class_name(&mutf8_to_string(signature.as_bytes())));
+    }
+    drop(classes);
+    let class_count = class_names.len() as u64;
+
+    let mut sizes = vec![0u64; class_names.len() + 1];
+    let mut object_classes = vec![NONE; class_names.len() + 1];
+    let mut edges = Vec::new();
+    let mut root_kinds = HashMap::new();
+    jvmti.follow_references(HeapFilterFlags::empty(), None, None, |mut reference| {
+        let root = RootKind::of(&reference);
+        let tags = reference.tags_mut();
+        if *tags.tag == 0 {
+            *tags.tag = sizes.len() as jlong;
+            sizes.push(0);
+            object_classes.push(NONE);
+        }
+
+        let node = *tags.tag as usize;
+        sizes[node] = tags.size as u64;
+        object_classes[node] = match tags.class_tag {
+            Some(tag) if (tag.get() as u64) <= class_count => tag.get() as u32 - 1,
+            _ => NONE,
+        };
+
+        let referrer = match (root, &tags.referrer_tag) {
+            (Some(kind), _) => {
+                root_kinds.entry(node as u32).or_insert(kind);
+                Some(ROOT)
+            }
+            (None, Some(tag)) => Some(**tag as u32),
+            // to itself
+            (None, None) => None,
+        };
+        if let Some(referrer) = referrer {
+            edges.push((referrer, node as u32));
+        }
+
+        HeapVisitControlFlags::VISIT_OBJECTS
+    })?;
+
+    assert!(sizes.len() < NONE as usize, "too many objects");
+    debug!(
+        "reference graph has {} objects and {} references",
+        sizes.len() - 1,
+        edges.len()
+    );
+
+    let successors = Graph::new(sizes.len(), edges.iter().copied());
+    let predecessors = Graph::new(sizes.len(), edges.iter().map(|&(from, to)| (to, from)));
+    drop(edges);
+
+    let tree = DominatorTree::new(
+        &successors,
+        &predecessors,
+        &sizes,
+        &object_classes,
+        root_kinds,
+        class_names,
+    );
+    debug!("built dominator tree of {} objects", tree.object_count());
+    Ok(tree)
+}
+
+impl Graph {
+    fn new(node_count: usize, edges: impl Iterator<Item = (u32, u32)> + Clone) -> Self {
+        let mut offsets = vec![0u32; node_count + 1];
+        for (from, _) in edges.clone() {
+            offsets[from as usize + 1] += 1;
+        }
+        for i in 1..offsets.len() {
+            offsets[i] += offsets[i - 1];
+        }
+
+        let mut next = offsets.clone();
+        let mut targets = vec![0u32; offsets[node_count] as usize];
+        for (from, to) in edges {
+            let slot = &mut next[from as usize];
+            targets[*slot as usize] = to;
+            *slot += 1;
+        }
+
+        Self { offsets, targets }
+    }
+
+    fn edges(&self, node: u32) -> &[u32] {
+        let start = self.offsets[node as usize] as usize;
+        let end = self.offsets[node as usize + 1] as usize;
+        &self.targets[start..end]
+    }
+}
+
+impl DominatorTree {
+    fn new(
+        successors: &Graph,
+        predecessors: &Graph,
+        sizes: &[u64],
+        object_classes: &[u32],
+        root_kinds: HashMap<u32, RootKind>,
+        class_names: Vec<String>,
+    ) -> Self {
+        let node_count = sizes.len();
+
+        // number nodes in depth first order
+        let mut numbers = vec![NONE; node_count];
+        let mut nodes = Vec::with_capacity(node_count);
+        let mut parents = Vec::with_capacity(node_count);
+        let mut stack = vec![(ROOT, NONE)];
+        while let Some((node, parent)) = stack.pop() {
+            if numbers[node as usize] != NONE {
+                continue;
+            }
+
+            numbers[node as usize] = nodes.len() as u32;
+            nodes.push(node);
+            parents.push(parent);
+            let number = numbers[node as usize];
+            for &succ in successors.edges(node).iter().rev() {
+                if numbers[succ as usize] == NONE {
+                    stack.push((succ, number));
+                }
+            }
+        }
+
+        let dominators = lengauer_tarjan(&nodes, &numbers, &parents, predecessors);
+
+        let shallow_sizes: Vec<u64> = nodes.iter().map(|&node| sizes[node as usize]).collect();
+        let mut retained_sizes = shallow_sizes.clone();
+        for w in (1..nodes.len()).rev() {
+            retained_sizes[dominators[w] as usize] += retained_sizes[w];
+        }
+
+        // breadth first for shortest paths
+        let mut path_parents = vec![NONE; nodes.len()];
+        let mut queue = VecDeque::from(vec![ROOT]);
+        path_parents[ROOT as usize] = ROOT;
+        while let Some(v) = queue.pop_front() {
+            for &succ in successors.edges(nodes[v as usize]) {
+                let w = numbers[succ as usize];
+                if path_parents[w as usize] == NONE {
+                    path_parents[w as usize] = v;
+                    queue.push_back(w);
+                }
+            }
+        }
+
+        let classes = nodes
+            .iter()
+            .map(|&node| object_classes[node as usize])
+            .collect();
+        let root_kinds = root_kinds
+            .into_iter()
+            .filter(|(node, _)| numbers[*node as usize] != NONE)
+            .map(|(node, kind)| (numbers[node as usize], kind))
+            .collect();
+
+        Self {
+            nodes,
+            classes,
+            shallow_sizes,
+            retained_sizes,
+            dominators,
+            path_parents,
+            root_kinds,
+            class_names,
+        }
+    }
+
+    pub fn object_count(&self) -> usize {
+        self.nodes.len() - 1
+    }
+
+    /// Of all reachable objects
+    pub fn total_size(&self) -> u64 {
+        self.retained_sizes[ROOT as usize]
+    }
+
+    pub fn objects(&self) -> impl Iterator<Item = ObjectId> {
+        (1..self.nodes.len() as u32).map(ObjectId)
+    }
+
+    pub fn shallow_size(&self, object: ObjectId) -> u64 {
+        self.shallow_sizes[object.0 as usize]
+    }
+
+    pub fn retained_size(&self, object: ObjectId) -> u64 {
+        self.retained_sizes[object.0 as usize]
+    }
+
+    /// None if the object is only dominated by the heap roots as a whole
+    pub fn immediate_dominator(&self, object: ObjectId) -> Option<ObjectId> {
+        match self.dominators[object.0 as usize] {
+            ROOT => None,
+            dominator => Some(ObjectId(dominator)),
+        }
+    }
+
+    /// Class name, or `class <name>` for a class itself
+    pub fn describe(&self, object: ObjectId) -> String {
+        let node = self.nodes[object.0 as usize] as usize;
+        if node <= self.class_names.len() {
+            return format!("class {}", self.class_names[node - 1]);
+        }
+
+        match self.classes[object.0 as usize] {
+            NONE => "<unknown>".to_owned(),
+            class => self.class_names[class as usize].clone(),
+        }
+    }
+
+    pub fn path_to_root(&self, object: ObjectId) -> PathToRoot {
+        let mut objects = Vec::new();
+        let mut v = object.0;
+        let mut first = v;
+        while v != ROOT {
+            objects.push(self.describe(ObjectId(v)));
+            first = v;
+            v = self.path_parents[v as usize];
+        }
+        objects.reverse();
+
+        PathToRoot {
+            root: self
+                .root_kinds
+                .get(&first)
+                .copied()
+                .unwrap_or(RootKind::Other),
+            objects,
+        }
+    }
+
+    /// The objects with the largest retained sizes, largest first
+    pub fn top_objects(&self, limit: usize) -> Vec<RetainedObject> {
+        let mut objects: Vec<ObjectId> = self.objects().collect();
+        objects.sort_by_key(|&obj| Reverse(self.retained_size(obj)));
+        objects
+            .into_iter()
+            .take(limit)
+            .map(|id| RetainedObject {
+                id,
+                description: self.describe(id),
+                shallow_size: self.shallow_size(id),
+                retained_size: self.retained_size(id),
+                path_to_root: self.path_to_root(id),
+            })
+            .collect()
+    }
+
+    /// The classes whose instances retain the most, largest first
+    pub fn top_classes(&self, limit: usize) -> Vec<RetainedClass> {
+        let mut classes: Vec<RetainedClass> = self
+            .class_names
+            .iter()
+            .map(|name| RetainedClass {
+                class_name: name.clone(),
+                instances: 0,
+                shallow_size: 0,
+                retained_size: 0,
+            })
+            .collect();
+
+        // children of each object in the dominator tree
+        let mut offsets = vec![0u32; self.nodes.len() + 1];
+        for &dominator in &self.dominators[1..] {
+            offsets[dominator as usize + 1] += 1;
+        }
+        for i in 1..offsets.len() {
+            offsets[i] += offsets[i - 1];
+        }
+        let mut next = offsets.clone();
+        let mut children = vec![0u32; self.nodes.len() - 1];
+        for (w, &dominator) in self.dominators.iter().enumerate().skip(1) {
+            let slot = &mut next[dominator as usize];
+            children[*slot as usize] = w as u32;
+            *slot += 1;
+        }
+
+        // instances of each class on the current path from the root
+        let mut on_path = vec![0u32; classes.len()];
+        let mut stack = vec![(ROOT, false)];
+        while let Some((v, exiting)) = stack.pop() {
+            let class = self.classes[v as usize];
+            let counted = class != NONE && v != ROOT;
+            if exiting {
+                if counted {
+                    on_path[class as usize] -= 1;
+                }
+                continue;
+            }
+
+            if counted {
+                let entry = &mut classes[class as usize];
+                entry.instances += 1;
+                entry.shallow_size += self.shallow_sizes[v as usize];
+                if on_path[class as usize] == 0 {
+                    entry.retained_size += self.retained_sizes[v as usize];
+                }
+                on_path[class as usize] += 1;
+            }
+
+            stack.push((v, true));
+            let start = offsets[v as usize] as usize;
+            let end = offsets[v as usize + 1] as usize;
+            stack.extend(children[start..end].iter().map(|&child| (child, false)));
+        }
+
+        classes.retain(|class| class.instances > 0);
+        classes.sort_by(|a, b| {
+            b.retained_size
+                .cmp(&a.retained_size)
+                .then_with(|| a.class_name.cmp(&b.class_name))
+        });
+        classes.truncate(limit);
+        classes
+    }
+
+    pub fn leak_report(&self, limit: usize) -> LeakReport {
+        LeakReport {
+            total_size: self.total_size(),
+            objects: self.top_objects(limit),
+            classes: self.top_classes(limit),
+        }
+    }
+}
+
+/// Immediate dominators by DFS number, using the simple version of Lengauer and Tarjan's
+/// algorithm with path compression. The root is its own dominator
+fn lengauer_tarjan(nodes: &[u32], numbers: &[u32], parents: &[u32], preds: &Graph) -> Vec<u32> {
+    let n = nodes.len();
+    let mut semi: Vec<u32> = (0..n as u32).collect();
+    let mut label = semi.clone();
+    let mut ancestor = vec![NONE; n];
+    let mut dominators = vec![ROOT; n];
+    let mut bucket_heads = vec![NONE; n];
+    let mut bucket_next = vec![NONE; n];
+    let mut path = Vec::new();
+
+    let mut eval = |v: u32, ancestor: &mut [u32], label: &mut [u32], semi: &[u32]| -> u32 {
+        if ancestor[v as usize] == NONE {
+            return v;
+        }
+
+        // compress the path to the root of v's tree in the forest
+        let mut x = v;
+        while ancestor[ancestor[x as usize] as usize] != NONE {
+            path.push(x);
+            x = ancestor[x as usize];
+        }
+        while let Some(x) = path.pop() {
+            let a = ancestor[x as usize] as usize;
+            if semi[label[a] as usize] < semi[label[x as usize] as usize] {
+                label[x as usize] = label[a];
+            }
+            ancestor[x as usize] = ancestor[a];
+        }
+        label[v as usize]
+    };
+
+    for w in (1..n).rev() {
+        for &pred in preds.edges(nodes[w]) {
+            let v = numbers[pred as usize];
+            if v == NONE {
+                continue;
+            }
+            let u = eval(v, &mut ancestor, &mut label, &semi);
+            if semi[u as usize] < semi[w] {
+                semi[w] = semi[u as usize];
+            }
+        }
+
+        let s = semi[w] as usize;
+        bucket_next[w] = bucket_heads[s];
+        bucket_heads[s] = w as u32;
+
+        let parent = parents[w];
+        ancestor[w] = parent;
+
+        let mut v = std::mem::replace(&mut bucket_heads[parent as usize], NONE);
+        while v != NONE {
+            let u = eval(v, &mut ancestor, &mut label, &semi);
+            dominators[v as usize] = if semi[u as usize] < semi[v as usize] {
+                u
+            } else {
+                parent
+            };
+            v = bucket_next[v as usize];
+        }
+    }
+
+    for w in 1..n {
+        if dominators[w] != semi[w] {
+            dominators[w] = dominators[dominators[w] as usize];
+        }
+    }
+    dominators
+}
+
+impl RootKind {
+    fn of(reference: &HeapReference) -> Option<Self> {
+        Some(match reference {
+            HeapReference::JniGlobal(_) => Self::JniGlobal,
+            HeapReference::SystemClass(_) => Self::SystemClass,
+            HeapReference::Monitor(_) => Self::Monitor,
+            HeapReference::StackLocal { .. } => Self::StackLocal,
+            HeapReference::JniLocal { .. } => Self::JniLocal,
+            HeapReference::Thread(_) => Self::Thread,
+            HeapReference::Other(_) => Self::Other,
+            _ => return None,
+        })
+    }
+}
+
+impl Display for RootKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::JniGlobal => "JNI global",
+            Self::SystemClass => "system class",
+            Self::Monitor => "busy monitor",
+            Self::StackLocal => "Java local",
+            Self::JniLocal => "JNI local",
+            Self::Thread => "thread",
+            Self::Other => "unknown",
+        };
+        f.write_str(name)
+    }
+}
+
+/// e.g. `JNI local -> java.util.ArrayList -> java.lang.Object[]`
+impl Display for PathToRoot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.root)?;
+        for object in &self.objects {
+            write!(f, " -> {}", object)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for LeakReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Reachable heap: {} bytes", self.total_size)?;
+        writeln!(f)?;
+        writeln!(f, "Biggest objects by retained size:")?;
+        writeln!(f, " num      #retained        #shallow  object")?;
+        for (i, object) in self.objects.iter().enumerate() {
+            writeln!(
+                f,
+                "{:4}: {:14} {:15}  {}",
+                i + 1,
+                object.retained_size,
+                object.shallow_size,
+                object.description
+            )?;
+            writeln!(f, "      via {}", object.path_to_root)?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "Biggest classes by retained size:")?;
+        writeln!(f, " num      #retained     #instances  class name")?;
+        for (i, class) in self.classes.iter().enumerate() {
+            writeln!(
+                f,
+                "{:4}: {:14} {:14}  {}",
+                i + 1,
+                class.retained_size,
+                class.instances,
+                class.class_name
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -1,36 +1,61 @@
 use core::ptr::null_mut;
 
 use jni::errors::jni_error_code_to_result;
-use jni::sys::{jchar, jclass, jint, jlong, jobject, jvalue};
+use jni::sys::{jboolean, jchar, jclass, jfieldID, jint, jlong, jobject, jvalue, JNI_TRUE};
 use jni::JavaVM;
 
+use crate::capability::Capability;
 use crate::event::{EventCallbacks, EventScope, EventType};
+use crate::handles::{register_vm, Class, Field, Location, Method, Thread};
 use crate::heap::{
-    FieldType, HeapFilterFlags, HeapIterationCallback, HeapVisitControlFlags, NonZeroJlong,
-    PrimitiveArray, U16StrPrintable,
+    FieldType, HeapFilterFlags, HeapIterationCallback, HeapReference, HeapVisitControlFlags,
+    HeapWalkCallback, NonZeroJlong, PrimitiveArray, ReferenceTags, U16StrPrintable,
 };
-use crate::memory::{AllocatedArray, AllocatedMutf8, LocalRef};
+use crate::memory::{
+    AllocatedArray, AllocatedMutf8, LocalClass, LocalRef, LocalThread, Tag, TaggedObjects,
+};
+use crate::monitor::{MonitorStackDepth, MonitorUsage};
+use crate::redefine::ClassDefinition;
+use crate::stack::StackInfo;
+use crate::thread::{ThreadGroupInfo, ThreadInfo, ThreadState};
+use crate::timer::{nanos_to_duration, TimerInfo};
 use crate::util::*;
 use core::ffi::c_void;
-use jni::objects::JValue;
+use jni::objects::{JFieldID, JObject, JValue};
 use jni_jvmti_sys::jvmtiEventMode::{JVMTI_DISABLE, JVMTI_ENABLE};
 use jni_jvmti_sys::{
-    jvmtiCapabilities, jvmtiEnv, jvmtiEventCallbacks, jvmtiHeapCallbacks, jvmtiHeapReferenceInfo,
-    jvmtiHeapReferenceKind, jvmtiInterface_1_, jvmtiPrimitiveType, JVMTI_VERSION_1_1,
+    jrawMonitorID, jthread, jthreadGroup, jvmtiCapabilities, jvmtiClassDefinition, jvmtiEnv,
+    jvmtiEventCallbacks, jvmtiHeapCallbacks, jvmtiHeapReferenceInfo, jvmtiHeapReferenceKind,
+    jvmtiInterface_1_, jvmtiLineNumberEntry, jvmtiMonitorStackDepthInfo, jvmtiMonitorUsage,
+    jvmtiPrimitiveType, jvmtiStackInfo, jvmtiStartFunction, jvmtiThreadGroupInfo, jvmtiThreadInfo,
+    jvmtiTimerInfo, JVMTI_VERSION_1_1,
 };
 use std::marker::PhantomData;
 use std::mem::MaybeUninit;
 
 use std::convert::TryFrom;
+use std::ffi::CString;
 use std::os::raw::c_char;
+use std::sync::Mutex;
+use std::time::Duration;
 use widestring::U16Str;
 
+/// The VM's default heap sampling interval, 512KiB
+pub(crate) const DEFAULT_HEAP_SAMPLING_INTERVAL: jint = 512 * 1024;
+
+/// The heap sampling interval last set through this crate, as JVMTI cannot read it back
+static HEAP_SAMPLING_INTERVAL: Mutex<jint> = Mutex::new(DEFAULT_HEAP_SAMPLING_INTERVAL);
+
 /// Shared across threads.
 /// TODO how to dispose via RAII?
 #[derive(Clone)]
 #[repr(transparent)]
 pub struct JvmtiEnv<'a>(*mut jvmtiEnv, PhantomData<&'a ()>);
 
+// unlike JNIEnv, the environment pointer is not thread-local
+unsafe impl Send for JvmtiEnv<'_> {}
+unsafe impl Sync for JvmtiEnv<'_> {}
+
 // TODO expose a low-level direct api to jvmti, then a higher-level api for ergonomic use
 //  e.g. capability builder that checks potential and automatically relinquishes/requests
 //  direct(*mut jvmtiEnv), ergonomic(direct), ergonomic.into_inner()
@@ -58,6 +83,7 @@ impl<'a> JvmtiEnv<'a> {
 
         assert!(!jvmti_ptr.is_null());
         debug!("got jvmtiEnv ptr {:?}", jvmti_ptr);
+        register_vm(jvm_ptr);
         Ok(Self(jvmti_ptr as *mut jvmtiEnv, PhantomData))
     }
 
@@ -82,6 +108,15 @@ impl<'a> JvmtiEnv<'a> {
         self.set_event_enabled(ty, scope, false)
     }
 
+    /// Sends events for the current state of the VM, only supported for `CompiledMethodLoad`
+    /// and `DynamicCodeGenerated`, e.g. for code generated before the agent attached. Only this
+    /// environment receives them
+    pub fn generate_events(&self, ty: EventType) -> JvmtiResult<()> {
+        jvmti_method!(self, GenerateEvents, ty.into());
+        debug!("generated events of type {:?}", ty);
+        Ok(())
+    }
+
     pub fn set_event_enabled(
         &self,
         ty: EventType,
@@ -95,7 +130,7 @@ impl<'a> JvmtiEnv<'a> {
             ty.into(),
             match scope {
                 EventScope::Global => null_mut(),
-                EventScope::Thread(thread) => thread,
+                EventScope::Thread(thread) => thread.into_inner(),
             }
         );
         debug!(
@@ -111,18 +146,605 @@ impl<'a> JvmtiEnv<'a> {
     pub fn get_loaded_classes<'b>(
         &'b self,
         jni: jni::JNIEnv<'b>,
-    ) -> JvmtiResult<AllocatedArray<'b, LocalRef>> {
+    ) -> JvmtiResult<AllocatedArray<'b, LocalClass<'b>>> {
         let mut count: jint = 0;
-        let mut classes: *mut jclass = null_mut();
+        let mut classes: *mut Class<'b> = null_mut();
         jvmti_method!(
             self,
             GetLoadedClasses,
             &mut count as *mut jint,
-            &mut classes as *mut *mut jclass
+            &mut classes as *mut *mut Class as *mut *mut jclass
         );
         debug!("got {} loaded classes", count);
 
-        Ok(unsafe { AllocatedArray::<LocalRef>::new(classes, count as usize, jni, self.clone()) })
+        Ok(
+            unsafe {
+                AllocatedArray::<LocalClass>::new(classes, count as usize, jni, self.clone())
+            },
+        )
+    }
+
+    pub fn get_all_threads<'b>(
+        &'b self,
+        jni: jni::JNIEnv<'b>,
+    ) -> JvmtiResult<AllocatedArray<'b, LocalThread<'b>>> {
+        let mut count: jint = 0;
+        let mut threads: *mut Thread<'b> = null_mut();
+        jvmti_method!(
+            self,
+            GetAllThreads,
+            &mut count as *mut jint,
+            &mut threads as *mut *mut Thread as *mut *mut jthread
+        );
+        debug!("got {} live threads", count);
+
+        Ok(unsafe {
+            AllocatedArray::<LocalThread>::new(threads, count as usize, jni, self.clone())
+        })
+    }
+
+    /// Object references in the result are local refs owned by the caller
+    pub fn get_thread_info<'b>(
+        &self,
+        _jni: jni::JNIEnv<'b>,
+        thread: Thread,
+    ) -> JvmtiResult<ThreadInfo<'b>> {
+        let mut info = MaybeUninit::<jvmtiThreadInfo>::zeroed();
+        jvmti_method!(self, GetThreadInfo, thread.into_inner(), info.as_mut_ptr());
+        let info = unsafe { info.assume_init() };
+
+        Ok(ThreadInfo {
+            name: unsafe { self.take_string(info.name) },
+            priority: info.priority,
+            is_daemon: info.is_daemon == JNI_TRUE,
+            thread_group: JObject::from(info.thread_group),
+            context_class_loader: JObject::from(info.context_class_loader),
+        })
+    }
+
+    pub fn get_top_thread_groups<'b>(
+        &'b self,
+        jni: jni::JNIEnv<'b>,
+    ) -> JvmtiResult<AllocatedArray<'b, LocalRef>> {
+        let mut count: jint = 0;
+        let mut groups: *mut jthreadGroup = null_mut();
+        jvmti_method!(
+            self,
+            GetTopThreadGroups,
+            &mut count as *mut jint,
+            &mut groups as *mut *mut jthreadGroup
+        );
+        debug!("got {} top thread groups", count);
+
+        Ok(unsafe { AllocatedArray::<LocalRef>::new(groups, count as usize, jni, self.clone()) })
+    }
+
+    /// The parent in the result is a local ref owned by the caller
+    pub fn get_thread_group_info<'b>(
+        &self,
+        _jni: jni::JNIEnv<'b>,
+        group: JObject,
+    ) -> JvmtiResult<ThreadGroupInfo<'b>> {
+        let mut info = MaybeUninit::<jvmtiThreadGroupInfo>::zeroed();
+        jvmti_method!(
+            self,
+            GetThreadGroupInfo,
+            group.into_inner(),
+            info.as_mut_ptr()
+        );
+        let info = unsafe { info.assume_init() };
+
+        Ok(ThreadGroupInfo {
+            parent: JObject::from(info.parent),
+            name: unsafe { self.take_string(info.name) },
+            max_priority: info.max_priority,
+            is_daemon: info.is_daemon == JNI_TRUE,
+        })
+    }
+
+    /// Returns (threads, groups)
+    #[allow(clippy::type_complexity)]
+    pub fn get_thread_group_children<'b>(
+        &'b self,
+        jni: jni::JNIEnv<'b>,
+        group: JObject,
+    ) -> JvmtiResult<(
+        AllocatedArray<'b, LocalThread<'b>>,
+        AllocatedArray<'b, LocalRef>,
+    )> {
+        let mut thread_count: jint = 0;
+        let mut threads: *mut Thread<'b> = null_mut();
+        let mut group_count: jint = 0;
+        let mut groups: *mut jthreadGroup = null_mut();
+        jvmti_method!(
+            self,
+            GetThreadGroupChildren,
+            group.into_inner(),
+            &mut thread_count as *mut jint,
+            &mut threads as *mut *mut Thread as *mut *mut jthread,
+            &mut group_count as *mut jint,
+            &mut groups as *mut *mut jthreadGroup
+        );
+
+        Ok(unsafe {
+            (
+                AllocatedArray::<LocalThread>::new(
+                    threads,
+                    thread_count as usize,
+                    jni,
+                    self.clone(),
+                ),
+                AllocatedArray::<LocalRef>::new(groups, group_count as usize, jni, self.clone()),
+            )
+        })
+    }
+
+    pub fn get_current_thread<'b>(&self, _jni: jni::JNIEnv<'b>) -> JvmtiResult<Thread<'b>> {
+        let mut thread: jthread = null_mut();
+        jvmti_method!(self, GetCurrentThread, &mut thread as *mut jthread);
+        Ok(Thread::from(thread))
+    }
+
+    /// Requests `can_get_owned_monitor_info` if not already possessed
+    pub fn get_owned_monitor_info<'b>(
+        &'b self,
+        jni: jni::JNIEnv<'b>,
+        thread: Thread,
+    ) -> JvmtiResult<AllocatedArray<'b, LocalRef>> {
+        self.require_capabilities(&[Capability::GetOwnedMonitorInfo])?;
+
+        let mut count: jint = 0;
+        let mut monitors: *mut jobject = null_mut();
+        jvmti_method!(
+            self,
+            GetOwnedMonitorInfo,
+            thread.into_inner(),
+            &mut count as *mut jint,
+            &mut monitors as *mut *mut jobject
+        );
+
+        Ok(unsafe { AllocatedArray::<LocalRef>::new(monitors, count as usize, jni, self.clone()) })
+    }
+
+    /// Requests `can_get_owned_monitor_stack_depth_info` if not already possessed. Depth is -1 for
+    /// monitors entered through JNI
+    pub fn get_owned_monitor_stack_depth_info<'b>(
+        &'b self,
+        jni: jni::JNIEnv<'b>,
+        thread: Thread,
+    ) -> JvmtiResult<AllocatedArray<'b, MonitorStackDepth>> {
+        self.require_capabilities(&[Capability::GetOwnedMonitorStackDepthInfo])?;
+
+        let mut count: jint = 0;
+        let mut infos: *mut jvmtiMonitorStackDepthInfo = null_mut();
+        jvmti_method!(
+            self,
+            GetOwnedMonitorStackDepthInfo,
+            thread.into_inner(),
+            &mut count as *mut jint,
+            &mut infos as *mut *mut jvmtiMonitorStackDepthInfo
+        );
+
+        Ok(unsafe {
+            AllocatedArray::<MonitorStackDepth>::new(infos, count as usize, jni, self.clone())
+        })
+    }
+
+    /// Requests `can_get_current_contended_monitor` if not already possessed. Returns the monitor
+    /// the thread is waiting to enter or waiting on with `Object.wait`, as a local ref
+    pub fn get_current_contended_monitor<'b>(
+        &self,
+        _jni: jni::JNIEnv<'b>,
+        thread: Thread,
+    ) -> JvmtiResult<Option<JObject<'b>>> {
+        self.require_capabilities(&[Capability::GetCurrentContendedMonitor])?;
+
+        let mut monitor: jobject = null_mut();
+        jvmti_method!(
+            self,
+            GetCurrentContendedMonitor,
+            thread.into_inner(),
+            &mut monitor as *mut jobject
+        );
+
+        Ok(if monitor.is_null() {
+            None
+        } else {
+            Some(JObject::from(monitor))
+        })
+    }
+
+    /// Requests `can_get_monitor_info` if not already possessed
+    pub fn get_object_monitor_usage<'b>(
+        &self,
+        _jni: jni::JNIEnv<'b>,
+        object: JObject,
+    ) -> JvmtiResult<MonitorUsage<'b>> {
+        self.require_capabilities(&[Capability::GetMonitorInfo])?;
+
+        let mut usage = MaybeUninit::<jvmtiMonitorUsage>::zeroed();
+        jvmti_method!(
+            self,
+            GetObjectMonitorUsage,
+            object.into_inner(),
+            usage.as_mut_ptr()
+        );
+        let usage = unsafe { usage.assume_init() };
+
+        let take_threads = |ptr: *mut jthread, count: jint| -> Vec<Thread<'b>> {
+            if ptr.is_null() {
+                return Vec::new();
+            }
+
+            let threads = unsafe { std::slice::from_raw_parts(ptr, count as usize) }
+                .iter()
+                .map(|t| Thread::from(*t))
+                .collect();
+
+            // the local refs are handed over to the caller
+            if let Err(err) = unsafe { self.deallocate(ptr as *mut ()) } {
+                error!("failed to deallocate monitor waiters: {}", err);
+            }
+            threads
+        };
+
+        Ok(MonitorUsage {
+            owner: if usage.owner.is_null() {
+                None
+            } else {
+                Some(Thread::from(usage.owner))
+            },
+            entry_count: usage.entry_count,
+            waiters: take_threads(usage.waiters, usage.waiter_count),
+            notify_waiters: take_threads(usage.notify_waiters, usage.notify_waiter_count),
+        })
+    }
+
+    /// Stable for the lifetime of the object, unlike its address
+    pub fn get_object_hash_code(&self, object: JObject) -> JvmtiResult<jint> {
+        let mut hash: jint = 0;
+        jvmti_method!(
+            self,
+            GetObjectHashCode,
+            object.into_inner(),
+            &mut hash as *mut jint
+        );
+        Ok(hash)
+    }
+
+    pub fn get_thread_state(&self, thread: Thread) -> JvmtiResult<ThreadState> {
+        let mut state: jint = 0;
+        jvmti_method!(
+            self,
+            GetThreadState,
+            thread.into_inner(),
+            &mut state as *mut jint
+        );
+        Ok(ThreadState::from_bits_truncate(state))
+    }
+
+    /// Up to `max_frames` frames starting at `start_depth`, where 0 is the current frame
+    pub fn get_stack_trace(
+        &self,
+        thread: Thread,
+        start_depth: jint,
+        max_frames: usize,
+    ) -> JvmtiResult<Vec<Location<'a>>> {
+        let mut frames: Vec<Location> = Vec::with_capacity(max_frames);
+        let mut count: jint = 0;
+        jvmti_method!(
+            self,
+            GetStackTrace,
+            thread.into_inner(),
+            start_depth,
+            jint::try_from(max_frames).expect("too many frames"),
+            // same layout as jvmtiFrameInfo
+            frames.as_mut_ptr() as *mut _,
+            &mut count as *mut jint
+        );
+
+        unsafe { frames.set_len(count as usize) };
+        Ok(frames)
+    }
+
+    /// Up to `max_frames` frames of every live thread from the top of its stack, sampled
+    /// atomically. Thread references in the result are local refs owned by the caller
+    pub fn get_all_stack_traces<'b>(
+        &self,
+        _jni: jni::JNIEnv<'b>,
+        max_frames: usize,
+    ) -> JvmtiResult<Vec<StackInfo<'b>>> {
+        let mut infos: *mut jvmtiStackInfo = null_mut();
+        let mut count: jint = 0;
+        jvmti_method!(
+            self,
+            GetAllStackTraces,
+            jint::try_from(max_frames).expect("too many frames"),
+            &mut infos as *mut *mut jvmtiStackInfo,
+            &mut count as *mut jint
+        );
+        unsafe { self.take_stack_infos(infos, count) }
+    }
+
+    /// As [get_all_stack_traces](Self::get_all_stack_traces) but for the given threads, in the
+    /// same order. Threads in the result are the given references
+    pub fn get_thread_list_stack_traces<'b>(
+        &self,
+        threads: &[Thread<'b>],
+        max_frames: usize,
+    ) -> JvmtiResult<Vec<StackInfo<'b>>> {
+        let count = jint::try_from(threads.len()).expect("too many threads");
+        let mut infos: *mut jvmtiStackInfo = null_mut();
+        jvmti_method!(
+            self,
+            GetThreadListStackTraces,
+            count,
+            threads.as_ptr() as *const jthread,
+            jint::try_from(max_frames).expect("too many frames"),
+            &mut infos as *mut *mut jvmtiStackInfo
+        );
+        unsafe { self.take_stack_infos(infos, count) }
+    }
+
+    /// Copies out and deallocates the single allocation holding the infos and their frames
+    unsafe fn take_stack_infos<'b>(
+        &self,
+        infos: *mut jvmtiStackInfo,
+        count: jint,
+    ) -> JvmtiResult<Vec<StackInfo<'b>>> {
+        if infos.is_null() {
+            return Ok(Vec::new());
+        }
+
+        let result = std::slice::from_raw_parts(infos, count as usize)
+            .iter()
+            .map(|info| StackInfo {
+                thread: Thread::from(info.thread),
+                state: ThreadState::from_bits_truncate(info.state),
+                frames: if info.frame_buffer.is_null() {
+                    Vec::new()
+                } else {
+                    // same layout as jvmtiFrameInfo
+                    std::slice::from_raw_parts(
+                        info.frame_buffer as *const Location,
+                        info.frame_count as usize,
+                    )
+                    .to_vec()
+                },
+            })
+            .collect();
+        self.deallocate(infos as *mut ())?;
+        Ok(result)
+    }
+
+    /// Name and signature
+    pub fn get_method_name(
+        &self,
+        method: Method,
+    ) -> JvmtiResult<(AllocatedMutf8<'_>, AllocatedMutf8<'_>)> {
+        let mut name: *mut c_char = null_mut();
+        let mut signature: *mut c_char = null_mut();
+        jvmti_method!(
+            self,
+            GetMethodName,
+            method.into_inner(),
+            (&mut name) as *mut *mut c_char,
+            (&mut signature) as *mut *mut c_char,
+            null_mut()
+        );
+
+        Ok(unsafe {
+            (
+                AllocatedMutf8::new(name, self.clone()),
+                AllocatedMutf8::new(signature, self.clone()),
+            )
+        })
+    }
+
+    /// The class is a local ref owned by the caller
+    pub fn get_method_declaring_class<'b>(
+        &self,
+        _jni: jni::JNIEnv<'b>,
+        method: Method,
+    ) -> JvmtiResult<Class<'b>> {
+        let mut class: jclass = null_mut();
+        jvmti_method!(
+            self,
+            GetMethodDeclaringClass,
+            method.into_inner(),
+            &mut class as *mut jclass
+        );
+        Ok(Class::from(class))
+    }
+
+    /// Name and signature
+    pub fn get_field_name(
+        &self,
+        field: Field,
+    ) -> JvmtiResult<(AllocatedMutf8<'_>, AllocatedMutf8<'_>)> {
+        let mut name: *mut c_char = null_mut();
+        let mut signature: *mut c_char = null_mut();
+        jvmti_method!(
+            self,
+            GetFieldName,
+            field.class.into_inner(),
+            field.id.into_inner(),
+            (&mut name) as *mut *mut c_char,
+            (&mut signature) as *mut *mut c_char,
+            null_mut()
+        );
+
+        Ok(unsafe {
+            (
+                AllocatedMutf8::new(name, self.clone()),
+                AllocatedMutf8::new(signature, self.clone()),
+            )
+        })
+    }
+
+    /// The class declaring the field, which may be a superclass of [Field::class]. The class is a
+    /// local ref owned by the caller
+    pub fn get_field_declaring_class<'b>(
+        &self,
+        _jni: jni::JNIEnv<'b>,
+        field: Field,
+    ) -> JvmtiResult<Class<'b>> {
+        let mut class: jclass = null_mut();
+        jvmti_method!(
+            self,
+            GetFieldDeclaringClass,
+            field.class.into_inner(),
+            field.id.into_inner(),
+            &mut class as *mut jclass
+        );
+        Ok(Class::from(class))
+    }
+
+    /// Access flags of the field, as in `java.lang.reflect.Modifier`
+    pub fn get_field_modifiers(&self, field: Field) -> JvmtiResult<jint> {
+        let mut modifiers: jint = 0;
+        jvmti_method!(
+            self,
+            GetFieldModifiers,
+            field.class.into_inner(),
+            field.id.into_inner(),
+            &mut modifiers as *mut jint
+        );
+        Ok(modifiers)
+    }
+
+    /// Fields declared by the class itself, excluding those inherited. Fails with
+    /// [JvmtiError::ClassNotPrepared] if the class is not yet prepared
+    pub fn get_class_fields<'b>(&self, class: Class<'b>) -> JvmtiResult<Vec<Field<'b>>> {
+        let mut count: jint = 0;
+        let mut fields: *mut jfieldID = null_mut();
+        jvmti_method!(
+            self,
+            GetClassFields,
+            class.into_inner(),
+            &mut count as *mut jint,
+            &mut fields as *mut *mut jfieldID
+        );
+
+        if fields.is_null() {
+            return Ok(Vec::new());
+        }
+
+        let ids = unsafe { std::slice::from_raw_parts(fields, count as usize) };
+        let fields = ids
+            .iter()
+            .map(|&id| Field::new(class, JFieldID::from(id)))
+            .collect();
+        unsafe { self.deallocate(ids.as_ptr() as *mut ()) }?;
+        Ok(fields)
+    }
+
+    /// Interfaces the class or interface directly declares that it implements or extends, as
+    /// local refs. Fails with [JvmtiError::ClassNotPrepared] if the class is not yet prepared
+    pub fn get_implemented_interfaces<'b>(
+        &'b self,
+        jni: jni::JNIEnv<'b>,
+        class: Class,
+    ) -> JvmtiResult<AllocatedArray<'b, LocalClass<'b>>> {
+        let mut count: jint = 0;
+        let mut interfaces: *mut Class<'b> = null_mut();
+        jvmti_method!(
+            self,
+            GetImplementedInterfaces,
+            class.into_inner(),
+            &mut count as *mut jint,
+            &mut interfaces as *mut *mut Class as *mut *mut jclass
+        );
+
+        Ok(unsafe {
+            AllocatedArray::<LocalClass>::new(interfaces, count as usize, jni, self.clone())
+        })
+    }
+
+    /// None for classes loaded by the bootstrap class loader. The loader is a local ref owned by
+    /// the caller
+    pub fn get_class_loader<'b>(
+        &self,
+        _jni: jni::JNIEnv<'b>,
+        class: Class,
+    ) -> JvmtiResult<Option<JObject<'b>>> {
+        let mut loader: jobject = null_mut();
+        jvmti_method!(
+            self,
+            GetClassLoader,
+            class.into_inner(),
+            &mut loader as *mut jobject
+        );
+        Ok(if loader.is_null() {
+            None
+        } else {
+            Some(JObject::from(loader))
+        })
+    }
+
+    /// Requests `can_get_line_numbers` if not already possessed. Fails with
+    /// [JvmtiError::AbsentInformation] if the class was compiled without line numbers
+    pub fn get_line_number_table(&self, method: Method) -> JvmtiResult<Vec<jvmtiLineNumberEntry>> {
+        self.require_capabilities(&[Capability::GetLineNumbers])?;
+
+        let mut count: jint = 0;
+        let mut table: *mut jvmtiLineNumberEntry = null_mut();
+        jvmti_method!(
+            self,
+            GetLineNumberTable,
+            method.into_inner(),
+            &mut count as *mut jint,
+            &mut table as *mut *mut jvmtiLineNumberEntry
+        );
+
+        if table.is_null() {
+            return Ok(Vec::new());
+        }
+
+        let entries = unsafe { std::slice::from_raw_parts(table, count as usize) }.to_vec();
+        unsafe { self.deallocate(table as *mut ()) }?;
+        Ok(entries)
+    }
+
+    /// Requests `can_get_source_file_name` if not already possessed. None if the class has no
+    /// `SourceFile` attribute
+    pub fn get_source_file_name(&self, class: Class) -> JvmtiResult<Option<AllocatedMutf8<'_>>> {
+        self.require_capabilities(&[Capability::GetSourceFileName])?;
+
+        absent_as_none((|| {
+            let mut name: *mut c_char = null_mut();
+            jvmti_method!(
+                self,
+                GetSourceFileName,
+                class.into_inner(),
+                (&mut name) as *mut *mut c_char
+            );
+            Ok(unsafe { AllocatedMutf8::new(name, self.clone()) })
+        })())
+    }
+
+    /// Starts a daemon agent thread running `start_fn` on the given `java.lang.Thread`, see
+    /// [crate::AgentThreadBuilder] for a safe interface
+    ///
+    /// # Safety
+    /// `arg` must be valid for however `start_fn` uses it
+    pub unsafe fn run_agent_thread(
+        &self,
+        thread: Thread,
+        start_fn: jvmtiStartFunction,
+        arg: *const c_void,
+        priority: jint,
+    ) -> JvmtiResult<()> {
+        jvmti_method!(
+            self,
+            RunAgentThread,
+            thread.into_inner(),
+            start_fn,
+            arg,
+            priority
+        );
+        debug!("started agent thread with priority {}", priority);
+        Ok(())
     }
 
     pub fn get_potential_capabilities(&self) -> JvmtiResult<jvmtiCapabilities> {
@@ -161,138 +783,38 @@ impl<'a> JvmtiEnv<'a> {
         Ok(())
     }
 
-    pub fn iterate_through_heap(
-        &self,
-        heap_filter: HeapFilterFlags,
-        instanceof: Option<jclass>,
-        mut callback: impl FnMut(HeapIterationCallback) -> HeapVisitControlFlags,
-    ) -> JvmtiResult<()> {
-        unsafe extern "C" fn heap_iteration_callback(
-            class_tag: jlong,
-            size: jlong,
-            tag_ptr: *mut jlong,
-            length: jint,
-            user_data: *mut c_void,
-        ) -> jint {
-            let closure: &mut &mut dyn FnMut(HeapIterationCallback) -> HeapVisitControlFlags =
-                &mut *(user_data as *mut &mut _);
-
-            let class_tag = NonZeroJlong::new(class_tag);
-            debug_assert!(size >= 0);
-            let tag = tag_ptr.as_mut().expect("tag pointer is null");
-            let array_length = if length < 0 {
-                None
-            } else {
-                Some(length as usize)
-            };
-            let arg = HeapIterationCallback::Object {
-                class_tag,
-                size: size as usize,
-                tag,
-                array_length,
-            };
-            closure(arg).bits()
+    /// Adds any of the given capabilities that are not already possessed, failing if any are not
+    /// potentially available
+    pub fn require_capabilities(&self, capabilities: &[Capability]) -> JvmtiResult<()> {
+        let active = self.get_capabilities()?;
+        let mut missing = jvmtiCapabilities::default();
+        let mut any_missing = false;
+        for capability in capabilities.iter().filter(|c| !c.is_set(&active)) {
+            capability.set(&mut missing);
+            any_missing = true;
         }
 
-        unsafe extern "C" fn primitive_field_callback(
-            kind: jvmtiHeapReferenceKind,
-            info: *const jvmtiHeapReferenceInfo,
-            object_class_tag: jlong,
-            object_tag_ptr: *mut jlong,
-            value: jvalue,
-            value_type: jvmtiPrimitiveType,
-            user_data: *mut ::core::ffi::c_void,
-        ) -> jint {
-            use jvmtiHeapReferenceKind::*;
-            use jvmtiPrimitiveType::*;
-
-            let closure: &mut &mut dyn FnMut(HeapIterationCallback) -> HeapVisitControlFlags =
-                &mut *(user_data as *mut &mut _);
-
-            let field_type = match kind {
-                JVMTI_HEAP_REFERENCE_FIELD => FieldType::Instance,
-                JVMTI_HEAP_REFERENCE_STATIC_FIELD => FieldType::Static,
-                _ => unreachable!("unexpected field kind {:?}", kind),
-            };
-
-            let field_index = (*info).field.index;
-            let object_class_tag = NonZeroJlong::new(object_class_tag);
-            let object_tag = object_tag_ptr.as_mut().expect("tag pointer is null");
-            let value = match value_type {
-                JVMTI_PRIMITIVE_TYPE_BOOLEAN => JValue::Bool(value.z),
-                JVMTI_PRIMITIVE_TYPE_BYTE => JValue::Byte(value.b),
-                JVMTI_PRIMITIVE_TYPE_CHAR => JValue::Char(value.c),
-                JVMTI_PRIMITIVE_TYPE_SHORT => JValue::Short(value.s),
-                JVMTI_PRIMITIVE_TYPE_INT => JValue::Int(value.i),
-                JVMTI_PRIMITIVE_TYPE_LONG => JValue::Long(value.j),
-                JVMTI_PRIMITIVE_TYPE_FLOAT => JValue::Float(value.f),
-                JVMTI_PRIMITIVE_TYPE_DOUBLE => JValue::Double(value.d),
-            };
-
-            let arg = HeapIterationCallback::PrimitiveField {
-                field_type,
-                field_index,
-                object_class_tag,
-                object_tag,
-                value,
-            };
-            closure(arg).bits()
+        if !any_missing {
+            return Ok(());
         }
 
-        unsafe extern "C" fn primitive_array_callback(
-            class_tag: jlong,
-            size: jlong,
-            tag_ptr: *mut jlong,
-            element_count: jint,
-            element_type: jvmtiPrimitiveType,
-            elements: *const c_void,
-            user_data: *mut c_void,
-        ) -> jint {
-            let closure: &mut &mut dyn FnMut(HeapIterationCallback) -> HeapVisitControlFlags =
-                &mut *(user_data as *mut &mut _);
-
-            let class_tag = NonZeroJlong::new(class_tag);
-            debug_assert!(size >= 0);
-            let tag = tag_ptr.as_mut().expect("tag pointer is null");
-            debug_assert!(element_count >= 0);
-            let elements =
-                PrimitiveArray::new(elements as *const _, element_count as usize, element_type);
-
-            let arg = HeapIterationCallback::PrimitiveArray {
-                class_tag,
-                size: size as usize,
-                tag,
-                elements,
-            };
-            closure(arg).bits()
+        let potential = self.get_potential_capabilities()?;
+        if let Some(unavailable) = capabilities
+            .iter()
+            .find(|c| c.is_set(&missing) && !c.is_set(&potential))
+        {
+            return Err(Error::UnavailableCapability(*unavailable));
         }
 
-        unsafe extern "C" fn string_callback(
-            class_tag: jlong,
-            size: jlong,
-            tag_ptr: *mut jlong,
-            value: *const jchar,
-            value_length: jint,
-            user_data: *mut ::core::ffi::c_void,
-        ) -> jint {
-            let closure: &mut &mut dyn FnMut(HeapIterationCallback) -> HeapVisitControlFlags =
-                &mut *(user_data as *mut &mut _);
-
-            let class_tag = NonZeroJlong::new(class_tag);
-            debug_assert!(size >= 0);
-            let tag = tag_ptr.as_mut().expect("tag pointer is null");
-            debug_assert!(value_length >= 0);
-            let value = U16StrPrintable(U16Str::from_ptr(value, value_length as usize));
-
-            let arg = HeapIterationCallback::String {
-                class_tag,
-                size: size as usize,
-                tag,
-                value,
-            };
-            closure(arg).bits()
-        }
+        self.add_capabilities(&missing)
+    }
 
+    pub fn iterate_through_heap(
+        &self,
+        heap_filter: HeapFilterFlags,
+        instanceof: Option<Class>,
+        mut callback: impl FnMut(HeapIterationCallback) -> HeapVisitControlFlags,
+    ) -> JvmtiResult<()> {
         let raw_callbacks = jvmtiHeapCallbacks {
             heap_iteration_callback: Some(heap_iteration_callback),
             heap_reference_callback: None,
@@ -302,6 +824,10 @@ impl<'a> JvmtiEnv<'a> {
             ..Default::default()
         };
 
+        let mut callback = |walked: HeapWalkCallback| match walked {
+            HeapWalkCallback::Value(value) => callback(value),
+            HeapWalkCallback::Reference(_) => unreachable!("iteration reports no references"),
+        };
         let mut callback: &mut dyn FnMut(_) -> _ = &mut callback;
         let callback = &mut callback;
         debug!("iterating over heap");
@@ -309,7 +835,7 @@ impl<'a> JvmtiEnv<'a> {
             self,
             IterateThroughHeap,
             heap_filter.bits(),
-            instanceof.unwrap_or(null_mut()),
+            instanceof.map_or(null_mut(), Class::into_inner),
             &raw_callbacks as *const jvmtiHeapCallbacks,
             callback as *mut _ as *mut c_void
         );
@@ -317,21 +843,122 @@ impl<'a> JvmtiEnv<'a> {
         Ok(())
     }
 
+    /// Needs `can_tag_objects`, which is not requested here as this is called per object. None if
+    /// the object is untagged
+    pub fn get_tag(&self, object: JObject) -> JvmtiResult<Option<NonZeroJlong>> {
+        let mut tag: jlong = 0;
+        jvmti_method!(self, GetTag, object.into_inner(), &mut tag as *mut jlong);
+        Ok(NonZeroJlong::new(tag))
+    }
+
+    /// Needs `can_tag_objects`, which is not requested here as this is called per object. None
+    /// clears the tag
+    pub fn set_tag(&self, object: JObject, tag: Option<NonZeroJlong>) -> JvmtiResult<()> {
+        jvmti_method!(
+            self,
+            SetTag,
+            object.into_inner(),
+            tag.map_or(0, NonZeroJlong::get)
+        );
+        Ok(())
+    }
+
+    /// Requests `can_tag_objects` if not already possessed. Visits every reference reachable from
+    /// `initial_object`, or from the heap roots if None, following the referree's references only
+    /// if the callback returns [HeapVisitControlFlags::VISIT_OBJECTS]. Only objects of the class
+    /// `class_filter` (not its subclasses) that pass `heap_filter` are reported
+    pub fn follow_references(
+        &self,
+        heap_filter: HeapFilterFlags,
+        class_filter: Option<Class>,
+        initial_object: Option<JObject>,
+        mut callback: impl FnMut(HeapReference) -> HeapVisitControlFlags,
+    ) -> JvmtiResult<()> {
+        let raw_callbacks = jvmtiHeapCallbacks {
+            heap_reference_callback: Some(heap_reference_callback),
+            ..Default::default()
+        };
+
+        let mut callback = |walked: HeapWalkCallback| match walked {
+            HeapWalkCallback::Reference(reference) => callback(reference),
+            HeapWalkCallback::Value(_) => unreachable!("no value callbacks are set"),
+        };
+        self.follow_references_raw(
+            heap_filter,
+            class_filter,
+            initial_object,
+            &raw_callbacks,
+            &mut callback,
+        )
+    }
+
+    /// Like [follow_references](Self::follow_references), but also reports the primitive fields
+    /// and primitive array elements of each visited object, and the value of each visited string,
+    /// as [HeapWalkCallback::Value]. These follow the references from the same object, all within
+    /// the single walk
+    pub fn follow_references_with_values(
+        &self,
+        heap_filter: HeapFilterFlags,
+        class_filter: Option<Class>,
+        initial_object: Option<JObject>,
+        mut callback: impl FnMut(HeapWalkCallback) -> HeapVisitControlFlags,
+    ) -> JvmtiResult<()> {
+        let raw_callbacks = jvmtiHeapCallbacks {
+            heap_reference_callback: Some(heap_reference_callback),
+            primitive_field_callback: Some(primitive_field_callback),
+            array_primitive_value_callback: Some(primitive_array_callback),
+            string_primitive_value_callback: Some(string_callback),
+            ..Default::default()
+        };
+
+        self.follow_references_raw(
+            heap_filter,
+            class_filter,
+            initial_object,
+            &raw_callbacks,
+            &mut callback,
+        )
+    }
+
+    fn follow_references_raw(
+        &self,
+        heap_filter: HeapFilterFlags,
+        class_filter: Option<Class>,
+        initial_object: Option<JObject>,
+        raw_callbacks: &jvmtiHeapCallbacks,
+        mut callback: &mut dyn FnMut(HeapWalkCallback) -> HeapVisitControlFlags,
+    ) -> JvmtiResult<()> {
+        self.require_capabilities(&[Capability::TagObjects])?;
+
+        let callback = &mut callback;
+        debug!("following references");
+        jvmti_method!(
+            self,
+            FollowReferences,
+            heap_filter.bits(),
+            class_filter.map_or(null_mut(), Class::into_inner),
+            initial_object.map_or(null_mut(), JObject::into_inner),
+            raw_callbacks as *const jvmtiHeapCallbacks,
+            callback as *mut _ as *mut c_void
+        );
+
+        Ok(())
+    }
+
     pub fn get_objects_with_tag(
         &self,
         tag: jlong,
         jni: jni::JNIEnv<'a>,
-    ) -> JvmtiResult<AllocatedArray<LocalRef>> {
+    ) -> JvmtiResult<AllocatedArray<'_, LocalRef>> {
         let tags = [tag];
         self.get_objects_with_tags(&tags, jni)
     }
 
-    // TODO generic param to also return array of tag results
     pub fn get_objects_with_tags(
         &self,
         tags: &[jlong],
         jni: jni::JNIEnv<'a>,
-    ) -> JvmtiResult<AllocatedArray<LocalRef>> {
+    ) -> JvmtiResult<AllocatedArray<'_, LocalRef>> {
         let tag_count = jint::try_from(tags.len()).expect("too many tags)");
 
         let mut obj_count: jint = 0;
@@ -351,13 +978,43 @@ impl<'a> JvmtiEnv<'a> {
         })
     }
 
+    /// Like [get_objects_with_tags](Self::get_objects_with_tags), but also returns the tag each
+    /// object matched
+    pub fn get_objects_with_tag_results(
+        &self,
+        tags: &[jlong],
+        jni: jni::JNIEnv<'a>,
+    ) -> JvmtiResult<TaggedObjects<'_>> {
+        let tag_count = jint::try_from(tags.len()).expect("too many tags)");
+
+        let mut obj_count: jint = 0;
+        let mut obj_array = null_mut();
+        let mut tag_array = null_mut();
+        jvmti_method!(
+            self,
+            GetObjectsWithTags,
+            tag_count,
+            tags.as_ptr(),
+            &mut obj_count as *mut jint,
+            (&mut obj_array) as *mut *mut jobject,
+            (&mut tag_array) as *mut *mut jlong
+        );
+
+        Ok(unsafe {
+            TaggedObjects::new(
+                AllocatedArray::<LocalRef>::new(obj_array, obj_count as usize, jni, self.clone()),
+                AllocatedArray::<Tag>::new(tag_array, obj_count as usize, jni, self.clone()),
+            )
+        })
+    }
+
     // TODO generic param to optionally get generic signature too
-    pub fn get_class_signature(&self, class: jclass) -> JvmtiResult<AllocatedMutf8> {
+    pub fn get_class_signature(&self, class: Class) -> JvmtiResult<AllocatedMutf8<'_>> {
         let mut jni_sig: *mut c_char = null_mut();
         jvmti_method!(
             self,
             GetClassSignature,
-            class,
+            class.into_inner(),
             (&mut jni_sig) as *mut *mut c_char,
             null_mut()
         );
@@ -366,12 +1023,258 @@ impl<'a> JvmtiEnv<'a> {
         Ok(unsafe { AllocatedMutf8::new(jni_sig, self.clone()) })
     }
 
+    pub fn is_modifiable_class(&self, class: Class) -> JvmtiResult<bool> {
+        let mut modifiable: jboolean = 0;
+        jvmti_method!(
+            self,
+            IsModifiableClass,
+            class.into_inner(),
+            &mut modifiable as *mut jboolean
+        );
+        Ok(modifiable == JNI_TRUE)
+    }
+
+    /// Requests `can_redefine_classes` if not already possessed
+    pub fn redefine_classes(&self, definitions: &[ClassDefinition]) -> JvmtiResult<()> {
+        self.require_capabilities(&[Capability::RedefineClasses])?;
+
+        let raw_definitions: Vec<jvmtiClassDefinition> =
+            definitions.iter().map(ClassDefinition::as_raw).collect();
+        let count = jint::try_from(raw_definitions.len()).expect("too many classes");
+
+        jvmti_method!(self, RedefineClasses, count, raw_definitions.as_ptr());
+        debug!("redefined {} classes", count);
+        Ok(())
+    }
+
+    /// Requests `can_retransform_classes` if not already possessed
+    pub fn retransform_classes(&self, classes: &[Class]) -> JvmtiResult<()> {
+        self.require_capabilities(&[Capability::RetransformClasses])?;
+
+        let count = jint::try_from(classes.len()).expect("too many classes");
+        jvmti_method!(
+            self,
+            RetransformClasses,
+            count,
+            classes.as_ptr() as *const jclass
+        );
+        debug!("retransformed {} classes", count);
+        Ok(())
+    }
+
+    /// Requests `can_get_source_debug_extension` if not already possessed. Returns None if the
+    /// class has no `SourceDebugExtension` attribute
+    pub fn get_source_debug_extension(
+        &self,
+        class: Class,
+    ) -> JvmtiResult<Option<AllocatedMutf8<'_>>> {
+        self.require_capabilities(&[Capability::GetSourceDebugExtension])?;
+
+        let mut extension: *mut c_char = null_mut();
+        let result = (|| {
+            jvmti_method!(
+                self,
+                GetSourceDebugExtension,
+                class.into_inner(),
+                (&mut extension) as *mut *mut c_char
+            );
+            Ok(())
+        })();
+
+        Ok(absent_as_none(result)?.map(|_| {
+            assert!(!extension.is_null());
+            unsafe { AllocatedMutf8::new(extension, self.clone()) }
+        }))
+    }
+
+    /// Requests `can_generate_sampled_object_alloc_events` if not already possessed. A
+    /// `SampledObjectAlloc` event is posted after about this many bytes are allocated on a thread,
+    /// or for every allocation if 0.
+    ///
+    /// The interval is global to the VM rather than to this environment. Returns the interval it
+    /// replaces so it can be restored, which as JVMTI cannot read it back is the one last set
+    /// through this crate, or the VM's default of 512KiB
+    pub fn set_heap_sampling_interval(&self, bytes: jint) -> JvmtiResult<jint> {
+        self.require_capabilities(&[Capability::GenerateSampledObjectAllocEvents])?;
+
+        let mut interval = HEAP_SAMPLING_INTERVAL
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        jvmti_method!(self, SetHeapSamplingInterval, bytes);
+        debug!("set heap sampling interval to {} bytes", bytes);
+        Ok(std::mem::replace(&mut *interval, bytes))
+    }
+
+    /// Only used through [RawMonitor](crate::RawMonitor), whose guard relies on nothing else
+    /// entering, exiting or destroying the monitor
+    pub(crate) fn create_raw_monitor(&self, name: &str) -> JvmtiResult<jrawMonitorID> {
+        let name = CString::new(name).expect("monitor name contains a nul byte");
+        let mut monitor: jrawMonitorID = null_mut();
+        jvmti_method!(
+            self,
+            CreateRawMonitor,
+            name.as_ptr(),
+            &mut monitor as *mut jrawMonitorID
+        );
+        debug!("created raw monitor {:?} ({:?})", name, monitor);
+        Ok(monitor)
+    }
+
+    pub(crate) fn destroy_raw_monitor(&self, monitor: jrawMonitorID) -> JvmtiResult<()> {
+        jvmti_method!(self, DestroyRawMonitor, monitor);
+        debug!("destroyed raw monitor {:?}", monitor);
+        Ok(())
+    }
+
+    pub(crate) fn raw_monitor_enter(&self, monitor: jrawMonitorID) -> JvmtiResult<()> {
+        jvmti_method!(self, RawMonitorEnter, monitor);
+        Ok(())
+    }
+
+    pub(crate) fn raw_monitor_exit(&self, monitor: jrawMonitorID) -> JvmtiResult<()> {
+        jvmti_method!(self, RawMonitorExit, monitor);
+        Ok(())
+    }
+
+    /// Timeout of 0 waits forever
+    pub(crate) fn raw_monitor_wait(
+        &self,
+        monitor: jrawMonitorID,
+        millis: jlong,
+    ) -> JvmtiResult<()> {
+        jvmti_method!(self, RawMonitorWait, monitor, millis);
+        Ok(())
+    }
+
+    pub(crate) fn raw_monitor_notify(&self, monitor: jrawMonitorID) -> JvmtiResult<()> {
+        jvmti_method!(self, RawMonitorNotify, monitor);
+        Ok(())
+    }
+
+    pub(crate) fn raw_monitor_notify_all(&self, monitor: jrawMonitorID) -> JvmtiResult<()> {
+        jvmti_method!(self, RawMonitorNotifyAll, monitor);
+        Ok(())
+    }
+
+    /// A VM system property, which may differ from `System.getProperty`. None if the property is
+    /// not available
+    pub fn get_system_property(&self, property: &str) -> JvmtiResult<Option<String>> {
+        let property = CString::new(property).expect("property name contains nul");
+        let mut value: *mut c_char = null_mut();
+        let result = (|| {
+            jvmti_method!(
+                self,
+                GetSystemProperty,
+                property.as_ptr(),
+                (&mut value) as *mut *mut c_char
+            );
+            Ok(())
+        })();
+
+        match result {
+            Ok(()) => Ok(Some(unsafe { self.take_string(value) })),
+            Err(Error::Jvmti(JvmtiError::NotAvailable)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Needs `can_get_current_thread_cpu_time`, which is not requested here as this is called per
+    /// sample. CPU time used by the current thread, as measured by the timer of
+    /// [get_current_thread_cpu_timer_info](Self::get_current_thread_cpu_timer_info)
+    pub fn get_current_thread_cpu_time(&self) -> JvmtiResult<Duration> {
+        let mut nanos: jlong = 0;
+        jvmti_method!(self, GetCurrentThreadCpuTime, &mut nanos as *mut jlong);
+        Ok(nanos_to_duration(nanos))
+    }
+
+    /// Needs `can_get_current_thread_cpu_time`, which is not requested here to match
+    /// [get_current_thread_cpu_time](Self::get_current_thread_cpu_time)
+    pub fn get_current_thread_cpu_timer_info(&self) -> JvmtiResult<TimerInfo> {
+        let mut info = MaybeUninit::<jvmtiTimerInfo>::zeroed();
+        jvmti_method!(self, GetCurrentThreadCpuTimerInfo, info.as_mut_ptr());
+        Ok(unsafe { info.assume_init() }.into())
+    }
+
+    /// Needs `can_get_thread_cpu_time`, which is not requested here as this is called per thread.
+    /// CPU time used by the thread, as measured by the timer of
+    /// [get_thread_cpu_timer_info](Self::get_thread_cpu_timer_info)
+    pub fn get_thread_cpu_time(&self, thread: Thread) -> JvmtiResult<Duration> {
+        let mut nanos: jlong = 0;
+        jvmti_method!(
+            self,
+            GetThreadCpuTime,
+            thread.into_inner(),
+            &mut nanos as *mut jlong
+        );
+        Ok(nanos_to_duration(nanos))
+    }
+
+    /// Needs `can_get_thread_cpu_time`, which is not requested here to match
+    /// [get_thread_cpu_time](Self::get_thread_cpu_time)
+    pub fn get_thread_cpu_timer_info(&self) -> JvmtiResult<TimerInfo> {
+        let mut info = MaybeUninit::<jvmtiTimerInfo>::zeroed();
+        jvmti_method!(self, GetThreadCpuTimerInfo, info.as_mut_ptr());
+        Ok(unsafe { info.assume_init() }.into())
+    }
+
+    /// Value of the system timer described by [get_timer_info](Self::get_timer_info), only
+    /// meaningful relative to another value
+    pub fn get_time(&self) -> JvmtiResult<Duration> {
+        let mut nanos: jlong = 0;
+        jvmti_method!(self, GetTime, &mut nanos as *mut jlong);
+        Ok(nanos_to_duration(nanos))
+    }
+
+    pub fn get_timer_info(&self) -> JvmtiResult<TimerInfo> {
+        let mut info = MaybeUninit::<jvmtiTimerInfo>::zeroed();
+        jvmti_method!(self, GetTimerInfo, info.as_mut_ptr());
+        Ok(unsafe { info.assume_init() }.into())
+    }
+
+    /// Processors available to the VM, which may change during its lifetime
+    pub fn get_available_processors(&self) -> JvmtiResult<jint> {
+        let mut count: jint = 0;
+        jvmti_method!(self, GetAvailableProcessors, &mut count as *mut jint);
+        Ok(count)
+    }
+
+    /// # Safety
+    /// Callbacks of this environment that read the storage must agree on what it points to
+    pub unsafe fn set_environment_local_storage(&self, data: *const c_void) -> JvmtiResult<()> {
+        jvmti_method!(self, SetEnvironmentLocalStorage, data);
+        Ok(())
+    }
+
+    pub fn get_environment_local_storage(&self) -> JvmtiResult<*mut c_void> {
+        let mut data: *mut c_void = null_mut();
+        jvmti_method!(
+            self,
+            GetEnvironmentLocalStorage,
+            &mut data as *mut *mut c_void
+        );
+        Ok(data)
+    }
+
     pub fn dispose(self) -> JvmtiResult<()> {
         jvmti_method!(self, DisposeEnvironment);
         debug!("disposed jvmti environment at {:?}", self.0);
         Ok(())
     }
 
+    /// Allocates `size` bytes that must be freed with [deallocate], or handed over to the JVM
+    /// where the spec requires a JVMTI allocation
+    pub fn allocate(&self, size: usize) -> JvmtiResult<*mut u8> {
+        let mut ptr: *mut u8 = null_mut();
+        jvmti_method!(
+            self,
+            Allocate,
+            jlong::try_from(size).expect("allocation too large"),
+            &mut ptr as *mut *mut u8
+        );
+        debug!("allocated {} bytes at {:?}", size, ptr);
+        Ok(ptr)
+    }
+
     /// # Safety
     /// Pointer must be a JVMTI allocation
     pub unsafe fn deallocate(&self, ptr: *mut ()) -> JvmtiResult<()> {
@@ -380,6 +1283,26 @@ impl<'a> JvmtiEnv<'a> {
         Ok(())
     }
 
+    /// Copies and frees a JVMTI allocated string, null becomes empty
+    ///
+    /// # Safety
+    /// Pointer must be a nul terminated JVMTI allocation or null
+    pub(crate) unsafe fn take_string(&self, ptr: *mut c_char) -> String {
+        if ptr.is_null() {
+            String::new()
+        } else {
+            let str = AllocatedMutf8::new(ptr, self.clone());
+            mutf8_to_string(str.as_bytes())
+        }
+    }
+
+    /// # Safety
+    /// Pointer must be a valid JVMTI environment
+    pub(crate) unsafe fn from_raw(ptr: *mut jvmtiEnv) -> Self {
+        debug_assert!(!ptr.is_null());
+        Self(ptr, PhantomData)
+    }
+
     pub(crate) fn as_ref(&self) -> &jvmtiInterface_1_ {
         debug_assert!(!self.0.is_null());
         unsafe { &**self.0 }
@@ -390,3 +1313,167 @@ impl<'a> JvmtiEnv<'a> {
         self.0
     }
 }
+
+#[allow(clippy::too_many_arguments)]
+unsafe extern "C" fn heap_reference_callback(
+    reference_kind: jvmtiHeapReferenceKind,
+    reference_info: *const jvmtiHeapReferenceInfo,
+    class_tag: jlong,
+    referrer_class_tag: jlong,
+    size: jlong,
+    tag_ptr: *mut jlong,
+    referrer_tag_ptr: *mut jlong,
+    length: jint,
+    user_data: *mut c_void,
+) -> jint {
+    let closure: &mut &mut dyn FnMut(HeapWalkCallback) -> HeapVisitControlFlags =
+        &mut *(user_data as *mut &mut _);
+
+    debug_assert!(size >= 0);
+    // must not alias the referree's tag for references to self
+    let referrer_tag = if referrer_tag_ptr == tag_ptr {
+        None
+    } else {
+        referrer_tag_ptr.as_mut()
+    };
+    let tags = ReferenceTags {
+        referrer_class_tag: NonZeroJlong::new(referrer_class_tag),
+        referrer_tag,
+        class_tag: NonZeroJlong::new(class_tag),
+        tag: tag_ptr.as_mut().expect("tag pointer is null"),
+        size: size as usize,
+        array_length: if length < 0 {
+            None
+        } else {
+            Some(length as usize)
+        },
+    };
+
+    let arg = HeapReference::from_raw(reference_kind, reference_info, tags);
+    closure(HeapWalkCallback::Reference(arg)).bits()
+}
+
+unsafe extern "C" fn heap_iteration_callback(
+    class_tag: jlong,
+    size: jlong,
+    tag_ptr: *mut jlong,
+    length: jint,
+    user_data: *mut c_void,
+) -> jint {
+    let closure: &mut &mut dyn FnMut(HeapWalkCallback) -> HeapVisitControlFlags =
+        &mut *(user_data as *mut &mut _);
+
+    let class_tag = NonZeroJlong::new(class_tag);
+    debug_assert!(size >= 0);
+    let tag = tag_ptr.as_mut().expect("tag pointer is null");
+    let array_length = if length < 0 {
+        None
+    } else {
+        Some(length as usize)
+    };
+    let arg = HeapIterationCallback::Object {
+        class_tag,
+        size: size as usize,
+        tag,
+        array_length,
+    };
+    closure(HeapWalkCallback::Value(arg)).bits()
+}
+
+unsafe extern "C" fn primitive_field_callback(
+    kind: jvmtiHeapReferenceKind,
+    info: *const jvmtiHeapReferenceInfo,
+    object_class_tag: jlong,
+    object_tag_ptr: *mut jlong,
+    value: jvalue,
+    value_type: jvmtiPrimitiveType,
+    user_data: *mut ::core::ffi::c_void,
+) -> jint {
+    use jvmtiHeapReferenceKind::*;
+    use jvmtiPrimitiveType::*;
+
+    let closure: &mut &mut dyn FnMut(HeapWalkCallback) -> HeapVisitControlFlags =
+        &mut *(user_data as *mut &mut _);
+
+    let field_type = match kind {
+        JVMTI_HEAP_REFERENCE_FIELD => FieldType::Instance,
+        JVMTI_HEAP_REFERENCE_STATIC_FIELD => FieldType::Static,
+        _ => unreachable!("unexpected field kind {:?}", kind),
+    };
+
+    let field_index = (*info).field.index;
+    let object_class_tag = NonZeroJlong::new(object_class_tag);
+    let object_tag = object_tag_ptr.as_mut().expect("tag pointer is null");
+    let value = match value_type {
+        JVMTI_PRIMITIVE_TYPE_BOOLEAN => JValue::Bool(value.z),
+        JVMTI_PRIMITIVE_TYPE_BYTE => JValue::Byte(value.b),
+        JVMTI_PRIMITIVE_TYPE_CHAR => JValue::Char(value.c),
+        JVMTI_PRIMITIVE_TYPE_SHORT => JValue::Short(value.s),
+        JVMTI_PRIMITIVE_TYPE_INT => JValue::Int(value.i),
+        JVMTI_PRIMITIVE_TYPE_LONG => JValue::Long(value.j),
+        JVMTI_PRIMITIVE_TYPE_FLOAT => JValue::Float(value.f),
+        JVMTI_PRIMITIVE_TYPE_DOUBLE => JValue::Double(value.d),
+    };
+
+    let arg = HeapIterationCallback::PrimitiveField {
+        field_type,
+        field_index,
+        object_class_tag,
+        object_tag,
+        value,
+    };
+    closure(HeapWalkCallback::Value(arg)).bits()
+}
+
+unsafe extern "C" fn primitive_array_callback(
+    class_tag: jlong,
+    size: jlong,
+    tag_ptr: *mut jlong,
+    element_count: jint,
+    element_type: jvmtiPrimitiveType,
+    elements: *const c_void,
+    user_data: *mut c_void,
+) -> jint {
+    let closure: &mut &mut dyn FnMut(HeapWalkCallback) -> HeapVisitControlFlags =
+        &mut *(user_data as *mut &mut _);
+
+    let class_tag = NonZeroJlong::new(class_tag);
+    debug_assert!(size >= 0);
+    let tag = tag_ptr.as_mut().expect("tag pointer is null");
+    debug_assert!(element_count >= 0);
+    let elements = PrimitiveArray::new(elements as *const _, element_count as usize, element_type);
+
+    let arg = HeapIterationCallback::PrimitiveArray {
+        class_tag,
+        size: size as usize,
+        tag,
+        elements,
+    };
+    closure(HeapWalkCallback::Value(arg)).bits()
+}
+
+unsafe extern "C" fn string_callback(
+    class_tag: jlong,
+    size: jlong,
+    tag_ptr: *mut jlong,
+    value: *const jchar,
+    value_length: jint,
+    user_data: *mut ::core::ffi::c_void,
+) -> jint {
+    let closure: &mut &mut dyn FnMut(HeapWalkCallback) -> HeapVisitControlFlags =
+        &mut *(user_data as *mut &mut _);
+
+    let class_tag = NonZeroJlong::new(class_tag);
+    debug_assert!(size >= 0);
+    let tag = tag_ptr.as_mut().expect("tag pointer is null");
+    debug_assert!(value_length >= 0);
+    let value = U16StrPrintable(U16Str::from_ptr(value, value_length as usize));
+
+    let arg = HeapIterationCallback::String {
+        class_tag,
+        size: size as usize,
+        tag,
+        value,
+    };
+    closure(HeapWalkCallback::Value(arg)).bits()
+}
@@ -1,3 +1,4 @@
+use crate::handles::Thread;
 use core::fmt::{Debug, Formatter};
 use jni_jvmti_sys::*;
 use std::mem::transmute;
@@ -75,9 +76,9 @@ pub enum EventType {
     SampledObjectAlloc = 86,
 }
 #[derive(Copy, Clone, Debug)]
-pub enum EventScope {
+pub enum EventScope<'a> {
     Global,
-    Thread(jthread), // TODO thread wrapper that knows its pid, and format that in Debug
+    Thread(Thread<'a>),
 }
 
 impl From<&EventCallbacks> for *const jvmtiEventCallbacks {
@@ -129,8 +130,8 @@ impl EventCallbacksBuilder {
         })
     }
 
-    pub fn with_vminit(mut self, callback: jvmtiEventVMInit) -> Self {
-        self.vminit = callback;
+    pub fn with_vminit(mut self, callback: Option<callback_types::VMInit>) -> Self {
+        self.vminit = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 
@@ -139,18 +140,21 @@ impl EventCallbacksBuilder {
         self
     }
 
-    pub fn with_thread_start(mut self, callback: jvmtiEventThreadStart) -> Self {
-        self.thread_start = callback;
+    pub fn with_thread_start(mut self, callback: Option<callback_types::ThreadStart>) -> Self {
+        self.thread_start = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 
-    pub fn with_thread_end(mut self, callback: jvmtiEventThreadEnd) -> Self {
-        self.thread_end = callback;
+    pub fn with_thread_end(mut self, callback: Option<callback_types::ThreadEnd>) -> Self {
+        self.thread_end = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 
-    pub fn with_class_file_load_hook(mut self, callback: jvmtiEventClassFileLoadHook) -> Self {
-        self.class_file_load_hook = callback;
+    pub fn with_class_file_load_hook(
+        mut self,
+        callback: Option<callback_types::ClassFileLoadHook>,
+    ) -> Self {
+        self.class_file_load_hook = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 
@@ -159,145 +163,172 @@ impl EventCallbacksBuilder {
         self
     }
 
-    pub fn with_class_prepare(mut self, callback: jvmtiEventClassPrepare) -> Self {
-        self.class_prepare = callback;
+    pub fn with_class_prepare(mut self, callback: Option<callback_types::ClassPrepare>) -> Self {
+        self.class_prepare = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 
-    pub fn with_vmstart(mut self, callback: jvmtiEventVMStart) -> Self {
-        self.vmstart = callback;
+    pub fn with_vmstart(mut self, callback: Option<callback_types::VMStart>) -> Self {
+        self.vmstart = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 
-    pub fn with_exception(mut self, callback: jvmtiEventException) -> Self {
-        self.exception = callback;
+    pub fn with_exception(mut self, callback: Option<callback_types::Exception>) -> Self {
+        self.exception = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 
-    pub fn with_exception_catch(mut self, callback: jvmtiEventExceptionCatch) -> Self {
-        self.exception_catch = callback;
+    pub fn with_exception_catch(
+        mut self,
+        callback: Option<callback_types::ExceptionCatch>,
+    ) -> Self {
+        self.exception_catch = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 
-    pub fn with_single_step(mut self, callback: jvmtiEventSingleStep) -> Self {
-        self.single_step = callback;
+    pub fn with_single_step(mut self, callback: Option<callback_types::SingleStep>) -> Self {
+        self.single_step = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 
-    pub fn with_frame_pop(mut self, callback: jvmtiEventFramePop) -> Self {
-        self.frame_pop = callback;
+    pub fn with_frame_pop(mut self, callback: Option<callback_types::FramePop>) -> Self {
+        self.frame_pop = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 
-    pub fn with_breakpoint(mut self, callback: jvmtiEventBreakpoint) -> Self {
-        self.breakpoint = callback;
+    pub fn with_breakpoint(mut self, callback: Option<callback_types::Breakpoint>) -> Self {
+        self.breakpoint = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 
-    pub fn with_field_access(mut self, callback: jvmtiEventFieldAccess) -> Self {
-        self.field_access = callback;
+    pub fn with_field_access(mut self, callback: Option<callback_types::FieldAccess>) -> Self {
+        self.field_access = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 
-    pub fn with_field_modification(mut self, callback: jvmtiEventFieldModification) -> Self {
-        self.field_modification = callback;
+    pub fn with_field_modification(
+        mut self,
+        callback: Option<callback_types::FieldModification>,
+    ) -> Self {
+        self.field_modification = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 
-    pub fn with_method_entry(mut self, callback: jvmtiEventMethodEntry) -> Self {
-        self.method_entry = callback;
+    pub fn with_method_entry(mut self, callback: Option<callback_types::MethodEntry>) -> Self {
+        self.method_entry = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 
-    pub fn with_method_exit(mut self, callback: jvmtiEventMethodExit) -> Self {
-        self.method_exit = callback;
+    pub fn with_method_exit(mut self, callback: Option<callback_types::MethodExit>) -> Self {
+        self.method_exit = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 
-    pub fn with_native_method_bind(mut self, callback: jvmtiEventNativeMethodBind) -> Self {
-        self.native_method_bind = callback;
+    pub fn with_native_method_bind(
+        mut self,
+        callback: Option<callback_types::NativeMethodBind>,
+    ) -> Self {
+        self.native_method_bind = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 
-    pub fn with_compiled_method_load(mut self, callback: jvmtiEventCompiledMethodLoad) -> Self {
-        self.compiled_method_load = callback;
+    pub fn with_compiled_method_load(
+        mut self,
+        callback: Option<callback_types::CompiledMethodLoad>,
+    ) -> Self {
+        self.compiled_method_load = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 
-    pub fn with_compiled_method_unload(mut self, callback: jvmtiEventCompiledMethodUnload) -> Self {
-        self.compiled_method_unload = callback;
+    pub fn with_compiled_method_unload(
+        mut self,
+        callback: Option<callback_types::CompiledMethodUnload>,
+    ) -> Self {
+        self.compiled_method_unload = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 
-    pub fn with_dynamic_code_generated(mut self, callback: jvmtiEventDynamicCodeGenerated) -> Self {
-        self.dynamic_code_generated = callback;
+    pub fn with_dynamic_code_generated(
+        mut self,
+        callback: Option<callback_types::DynamicCodeGenerated>,
+    ) -> Self {
+        self.dynamic_code_generated = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 
-    pub fn with_data_dump_request(mut self, callback: jvmtiEventDataDumpRequest) -> Self {
-        self.data_dump_request = callback;
+    pub fn with_data_dump_request(
+        mut self,
+        callback: Option<callback_types::DataDumpRequest>,
+    ) -> Self {
+        self.data_dump_request = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 
-    pub fn with_monitor_wait(mut self, callback: jvmtiEventMonitorWait) -> Self {
-        self.monitor_wait = callback;
+    pub fn with_monitor_wait(mut self, callback: Option<callback_types::MonitorWait>) -> Self {
+        self.monitor_wait = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 
-    pub fn with_monitor_waited(mut self, callback: jvmtiEventMonitorWaited) -> Self {
-        self.monitor_waited = callback;
+    pub fn with_monitor_waited(mut self, callback: Option<callback_types::MonitorWaited>) -> Self {
+        self.monitor_waited = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 
     pub fn with_monitor_contended_enter(
         mut self,
-        callback: jvmtiEventMonitorContendedEnter,
+        callback: Option<callback_types::MonitorContendedEnter>,
     ) -> Self {
-        self.monitor_contended_enter = callback;
+        self.monitor_contended_enter = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 
     pub fn with_monitor_contended_entered(
         mut self,
-        callback: jvmtiEventMonitorContendedEntered,
+        callback: Option<callback_types::MonitorContendedEntered>,
     ) -> Self {
-        self.monitor_contended_entered = callback;
+        self.monitor_contended_entered = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 
-    pub fn with_resource_exhausted(mut self, callback: jvmtiEventResourceExhausted) -> Self {
-        self.resource_exhausted = callback;
+    pub fn with_resource_exhausted(
+        mut self,
+        callback: Option<callback_types::ResourceExhausted>,
+    ) -> Self {
+        self.resource_exhausted = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 
     pub fn with_garbage_collection_start(
         mut self,
-        callback: jvmtiEventGarbageCollectionStart,
+        callback: Option<callback_types::GarbageCollectionStart>,
     ) -> Self {
-        self.garbage_collection_start = callback;
+        self.garbage_collection_start = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 
     pub fn with_garbage_collection_finish(
         mut self,
-        callback: jvmtiEventGarbageCollectionFinish,
+        callback: Option<callback_types::GarbageCollectionFinish>,
     ) -> Self {
-        self.garbage_collection_finish = callback;
+        self.garbage_collection_finish = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 
-    pub fn with_object_free(mut self, callback: jvmtiEventObjectFree) -> Self {
-        self.object_free = callback;
+    pub fn with_object_free(mut self, callback: Option<callback_types::ObjectFree>) -> Self {
+        self.object_free = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 
-    pub fn with_vmobject_alloc(mut self, callback: jvmtiEventVMObjectAlloc) -> Self {
-        self.vmobject_alloc = callback;
+    pub fn with_vmobject_alloc(mut self, callback: Option<callback_types::VMObjectAlloc>) -> Self {
+        self.vmobject_alloc = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 
-    pub fn with_sampled_object_alloc(mut self, callback: jvmtiEventSampledObjectAlloc) -> Self {
-        self.sampled_object_alloc = callback;
+    pub fn with_sampled_object_alloc(
+        mut self,
+        callback: Option<callback_types::SampledObjectAlloc>,
+    ) -> Self {
+        self.sampled_object_alloc = callback.map(|ptr| unsafe { transmute(ptr) });
         self
     }
 }
@@ -313,7 +344,7 @@ impl Debug for EventCallbacks {
         }
         macro_rules! callback {
             ($name:ident) => {
-                if let Some(ptr) = self.0. $name {
+                if let Some(ptr) = self.0.$name {
                     list.entry(&CallbackDebug(stringify!($name), ptr as *const ()));
                 }
             };
@@ -398,25 +429,26 @@ impl From<EventType> for jvmtiEvent {
 //noinspection ALL
 mod callback_types {
     #![allow(dead_code)]
-    // TODO more nicer callback types
+    use crate::handles::{Class, Method, Thread};
+    use jni::objects::{JFieldID, JObject};
     use jni::sys::*;
     use jni_jvmti_sys::*;
 
     pub type Breakpoint = for<'a> unsafe extern "C" fn(
         jvmti_env: crate::env::JvmtiEnv,
         jni_env: jni::JNIEnv<'a>,
-        thread: jthread,
-        method: jmethodID,
+        thread: Thread<'a>,
+        method: Method<'a>,
         location: jlocation,
     );
 
     pub type ClassFileLoadHook = for<'a> unsafe extern "C" fn(
         jvmti_env: crate::env::JvmtiEnv,
         jni_env: jni::JNIEnv<'a>,
-        class_being_redefined: jclass,
-        loader: jobject,
+        class_being_redefined: Class<'a>,
+        loader: JObject<'a>,
         name: *const ::std::os::raw::c_char,
-        protection_domain: jobject,
+        protection_domain: JObject<'a>,
         class_data_len: jint,
         class_data: *const ::std::os::raw::c_uchar,
         new_class_data_len: *mut jint,
@@ -426,20 +458,20 @@ mod callback_types {
     pub type ClassLoad = for<'a> unsafe extern "C" fn(
         jvmti_env: crate::env::JvmtiEnv,
         jni_env: jni::JNIEnv<'a>,
-        thread: jthread,
-        klass: jclass,
+        thread: Thread<'a>,
+        klass: Class<'a>,
     );
 
     pub type ClassPrepare = for<'a> unsafe extern "C" fn(
         jvmti_env: crate::env::JvmtiEnv,
         jni_env: jni::JNIEnv<'a>,
-        thread: jthread,
-        klass: jclass,
+        thread: Thread<'a>,
+        klass: Class<'a>,
     );
 
     pub type CompiledMethodLoad = for<'a> unsafe extern "C" fn(
         jvmti_env: crate::env::JvmtiEnv,
-        method: jmethodID,
+        method: Method<'a>,
         code_size: jint,
         code_addr: *const ::core::ffi::c_void,
         map_length: jint,
@@ -449,7 +481,7 @@ mod callback_types {
 
     pub type CompiledMethodUnload = for<'a> unsafe extern "C" fn(
         jvmti_env: crate::env::JvmtiEnv,
-        method: jmethodID,
+        method: Method<'a>,
         code_addr: *const ::core::ffi::c_void,
     );
 
@@ -465,43 +497,43 @@ mod callback_types {
     pub type Exception = for<'a> unsafe extern "C" fn(
         jvmti_env: crate::env::JvmtiEnv,
         jni_env: jni::JNIEnv<'a>,
-        thread: jthread,
-        method: jmethodID,
+        thread: Thread<'a>,
+        method: Method<'a>,
         location: jlocation,
-        exception: jobject,
-        catch_method: jmethodID,
+        exception: JObject<'a>,
+        catch_method: Method<'a>,
         catch_location: jlocation,
     );
 
     pub type ExceptionCatch = for<'a> unsafe extern "C" fn(
         jvmti_env: crate::env::JvmtiEnv,
         jni_env: jni::JNIEnv<'a>,
-        thread: jthread,
-        method: jmethodID,
+        thread: Thread<'a>,
+        method: Method<'a>,
         location: jlocation,
-        exception: jobject,
+        exception: JObject<'a>,
     );
 
     pub type FieldAccess = for<'a> unsafe extern "C" fn(
         jvmti_env: crate::env::JvmtiEnv,
         jni_env: jni::JNIEnv<'a>,
-        thread: jthread,
-        method: jmethodID,
+        thread: Thread<'a>,
+        method: Method<'a>,
         location: jlocation,
-        field_klass: jclass,
-        object: jobject,
-        field: jfieldID,
+        field_klass: Class<'a>,
+        object: JObject<'a>,
+        field: JFieldID<'a>,
     );
 
     pub type FieldModification = for<'a> unsafe extern "C" fn(
         jvmti_env: crate::env::JvmtiEnv,
         jni_env: jni::JNIEnv<'a>,
-        thread: jthread,
-        method: jmethodID,
+        thread: Thread<'a>,
+        method: Method<'a>,
         location: jlocation,
-        field_klass: jclass,
-        object: jobject,
-        field: jfieldID,
+        field_klass: Class<'a>,
+        object: JObject<'a>,
+        field: JFieldID<'a>,
         signature_type: ::std::os::raw::c_char,
         new_value: jvalue,
     );
@@ -509,8 +541,8 @@ mod callback_types {
     pub type FramePop = for<'a> unsafe extern "C" fn(
         jvmti_env: crate::env::JvmtiEnv,
         jni_env: jni::JNIEnv<'a>,
-        thread: jthread,
-        method: jmethodID,
+        thread: Thread<'a>,
+        method: Method<'a>,
         was_popped_by_exception: jboolean,
     );
 
@@ -522,15 +554,15 @@ mod callback_types {
     pub type MethodEntry = for<'a> unsafe extern "C" fn(
         jvmti_env: crate::env::JvmtiEnv,
         jni_env: jni::JNIEnv<'a>,
-        thread: jthread,
-        method: jmethodID,
+        thread: Thread<'a>,
+        method: Method<'a>,
     );
 
     pub type MethodExit = for<'a> unsafe extern "C" fn(
         jvmti_env: crate::env::JvmtiEnv,
         jni_env: jni::JNIEnv<'a>,
-        thread: jthread,
-        method: jmethodID,
+        thread: Thread<'a>,
+        method: Method<'a>,
         was_popped_by_exception: jboolean,
         return_value: jvalue,
     );
@@ -538,38 +570,38 @@ mod callback_types {
     pub type MonitorContendedEnter = for<'a> unsafe extern "C" fn(
         jvmti_env: crate::env::JvmtiEnv,
         jni_env: jni::JNIEnv<'a>,
-        thread: jthread,
-        object: jobject,
+        thread: Thread<'a>,
+        object: JObject<'a>,
     );
 
     pub type MonitorContendedEntered = for<'a> unsafe extern "C" fn(
         jvmti_env: crate::env::JvmtiEnv,
         jni_env: jni::JNIEnv<'a>,
-        thread: jthread,
-        object: jobject,
+        thread: Thread<'a>,
+        object: JObject<'a>,
     );
 
     pub type MonitorWait = for<'a> unsafe extern "C" fn(
         jvmti_env: crate::env::JvmtiEnv,
         jni_env: jni::JNIEnv<'a>,
-        thread: jthread,
-        object: jobject,
+        thread: Thread<'a>,
+        object: JObject<'a>,
         timeout: jlong,
     );
 
     pub type MonitorWaited = for<'a> unsafe extern "C" fn(
         jvmti_env: crate::env::JvmtiEnv,
         jni_env: jni::JNIEnv<'a>,
-        thread: jthread,
-        object: jobject,
+        thread: Thread<'a>,
+        object: JObject<'a>,
         timed_out: jboolean,
     );
 
     pub type NativeMethodBind = for<'a> unsafe extern "C" fn(
         jvmti_env: crate::env::JvmtiEnv,
         jni_env: jni::JNIEnv<'a>,
-        thread: jthread,
-        method: jmethodID,
+        thread: Thread<'a>,
+        method: Method<'a>,
         address: *mut ::core::ffi::c_void,
         new_address_ptr: *mut *mut ::core::ffi::c_void,
     );
@@ -587,30 +619,30 @@ mod callback_types {
     pub type SampledObjectAlloc = for<'a> unsafe extern "C" fn(
         jvmti_env: crate::env::JvmtiEnv,
         jni_env: jni::JNIEnv<'a>,
-        thread: jthread,
-        object: jobject,
-        object_klass: jclass,
+        thread: Thread<'a>,
+        object: JObject<'a>,
+        object_klass: Class<'a>,
         size: jlong,
     );
 
     pub type SingleStep = for<'a> unsafe extern "C" fn(
         jvmti_env: crate::env::JvmtiEnv,
         jni_env: jni::JNIEnv<'a>,
-        thread: jthread,
-        method: jmethodID,
+        thread: Thread<'a>,
+        method: Method<'a>,
         location: jlocation,
     );
 
     pub type ThreadEnd = for<'a> unsafe extern "C" fn(
         jvmti_env: crate::env::JvmtiEnv,
         jni_env: jni::JNIEnv<'a>,
-        thread: jthread,
+        thread: Thread<'a>,
     );
 
     pub type ThreadStart = for<'a> unsafe extern "C" fn(
         jvmti_env: crate::env::JvmtiEnv,
         jni_env: jni::JNIEnv<'a>,
-        thread: jthread,
+        thread: Thread<'a>,
     );
 
     pub type VMDeath =
@@ -619,15 +651,15 @@ mod callback_types {
     pub type VMInit = for<'a> unsafe extern "C" fn(
         jvmti_env: crate::env::JvmtiEnv,
         jni_env: jni::JNIEnv<'a>,
-        thread: jthread,
+        thread: Thread<'a>,
     );
 
     pub type VMObjectAlloc = for<'a> unsafe extern "C" fn(
         jvmti_env: crate::env::JvmtiEnv,
         jni_env: jni::JNIEnv<'a>,
-        thread: jthread,
-        object: jobject,
-        object_klass: jclass,
+        thread: Thread<'a>,
+        object: JObject<'a>,
+        object_klass: Class<'a>,
         size: jlong,
     );
 
@@ -0,0 +1,548 @@
+use crate::callback_state::CallbackState;
+use crate::capability::Capability;
+use crate::event::{EventCallbacksBuilder, EventScope, EventType};
+use crate::handles::{Location, Method, Thread};
+use crate::raw_monitor::RawMonitor;
+use crate::stack::{self, FrameResolver, RawFrame, StackFrame};
+use crate::util::*;
+use crate::JvmtiEnv;
+use jni::objects::{GlobalRef, JObject, JString};
+use jni::{JNIEnv, JavaVM};
+use jni_jvmti_sys::jlocation;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::thread::ThreadId;
+
+const DEFAULT_MAX_FRAMES: usize = 64;
+const DEFAULT_MAX_EXCEPTIONS: usize = 10_000;
+
+pub struct ExceptionTracerBuilder {
+    include: Vec<String>,
+    exclude: Vec<String>,
+    max_frames: usize,
+    max_exceptions: usize,
+}
+
+/// Records exceptions from `Exception` and `ExceptionCatch` events in a dedicated JVMTI
+/// environment, with where each was thrown and caught. Counts are aggregated per throw site, to
+/// find hot paths that throw and swallow exceptions.
+pub struct ExceptionTracer<'a> {
+    state: CallbackState<'a, TracerState<'a>>,
+}
+
+struct TracerState<'a> {
+    include: Vec<String>,
+    exclude: Vec<String>,
+    max_frames: usize,
+    max_exceptions: usize,
+    exceptions: RawMonitor<'a, Exceptions>,
+}
+
+#[derive(Default)]
+struct Exceptions {
+    site_indices: HashMap<SiteKey, usize>,
+    sites: Vec<(SiteKey, SiteCounts)>,
+    recorded: Vec<RawException>,
+    dropped: u64,
+    /// The last exception thrown on each thread that a Java method is expected to catch
+    pending: HashMap<ThreadId, PendingCatch>,
+}
+
+struct PendingCatch {
+    exception: GlobalRef,
+    site: usize,
+    /// Index into `recorded`, None if dropped
+    recorded: Option<usize>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+struct SiteKey {
+    class_name: String,
+    throw_frame: RawFrame,
+}
+
+#[derive(Debug, Clone, Default)]
+struct SiteCounts {
+    thrown: u64,
+    uncaught: u64,
+    catches: Vec<(RawFrame, u64)>,
+}
+
+#[derive(Debug, Clone)]
+struct RawException {
+    site: usize,
+    message: Option<String>,
+    frames: Vec<RawFrame>,
+    uncaught: bool,
+    catch_frame: Option<RawFrame>,
+}
+
+/// A thrown exception
+#[derive(Debug, Clone)]
+pub struct TracedException {
+    /// e.g. `java.lang.NumberFormatException`
+    pub class_name: String,
+    /// As passed to the constructor, rather than from a possibly overridden `getMessage`
+    pub message: Option<String>,
+    /// None if the method has been unloaded
+    pub throw_frame: Option<StackFrame>,
+    /// From the top of the stack when thrown. Frames of unloaded methods are omitted
+    pub stack: Vec<StackFrame>,
+    /// No Java method was going to catch it, so it ended the thread or was returned to native code
+    pub uncaught: bool,
+    /// None if uncaught, not caught yet, or the method has been unloaded
+    pub catch_frame: Option<StackFrame>,
+}
+
+/// Where a Java method caught exceptions thrown at a site
+#[derive(Debug, Clone)]
+pub struct CatchSite {
+    /// None if the method has been unloaded
+    pub frame: Option<StackFrame>,
+    pub caught: u64,
+}
+
+/// Where exceptions of a class were thrown
+#[derive(Debug, Clone)]
+pub struct ExceptionSite {
+    /// e.g. `java.lang.NumberFormatException`
+    pub class_name: String,
+    /// None if the method has been unloaded
+    pub throw_frame: Option<StackFrame>,
+    pub thrown: u64,
+    pub uncaught: u64,
+    /// By descending count
+    pub catches: Vec<CatchSite>,
+}
+
+/// Exceptions seen since the tracer started
+#[derive(Debug, Clone)]
+pub struct ExceptionReport {
+    /// By descending count
+    pub sites: Vec<ExceptionSite>,
+    /// In the order they were thrown, up to the limit
+    pub exceptions: Vec<TracedException>,
+    /// Exceptions counted in `sites` but not recorded after reaching the limit
+    pub dropped_exceptions: u64,
+}
+
+impl Default for ExceptionTracerBuilder {
+    fn default() -> Self {
+        Self {
+            include: Vec::new(),
+            exclude: Vec::new(),
+            max_frames: DEFAULT_MAX_FRAMES,
+            max_exceptions: DEFAULT_MAX_EXCEPTIONS,
+        }
+    }
+}
+
+impl ExceptionTracerBuilder {
+    /// Only traces exceptions whose class matches one of the patterns, which are either exact
+    /// class names or end in `*` to match by prefix, e.g. `java.io.*`. All are traced if no
+    /// patterns are included
+    pub fn with_include(mut self, pattern: impl Into<String>) -> Self {
+        self.include.push(pattern.into());
+        self
+    }
+
+    /// Ignores exceptions whose class matches the pattern, even if included
+    pub fn with_exclude(mut self, pattern: impl Into<String>) -> Self {
+        self.exclude.push(pattern.into());
+        self
+    }
+
+    /// Deepest stack recorded for an exception, defaults to 64
+    pub fn with_max_frames(mut self, max_frames: usize) -> Self {
+        assert_ne!(max_frames, 0, "max frames must be non-zero");
+        self.max_frames = max_frames;
+        self
+    }
+
+    /// Exceptions after this many are counted per site but not recorded, defaults to 10000
+    pub fn with_max_exceptions(mut self, max_exceptions: usize) -> Self {
+        self.max_exceptions = max_exceptions;
+        self
+    }
+
+    /// Creates a new JVMTI environment and starts tracing. `can_generate_exception_events` is
+    /// only available in the live phase if another agent acquired it during startup
+    pub fn start<'a>(self, jvm: &JavaVM) -> JvmtiResult<ExceptionTracer<'a>> {
+        let jvmti = JvmtiEnv::from_jvm(jvm)?;
+        jvmti.require_capabilities(&[Capability::GenerateExceptionEvents])?;
+
+        let state = TracerState {
+            include: self.include,
+            exclude: self.exclude,
+            max_frames: self.max_frames,
+            max_exceptions: self.max_exceptions,
+            exceptions: RawMonitor::new(&jvmti, "exception tracer", Exceptions::default())?,
+        };
+        let tracer = ExceptionTracer {
+            state: CallbackState::new(jvmti, state)?,
+        };
+
+        let jvmti = tracer.state.jvmti();
+
+        let callbacks = EventCallbacksBuilder::default()
+            .with_exception(Some(exception))
+            .with_exception_catch(Some(exception_catch))
+            .build();
+        jvmti.install_event_callbacks(&callbacks)?;
+        jvmti.enable_event(EventType::Exception, EventScope::Global)?;
+        jvmti.enable_event(EventType::ExceptionCatch, EventScope::Global)?;
+
+        debug!(
+            "started exception tracer including {:?} excluding {:?}",
+            tracer.state.include, tracer.state.exclude
+        );
+        Ok(tracer)
+    }
+}
+
+impl<'a> ExceptionTracer<'a> {
+    pub fn builder() -> ExceptionTracerBuilder {
+        ExceptionTracerBuilder::default()
+    }
+
+    /// Resolves the exceptions traced so far
+    pub fn report(&self, jni: JNIEnv) -> JvmtiResult<ExceptionReport> {
+        let (sites, recorded, dropped) = {
+            let exceptions = self.state.exceptions.lock()?;
+            (
+                exceptions.sites.clone(),
+                exceptions.recorded.clone(),
+                exceptions.dropped,
+            )
+        };
+
+        let mut resolver = FrameResolver::new(self.state.jvmti(), jni);
+
+        let exceptions = recorded
+            .into_iter()
+            .map(|exception| TracedException {
+                class_name: sites[exception.site].0.class_name.clone(),
+                message: exception.message,
+                throw_frame: exception.frames.first().and_then(|&f| resolver.resolve(f)),
+                stack: exception
+                    .frames
+                    .iter()
+                    .filter_map(|&frame| resolver.resolve(frame))
+                    .collect(),
+                uncaught: exception.uncaught,
+                catch_frame: exception.catch_frame.and_then(|f| resolver.resolve(f)),
+            })
+            .collect();
+
+        let mut sites: Vec<ExceptionSite> = sites
+            .into_iter()
+            .map(|(key, mut counts)| {
+                counts.catches.sort_by_key(|&(_, caught)| Reverse(caught));
+                ExceptionSite {
+                    class_name: key.class_name,
+                    throw_frame: resolver.resolve(key.throw_frame),
+                    thrown: counts.thrown,
+                    uncaught: counts.uncaught,
+                    catches: counts
+                        .catches
+                        .into_iter()
+                        .map(|(frame, caught)| CatchSite {
+                            frame: resolver.resolve(frame),
+                            caught,
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+        sites.sort_by_key(|site| Reverse(site.thrown));
+
+        Ok(ExceptionReport {
+            sites,
+            exceptions,
+            dropped_exceptions: dropped,
+        })
+    }
+}
+
+impl Drop for ExceptionTracer<'_> {
+    fn drop(&mut self) {
+        for event in [EventType::Exception, EventType::ExceptionCatch] {
+            if let Err(err) = self.state.jvmti().disable_event(event, EventScope::Global) {
+                error!("failed to disable {:?}: {}", event, err);
+            }
+        }
+    }
+}
+
+impl TracerState<'_> {
+    fn traces(&self, class_name: &str) -> bool {
+        let matches = |patterns: &[String]| {
+            patterns
+                .iter()
+                .any(|pattern| matches_class_pattern(pattern, class_name))
+        };
+        (self.include.is_empty() || matches(&self.include)) && !matches(&self.exclude)
+    }
+}
+
+impl Exceptions {
+    fn throw(
+        &mut self,
+        key: SiteKey,
+        exception: RawException,
+        max_exceptions: usize,
+        pending: Option<GlobalRef>,
+    ) {
+        let site = match self.site_indices.get(&key) {
+            Some(&site) => site,
+            None => {
+                let site = self.sites.len();
+                self.site_indices.insert(key.clone(), site);
+                self.sites.push((key, SiteCounts::default()));
+                site
+            }
+        };
+
+        let counts = &mut self.sites[site].1;
+        counts.thrown += 1;
+        if exception.uncaught {
+            counts.uncaught += 1;
+        }
+
+        let recorded = if self.recorded.len() < max_exceptions {
+            self.recorded.push(RawException { site, ..exception });
+            Some(self.recorded.len() - 1)
+        } else {
+            self.dropped += 1;
+            None
+        };
+
+        let thread = std::thread::current().id();
+        match pending {
+            Some(exception) => {
+                self.pending.insert(
+                    thread,
+                    PendingCatch {
+                        exception,
+                        site,
+                        recorded,
+                    },
+                );
+            }
+            None => {
+                self.pending.remove(&thread);
+            }
+        }
+    }
+
+    /// Called on the catching thread
+    fn catch(&mut self, jni: JNIEnv, exception: JObject, catch_frame: RawFrame) {
+        let thread = std::thread::current().id();
+        let is_pending = match self.pending.get(&thread) {
+            Some(pending) => jni
+                .is_same_object(pending.exception.as_obj(), exception)
+                .unwrap_or(false),
+            None => false,
+        };
+        if !is_pending {
+            return;
+        }
+
+        let pending = self.pending.remove(&thread).expect("pending");
+        let catches = &mut self.sites[pending.site].1.catches;
+        match catches.iter_mut().find(|(frame, _)| *frame == catch_frame) {
+            Some((_, caught)) => *caught += 1,
+            None => catches.push((catch_frame, 1)),
+        }
+        if let Some(recorded) = pending.recorded {
+            self.recorded[recorded].catch_frame = Some(catch_frame);
+        }
+    }
+}
+
+fn raw_frame(method: Method, location: jlocation) -> RawFrame {
+    stack::raw_frame(Location::new(method, location))
+}
+
+fn exception_class_name(jvmti: &JvmtiEnv, jni: JNIEnv, exception: JObject) -> JvmtiResult<String> {
+    let class = jni.get_object_class(exception)?;
+    let signature = jvmti.get_class_signature(class.into());
+    jni.delete_local_ref(class.into())?;
+    Ok(java_class_name(&mutf8_to_string(signature?.as_bytes())))
+}
+
+/// Reads `Throwable.detailMessage` rather than calling Java code from the callback
+fn detail_message(jni: JNIEnv, exception: JObject) -> JvmtiResult<Option<String>> {
+    let message = jni
+        .get_field(exception, "detailMessage", "Ljava/lang/String;")?
+        .l()?;
+    if message.is_null() {
+        return Ok(None);
+    }
+
+    let string = jni.get_string(JString::from(message)).map(String::from);
+    jni.delete_local_ref(message)?;
+    Ok(Some(string?))
+}
+
+#[allow(clippy::too_many_arguments)]
+unsafe extern "C" fn exception(
+    jvmti_env: JvmtiEnv,
+    jni_env: JNIEnv,
+    thread: Thread,
+    method: Method,
+    location: jlocation,
+    exception: JObject,
+    catch_method: Method,
+    _catch_location: jlocation,
+) {
+    CallbackState::<TracerState>::with(&jvmti_env, |state| {
+        let class_name = match exception_class_name(&jvmti_env, jni_env, exception) {
+            Ok(class_name) => class_name,
+            Err(err) => {
+                warn!("failed to get exception class: {}", err);
+                return;
+            }
+        };
+        if !state.traces(&class_name) {
+            return;
+        }
+
+        let message = detail_message(jni_env, exception).unwrap_or_else(|err| {
+            debug!("failed to get {} message: {}", class_name, err);
+            let _ = jni_env.exception_clear();
+            None
+        });
+        let frames = match jvmti_env.get_stack_trace(thread, 0, state.max_frames) {
+            Ok(frames) => frames
+                .iter()
+                .map(|&frame| stack::raw_frame(frame))
+                .collect(),
+            Err(err) => {
+                warn!("failed to get {} stack trace: {}", class_name, err);
+                Vec::new()
+            }
+        };
+
+        let uncaught = catch_method.into_inner().is_null();
+        let pending = if uncaught {
+            None
+        } else {
+            match jni_env.new_global_ref(exception) {
+                Ok(exception) => Some(exception),
+                Err(err) => {
+                    warn!("failed to reference thrown {}: {}", class_name, err);
+                    None
+                }
+            }
+        };
+
+        let key = SiteKey {
+            class_name,
+            throw_frame: raw_frame(method, location),
+        };
+        let exception = RawException {
+            site: 0,
+            message,
+            frames,
+            uncaught,
+            catch_frame: None,
+        };
+        match state.exceptions.lock() {
+            Ok(mut exceptions) => exceptions.throw(key, exception, state.max_exceptions, pending),
+            Err(err) => error!("failed to record exception: {}", err),
+        }
+    });
+}
+
+unsafe extern "C" fn exception_catch(
+    jvmti_env: JvmtiEnv,
+    jni_env: JNIEnv,
+    _thread: Thread,
+    method: Method,
+    location: jlocation,
+    exception: JObject,
+) {
+    CallbackState::<TracerState>::with(&jvmti_env, |state| match state.exceptions.lock() {
+        Ok(mut exceptions) => exceptions.catch(jni_env, exception, raw_frame(method, location)),
+        Err(err) => error!("failed to record exception catch: {}", err),
+    });
+}
+
+impl ExceptionReport {
+    pub fn total_thrown(&self) -> u64 {
+        self.sites.iter().map(|site| site.thrown).sum()
+    }
+
+    /// Recorded exceptions that no Java method was going to catch
+    pub fn uncaught(&self) -> impl Iterator<Item = &TracedException> {
+        self.exceptions
+            .iter()
+            .filter(|exception| exception.uncaught)
+    }
+}
+
+fn write_frame(f: &mut Formatter<'_>, frame: &Option<StackFrame>) -> std::fmt::Result {
+    match frame {
+        Some(frame) => write!(f, "{}", frame),
+        None => write!(f, "<unloaded method>"),
+    }
+}
+
+/// As `Throwable.printStackTrace`, followed by where it was caught
+impl Display for TracedException {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.class_name)?;
+        if let Some(message) = &self.message {
+            write!(f, ": {}", message)?;
+        }
+        writeln!(f)?;
+        for frame in &self.stack {
+            writeln!(f, "\tat {}", frame)?;
+        }
+        if self.uncaught {
+            writeln!(f, "\tuncaught")?;
+        } else if let Some(frame) = &self.catch_frame {
+            writeln!(f, "\tcaught at {}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+/// A table of the throw sites with their catch sites, followed by the uncaught exceptions
+impl Display for ExceptionReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Exception sites by count, {} thrown in total:",
+            self.total_thrown()
+        )?;
+        writeln!(f, "   #thrown  #uncaught  exception")?;
+        for site in &self.sites {
+            writeln!(
+                f,
+                "{:10} {:10}  {}",
+                site.thrown, site.uncaught, site.class_name
+            )?;
+            write!(f, "\tat ")?;
+            write_frame(f, &site.throw_frame)?;
+            writeln!(f)?;
+            for catch in &site.catches {
+                write!(f, "\tcaught {} at ", catch.caught)?;
+                write_frame(f, &catch.frame)?;
+                writeln!(f)?;
+            }
+        }
+
+        writeln!(f)?;
+        writeln!(f, "Uncaught exceptions:")?;
+        for exception in self.uncaught() {
+            write!(f, "{}", exception)?;
+        }
+        if self.dropped_exceptions > 0 {
+            writeln!(f, "{} exceptions not recorded", self.dropped_exceptions)?;
+        }
+        Ok(())
+    }
+}
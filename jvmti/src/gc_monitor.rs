@@ -0,0 +1,343 @@
+use crate::agent_thread::{self, AgentThread, AgentThreadBuilder, Stoppable};
+use crate::callback_state::CallbackState;
+use crate::capability::Capability;
+use crate::event::{EventCallbacksBuilder, EventScope, EventType};
+use crate::raw_monitor::RawMonitor;
+use crate::util::*;
+use crate::JvmtiEnv;
+use jni::objects::JObject;
+use jni::{JNIEnv, JavaVM};
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+const DEFAULT_HISTORY: usize = 1000;
+const DEFAULT_QUEUE_CAPACITY: usize = 64;
+const EVENTS: [EventType; 2] = [
+    EventType::GarbageCollectionStart,
+    EventType::GarbageCollectionFinish,
+];
+
+type OnGc = Box<dyn FnMut(&GcPause, &JvmtiEnv, JNIEnv) + Send>;
+
+pub struct GcMonitorBuilder {
+    history: usize,
+    queue_capacity: usize,
+    on_gc: Option<OnGc>,
+}
+
+/// Measures stop the world garbage collection pauses from `GarbageCollectionStart` and
+/// `GarbageCollectionFinish` events in a dedicated JVMTI environment.
+///
+/// The callbacks run while the VM is stopped and may only use raw monitors, environment local
+/// storage and the `GetTime` timer, so they only timestamp each pause and queue it. An agent
+/// thread computes the statistics and runs the optional `on_gc` callback, where JNI and the rest
+/// of JVMTI are usable again.
+pub struct GcMonitor {
+    thread: AgentThread,
+    state: CallbackState<'static, RawMonitor<'static, Stoppable<GcState>>>,
+}
+
+#[derive(Default)]
+struct GcState {
+    /// `GetTime` at the start of the collection in progress
+    started: Option<Duration>,
+    /// Collections finished since the monitor started, including dropped ones
+    collections: u64,
+    /// Pauses not yet seen by the agent thread, preallocated as the callbacks must not allocate
+    queue: VecDeque<GcPause>,
+    stats: GcStats,
+    history: usize,
+}
+
+/// A single collection, timed with the `GetTime` timer whose origin is arbitrary
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct GcPause {
+    /// Counts from 1 since the monitor started, including dropped pauses
+    pub number: u64,
+    pub start: Duration,
+    pub end: Duration,
+    pub duration: Duration,
+}
+
+/// The pauses measured since the monitor started
+#[derive(Debug, Clone, Default)]
+pub struct GcStats {
+    /// Seen by the agent thread, which the total, min and max are of
+    pub pauses: u64,
+    pub total: Duration,
+    pub min: Duration,
+    pub max: Duration,
+    /// Up to the configured history, oldest first
+    pub recent: Vec<GcPause>,
+    /// Pauses the agent thread fell too far behind to see
+    pub dropped: u64,
+}
+
+impl Default for GcMonitorBuilder {
+    fn default() -> Self {
+        Self {
+            history: DEFAULT_HISTORY,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            on_gc: None,
+        }
+    }
+}
+
+impl GcMonitorBuilder {
+    /// How many of the most recent pauses to keep for percentiles, defaults to 1000
+    pub fn with_history(mut self, history: usize) -> Self {
+        assert_ne!(history, 0, "history must be non-zero");
+        self.history = history;
+        self
+    }
+
+    /// How many pauses can be waiting for the agent thread before further ones are dropped,
+    /// defaults to 64
+    pub fn with_queue_capacity(mut self, queue_capacity: usize) -> Self {
+        assert_ne!(queue_capacity, 0, "queue capacity must be non-zero");
+        self.queue_capacity = queue_capacity;
+        self
+    }
+
+    /// Called on the agent thread after each pause, e.g. to take a [histogram](crate::histogram).
+    /// The environment is the monitor's own, and each call has its own local reference frame.
+    /// Pauses are not reported once the monitor is stopping
+    pub fn with_on_gc(
+        mut self,
+        on_gc: impl FnMut(&GcPause, &JvmtiEnv, JNIEnv) + Send + 'static,
+    ) -> Self {
+        self.on_gc = Some(Box::new(on_gc));
+        self
+    }
+
+    /// Creates a new JVMTI environment and starts monitoring on a new agent thread
+    pub fn start(self, jvm: &JavaVM, jni: JNIEnv) -> JvmtiResult<GcMonitor> {
+        let jvmti = JvmtiEnv::from_jvm(jvm)?;
+        jvmti.require_capabilities(&[Capability::GenerateGarbageCollectionEvents])?;
+
+        let state = GcState {
+            queue: VecDeque::with_capacity(self.queue_capacity),
+            history: self.history,
+            ..GcState::default()
+        };
+        let state = RawMonitor::new(&jvmti, "gc monitor", Stoppable::new(state))?;
+        let state = CallbackState::new(jvmti, state)?;
+        let jvmti = state.jvmti();
+
+        let mut on_gc = self.on_gc;
+        let run = move |jvmti: JvmtiEnv, jni: JNIEnv, state: &RawMonitor<Stoppable<GcState>>| {
+            while let Some(pauses) = next_pauses(state) {
+                if let Some(on_gc) = on_gc.as_mut() {
+                    for pause in &pauses {
+                        if let Err(err) = jni.push_local_frame(64) {
+                            error!("gc monitor failed to push local frame: {}", err);
+                            continue;
+                        }
+                        on_gc(pause, &jvmti, jni);
+                        let _ = jni.pop_local_frame(JObject::null());
+                    }
+                }
+            }
+            debug!("gc monitor stopped");
+        };
+
+        // boxed by the callback state and stopped on drop, so outlives the thread
+        let thread = unsafe {
+            AgentThreadBuilder::new("gc monitor").spawn_stoppable(jvmti, jni, &[], &state, run)?
+        };
+        let monitor = GcMonitor { thread, state };
+
+        let jvmti = monitor.state.jvmti();
+
+        let callbacks = EventCallbacksBuilder::default()
+            .with_garbage_collection_start(Some(garbage_collection_start))
+            .with_garbage_collection_finish(Some(garbage_collection_finish))
+            .build();
+        jvmti.install_event_callbacks(&callbacks)?;
+        for event in EVENTS {
+            jvmti.enable_event(event, EventScope::Global)?;
+        }
+
+        debug!("started gc monitor");
+        Ok(monitor)
+    }
+}
+
+impl GcMonitor {
+    pub fn builder() -> GcMonitorBuilder {
+        GcMonitorBuilder::default()
+    }
+
+    pub fn thread(&self) -> &AgentThread {
+        &self.thread
+    }
+
+    /// The pauses seen by the agent thread so far
+    pub fn stats(&self) -> JvmtiResult<GcStats> {
+        let state = self.state.lock()?;
+        Ok(state.stats.clone())
+    }
+
+    /// Wakes the agent thread and waits for it to end. Pauses after this are still queued but
+    /// never reported. Also called on drop, and must not be called from the `on_gc` callback
+    pub fn stop(&self) -> JvmtiResult<()> {
+        agent_thread::stop(&self.state)
+    }
+}
+
+impl Drop for GcMonitor {
+    fn drop(&mut self) {
+        for event in EVENTS {
+            if let Err(err) = self.state.jvmti().disable_event(event, EventScope::Global) {
+                error!("failed to disable {:?}: {}", event, err);
+            }
+        }
+
+        if let Err(err) = self.stop() {
+            error!("failed to stop gc monitor: {}", err);
+        }
+    }
+}
+
+impl GcState {
+    /// Moves the queued pauses into the stats
+    fn drain(&mut self) -> Vec<GcPause> {
+        let mut pauses = Vec::with_capacity(self.queue.len());
+        while let Some(pause) = self.queue.pop_front() {
+            let stats = &mut self.stats;
+            stats.pauses += 1;
+            if stats.pauses == 1 {
+                stats.min = pause.duration;
+            } else {
+                stats.min = stats.min.min(pause.duration);
+            }
+            stats.max = stats.max.max(pause.duration);
+            stats.total += pause.duration;
+            if stats.recent.len() == self.history {
+                stats.recent.remove(0);
+            }
+            stats.recent.push(pause);
+            pauses.push(pause);
+        }
+        pauses
+    }
+}
+
+/// Blocks until pauses are queued, returning None once stopped
+fn next_pauses(state: &RawMonitor<Stoppable<GcState>>) -> Option<Vec<GcPause>> {
+    let mut state = match state.lock() {
+        Ok(state) => state,
+        Err(err) => {
+            error!("gc monitor failed to lock monitor: {}", err);
+            return None;
+        }
+    };
+
+    while state.queue.is_empty() && !state.is_stopped() {
+        if let Err(err) = state.wait(None) {
+            error!("gc monitor failed to wait: {}", err);
+            return None;
+        }
+    }
+
+    let pauses = state.drain();
+    if state.is_stopped() {
+        None
+    } else {
+        Some(pauses)
+    }
+}
+
+impl GcStats {
+    pub fn mean(&self) -> Option<Duration> {
+        if self.pauses == 0 {
+            None
+        } else {
+            Some(self.total.div_f64(self.pauses as f64))
+        }
+    }
+
+    /// Of the recent pauses by nearest rank, with `percentile` from 0 to 100
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        assert!(
+            (0.0..=100.0).contains(&percentile),
+            "percentile must be from 0 to 100"
+        );
+        if self.recent.is_empty() {
+            return None;
+        }
+
+        let mut durations: Vec<Duration> = self.recent.iter().map(|pause| pause.duration).collect();
+        durations.sort();
+        let rank = (percentile / 100.0 * durations.len() as f64).ceil() as usize;
+        Some(durations[rank.saturating_sub(1)])
+    }
+}
+
+// CallbackState::with only uses the functions the spec allows during garbage collection
+unsafe extern "C" fn garbage_collection_start(jvmti_env: JvmtiEnv) {
+    CallbackState::<RawMonitor<Stoppable<GcState>>>::with(&jvmti_env, |state| {
+        let time = jvmti_env.get_time();
+        if let Ok(mut state) = state.lock() {
+            state.started = time.ok();
+        }
+    });
+}
+
+unsafe extern "C" fn garbage_collection_finish(jvmti_env: JvmtiEnv) {
+    CallbackState::<RawMonitor<Stoppable<GcState>>>::with(&jvmti_env, |state| {
+        let time = jvmti_env.get_time();
+        if let Ok(mut state) = state.lock() {
+            // missing if the monitor started during this collection
+            if let (Some(start), Ok(end)) = (state.started.take(), time) {
+                state.collections += 1;
+                if state.queue.len() < state.queue.capacity() {
+                    let pause = GcPause {
+                        number: state.collections,
+                        start,
+                        end,
+                        duration: end.saturating_sub(start),
+                    };
+                    state.queue.push_back(pause);
+                    let _ = state.notify_all();
+                } else {
+                    state.stats.dropped += 1;
+                }
+            }
+        }
+    });
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+impl Display for GcStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} pauses", self.pauses)?;
+        if self.dropped > 0 {
+            write!(f, " ({} dropped)", self.dropped)?;
+        }
+        let mean = match self.mean() {
+            Some(mean) => mean,
+            None => return writeln!(f),
+        };
+        writeln!(
+            f,
+            ", total {:.3}ms, mean {:.3}ms, min {:.3}ms, max {:.3}ms",
+            millis(self.total),
+            millis(mean),
+            millis(self.min),
+            millis(self.max)
+        )?;
+
+        write!(f, "last {} pauses:", self.recent.len())?;
+        for percentile in [50.0, 90.0, 99.0, 100.0] {
+            if let Some(duration) = self.percentile(percentile) {
+                write!(f, " p{} {:.3}ms", percentile, millis(duration))?;
+            }
+        }
+        writeln!(f)
+    }
+}
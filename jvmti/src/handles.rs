@@ -0,0 +1,282 @@
+//! Typed wrappers for the JNI handles JVMTI passes around, so a `jclass` can't be passed where a
+//! `jthread` is expected.
+//!
+//! Their `Debug` impls resolve names on demand on attached threads, through a JVMTI environment
+//! that is created and disposed for each call, falling back to the raw pointer otherwise. Avoid
+//! formatting them where JVMTI functions are restricted, e.g. during garbage collection, and
+//! prefer `describe` where an environment is at hand.
+
+use crate::util::*;
+use crate::JvmtiEnv;
+use jni::objects::{JClass, JFieldID, JMethodID, JObject, JStaticMethodID};
+use jni::sys::{jclass, jlong, jmethodID};
+use jni::{JNIEnv, JavaVM};
+use jni_jvmti_sys::jthread;
+use std::fmt::{Debug, Formatter};
+use std::ops::Deref;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+/// A `java.lang.Thread`
+#[repr(transparent)]
+#[derive(Copy, Clone)]
+pub struct Thread<'a>(JObject<'a>);
+
+/// A `java.lang.Class`
+#[repr(transparent)]
+#[derive(Copy, Clone)]
+pub struct Class<'a>(JObject<'a>);
+
+/// A method, valid until its class is unloaded
+#[repr(transparent)]
+#[derive(Copy, Clone)]
+pub struct Method<'a>(JMethodID<'a>);
+
+/// A field, which needs its class to be resolved
+#[derive(Copy, Clone)]
+pub struct Field<'a> {
+    pub class: Class<'a>,
+    pub id: JFieldID<'a>,
+}
+
+/// An executable position within a method, laid out like `jvmtiFrameInfo`
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Location<'a> {
+    pub method: Method<'a>,
+    /// `jlocation`, which is the bytecode index in HotSpot, or -1 for native methods
+    pub bci: jlong,
+}
+
+static JAVA_VM: AtomicPtr<jni::sys::JavaVM> = AtomicPtr::new(null_mut());
+
+/// Remembers the VM so `Debug` impls can resolve names. Only the first call has any effect
+pub(crate) fn register_vm(jvm: *mut jni::sys::JavaVM) {
+    let _ = JAVA_VM.compare_exchange(null_mut(), jvm, Ordering::AcqRel, Ordering::Acquire);
+}
+
+/// None if no VM is registered, the current thread is not attached, or resolution fails
+fn resolve(f: impl FnOnce(&JvmtiEnv, JNIEnv) -> JvmtiResult<String>) -> Option<String> {
+    let jvm = JAVA_VM.load(Ordering::Acquire);
+    if jvm.is_null() {
+        return None;
+    }
+
+    let jvm = unsafe { JavaVM::from_raw(jvm) }.ok()?;
+    let jni = jvm.get_env().ok()?;
+    let jvmti = JvmtiEnv::from_jvm(&jvm).ok()?;
+    let resolved = f(&jvmti, jni);
+    if let Err(err) = jvmti.dispose() {
+        warn!("failed to dispose name resolution environment: {}", err);
+    }
+    resolved.ok()
+}
+
+fn class_name(jvmti: &JvmtiEnv, class: Class) -> JvmtiResult<String> {
+    let signature = jvmti.get_class_signature(class)?;
+    Ok(java_class_name(&mutf8_to_string(signature.as_bytes())))
+}
+
+fn method_name(jvmti: &JvmtiEnv, jni: JNIEnv, method: Method) -> JvmtiResult<String> {
+    let class = jvmti.get_method_declaring_class(jni, method)?;
+    let class_name = class_name(jvmti, class);
+    jni.delete_local_ref(*class)?;
+
+    let (name, signature) = jvmti.get_method_name(method)?;
+    Ok(format!(
+        "{}.{}{}",
+        class_name?,
+        mutf8_to_string(name.as_bytes()),
+        mutf8_to_string(signature.as_bytes())
+    ))
+}
+
+impl<'a> Thread<'a> {
+    pub fn into_inner(self) -> jthread {
+        self.0.into_inner()
+    }
+
+    /// The thread's name, e.g. `main`
+    pub fn describe(&self, jvmti: &JvmtiEnv, jni: JNIEnv) -> JvmtiResult<String> {
+        let info = jvmti.get_thread_info(jni, *self)?;
+        info.delete_local_refs(jni)?;
+        Ok(info.name)
+    }
+}
+
+impl<'a> Class<'a> {
+    pub fn into_inner(self) -> jclass {
+        self.0.into_inner()
+    }
+
+    /// e.g. `java.lang.String`
+    pub fn describe(&self, jvmti: &JvmtiEnv, _jni: JNIEnv) -> JvmtiResult<String> {
+        class_name(jvmti, *self)
+    }
+}
+
+impl<'a> Method<'a> {
+    pub fn into_inner(self) -> jmethodID {
+        self.0.into_inner()
+    }
+
+    /// e.g. `java.lang.Math.abs(I)I`
+    pub fn describe(&self, jvmti: &JvmtiEnv, jni: JNIEnv) -> JvmtiResult<String> {
+        method_name(jvmti, jni, *self)
+    }
+}
+
+impl<'a> Field<'a> {
+    pub fn new(class: Class<'a>, id: impl Into<JFieldID<'a>>) -> Self {
+        Self {
+            class,
+            id: id.into(),
+        }
+    }
+
+    /// e.g. `java.lang.String.value:[B`
+    pub fn describe(&self, jvmti: &JvmtiEnv, _jni: JNIEnv) -> JvmtiResult<String> {
+        let (name, signature) = jvmti.get_field_name(*self)?;
+        Ok(format!(
+            "{}.{}:{}",
+            class_name(jvmti, self.class)?,
+            mutf8_to_string(name.as_bytes()),
+            mutf8_to_string(signature.as_bytes())
+        ))
+    }
+}
+
+impl<'a> Location<'a> {
+    pub fn new(method: Method<'a>, bci: jlong) -> Self {
+        Self { method, bci }
+    }
+
+    pub fn is_native(&self) -> bool {
+        self.bci == -1
+    }
+
+    /// e.g. `java.lang.Math.abs(I)I@3`
+    pub fn describe(&self, jvmti: &JvmtiEnv, jni: JNIEnv) -> JvmtiResult<String> {
+        Ok(format!(
+            "{}@{}",
+            self.method.describe(jvmti, jni)?,
+            self.bci
+        ))
+    }
+}
+
+macro_rules! object_handle {
+    ($ty:ident, $raw:ty) => {
+        impl<'a> From<$raw> for $ty<'a> {
+            fn from(raw: $raw) -> Self {
+                Self(JObject::from(raw))
+            }
+        }
+
+        impl<'a> From<JObject<'a>> for $ty<'a> {
+            fn from(obj: JObject<'a>) -> Self {
+                Self(obj)
+            }
+        }
+
+        impl<'a> From<$ty<'a>> for JObject<'a> {
+            fn from(handle: $ty<'a>) -> Self {
+                handle.0
+            }
+        }
+
+        impl<'a> Deref for $ty<'a> {
+            type Target = JObject<'a>;
+
+            fn deref(&self) -> &Self::Target {
+                &self.0
+            }
+        }
+    };
+}
+
+object_handle!(Thread, jthread);
+object_handle!(Class, jclass);
+
+impl<'a> From<JClass<'a>> for Class<'a> {
+    fn from(class: JClass<'a>) -> Self {
+        Self(class.into())
+    }
+}
+
+impl<'a> From<Class<'a>> for JClass<'a> {
+    fn from(class: Class<'a>) -> Self {
+        JClass::from(class.0)
+    }
+}
+
+impl<'a> From<jmethodID> for Method<'a> {
+    fn from(method: jmethodID) -> Self {
+        Self(JMethodID::from(method))
+    }
+}
+
+impl<'a> From<JMethodID<'a>> for Method<'a> {
+    fn from(method: JMethodID<'a>) -> Self {
+        Self(method)
+    }
+}
+
+impl<'a> From<JStaticMethodID<'a>> for Method<'a> {
+    fn from(method: JStaticMethodID<'a>) -> Self {
+        Self::from(method.into_inner())
+    }
+}
+
+impl<'a> From<Method<'a>> for JMethodID<'a> {
+    fn from(method: Method<'a>) -> Self {
+        method.0
+    }
+}
+
+/// e.g. `Thread("main")`
+impl Debug for Thread<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match resolve(|jvmti, jni| self.describe(jvmti, jni)) {
+            Some(name) => write!(f, "Thread({:?})", name),
+            None => write!(f, "Thread({:?})", self.into_inner()),
+        }
+    }
+}
+
+/// e.g. `java.lang.String`
+impl Debug for Class<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match resolve(|jvmti, jni| self.describe(jvmti, jni)) {
+            Some(name) => write!(f, "{}", name),
+            None => write!(f, "Class({:?})", self.into_inner()),
+        }
+    }
+}
+
+/// e.g. `java.lang.Math.abs(I)I`
+impl Debug for Method<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match resolve(|jvmti, jni| self.describe(jvmti, jni)) {
+            Some(name) => write!(f, "{}", name),
+            None => write!(f, "Method({:?})", self.into_inner()),
+        }
+    }
+}
+
+/// e.g. `java.lang.String.value:[B`
+impl Debug for Field<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match resolve(|jvmti, jni| self.describe(jvmti, jni)) {
+            Some(name) => write!(f, "{}", name),
+            None => write!(f, "Field({:?})", self.id.into_inner()),
+        }
+    }
+}
+
+/// e.g. `java.lang.Math.abs(I)I@3`
+impl Debug for Location<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}@{}", self.method, self.bci)
+    }
+}
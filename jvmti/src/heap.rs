@@ -1,7 +1,13 @@
-use jni::objects::JValue;
+use crate::capability::Capability;
+use crate::handles::{Location, Method};
+use crate::tag::TagAllocator;
+use crate::util::*;
+use crate::JvmtiEnv;
+use jni::objects::{JObject, JValue};
 use jni::sys::*;
+use jni::JNIEnv;
 use jni_jvmti_sys::*;
-use std::fmt::{Debug, Formatter};
+use std::fmt::{Debug, Display, Formatter};
 use std::marker::PhantomData;
 use std::num::NonZeroI64;
 
@@ -74,6 +80,237 @@ pub enum HeapIterationCallback<'a> {
     },
 }
 
+/// Callback for `FollowReferences` with values, see [JvmtiEnv::follow_references_with_values]
+#[derive(Debug)]
+pub enum HeapWalkCallback<'a> {
+    Reference(HeapReference<'a>),
+    /// Any but [HeapIterationCallback::Object], which only heap iteration reports
+    Value(HeapIterationCallback<'a>),
+}
+
+/// Instance counts and sizes per class, as printed by `jmap -histo`
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    /// Sorted by descending size. Classes without instances are omitted
+    pub entries: Vec<HistogramEntry>,
+    /// Whether the VM has modules, i.e. is JDK 9 or later, so the module of each class is printed
+    pub modules: bool,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct HistogramEntry {
+    /// As printed by jmap, e.g. `java.lang.String` or `[Ljava.lang.Object;`
+    pub class_name: String,
+    /// Name and version of the class's module as printed by jmap, e.g. `java.base@17.0.2`. None
+    /// for the unnamed module and VMs without modules
+    pub module: Option<String>,
+    pub instances: u64,
+    pub bytes: u64,
+}
+
+/// The objects at either end of a reference reported by `FollowReferences`
+#[derive(Debug)]
+pub struct ReferenceTags<'a> {
+    /// None for heap roots
+    pub referrer_class_tag: Option<NonZeroJlong>,
+    /// None for heap roots and references from an object to itself, in which case `tag` is the
+    /// referrer's tag too
+    pub referrer_tag: Option<&'a mut jlong>,
+    /// Of the referree
+    pub class_tag: Option<NonZeroJlong>,
+    /// Of the referree
+    pub tag: &'a mut jlong,
+    /// Of the referree, in bytes
+    pub size: usize,
+    /// None if the referree is not an array
+    pub array_length: Option<usize>,
+}
+
+/// Callback for `FollowReferences`, one per reference from a root or an object to an object
+#[derive(Debug)]
+pub enum HeapReference<'a> {
+    /// From an object to its class
+    Class(ReferenceTags<'a>),
+    /// From an object to the value of one of its instance fields
+    Field {
+        /// As in [HeapIterationCallback::PrimitiveField]
+        index: jint,
+        tags: ReferenceTags<'a>,
+    },
+    /// From an array to one of its elements
+    ArrayElement {
+        index: jint,
+        tags: ReferenceTags<'a>,
+    },
+    /// From a class to its class loader
+    ClassLoader(ReferenceTags<'a>),
+    /// From a class to its signers array
+    Signers(ReferenceTags<'a>),
+    /// From a class to its protection domain
+    ProtectionDomain(ReferenceTags<'a>),
+    /// From a class to one of its interfaces
+    Interface(ReferenceTags<'a>),
+    /// From a class to the value of one of its static fields
+    StaticField {
+        index: jint,
+        tags: ReferenceTags<'a>,
+    },
+    /// From a class to a resolved entry in its constant pool
+    ConstantPool {
+        index: jint,
+        tags: ReferenceTags<'a>,
+    },
+    /// From a class to its superclass
+    Superclass(ReferenceTags<'a>),
+    /// Heap root: a JNI global reference
+    JniGlobal(ReferenceTags<'a>),
+    /// Heap root: a system class
+    SystemClass(ReferenceTags<'a>),
+    /// Heap root: a monitor
+    Monitor(ReferenceTags<'a>),
+    /// Heap root: a local variable on a thread's stack
+    StackLocal {
+        thread_tag: Option<NonZeroJlong>,
+        thread_id: jlong,
+        depth: jint,
+        location: Location<'a>,
+        slot: jint,
+        tags: ReferenceTags<'a>,
+    },
+    /// Heap root: a JNI local reference
+    JniLocal {
+        thread_tag: Option<NonZeroJlong>,
+        thread_id: jlong,
+        depth: jint,
+        method: Method<'a>,
+        tags: ReferenceTags<'a>,
+    },
+    /// Heap root: a thread
+    Thread(ReferenceTags<'a>),
+    /// Heap root: anything else
+    Other(ReferenceTags<'a>),
+}
+
+impl<'a> HeapReference<'a> {
+    /// # Safety
+    /// Info must be that passed to a `jvmtiHeapReferenceCallback` for the given kind
+    pub(crate) unsafe fn from_raw(
+        kind: jvmtiHeapReferenceKind,
+        info: *const jvmtiHeapReferenceInfo,
+        tags: ReferenceTags<'a>,
+    ) -> Self {
+        use jvmtiHeapReferenceKind::*;
+        match kind {
+            JVMTI_HEAP_REFERENCE_CLASS => Self::Class(tags),
+            JVMTI_HEAP_REFERENCE_FIELD => Self::Field {
+                index: (*info).field.index,
+                tags,
+            },
+            JVMTI_HEAP_REFERENCE_ARRAY_ELEMENT => Self::ArrayElement {
+                index: (*info).array.index,
+                tags,
+            },
+            JVMTI_HEAP_REFERENCE_CLASS_LOADER => Self::ClassLoader(tags),
+            JVMTI_HEAP_REFERENCE_SIGNERS => Self::Signers(tags),
+            JVMTI_HEAP_REFERENCE_PROTECTION_DOMAIN => Self::ProtectionDomain(tags),
+            JVMTI_HEAP_REFERENCE_INTERFACE => Self::Interface(tags),
+            JVMTI_HEAP_REFERENCE_STATIC_FIELD => Self::StaticField {
+                index: (*info).field.index,
+                tags,
+            },
+            JVMTI_HEAP_REFERENCE_CONSTANT_POOL => Self::ConstantPool {
+                index: (*info).constant_pool.index,
+                tags,
+            },
+            JVMTI_HEAP_REFERENCE_SUPERCLASS => Self::Superclass(tags),
+            JVMTI_HEAP_REFERENCE_JNI_GLOBAL => Self::JniGlobal(tags),
+            JVMTI_HEAP_REFERENCE_SYSTEM_CLASS => Self::SystemClass(tags),
+            JVMTI_HEAP_REFERENCE_MONITOR => Self::Monitor(tags),
+            JVMTI_HEAP_REFERENCE_STACK_LOCAL => {
+                let info = &(*info).stack_local;
+                Self::StackLocal {
+                    thread_tag: NonZeroJlong::new(info.thread_tag),
+                    thread_id: info.thread_id,
+                    depth: info.depth,
+                    location: Location::new(Method::from(info.method), info.location),
+                    slot: info.slot,
+                    tags,
+                }
+            }
+            JVMTI_HEAP_REFERENCE_JNI_LOCAL => {
+                let info = &(*info).jni_local;
+                Self::JniLocal {
+                    thread_tag: NonZeroJlong::new(info.thread_tag),
+                    thread_id: info.thread_id,
+                    depth: info.depth,
+                    method: Method::from(info.method),
+                    tags,
+                }
+            }
+            JVMTI_HEAP_REFERENCE_THREAD => Self::Thread(tags),
+            JVMTI_HEAP_REFERENCE_OTHER => Self::Other(tags),
+        }
+    }
+
+    pub fn tags(&self) -> &ReferenceTags<'a> {
+        match self {
+            Self::Class(tags)
+            | Self::ClassLoader(tags)
+            | Self::Signers(tags)
+            | Self::ProtectionDomain(tags)
+            | Self::Interface(tags)
+            | Self::Superclass(tags)
+            | Self::JniGlobal(tags)
+            | Self::SystemClass(tags)
+            | Self::Monitor(tags)
+            | Self::Thread(tags)
+            | Self::Other(tags)
+            | Self::Field { tags, .. }
+            | Self::ArrayElement { tags, .. }
+            | Self::StaticField { tags, .. }
+            | Self::ConstantPool { tags, .. }
+            | Self::StackLocal { tags, .. }
+            | Self::JniLocal { tags, .. } => tags,
+        }
+    }
+
+    pub fn tags_mut(&mut self) -> &mut ReferenceTags<'a> {
+        match self {
+            Self::Class(tags)
+            | Self::ClassLoader(tags)
+            | Self::Signers(tags)
+            | Self::ProtectionDomain(tags)
+            | Self::Interface(tags)
+            | Self::Superclass(tags)
+            | Self::JniGlobal(tags)
+            | Self::SystemClass(tags)
+            | Self::Monitor(tags)
+            | Self::Thread(tags)
+            | Self::Other(tags)
+            | Self::Field { tags, .. }
+            | Self::ArrayElement { tags, .. }
+            | Self::StaticField { tags, .. }
+            | Self::ConstantPool { tags, .. }
+            | Self::StackLocal { tags, .. }
+            | Self::JniLocal { tags, .. } => tags,
+        }
+    }
+
+    /// Whether the reference is from a heap root rather than an object
+    pub fn is_root(&self) -> bool {
+        matches!(
+            self,
+            Self::JniGlobal(_)
+                | Self::SystemClass(_)
+                | Self::Monitor(_)
+                | Self::StackLocal { .. }
+                | Self::JniLocal { .. }
+                | Self::Thread(_)
+                | Self::Other(_)
+        )
+    }
+}
+
 macro_rules! try_slice {
     ($name:ident, $ty:ty, $prim_type:ident) => {
         pub fn $name(&self) -> Option<&[$ty]> {
@@ -95,6 +332,18 @@ impl<'a> PrimitiveArray<'a> {
         }
     }
 
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn element_type(&self) -> jvmtiPrimitiveType {
+        self.ty
+    }
+
     try_slice!(z, jboolean, JVMTI_PRIMITIVE_TYPE_BOOLEAN);
     try_slice!(b, jbyte, JVMTI_PRIMITIVE_TYPE_BYTE);
     try_slice!(c, jchar, JVMTI_PRIMITIVE_TYPE_CHAR);
@@ -148,3 +397,180 @@ impl Debug for U16StrPrintable<'_> {
         write!(f, "{:?}", str)
     }
 }
+
+/// Counts the instances and bytes of every loaded class by iterating over the whole heap. Needs
+/// nothing but JVMTI, unlike `jmap` which needs a JDK.
+///
+/// `can_tag_objects` is requested if not already possessed. Each class is temporarily tagged with
+/// its index, and its original tag restored afterwards.
+pub fn histogram(jvmti: &JvmtiEnv, jni: JNIEnv) -> JvmtiResult<Histogram> {
+    jvmti.require_capabilities(&[Capability::TagObjects])?;
+
+    let classes = jvmti.get_loaded_classes(jni)?;
+    let allocator = TagAllocator::new();
+    let mut original_tags = Vec::with_capacity(classes.len());
+    let mut counts = vec![(0u64, 0u64); classes.len()];
+    let result = (|| {
+        for (index, class) in classes.iter().enumerate() {
+            let original = jvmti.get_tag(**class)?;
+            let tag = allocator.encode(index).expect("too many classes");
+            jvmti.set_tag(**class, Some(tag))?;
+            original_tags.push(original);
+        }
+
+        jvmti.iterate_through_heap(HeapFilterFlags::CLASS_UNTAGGED, None, |obj| {
+            if let HeapIterationCallback::Object {
+                class_tag: Some(class_tag),
+                size,
+                ..
+            } = obj
+            {
+                if let Some(index) = allocator.decode::<usize>(class_tag) {
+                    let (instances, bytes) = &mut counts[index];
+                    *instances += 1;
+                    *bytes += size as u64;
+                }
+            }
+            HeapVisitControlFlags::empty()
+        })
+    })();
+
+    // restore whatever was tagged, even if tagging failed part way through
+    let mut restored = Ok(());
+    for (class, tag) in classes.iter().zip(original_tags) {
+        let set = jvmti.set_tag(**class, tag);
+        if restored.is_ok() {
+            restored = set;
+        }
+    }
+    result?;
+    restored?;
+
+    let modules = has_modules(jni)?;
+    let mut entries = Vec::new();
+    for (class, &(instances, bytes)) in classes.iter().zip(&counts) {
+        if instances == 0 {
+            continue;
+        }
+
+        let signature = jvmti.get_class_signature(*class)?;
+        entries.push(HistogramEntry {
+            class_name: histogram_class_name(&mutf8_to_string(signature.as_bytes())),
+            module: if modules {
+                module_name(jni, **class)?
+            } else {
+                None
+            },
+            instances,
+            bytes,
+        });
+    }
+
+    entries.sort_by(|a, b| {
+        b.bytes
+            .cmp(&a.bytes)
+            .then_with(|| a.class_name.cmp(&b.class_name))
+    });
+    debug!("heap histogram has {} classes", entries.len());
+    Ok(Histogram { entries, modules })
+}
+
+/// Whether `Class.getModule` exists, which it does from JDK 9
+fn has_modules(jni: JNIEnv) -> JvmtiResult<bool> {
+    if jni
+        .get_method_id("java/lang/Class", "getModule", "()Ljava/lang/Module;")
+        .is_ok()
+    {
+        return Ok(true);
+    }
+    if jni.exception_check()? {
+        jni.exception_clear()?;
+    }
+    Ok(false)
+}
+
+/// `name@version` of the class's module, or None for the unnamed module. Arrays are in the module
+/// of their element type, and primitive arrays in `java.base`, as jmap prints them
+fn module_name(jni: JNIEnv, class: JObject) -> JvmtiResult<Option<String>> {
+    jni.push_local_frame(4)?;
+    let name = (|| -> JvmtiResult<Option<String>> {
+        let module = jni
+            .call_method(class, "getModule", "()Ljava/lang/Module;", &[])?
+            .l()?;
+        let descriptor = jni
+            .call_method(
+                module,
+                "getDescriptor",
+                "()Ljava/lang/module/ModuleDescriptor;",
+                &[],
+            )?
+            .l()?;
+        if descriptor.is_null() {
+            return Ok(None);
+        }
+        let name = jni
+            .call_method(descriptor, "toNameAndVersion", "()Ljava/lang/String;", &[])?
+            .l()?;
+        Ok(Some(jni.get_string(name.into())?.into()))
+    })();
+    jni.pop_local_frame(JObject::null())?;
+    name
+}
+
+/// `Ljava/lang/String;` to `java.lang.String`, while arrays keep their descriptor as in
+/// `[Ljava.lang.String;`
+fn histogram_class_name(signature: &str) -> String {
+    let name = signature
+        .strip_prefix('L')
+        .and_then(|s| s.strip_suffix(';'))
+        .unwrap_or(signature);
+    name.replace('/', ".")
+}
+
+impl Histogram {
+    pub fn total_instances(&self) -> u64 {
+        self.entries.iter().map(|e| e.instances).sum()
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.entries.iter().map(|e| e.bytes).sum()
+    }
+
+    pub fn get(&self, class_name: &str) -> Option<&HistogramEntry> {
+        self.entries.iter().find(|e| e.class_name == class_name)
+    }
+}
+
+/// In the format of `jmap -histo` from the same JDK, which adds the module of each class from
+/// JDK 9
+impl Display for Histogram {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.modules {
+            writeln!(f, " num     #instances         #bytes  class name (module)")?;
+            writeln!(f, "-------------------------------------------------------")?;
+        } else {
+            writeln!(f, " num     #instances         #bytes  class name")?;
+            writeln!(f, "----------------------------------------------")?;
+        }
+        for (i, entry) in self.entries.iter().enumerate() {
+            write!(
+                f,
+                "{:4}: {:13} {:13}  {}",
+                i + 1,
+                entry.instances,
+                entry.bytes,
+                entry.class_name
+            )?;
+            match &entry.module {
+                Some(module) => writeln!(f, " ({})", module)?,
+                None => writeln!(f)?,
+            }
+        }
+        writeln!(
+            f,
+            "Total {:13} {:13}",
+            self.total_instances(),
+            self.total_bytes()
+        )
+    }
+}
@@ -0,0 +1,1105 @@
+use crate::capability::Capability;
+use crate::handles::Class;
+use crate::heap::{
+    FieldType, HeapFilterFlags, HeapIterationCallback, HeapReference, HeapVisitControlFlags,
+    HeapWalkCallback, NonZeroJlong, PrimitiveArray, ReferenceTags,
+};
+use crate::stack::StackFrame;
+use crate::util::*;
+use crate::JvmtiEnv;
+use jni::objects::{JClass, JObject, JValue};
+use jni::sys::{jint, jlong};
+use jni::JNIEnv;
+use jni_jvmti_sys::jvmtiPrimitiveType;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const HEADER: &[u8] = b"JAVA PROFILE 1.0.2\0";
+const ID_SIZE: usize = 8;
+
+const TAG_STRING: u8 = 0x01;
+const TAG_LOAD_CLASS: u8 = 0x02;
+const TAG_FRAME: u8 = 0x04;
+const TAG_TRACE: u8 = 0x05;
+const TAG_HEAP_DUMP_SEGMENT: u8 = 0x1c;
+const TAG_HEAP_DUMP_END: u8 = 0x2c;
+
+const ROOT_UNKNOWN: u8 = 0xff;
+const ROOT_JNI_GLOBAL: u8 = 0x01;
+const ROOT_JNI_LOCAL: u8 = 0x02;
+const ROOT_JAVA_FRAME: u8 = 0x03;
+const ROOT_STICKY_CLASS: u8 = 0x05;
+const ROOT_MONITOR_USED: u8 = 0x07;
+const ROOT_THREAD_OBJECT: u8 = 0x08;
+const CLASS_DUMP: u8 = 0x20;
+const INSTANCE_DUMP: u8 = 0x21;
+const OBJ_ARRAY_DUMP: u8 = 0x22;
+const PRIM_ARRAY_DUMP: u8 = 0x23;
+
+/// Empty stack trace given as the allocation site of every object, which is unknown
+const NO_TRACE: u32 = 1;
+const MAX_FRAMES: usize = 1024;
+/// Line numbers of `FRAME` records without one
+const UNKNOWN_LINE: i32 = -1;
+const NATIVE_LINE: i32 = -3;
+
+/// Heap dump segments are written out once they grow past this many bytes
+const SEGMENT_SIZE: usize = 1 << 20;
+
+const ACC_STATIC: jint = 0x0008;
+
+/// Counts of what was written to a heap dump
+#[derive(Debug, Clone, Default)]
+pub struct HeapDumpSummary {
+    pub classes: usize,
+    pub instances: u64,
+    pub object_arrays: u64,
+    pub primitive_arrays: u64,
+    pub roots: u64,
+    pub bytes: u64,
+}
+
+/// Writes a binary HPROF heap dump of the live heap to a new file, in the format of
+/// `jmap -dump:live,format=b` so it can be opened in Eclipse MAT or VisualVM. See [write_heap_dump]
+pub fn dump_heap(jni: JNIEnv, path: impl AsRef<Path>) -> JvmtiResult<HeapDumpSummary> {
+    let path = path.as_ref();
+    let file = File::create(path)?;
+    let summary = write_heap_dump(jni, BufWriter::new(file))?;
+    info!("dumped heap to {}: {:?}", path.display(), summary);
+    Ok(summary)
+}
+
+/// Writes a binary HPROF heap dump of every object reachable from the heap roots, streaming
+/// records as the heap is walked rather than holding the object graph in memory.
+///
+/// Objects are identified by tags in a private environment with `can_tag_objects`, created for
+/// the dump and disposed after, so no tags of the caller's environments are touched. Instances and
+/// arrays are written from a single `FollowReferences` walk, which runs with the VM stopped, so
+/// they are a snapshot. Static field values are reported by the same walk, and are zero for
+/// classes it does not reach. Objects of classes loaded after the dump started are left out.
+pub fn write_heap_dump<W: Write>(jni: JNIEnv, out: W) -> JvmtiResult<HeapDumpSummary> {
+    let jvm = jni.get_java_vm()?;
+    let ids = JvmtiEnv::from_jvm(&jvm)?;
+
+    let result = (|| {
+        ids.require_capabilities(&[Capability::TagObjects])?;
+
+        let mut dumper = HeapDumper {
+            ids: &ids,
+            jni,
+            writer: HprofWriter::new(out),
+            classes: Vec::new(),
+            class_class: None,
+            thread_serials: HashMap::new(),
+            next_id: 1,
+            array_lengths: HashMap::new(),
+            object: None,
+            summary: HeapDumpSummary::default(),
+        };
+        dumper.dump()
+    })();
+
+    if let Err(err) = ids.dispose() {
+        warn!("failed to dispose heap dump environment: {}", err);
+    }
+    result
+}
+
+/// HPROF basic types
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum BasicType {
+    Object = 2,
+    Boolean = 4,
+    Char = 5,
+    Float = 6,
+    Double = 7,
+    Byte = 8,
+    Short = 9,
+    Int = 10,
+    Long = 11,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum ClassKind {
+    Instance,
+    ObjectArray,
+    PrimitiveArray,
+}
+
+struct FieldInfo {
+    name: u64,
+    ty: BasicType,
+}
+
+/// Where a field value goes in a record
+#[derive(Debug, Copy, Clone)]
+struct Slot {
+    offset: usize,
+    ty: BasicType,
+}
+
+struct ClassInfo {
+    kind: ClassKind,
+    /// 0 for `java.lang.Object`, interfaces and primitive arrays' absent superclass
+    super_id: u64,
+    /// Ids of the interfaces the class directly implements or extends
+    interface_ids: Vec<u64>,
+    /// Whether each field declared by the class is static, in `GetClassFields` order
+    declared_static: Vec<bool>,
+    instance_fields: Vec<FieldInfo>,
+    static_fields: Vec<FieldInfo>,
+    /// Bytes of instance field values, including those of superclasses
+    instance_size: usize,
+    /// Indexed by the field index the heap walk reports, into the values of an instance dump
+    instance_slots: Vec<Option<Slot>>,
+    /// Indexed by the field index the heap walk reports, into [static_values](Self::static_values)
+    static_slots: Vec<Option<Slot>>,
+    /// Static field values in order, filled in when the heap walk reaches the class
+    static_values: Vec<u8>,
+}
+
+/// An instance or object array whose fields or elements the heap walk is reporting
+struct ObjectRecord {
+    id: u64,
+    class_index: usize,
+    /// Instance field values in dump order, or element ids
+    values: Vec<u8>,
+}
+
+struct HprofWriter<W: Write> {
+    out: W,
+    /// Sub-records of the heap dump segment being built
+    segment: Vec<u8>,
+    strings: HashMap<String, u64>,
+    bytes: u64,
+}
+
+struct HeapDumper<'e, 'j, W: Write> {
+    /// Tags every object with its HPROF id. Classes are tagged with ids from 1 which double as
+    /// their class serial numbers
+    ids: &'e JvmtiEnv<'e>,
+    jni: JNIEnv<'j>,
+    writer: HprofWriter<W>,
+    /// Indexed by class id - 1
+    classes: Vec<ClassInfo>,
+    /// Index of `java.lang.Class`, whose instances are dumped as classes
+    class_class: Option<usize>,
+    /// Thread id to serial number from 1
+    thread_serials: HashMap<jlong, u32>,
+    next_id: jlong,
+    /// Lengths of object arrays reached but not yet visited by the heap walk
+    array_lengths: HashMap<u64, usize>,
+    /// Written out once the heap walk moves on to the next object
+    object: Option<ObjectRecord>,
+    summary: HeapDumpSummary,
+}
+
+/// Big endian encoding of HPROF values
+trait Put {
+    fn u1(&mut self, val: u8);
+    fn u2(&mut self, val: u16);
+    fn u4(&mut self, val: u32);
+    fn u8(&mut self, val: u64);
+    fn id(&mut self, id: u64) {
+        self.u8(id)
+    }
+}
+
+impl Put for Vec<u8> {
+    fn u1(&mut self, val: u8) {
+        self.push(val);
+    }
+
+    fn u2(&mut self, val: u16) {
+        self.extend_from_slice(&val.to_be_bytes());
+    }
+
+    fn u4(&mut self, val: u32) {
+        self.extend_from_slice(&val.to_be_bytes());
+    }
+
+    fn u8(&mut self, val: u64) {
+        self.extend_from_slice(&val.to_be_bytes());
+    }
+}
+
+/// Overwrites values in place, for records whose layout is known up front
+impl Put for &mut [u8] {
+    fn u1(&mut self, val: u8) {
+        overwrite(self, &[val]);
+    }
+
+    fn u2(&mut self, val: u16) {
+        overwrite(self, &val.to_be_bytes());
+    }
+
+    fn u4(&mut self, val: u32) {
+        overwrite(self, &val.to_be_bytes());
+    }
+
+    fn u8(&mut self, val: u64) {
+        overwrite(self, &val.to_be_bytes());
+    }
+}
+
+fn overwrite(dst: &mut &mut [u8], bytes: &[u8]) {
+    let (head, tail) = std::mem::take(dst).split_at_mut(bytes.len());
+    head.copy_from_slice(bytes);
+    *dst = tail;
+}
+
+impl<'e, 'j, W: Write> HeapDumper<'e, 'j, W> {
+    fn dump(&mut self) -> JvmtiResult<HeapDumpSummary> {
+        self.writer.header()?;
+        let classes = self.ids.get_loaded_classes(self.jni)?;
+        self.load_classes(&classes)?;
+        self.layout_fields();
+        // objects are numbered after the classes
+        self.next_id = classes.len() as jlong + 1;
+        self.write_threads()?;
+
+        self.dump_objects()?;
+        self.dump_classes(&classes)?;
+
+        self.summary.classes = self.classes.len();
+        self.summary.bytes = self.writer.finish()?;
+        debug!("wrote heap dump: {:?}", self.summary);
+        Ok(self.summary.clone())
+    }
+
+    /// Assigns class ids and writes a `LOAD CLASS` for each
+    fn load_classes(&mut self, classes: &[Class]) -> JvmtiResult<()> {
+        for (index, &class) in classes.iter().enumerate() {
+            let id = index as jlong + 1;
+            self.ids.set_tag(*class, NonZeroJlong::new(id))?;
+
+            let signature = mutf8_to_string(self.ids.get_class_signature(class)?.as_bytes());
+            let kind = match signature.as_bytes() {
+                [b'[', b'L', ..] | [b'[', b'[', ..] => ClassKind::ObjectArray,
+                [b'[', ..] => ClassKind::PrimitiveArray,
+                _ => ClassKind::Instance,
+            };
+            if signature == "Ljava/lang/Class;" {
+                self.class_class = Some(index);
+            }
+
+            let name = self.writer.string(hprof_class_name(&signature))?;
+            self.writer.record(TAG_LOAD_CLASS, |body| {
+                body.u4(id as u32);
+                body.id(id as u64);
+                body.u4(NO_TRACE);
+                body.id(name);
+            })?;
+
+            let fields = match self.ids.get_class_fields(class) {
+                Err(Error::Jvmti(JvmtiError::ClassNotPrepared)) => Vec::new(),
+                result => result?,
+            };
+            let mut info = ClassInfo {
+                kind,
+                super_id: 0,
+                interface_ids: Vec::new(),
+                declared_static: Vec::with_capacity(fields.len()),
+                instance_fields: Vec::new(),
+                static_fields: Vec::new(),
+                instance_size: 0,
+                instance_slots: Vec::new(),
+                static_slots: Vec::new(),
+                static_values: Vec::new(),
+            };
+            for field in fields {
+                let (name, signature) = self.ids.get_field_name(field)?;
+                let field_info = FieldInfo {
+                    name: self.writer.string(&mutf8_to_string(name.as_bytes()))?,
+                    ty: BasicType::from_signature(signature.as_bytes()),
+                };
+                let is_static = self.ids.get_field_modifiers(field)? & ACC_STATIC != 0;
+                info.declared_static.push(is_static);
+                if is_static {
+                    info.static_fields.push(field_info);
+                } else {
+                    info.instance_fields.push(field_info);
+                }
+            }
+            self.classes.push(info);
+        }
+
+        // now every class has an id
+        for (info, &class) in self.classes.iter_mut().zip(classes) {
+            let superclass = self.jni.get_superclass(JClass::from(class))?;
+            info.super_id = local_ref_id(self.ids, self.jni, *superclass)?;
+
+            match self.ids.get_implemented_interfaces(self.jni, class) {
+                Err(Error::Jvmti(JvmtiError::ClassNotPrepared)) => {}
+                result => {
+                    for &interface in result?.iter() {
+                        info.interface_ids.push(object_id(self.ids, *interface)?);
+                    }
+                }
+            }
+        }
+
+        debug!("loaded {} classes", classes.len());
+        Ok(())
+    }
+
+    /// Works out where the field values the heap walk reports go. As in the JVMTI spec, the fields
+    /// of a class are numbered after those of all the interfaces it implements, then after those
+    /// of its superclasses from `java.lang.Object` down, each class's in `GetClassFields` order
+    fn layout_fields(&mut self) {
+        for index in 0..self.classes.len() {
+            if self.classes[index].kind != ClassKind::Instance {
+                continue;
+            }
+
+            // superclasses first
+            let mut hierarchy = vec![index];
+            while let Some(superclass) = self.super_index(&self.classes[hierarchy[0]]) {
+                hierarchy.insert(0, superclass);
+            }
+
+            let instance_size: usize = hierarchy
+                .iter()
+                .flat_map(|&class| &self.classes[class].instance_fields)
+                .map(|field| field.ty.size())
+                .sum();
+            let mut instance_slots = Vec::new();
+            let mut static_slots = Vec::new();
+            let mut field_index = self.interface_field_count(&hierarchy);
+            // an instance dump holds the class's own fields first, so the superclasses' go last
+            let mut class_offset = instance_size;
+            for &class in &hierarchy {
+                let info = &self.classes[class];
+                let mut instance_fields = info.instance_fields.iter();
+                let mut static_fields = info.static_fields.iter();
+                class_offset -= instance_fields
+                    .clone()
+                    .map(|field| field.ty.size())
+                    .sum::<usize>();
+                let mut instance_offset = class_offset;
+                let mut static_offset = 0;
+
+                for &is_static in &info.declared_static {
+                    let (fields, offset, slots) = if is_static {
+                        (&mut static_fields, &mut static_offset, &mut static_slots)
+                    } else {
+                        (
+                            &mut instance_fields,
+                            &mut instance_offset,
+                            &mut instance_slots,
+                        )
+                    };
+                    let ty = fields.next().expect("declared field").ty;
+                    // a class's statics are its own, not its subclasses'
+                    if !is_static || class == index {
+                        if slots.len() <= field_index {
+                            slots.resize(field_index + 1, None);
+                        }
+                        slots[field_index] = Some(Slot {
+                            offset: *offset,
+                            ty,
+                        });
+                    }
+                    *offset += ty.size();
+                    field_index += 1;
+                }
+            }
+
+            let info = &mut self.classes[index];
+            info.static_values = vec![0; info.static_fields.iter().map(|f| f.ty.size()).sum()];
+            info.instance_size = instance_size;
+            info.instance_slots = instance_slots;
+            info.static_slots = static_slots;
+        }
+    }
+
+    /// Fields of every interface implemented by the classes, counting each interface once
+    fn interface_field_count(&self, classes: &[usize]) -> usize {
+        let mut interfaces: Vec<u64> = classes
+            .iter()
+            .flat_map(|&class| self.classes[class].interface_ids.iter().copied())
+            .collect();
+        let mut seen = HashSet::new();
+        let mut count = 0;
+        while let Some(id) = interfaces.pop() {
+            let info = match self.class_index(id) {
+                Some(index) if seen.insert(id) => &self.classes[index],
+                _ => continue,
+            };
+            count += info.declared_static.len();
+            interfaces.extend_from_slice(&info.interface_ids);
+        }
+        count
+    }
+
+    /// Writes the stack trace of every thread, and assigns their ids and serial numbers
+    fn write_threads(&mut self) -> JvmtiResult<()> {
+        self.writer.record(TAG_TRACE, |body| {
+            body.u4(NO_TRACE);
+            body.u4(0);
+            body.u4(0);
+        })?;
+
+        let threads = self.ids.get_all_threads(self.jni)?;
+        let mut next_frame_id = 1;
+        for (index, &thread) in threads.iter().enumerate() {
+            let serial = index as u32 + 1;
+            let id = self.next_id();
+            self.ids.set_tag(*thread, NonZeroJlong::new(id))?;
+            self.thread_serials.insert(id, serial);
+
+            let locations = match self.ids.get_stack_trace(thread, 0, MAX_FRAMES) {
+                Err(Error::Jvmti(JvmtiError::ThreadNotAlive)) => Vec::new(),
+                result => result?,
+            };
+
+            let mut frame_ids = Vec::with_capacity(locations.len());
+            for location in locations {
+                let frame = StackFrame::resolve(self.ids, self.jni, location)?;
+                let class = self
+                    .ids
+                    .get_method_declaring_class(self.jni, location.method)?;
+                let class_serial = local_ref_id(self.ids, self.jni, *class)? as u32;
+
+                let method_name = self.writer.string(&frame.method_name)?;
+                let method_signature = self.writer.string(&frame.method_signature)?;
+                let source_file = match &frame.source_file {
+                    Some(file) => self.writer.string(file)?,
+                    None => 0,
+                };
+                let line = match frame.line_number {
+                    _ if frame.is_native() => NATIVE_LINE,
+                    Some(line) => line,
+                    None => UNKNOWN_LINE,
+                };
+
+                let frame_id = next_frame_id;
+                next_frame_id += 1;
+                frame_ids.push(frame_id);
+                self.writer.record(TAG_FRAME, |body| {
+                    body.id(frame_id);
+                    body.id(method_name);
+                    body.id(method_signature);
+                    body.id(source_file);
+                    body.u4(class_serial);
+                    body.u4(line as u32);
+                })?;
+            }
+
+            self.writer.record(TAG_TRACE, |body| {
+                body.u4(thread_trace(serial));
+                body.u4(serial);
+                body.u4(frame_ids.len() as u32);
+                for &frame_id in &frame_ids {
+                    body.id(frame_id);
+                }
+            })?;
+        }
+
+        debug!("wrote stack traces of {} threads", threads.len());
+        Ok(())
+    }
+
+    /// Writes the roots, and every instance and array reachable from them, in a single walk of
+    /// the heap. Each object's fields or elements are reported together as the walk visits it
+    fn dump_objects(&mut self) -> JvmtiResult<()> {
+        let ids = self.ids;
+        let mut io_result = Ok(());
+        ids.follow_references_with_values(
+            HeapFilterFlags::empty(),
+            None,
+            None,
+            |walked| match self.visit(walked) {
+                Ok(flags) => flags,
+                Err(err) => {
+                    io_result = Err(err);
+                    HeapVisitControlFlags::ABORT
+                }
+            },
+        )?;
+        io_result?;
+        self.write_object()?;
+
+        debug!(
+            "dumped {} roots, {} instances, {} object arrays and {} primitive arrays",
+            self.summary.roots,
+            self.summary.instances,
+            self.summary.object_arrays,
+            self.summary.primitive_arrays
+        );
+        Ok(())
+    }
+
+    fn visit(&mut self, walked: HeapWalkCallback) -> io::Result<HeapVisitControlFlags> {
+        match walked {
+            HeapWalkCallback::Reference(mut reference) => {
+                let id = match self.reach(reference.tags_mut()) {
+                    Some(id) => id,
+                    None => return Ok(HeapVisitControlFlags::empty()),
+                };
+                if reference.is_root() {
+                    self.write_root(&reference, id)?;
+                } else {
+                    self.put_reference(&reference, id)?;
+                }
+                Ok(HeapVisitControlFlags::VISIT_OBJECTS)
+            }
+            HeapWalkCallback::Value(HeapIterationCallback::PrimitiveField {
+                field_type,
+                field_index,
+                object_class_tag,
+                object_tag,
+                value,
+            }) => {
+                let id = *object_tag as u64;
+                match field_type {
+                    FieldType::Static => self.put_static(id, field_index, |slot, values| {
+                        put_value(values, slot, value)
+                    }),
+                    FieldType::Instance => {
+                        self.put_field(id, object_class_tag, field_index, |slot, values| {
+                            put_value(values, slot, value)
+                        })?
+                    }
+                }
+                Ok(HeapVisitControlFlags::empty())
+            }
+            HeapWalkCallback::Value(HeapIterationCallback::PrimitiveArray {
+                tag,
+                elements,
+                ..
+            }) => {
+                if *tag != 0 {
+                    self.summary.primitive_arrays += 1;
+                    self.writer.primitive_array(*tag as u64, &elements)?;
+                }
+                Ok(HeapVisitControlFlags::empty())
+            }
+            HeapWalkCallback::Value(_) => Ok(HeapVisitControlFlags::empty()),
+        }
+    }
+
+    /// The referree's id, assigned when it is first reached. None for objects the dump leaves out
+    fn reach(&mut self, tags: &mut ReferenceTags) -> Option<u64> {
+        if *tags.tag != 0 {
+            return Some(*tags.tag as u64);
+        }
+
+        // an instance of a class loaded after the dump started
+        let class_index = self.class_index(tag_id(tags.class_tag))?;
+        // loaded classes are tagged up front, so this is a class loaded after the dump started or
+        // a primitive type's
+        if Some(class_index) == self.class_class {
+            return None;
+        }
+
+        let id = self.next_id();
+        *tags.tag = id;
+        if self.classes[class_index].kind == ClassKind::ObjectArray {
+            let length = tags.array_length.unwrap_or(0);
+            self.array_lengths.insert(id as u64, length);
+        }
+        Some(id as u64)
+    }
+
+    fn write_root(&mut self, reference: &HeapReference, id: u64) -> io::Result<()> {
+        let mut root = Vec::with_capacity(1 + ID_SIZE * 2);
+        match *reference {
+            HeapReference::JniGlobal(_) => {
+                root.u1(ROOT_JNI_GLOBAL);
+                root.id(id);
+                root.id(0);
+            }
+            HeapReference::JniLocal {
+                thread_tag, depth, ..
+            } => {
+                root.u1(ROOT_JNI_LOCAL);
+                root.id(id);
+                root.u4(self.thread_serial(tag_id(thread_tag)));
+                root.u4(depth as u32);
+            }
+            HeapReference::StackLocal {
+                thread_tag, depth, ..
+            } => {
+                root.u1(ROOT_JAVA_FRAME);
+                root.id(id);
+                root.u4(self.thread_serial(tag_id(thread_tag)));
+                root.u4(depth as u32);
+            }
+            HeapReference::SystemClass(_) => {
+                root.u1(ROOT_STICKY_CLASS);
+                root.id(id);
+            }
+            HeapReference::Monitor(_) => {
+                root.u1(ROOT_MONITOR_USED);
+                root.id(id);
+            }
+            HeapReference::Thread(_) => {
+                let serial = self.thread_serial(id);
+                root.u1(ROOT_THREAD_OBJECT);
+                root.id(id);
+                root.u4(serial);
+                root.u4(if serial == 0 {
+                    NO_TRACE
+                } else {
+                    thread_trace(serial)
+                });
+            }
+            _ => {
+                root.u1(ROOT_UNKNOWN);
+                root.id(id);
+            }
+        }
+
+        self.summary.roots += 1;
+        self.writer.sub_record(&root)
+    }
+
+    /// Puts the referree's id in the referrer's record, for references to field values and
+    /// array elements
+    fn put_reference(&mut self, reference: &HeapReference, id: u64) -> io::Result<()> {
+        let tags = reference.tags();
+        // references from an object to itself have no referrer tag
+        let referrer = tags.referrer_tag.as_deref().map_or(id, |&tag| tag as u64);
+        let referrer_class_tag = tags.referrer_class_tag;
+
+        match *reference {
+            HeapReference::StaticField { index, .. } => {
+                self.put_static(referrer, index, |slot, values| put_id(values, slot, id));
+            }
+            HeapReference::Field { index, .. } => {
+                self.put_field(referrer, referrer_class_tag, index, |slot, values| {
+                    put_id(values, slot, id)
+                })?;
+            }
+            HeapReference::ArrayElement { index, .. } => {
+                if let Some(object) = self.object(referrer, referrer_class_tag)? {
+                    let slot = Slot {
+                        offset: index as usize * ID_SIZE,
+                        ty: BasicType::Object,
+                    };
+                    put_id(&mut object.values, slot, id);
+                }
+            }
+            // every visited object reports its class, so even those without fields or elements
+            // to report get a record
+            HeapReference::Class(_) if self.class_index(referrer).is_none() => {
+                self.object(referrer, referrer_class_tag)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn put_static(&mut self, class_id: u64, field_index: jint, put: impl FnOnce(Slot, &mut [u8])) {
+        if let Some(index) = self.class_index(class_id) {
+            let info = &mut self.classes[index];
+            if let Some(&Some(slot)) = info.static_slots.get(field_index as usize) {
+                put(slot, &mut info.static_values);
+            }
+        }
+    }
+
+    fn put_field(
+        &mut self,
+        id: u64,
+        class_tag: Option<NonZeroJlong>,
+        field_index: jint,
+        put: impl FnOnce(Slot, &mut [u8]),
+    ) -> io::Result<()> {
+        let slot = self
+            .class_index(tag_id(class_tag))
+            .and_then(|index| self.classes[index].instance_slots.get(field_index as usize))
+            .copied()
+            .flatten();
+        if let (Some(slot), Some(object)) = (slot, self.object(id, class_tag)?) {
+            put(slot, &mut object.values);
+        }
+        Ok(())
+    }
+
+    /// The record of the instance or object array the walk is visiting, writing out that of the
+    /// previous object if the walk has moved on. None for objects of unknown or primitive array
+    /// classes, and classes, which are dumped separately
+    fn object(
+        &mut self,
+        id: u64,
+        class_tag: Option<NonZeroJlong>,
+    ) -> io::Result<Option<&mut ObjectRecord>> {
+        if self.object.as_ref().map(|object| object.id) != Some(id) {
+            self.write_object()?;
+
+            let class_index = match self.class_index(tag_id(class_tag)) {
+                Some(index) if self.class_index(id).is_none() => index,
+                _ => return Ok(None),
+            };
+            let size = match self.classes[class_index].kind {
+                ClassKind::Instance => self.classes[class_index].instance_size,
+                ClassKind::ObjectArray => self.array_lengths.remove(&id).unwrap_or(0) * ID_SIZE,
+                ClassKind::PrimitiveArray => return Ok(None),
+            };
+            self.object = Some(ObjectRecord {
+                id,
+                class_index,
+                values: vec![0; size],
+            });
+        }
+        Ok(self.object.as_mut())
+    }
+
+    /// Writes out the record of the object last visited
+    fn write_object(&mut self) -> io::Result<()> {
+        let object = match self.object.take() {
+            Some(object) => object,
+            None => return Ok(()),
+        };
+
+        let class_id = object.class_index as u64 + 1;
+        let mut body = Vec::with_capacity(1 + ID_SIZE * 2 + 8 + object.values.len());
+        if self.classes[object.class_index].kind == ClassKind::ObjectArray {
+            body.u1(OBJ_ARRAY_DUMP);
+            body.id(object.id);
+            body.u4(NO_TRACE);
+            body.u4((object.values.len() / ID_SIZE) as u32);
+            body.id(class_id);
+            self.summary.object_arrays += 1;
+        } else {
+            body.u1(INSTANCE_DUMP);
+            body.id(object.id);
+            body.u4(NO_TRACE);
+            body.id(class_id);
+            body.u4(object.values.len() as u32);
+            self.summary.instances += 1;
+        }
+        body.extend_from_slice(&object.values);
+        self.writer.sub_record(&body)
+    }
+
+    fn dump_classes(&mut self, classes: &[Class]) -> JvmtiResult<()> {
+        for (index, &class) in classes.iter().enumerate() {
+            let info = &self.classes[index];
+            let loader = match self.ids.get_class_loader(self.jni, class)? {
+                Some(loader) => local_ref_id(self.ids, self.jni, loader)?,
+                None => 0,
+            };
+
+            let mut body = Vec::new();
+            body.u1(CLASS_DUMP);
+            body.id(index as u64 + 1);
+            body.u4(NO_TRACE);
+            body.id(info.super_id);
+            body.id(loader);
+            // signers, protection domain and 2 reserved
+            for _ in 0..4 {
+                body.id(0);
+            }
+            body.u4(info.instance_size as u32);
+            // constant pool
+            body.u2(0);
+
+            body.u2(info.static_fields.len() as u16);
+            let mut values = info.static_values.as_slice();
+            for field in &info.static_fields {
+                let (value, rest) = values.split_at(field.ty.size());
+                values = rest;
+                body.id(field.name);
+                body.u1(field.ty as u8);
+                body.extend_from_slice(value);
+            }
+
+            body.u2(info.instance_fields.len() as u16);
+            for field in &info.instance_fields {
+                body.id(field.name);
+                body.u1(field.ty as u8);
+            }
+
+            self.writer.sub_record(&body)?;
+        }
+
+        debug!("dumped {} classes", classes.len());
+        Ok(())
+    }
+
+    /// Index of the class with the id, or None if the id is not a class's
+    fn class_index(&self, id: u64) -> Option<usize> {
+        match id as usize {
+            0 => None,
+            id if id <= self.classes.len() => Some(id - 1),
+            _ => None,
+        }
+    }
+
+    fn super_index(&self, info: &ClassInfo) -> Option<usize> {
+        self.class_index(info.super_id)
+    }
+
+    /// 0 for threads not alive when the dump started
+    fn thread_serial(&self, thread_id: u64) -> u32 {
+        self.thread_serials
+            .get(&(thread_id as jlong))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    fn next_id(&mut self) -> jlong {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+}
+
+/// Serial number of the stack trace of the thread with the given serial number
+fn thread_trace(thread_serial: u32) -> u32 {
+    NO_TRACE + thread_serial
+}
+
+/// The object's HPROF id, or 0 for null or objects without one
+fn object_id(ids: &JvmtiEnv, object: JObject) -> JvmtiResult<u64> {
+    if object.is_null() {
+        return Ok(0);
+    }
+
+    Ok(ids.get_tag(object)?.map_or(0, |tag| tag.get() as u64))
+}
+
+/// As [object_id], deleting the local ref after
+fn local_ref_id(ids: &JvmtiEnv, jni: JNIEnv, object: JObject) -> JvmtiResult<u64> {
+    let id = object_id(ids, object)?;
+    if !object.is_null() {
+        jni.delete_local_ref(object)?;
+    }
+    Ok(id)
+}
+
+/// Puts a primitive field value in its slot, unless the walk reported a value of another type
+fn put_value(mut values: &mut [u8], slot: Slot, value: JValue) {
+    if BasicType::from_value(&value) != Some(slot.ty) || values.len() < slot.offset + slot.ty.size()
+    {
+        return;
+    }
+
+    values = &mut values[slot.offset..];
+    match value {
+        JValue::Bool(val) => values.u1(val),
+        JValue::Byte(val) => values.u1(val as u8),
+        JValue::Char(val) => values.u2(val),
+        JValue::Short(val) => values.u2(val as u16),
+        JValue::Int(val) => values.u4(val as u32),
+        JValue::Long(val) => values.u8(val as u64),
+        JValue::Float(val) => values.u4(val.to_bits()),
+        JValue::Double(val) => values.u8(val.to_bits()),
+        JValue::Object(_) | JValue::Void => {}
+    }
+}
+
+/// Puts an object id in its slot, unless it is a primitive field's
+fn put_id(values: &mut [u8], slot: Slot, id: u64) {
+    if slot.ty == BasicType::Object && values.len() >= slot.offset + ID_SIZE {
+        (&mut values[slot.offset..]).id(id);
+    }
+}
+
+/// The id of an object with the tag, or 0 for none
+fn tag_id(tag: Option<NonZeroJlong>) -> u64 {
+    tag.map_or(0, |tag| tag.get() as u64)
+}
+
+/// `Ljava/lang/String;` to `java/lang/String`, while arrays keep their descriptor
+fn hprof_class_name(signature: &str) -> &str {
+    signature
+        .strip_prefix('L')
+        .and_then(|s| s.strip_suffix(';'))
+        .unwrap_or(signature)
+}
+
+impl BasicType {
+    fn from_signature(signature: &[u8]) -> Self {
+        match signature.first() {
+            Some(b'Z') => Self::Boolean,
+            Some(b'C') => Self::Char,
+            Some(b'F') => Self::Float,
+            Some(b'D') => Self::Double,
+            Some(b'B') => Self::Byte,
+            Some(b'S') => Self::Short,
+            Some(b'I') => Self::Int,
+            Some(b'J') => Self::Long,
+            _ => Self::Object,
+        }
+    }
+
+    fn from_primitive(ty: jvmtiPrimitiveType) -> Self {
+        use jvmtiPrimitiveType::*;
+        match ty {
+            JVMTI_PRIMITIVE_TYPE_BOOLEAN => Self::Boolean,
+            JVMTI_PRIMITIVE_TYPE_BYTE => Self::Byte,
+            JVMTI_PRIMITIVE_TYPE_CHAR => Self::Char,
+            JVMTI_PRIMITIVE_TYPE_SHORT => Self::Short,
+            JVMTI_PRIMITIVE_TYPE_INT => Self::Int,
+            JVMTI_PRIMITIVE_TYPE_LONG => Self::Long,
+            JVMTI_PRIMITIVE_TYPE_FLOAT => Self::Float,
+            JVMTI_PRIMITIVE_TYPE_DOUBLE => Self::Double,
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Self::Object => ID_SIZE,
+            Self::Boolean | Self::Byte => 1,
+            Self::Char | Self::Short => 2,
+            Self::Float | Self::Int => 4,
+            Self::Double | Self::Long => 8,
+        }
+    }
+
+    fn from_value(value: &JValue) -> Option<Self> {
+        Some(match value {
+            JValue::Bool(_) => Self::Boolean,
+            JValue::Byte(_) => Self::Byte,
+            JValue::Char(_) => Self::Char,
+            JValue::Short(_) => Self::Short,
+            JValue::Int(_) => Self::Int,
+            JValue::Long(_) => Self::Long,
+            JValue::Float(_) => Self::Float,
+            JValue::Double(_) => Self::Double,
+            JValue::Object(_) | JValue::Void => return None,
+        })
+    }
+}
+
+impl<W: Write> HprofWriter<W> {
+    fn new(out: W) -> Self {
+        Self {
+            out,
+            segment: Vec::new(),
+            strings: HashMap::new(),
+            bytes: 0,
+        }
+    }
+
+    fn header(&mut self) -> io::Result<()> {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_millis() as u64);
+
+        let mut header = HEADER.to_vec();
+        header.u4(ID_SIZE as u32);
+        header.u8(millis);
+        self.write(&header)
+    }
+
+    /// A top level record, written after any pending heap dump segment
+    fn record(&mut self, tag: u8, body: impl FnOnce(&mut Vec<u8>)) -> io::Result<()> {
+        let mut record = vec![tag, 0, 0, 0, 0, 0, 0, 0, 0];
+        body(&mut record);
+        let length = (record.len() - 9) as u32;
+        record[5..9].copy_from_slice(&length.to_be_bytes());
+
+        self.flush_segment()?;
+        self.write(&record)
+    }
+
+    /// The id of a `STRING` record holding the string, written the first time it is seen
+    fn string(&mut self, string: &str) -> io::Result<u64> {
+        if let Some(&id) = self.strings.get(string) {
+            return Ok(id);
+        }
+
+        let id = self.strings.len() as u64 + 1;
+        self.record(TAG_STRING, |body| {
+            body.id(id);
+            body.extend_from_slice(string.as_bytes());
+        })?;
+        self.strings.insert(string.to_owned(), id);
+        Ok(id)
+    }
+
+    /// Appends a sub-record to the current heap dump segment
+    fn sub_record(&mut self, sub_record: &[u8]) -> io::Result<()> {
+        self.reserve(sub_record.len())?;
+        self.segment.extend_from_slice(sub_record);
+        Ok(())
+    }
+
+    fn primitive_array(&mut self, id: u64, elements: &PrimitiveArray) -> io::Result<()> {
+        let ty = BasicType::from_primitive(elements.element_type());
+        let header_size = 1 + ID_SIZE + 4 + 4 + 1;
+        // segment lengths are a u4, so bigger arrays are truncated
+        let max_len = (u32::MAX as usize - header_size) / ty.size();
+        let len = if elements.len() > max_len {
+            warn!(
+                "truncating primitive array {} of {} elements to {}",
+                id,
+                elements.len(),
+                max_len
+            );
+            max_len
+        } else {
+            elements.len()
+        };
+
+        self.reserve(header_size + len * ty.size())?;
+        let segment = &mut self.segment;
+        segment.u1(PRIM_ARRAY_DUMP);
+        segment.id(id);
+        segment.u4(NO_TRACE);
+        segment.u4(len as u32);
+        segment.u1(ty as u8);
+
+        macro_rules! put_elements {
+            ($($accessor:ident),*) => {
+                $(
+                    if let Some(array) = elements.$accessor() {
+                        for val in &array[..len] {
+                            segment.extend_from_slice(&val.to_be_bytes());
+                        }
+                        return Ok(());
+                    }
+                )*
+            };
+        }
+        put_elements!(z, b, c, s, i, j, f, d);
+        unreachable!()
+    }
+
+    /// Writes out the current segment if appending `len` bytes would grow it past [SEGMENT_SIZE]
+    fn reserve(&mut self, len: usize) -> io::Result<()> {
+        if !self.segment.is_empty() && self.segment.len() + len > SEGMENT_SIZE {
+            self.flush_segment()?;
+        }
+        self.segment.reserve(len);
+        Ok(())
+    }
+
+    fn flush_segment(&mut self) -> io::Result<()> {
+        if self.segment.is_empty() {
+            return Ok(());
+        }
+
+        let mut header = vec![TAG_HEAP_DUMP_SEGMENT];
+        header.u4(0);
+        header.u4(self.segment.len() as u32);
+        self.write(&header)?;
+
+        self.out.write_all(&self.segment)?;
+        self.bytes += self.segment.len() as u64;
+        self.segment.clear();
+        Ok(())
+    }
+
+    /// Ends the heap dump, returning the total bytes written
+    fn finish(&mut self) -> io::Result<u64> {
+        self.flush_segment()?;
+        self.write(&[TAG_HEAP_DUMP_END, 0, 0, 0, 0, 0, 0, 0, 0])?;
+        self.out.flush()?;
+        Ok(self.bytes)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.out.write_all(bytes)?;
+        self.bytes += bytes.len() as u64;
+        Ok(())
+    }
+}
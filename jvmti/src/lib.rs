@@ -4,13 +4,89 @@
 #[macro_use]
 mod util;
 
+mod agent_thread;
+mod alloc_profiler;
+mod callback_state;
 mod capability;
+mod cpu_profiler;
+mod deadlock;
+mod dominator;
 mod env;
 mod event;
+mod exception_tracer;
+mod gc_monitor;
+mod handles;
 mod heap;
+mod hprof;
+mod lifetime;
+mod lock_profiler;
 mod memory;
+mod method_tracer;
+mod monitor;
+mod perf_map;
+mod raw_monitor;
+mod redefine;
+mod retransform;
+mod stack;
+mod tag;
+mod thread;
+mod thread_dump;
+mod thread_top;
+mod timer;
 
+pub use agent_thread::{AgentThread, AgentThreadBuilder};
+pub use alloc_profiler::{
+    AllocationProfile, AllocationProfiler, AllocationProfilerBuilder, AllocationSite,
+};
+pub use capability::Capability;
+pub use cpu_profiler::{
+    CpuProfile, CpuProfiler, CpuProfilerBuilder, ProfiledFrame, ProfiledMethod, SampledStack,
+};
+pub use deadlock::{find_deadlocks, Deadlock, DeadlockWatcher, DeadlockedThread};
+pub use dominator::{
+    dominator_tree, DominatorTree, LeakReport, ObjectId, PathToRoot, RetainedClass, RetainedObject,
+    RootKind,
+};
 pub use env::JvmtiEnv;
 pub use event::{EventCallbacks, EventCallbacksBuilder, EventScope, EventType};
-pub use heap::{HeapFilterFlags, HeapIterationCallback, HeapVisitControlFlags};
-pub use util::{Error, JvmtiResult};
+pub use exception_tracer::{
+    CatchSite, ExceptionReport, ExceptionSite, ExceptionTracer, ExceptionTracerBuilder,
+    TracedException,
+};
+pub use gc_monitor::{GcMonitor, GcMonitorBuilder, GcPause, GcStats};
+pub use handles::{Class, Field, Location, Method, Thread};
+pub use heap::{
+    histogram, HeapFilterFlags, HeapIterationCallback, HeapReference, HeapVisitControlFlags,
+    HeapWalkCallback, Histogram, HistogramEntry, NonZeroJlong, ReferenceTags,
+};
+pub use hprof::{dump_heap, write_heap_dump, HeapDumpSummary};
+pub use lifetime::{
+    LifetimeReport, LifetimeStack, LifetimeTracker, LifetimeTrackerBuilder, SurvivingObject,
+};
+pub use lock_profiler::{
+    ContendedClass, ContendedMonitor, ContendedStack, ContentionProfile, ContentionStats,
+    LockProfiler, LockProfilerBuilder, MonitorOwner,
+};
+pub use memory::TaggedObjects;
+pub use method_tracer::{
+    MethodTrace, MethodTracer, MethodTracerBuilder, ThreadTrace, TracedCall, TracedMethod,
+};
+pub use monitor::{MonitorObject, MonitorStackDepth, MonitorUsage};
+pub use perf_map::{PerfMap, PerfMapBuilder, PerfMapEntry};
+pub use raw_monitor::{RawMonitor, RawMonitorGuard};
+pub use redefine::{hot_swap, ClassDefinition};
+pub use retransform::{
+    ClassFileLoad, RetransformFailure, RetransformReport, Retransformer, RetransformerBuilder,
+};
+pub use stack::{get_stack_trace, StackFrame, StackInfo};
+pub use tag::{TagAllocator, TagPayload};
+pub use thread::{
+    thread_group_tree, ThreadGroup, ThreadGroupInfo, ThreadGroupMember, ThreadInfo, ThreadState,
+};
+pub use thread_dump::{
+    thread_dump, DumpedFrame, DumpedThread, FrameMonitor, NativeThreadInfo, ThreadDump,
+    ThreadDumpOnSignal, ThreadDumpSinkFn,
+};
+pub use thread_top::{thread_top, ThreadCpuUsage, ThreadTop, ThreadTopMonitor};
+pub use timer::{TimerInfo, TimerKind};
+pub use util::{java_class_name, Error, JvmtiError, JvmtiResult, UnsupportedRedefinition};
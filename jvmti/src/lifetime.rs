@@ -0,0 +1,592 @@
+use crate::callback_state::CallbackState;
+use crate::capability::Capability;
+use crate::env::DEFAULT_HEAP_SAMPLING_INTERVAL;
+use crate::event::{EventCallbacksBuilder, EventScope, EventType};
+use crate::handles::{Class, Thread};
+use crate::heap::{HeapFilterFlags, HeapIterationCallback, HeapVisitControlFlags, NonZeroJlong};
+use crate::raw_monitor::RawMonitor;
+use crate::stack::{self, FrameResolver, RawFrame, StackFrame};
+use crate::util::*;
+use crate::JvmtiEnv;
+use jni::objects::JObject;
+use jni::sys::{jint, jlong};
+use jni::{JNIEnv, JavaVM};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+const DEFAULT_MAX_FRAMES: usize = 64;
+const DEFAULT_MAX_OBJECTS: usize = 1_000_000;
+
+pub struct LifetimeTrackerBuilder {
+    classes: Vec<String>,
+    sampling_interval: Option<jint>,
+    vm_object_alloc: bool,
+    max_frames: usize,
+    max_objects: usize,
+}
+
+/// Tags objects of selected classes from a dedicated JVMTI environment as they are allocated,
+/// recording the time and allocation stack of each, and pairs them with their `ObjectFree`
+/// events to measure how long they lived. Objects that outlive a threshold are likely leaks.
+///
+/// Objects are tagged from `SampledObjectAlloc` and optionally `VMObjectAlloc` events, and those
+/// allocated before tracking started can be tagged with [LifetimeTracker::tag_live_objects].
+/// Lifetimes end when the collector frees an object, not when it becomes unreachable.
+///
+/// The sampling interval is global to the VM, so it also applies to any other sampling agent
+/// while the tracker runs. The previous interval is restored when the tracker is dropped.
+pub struct LifetimeTracker<'a> {
+    state: CallbackState<'a, TrackerState<'a>>,
+    events: Vec<EventType>,
+    /// Restored on drop, None if sampled allocations aren't tracked
+    previous_interval: Option<jint>,
+}
+
+struct TrackerState<'a> {
+    /// Patterns as in [matches_class_pattern], empty to track every class
+    classes: Vec<String>,
+    max_frames: usize,
+    objects: RawMonitor<'a, Objects>,
+}
+
+/// Class name and allocation stack, empty for objects tagged by a heap iteration
+type StackKey = (String, Vec<RawFrame>);
+
+#[derive(Default)]
+struct Objects {
+    max_objects: usize,
+    stack_indices: HashMap<StackKey, usize>,
+    stacks: Vec<(StackKey, StackCounts)>,
+    /// Each tracked object, indexed by its tag - 1
+    live: Vec<Option<LiveObject>>,
+    free_slots: Vec<usize>,
+    dropped: u64,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct LiveObject {
+    stack: usize,
+    size: u64,
+    tagged: Instant,
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+struct StackCounts {
+    tagged: u64,
+    freed: u64,
+    total_lifetime: Duration,
+    max_lifetime: Duration,
+}
+
+/// The objects of a class allocated at one stack
+#[derive(Debug, Clone)]
+pub struct LifetimeStack {
+    /// e.g. `byte[]`
+    pub class_name: String,
+    /// From the top of the stack. Frames of unloaded methods are omitted, and the stack is empty
+    /// for objects tagged by [LifetimeTracker::tag_live_objects]
+    pub frames: Vec<StackFrame>,
+    pub tagged: u64,
+    /// Reported by `ObjectFree`
+    pub freed: u64,
+    /// Of the freed objects
+    pub total_lifetime: Duration,
+    pub max_lifetime: Duration,
+    /// Live objects older than the report's threshold
+    pub survivors: u64,
+    /// Bytes of the survivors
+    pub surviving_bytes: u64,
+}
+
+/// A live object older than the report's threshold
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SurvivingObject {
+    /// Index into [LifetimeReport::stacks]
+    pub stack: usize,
+    /// Since the object was tagged
+    pub age: Duration,
+    pub size: u64,
+}
+
+/// Objects tracked since the tracker started
+#[derive(Debug, Clone)]
+pub struct LifetimeReport {
+    pub threshold: Duration,
+    /// By descending surviving bytes, then by descending tagged objects
+    pub stacks: Vec<LifetimeStack>,
+    /// Oldest first
+    pub survivors: Vec<SurvivingObject>,
+    /// Objects not tracked as the maximum were already live
+    pub dropped: u64,
+}
+
+impl Default for LifetimeTrackerBuilder {
+    fn default() -> Self {
+        Self {
+            classes: Vec::new(),
+            sampling_interval: Some(DEFAULT_HEAP_SAMPLING_INTERVAL),
+            vm_object_alloc: false,
+            max_frames: DEFAULT_MAX_FRAMES,
+            max_objects: DEFAULT_MAX_OBJECTS,
+        }
+    }
+}
+
+impl LifetimeTrackerBuilder {
+    /// Tracks objects whose class matches the pattern, either an exact class name such as
+    /// `byte[]` or one ending in `*` to match by prefix. Every class is tracked if none are added
+    pub fn with_class(mut self, pattern: impl Into<String>) -> Self {
+        self.classes.push(pattern.into());
+        self
+    }
+
+    /// Mean bytes allocated by a thread between `SampledObjectAlloc` events, or None to not
+    /// track sampled allocations. Defaults to 512KiB, while 0 samples every allocation at a cost
+    /// that shows in allocation heavy code. Set for the whole VM while the tracker runs
+    pub fn with_sampling_interval(mut self, bytes: Option<jint>) -> Self {
+        assert!(
+            bytes.is_none_or(|bytes| bytes >= 0),
+            "sampling interval must not be negative"
+        );
+        self.sampling_interval = bytes;
+        self
+    }
+
+    /// Whether to also track objects allocated by the VM itself through `VMObjectAlloc`, e.g.
+    /// by reflection or JNI. Defaults to false
+    pub fn with_vm_object_alloc(mut self, vm_object_alloc: bool) -> Self {
+        self.vm_object_alloc = vm_object_alloc;
+        self
+    }
+
+    /// Deepest allocation stack recorded, defaults to 64
+    pub fn with_max_frames(mut self, max_frames: usize) -> Self {
+        assert_ne!(max_frames, 0, "max frames must be non-zero");
+        self.max_frames = max_frames;
+        self
+    }
+
+    /// Most objects tracked at once, defaults to 1,000,000. Further allocations are dropped
+    /// until tracked objects are freed
+    pub fn with_max_objects(mut self, max_objects: usize) -> Self {
+        assert_ne!(max_objects, 0, "max objects must be non-zero");
+        self.max_objects = max_objects;
+        self
+    }
+
+    /// Creates a new JVMTI environment and starts tracking
+    pub fn start<'a>(self, jvm: &JavaVM) -> JvmtiResult<LifetimeTracker<'a>> {
+        let jvmti = JvmtiEnv::from_jvm(jvm)?;
+        jvmti.require_capabilities(&[
+            Capability::GenerateObjectFreeEvents,
+            Capability::TagObjects,
+        ])?;
+
+        let mut events = vec![EventType::ObjectFree];
+        if self.sampling_interval.is_some() {
+            jvmti.require_capabilities(&[Capability::GenerateSampledObjectAllocEvents])?;
+            events.push(EventType::SampledObjectAlloc);
+        }
+        if self.vm_object_alloc {
+            jvmti.require_capabilities(&[Capability::GenerateVmObjectAllocEvents])?;
+            events.push(EventType::VmObjectAlloc);
+        }
+
+        let objects = Objects {
+            max_objects: self.max_objects,
+            ..Objects::default()
+        };
+        let state = TrackerState {
+            classes: self.classes,
+            max_frames: self.max_frames,
+            objects: RawMonitor::new(&jvmti, "lifetime tracker", objects)?,
+        };
+        let mut tracker = LifetimeTracker {
+            state: CallbackState::new(jvmti, state)?,
+            events,
+            previous_interval: None,
+        };
+
+        let jvmti = tracker.state.jvmti();
+
+        let callbacks = EventCallbacksBuilder::default()
+            .with_sampled_object_alloc(Some(object_alloc))
+            .with_vmobject_alloc(Some(object_alloc))
+            .with_object_free(Some(object_free))
+            .build();
+        jvmti.install_event_callbacks(&callbacks)?;
+        if let Some(interval) = self.sampling_interval {
+            tracker.previous_interval = Some(jvmti.set_heap_sampling_interval(interval)?);
+        }
+        let jvmti = tracker.state.jvmti();
+        for &event in &tracker.events {
+            jvmti.enable_event(event, EventScope::Global)?;
+        }
+
+        debug!("started lifetime tracker for {:?}", tracker.state.classes);
+        Ok(tracker)
+    }
+}
+
+impl<'a> LifetimeTracker<'a> {
+    pub fn builder() -> LifetimeTrackerBuilder {
+        LifetimeTrackerBuilder::default()
+    }
+
+    /// Tags the live objects of the selected classes that are not tracked yet, such as those
+    /// allocated before the tracker started, by iterating over the whole heap. Their allocation
+    /// stacks are unknown and their ages start now. Returns how many were tagged
+    pub fn tag_live_objects(&self, jni: JNIEnv) -> JvmtiResult<u64> {
+        let jvmti = self.state.jvmti();
+        let loaded = jvmti.get_loaded_classes(jni)?;
+        let mut classes: Vec<(Class, String)> = Vec::new();
+        for class in loaded.iter() {
+            let class_name = class_name(jvmti, *class)?;
+            if self.state.selects(&class_name) {
+                classes.push((*class, class_name));
+            }
+        }
+
+        let mut objects = self.state.objects.lock()?;
+        // negative so they can't be mistaken for object tags, and cleared before returning
+        for (index, (class, _)) in classes.iter().enumerate() {
+            jvmti.set_tag(**class, NonZeroJlong::new(-(index as jlong) - 1))?;
+        }
+
+        let stacks: Vec<usize> = classes
+            .iter()
+            .map(|(_, class_name)| objects.stack((class_name.clone(), Vec::new())))
+            .collect();
+        let now = Instant::now();
+        let mut tagged = 0;
+        let filter = HeapFilterFlags::TAGGED | HeapFilterFlags::CLASS_UNTAGGED;
+        let result = jvmti
+            .iterate_through_heap(filter, None, |callback| {
+                if let HeapIterationCallback::Object {
+                    class_tag: Some(class_tag),
+                    size,
+                    tag,
+                    ..
+                } = callback
+                {
+                    let stack = match stacks.get((-class_tag.get() - 1) as usize) {
+                        Some(&stack) => stack,
+                        None => return HeapVisitControlFlags::empty(),
+                    };
+                    match objects.track(stack, size as u64, now) {
+                        Some(new_tag) => {
+                            *tag = new_tag;
+                            tagged += 1;
+                        }
+                        None => return HeapVisitControlFlags::ABORT,
+                    }
+                }
+                HeapVisitControlFlags::empty()
+            })
+            .map(|()| tagged);
+
+        drop(objects);
+        for (class, _) in &classes {
+            jvmti.set_tag(**class, None)?;
+        }
+        let tagged = result?;
+        debug!("tagged {} live objects", tagged);
+        Ok(tagged)
+    }
+
+    /// Resolves the stacks tracked so far, counting live objects tagged more than `threshold`
+    /// ago as survivors
+    pub fn report(&self, jni: JNIEnv, threshold: Duration) -> JvmtiResult<LifetimeReport> {
+        let now = Instant::now();
+        let (snapshot, mut survivors, dropped) = {
+            let objects = self.state.objects.lock()?;
+            let survivors: Vec<SurvivingObject> = objects
+                .live
+                .iter()
+                .flatten()
+                .map(|object| SurvivingObject {
+                    stack: object.stack,
+                    age: now.saturating_duration_since(object.tagged),
+                    size: object.size,
+                })
+                .filter(|object| object.age > threshold)
+                .collect();
+            (objects.stacks.clone(), survivors, objects.dropped)
+        };
+
+        let mut resolver = FrameResolver::new(self.state.jvmti(), jni);
+        let mut stacks: Vec<LifetimeStack> = snapshot
+            .into_iter()
+            .map(|((class_name, frames), counts)| LifetimeStack {
+                class_name,
+                frames: frames
+                    .iter()
+                    .filter_map(|&frame| resolver.resolve(frame))
+                    .collect(),
+                tagged: counts.tagged,
+                freed: counts.freed,
+                total_lifetime: counts.total_lifetime,
+                max_lifetime: counts.max_lifetime,
+                survivors: 0,
+                surviving_bytes: 0,
+            })
+            .collect();
+        for survivor in &survivors {
+            let stack = &mut stacks[survivor.stack];
+            stack.survivors += 1;
+            stack.surviving_bytes += survivor.size;
+        }
+
+        // sorts the stacks, keeping the survivors' indices pointing at the same ones
+        let mut order: Vec<usize> = (0..stacks.len()).collect();
+        order.sort_by_key(|&index| {
+            let stack = &stacks[index];
+            (Reverse(stack.surviving_bytes), Reverse(stack.tagged))
+        });
+        let mut new_indices = vec![0; stacks.len()];
+        for (new_index, &index) in order.iter().enumerate() {
+            new_indices[index] = new_index;
+        }
+        for survivor in &mut survivors {
+            survivor.stack = new_indices[survivor.stack];
+        }
+        let mut stacks: Vec<(usize, LifetimeStack)> = stacks.into_iter().enumerate().collect();
+        stacks.sort_by_key(|(index, _)| new_indices[*index]);
+        let stacks = stacks.into_iter().map(|(_, stack)| stack).collect();
+        survivors.sort_by_key(|survivor| Reverse(survivor.age));
+
+        Ok(LifetimeReport {
+            threshold,
+            stacks,
+            survivors,
+            dropped,
+        })
+    }
+}
+
+impl Drop for LifetimeTracker<'_> {
+    fn drop(&mut self) {
+        for &event in &self.events {
+            if let Err(err) = self.state.jvmti().disable_event(event, EventScope::Global) {
+                error!("failed to disable {:?}: {}", event, err);
+            }
+        }
+
+        if let Some(previous) = self.previous_interval {
+            if let Err(err) = self.state.jvmti().set_heap_sampling_interval(previous) {
+                error!("failed to restore heap sampling interval: {}", err);
+            }
+        }
+    }
+}
+
+impl TrackerState<'_> {
+    fn selects(&self, class_name: &str) -> bool {
+        self.classes.is_empty()
+            || self
+                .classes
+                .iter()
+                .any(|pattern| matches_class_pattern(pattern, class_name))
+    }
+}
+
+impl Objects {
+    fn stack(&mut self, key: StackKey) -> usize {
+        match self.stack_indices.get(&key) {
+            Some(&stack) => stack,
+            None => {
+                let stack = self.stacks.len();
+                self.stack_indices.insert(key.clone(), stack);
+                self.stacks.push((key, StackCounts::default()));
+                stack
+            }
+        }
+    }
+
+    /// Returns the tag for the object, or None if too many are tracked already
+    fn track(&mut self, stack: usize, size: u64, now: Instant) -> Option<jlong> {
+        if self.live.len() - self.free_slots.len() >= self.max_objects {
+            self.dropped += 1;
+            return None;
+        }
+
+        self.stacks[stack].1.tagged += 1;
+        let object = LiveObject {
+            stack,
+            size,
+            tagged: now,
+        };
+        let slot = match self.free_slots.pop() {
+            Some(slot) => {
+                self.live[slot] = Some(object);
+                slot
+            }
+            None => {
+                self.live.push(Some(object));
+                self.live.len() - 1
+            }
+        };
+        Some(slot as jlong + 1)
+    }
+
+    /// Stops tracking the object, without counting it as freed
+    fn untrack(&mut self, tag: jlong) -> Option<LiveObject> {
+        let slot = usize::try_from(tag).ok()?.checked_sub(1)?;
+        let object = self.live.get_mut(slot).and_then(Option::take)?;
+        self.free_slots.push(slot);
+        Some(object)
+    }
+
+    fn free(&mut self, tag: jlong, now: Instant) {
+        if let Some(object) = self.untrack(tag) {
+            let lifetime = now.saturating_duration_since(object.tagged);
+            let counts = &mut self.stacks[object.stack].1;
+            counts.freed += 1;
+            counts.total_lifetime += lifetime;
+            counts.max_lifetime = counts.max_lifetime.max(lifetime);
+        }
+    }
+}
+
+fn class_name(jvmti: &JvmtiEnv, class: Class) -> JvmtiResult<String> {
+    let signature = jvmti.get_class_signature(class)?;
+    Ok(java_class_name(&mutf8_to_string(signature.as_bytes())))
+}
+
+/// For both `SampledObjectAlloc` and `VMObjectAlloc`, which may report the same object
+unsafe extern "C" fn object_alloc(
+    jvmti_env: JvmtiEnv,
+    _jni_env: JNIEnv,
+    thread: Thread,
+    object: JObject,
+    object_class: Class,
+    size: jlong,
+) {
+    let now = Instant::now();
+    CallbackState::<TrackerState>::with(&jvmti_env, |state| {
+        let class_name = match class_name(&jvmti_env, object_class) {
+            Ok(class_name) => class_name,
+            Err(err) => {
+                warn!("failed to get allocated class name: {}", err);
+                return;
+            }
+        };
+        if !state.selects(&class_name) {
+            return;
+        }
+
+        match jvmti_env.get_tag(object) {
+            Ok(None) => {}
+            Ok(Some(_)) => return,
+            Err(err) => {
+                warn!("failed to get allocated object tag: {}", err);
+                return;
+            }
+        }
+
+        let frames = match jvmti_env.get_stack_trace(thread, 0, state.max_frames) {
+            Ok(frames) => frames
+                .iter()
+                .map(|&frame| stack::raw_frame(frame))
+                .collect(),
+            Err(err) => {
+                warn!("failed to get {} allocation stack: {}", class_name, err);
+                return;
+            }
+        };
+
+        let tag = match state.objects.lock() {
+            Ok(mut objects) => {
+                let stack = objects.stack((class_name, frames));
+                match objects.track(stack, size as u64, now) {
+                    Some(tag) => tag,
+                    None => return,
+                }
+            }
+            Err(err) => {
+                error!("failed to track allocated object: {}", err);
+                return;
+            }
+        };
+
+        if let Err(err) = jvmti_env.set_tag(object, NonZeroJlong::new(tag)) {
+            warn!("failed to tag allocated object: {}", err);
+            if let Ok(mut objects) = state.objects.lock() {
+                if let Some(object) = objects.untrack(tag) {
+                    objects.stacks[object.stack].1.tagged -= 1;
+                }
+            }
+        }
+    });
+}
+
+/// Only raw monitor and environment local storage functions may be called from here
+unsafe extern "C" fn object_free(jvmti_env: JvmtiEnv, tag: jlong) {
+    let now = Instant::now();
+    CallbackState::<TrackerState>::with(&jvmti_env, |state| match state.objects.lock() {
+        Ok(mut objects) => objects.free(tag, now),
+        Err(err) => error!("failed to record freed object: {}", err),
+    });
+}
+
+impl LifetimeStack {
+    pub fn live(&self) -> u64 {
+        self.tagged - self.freed
+    }
+
+    /// Of the freed objects
+    pub fn mean_lifetime(&self) -> Option<Duration> {
+        if self.freed == 0 {
+            None
+        } else {
+            Some(self.total_lifetime.div_f64(self.freed as f64))
+        }
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+impl Display for LifetimeReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Allocation stacks by bytes surviving over {:.3}ms:",
+            millis(self.threshold)
+        )?;
+        writeln!(
+            f,
+            "        #bytes #survivors      #live    #tagged  mean(ms)   max(ms)  class"
+        )?;
+        for stack in &self.stacks {
+            let mean = stack.mean_lifetime().map_or(0.0, millis);
+            writeln!(
+                f,
+                "{:14} {:10} {:10} {:10} {:9.3} {:9.3}  {}",
+                stack.surviving_bytes,
+                stack.survivors,
+                stack.live(),
+                stack.tagged,
+                mean,
+                millis(stack.max_lifetime),
+                stack.class_name
+            )?;
+            if stack.frames.is_empty() {
+                writeln!(f, "\t(tagged on the heap)")?;
+            }
+            for frame in &stack.frames {
+                writeln!(f, "\tat {}", frame)?;
+            }
+        }
+
+        if self.dropped > 0 {
+            writeln!(f)?;
+            writeln!(f, "{} objects were not tracked", self.dropped)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,580 @@
+use crate::callback_state::CallbackState;
+use crate::capability::Capability;
+use crate::event::{EventCallbacksBuilder, EventScope, EventType};
+use crate::handles::Thread;
+use crate::heap::NonZeroJlong;
+use crate::monitor::MonitorObject;
+use crate::raw_monitor::RawMonitor;
+use crate::stack::{self, FrameResolver, RawFrame, StackFrame};
+use crate::util::*;
+use crate::JvmtiEnv;
+use jni::objects::JObject;
+use jni::sys::{jboolean, jlong};
+use jni::{JNIEnv, JavaVM};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::thread::ThreadId;
+use std::time::{Duration, Instant};
+
+const DEFAULT_MAX_FRAMES: usize = 64;
+const EVENTS: [EventType; 4] = [
+    EventType::MonitorContendedEnter,
+    EventType::MonitorContendedEntered,
+    EventType::MonitorWait,
+    EventType::MonitorWaited,
+];
+
+pub struct LockProfilerBuilder {
+    max_frames: usize,
+    owners: bool,
+}
+
+/// Measures how long threads block entering contended monitors and wait in `Object.wait`, from
+/// monitor events in a dedicated JVMTI environment. Each thread's enter and entered events are
+/// paired up to time the contention, which is aggregated per monitor, per monitor class and per
+/// acquiring stack.
+///
+/// Monitors are told apart by tagging them in the profiler's environment, as identity hash codes
+/// may collide.
+pub struct LockProfiler<'a> {
+    state: CallbackState<'a, ProfilerState<'a>>,
+}
+
+struct ProfilerState<'a> {
+    max_frames: usize,
+    owners: bool,
+    contention: RawMonitor<'a, Contention>,
+}
+
+#[derive(Default)]
+struct Contention {
+    /// Indexed by the tag of the monitor - 1
+    monitors: Vec<MonitorRecord>,
+    stack_indices: HashMap<(String, Vec<RawFrame>), usize>,
+    stacks: Vec<((String, Vec<RawFrame>), ContentionStats)>,
+    /// Threads blocked entering a monitor
+    entering: HashMap<ThreadId, Pending>,
+    /// Threads in `Object.wait`
+    waiting: HashMap<ThreadId, Pending>,
+}
+
+struct MonitorRecord {
+    monitor: MonitorObject,
+    stats: ContentionStats,
+    owners: Vec<MonitorOwner>,
+}
+
+struct Pending {
+    monitor: usize,
+    stack: usize,
+    /// Name of the thread owning the monitor when blocked
+    owner: Option<String>,
+    start: Instant,
+}
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct ContentionStats {
+    /// Times a thread blocked entering the monitor
+    pub contended: u64,
+    /// Total time blocked entering
+    pub blocked: Duration,
+    /// Calls to `Object.wait` that returned
+    pub waits: u64,
+    /// Total time spent in `Object.wait`
+    pub waited: Duration,
+}
+
+/// A thread that owned a monitor while others blocked entering it
+#[derive(Debug, Clone)]
+pub struct MonitorOwner {
+    pub name: String,
+    /// Times a thread blocked while this one owned the monitor
+    pub contended: u64,
+    pub blocked: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct ContendedMonitor {
+    pub monitor: MonitorObject,
+    pub stats: ContentionStats,
+    /// By descending blocked time. Empty unless owners are recorded
+    pub owners: Vec<MonitorOwner>,
+}
+
+/// Contention summed over all monitors of a class
+#[derive(Debug, Clone)]
+pub struct ContendedClass {
+    /// e.g. `java.lang.Object`
+    pub class_name: String,
+    /// Number of distinct monitors
+    pub monitors: usize,
+    pub stats: ContentionStats,
+}
+
+/// Where threads blocked entering or waited on monitors of a class
+#[derive(Debug, Clone)]
+pub struct ContendedStack {
+    /// e.g. `java.lang.Object`
+    pub class_name: String,
+    /// From the top of the stack. Frames of unloaded methods are omitted
+    pub frames: Vec<StackFrame>,
+    pub stats: ContentionStats,
+}
+
+/// Monitor contention since the profiler started, each list by descending blocked time then
+/// waited time
+#[derive(Debug, Clone)]
+pub struct ContentionProfile {
+    pub monitors: Vec<ContendedMonitor>,
+    pub classes: Vec<ContendedClass>,
+    pub stacks: Vec<ContendedStack>,
+}
+
+impl Default for LockProfilerBuilder {
+    fn default() -> Self {
+        Self {
+            max_frames: DEFAULT_MAX_FRAMES,
+            owners: false,
+        }
+    }
+}
+
+impl LockProfilerBuilder {
+    /// Deepest acquiring stack recorded, defaults to 64
+    pub fn with_max_frames(mut self, max_frames: usize) -> Self {
+        assert_ne!(max_frames, 0, "max frames must be non-zero");
+        self.max_frames = max_frames;
+        self
+    }
+
+    /// Whether to record which thread owned a monitor when another blocked entering it, which
+    /// requires `can_get_monitor_info`. Defaults to false, as it calls `GetObjectMonitorUsage` on
+    /// every contended enter
+    pub fn with_owners(mut self, owners: bool) -> Self {
+        self.owners = owners;
+        self
+    }
+
+    /// Creates a new JVMTI environment and starts profiling. `can_generate_monitor_events` is
+    /// only available in the live phase if another agent acquired it during startup
+    pub fn start<'a>(self, jvm: &JavaVM) -> JvmtiResult<LockProfiler<'a>> {
+        let jvmti = JvmtiEnv::from_jvm(jvm)?;
+        jvmti.require_capabilities(&[Capability::GenerateMonitorEvents, Capability::TagObjects])?;
+        if self.owners {
+            jvmti.require_capabilities(&[Capability::GetMonitorInfo])?;
+        }
+
+        let state = ProfilerState {
+            max_frames: self.max_frames,
+            owners: self.owners,
+            contention: RawMonitor::new(&jvmti, "lock profiler", Contention::default())?,
+        };
+        let profiler = LockProfiler {
+            state: CallbackState::new(jvmti, state)?,
+        };
+
+        let jvmti = profiler.state.jvmti();
+
+        let callbacks = EventCallbacksBuilder::default()
+            .with_monitor_contended_enter(Some(monitor_contended_enter))
+            .with_monitor_contended_entered(Some(monitor_contended_entered))
+            .with_monitor_wait(Some(monitor_wait))
+            .with_monitor_waited(Some(monitor_waited))
+            .build();
+        jvmti.install_event_callbacks(&callbacks)?;
+        for event in EVENTS {
+            jvmti.enable_event(event, EventScope::Global)?;
+        }
+
+        debug!("started lock profiler");
+        Ok(profiler)
+    }
+}
+
+impl<'a> LockProfiler<'a> {
+    pub fn builder() -> LockProfilerBuilder {
+        LockProfilerBuilder::default()
+    }
+
+    /// Resolves the contention measured so far. Threads still blocked or waiting are not counted
+    pub fn profile(&self, jni: JNIEnv) -> JvmtiResult<ContentionProfile> {
+        let (mut monitors, stacks) = {
+            let contention = self.state.contention.lock()?;
+            (
+                contention
+                    .monitors
+                    .iter()
+                    .map(|record| ContendedMonitor {
+                        monitor: record.monitor.clone(),
+                        stats: record.stats,
+                        owners: record.owners.clone(),
+                    })
+                    .collect::<Vec<_>>(),
+                contention.stacks.clone(),
+            )
+        };
+
+        let mut classes: Vec<ContendedClass> = Vec::new();
+        for monitor in &mut monitors {
+            monitor.owners.sort_by_key(|owner| Reverse(owner.blocked));
+            match classes
+                .iter_mut()
+                .find(|class| class.class_name == monitor.monitor.class_name)
+            {
+                Some(class) => {
+                    class.monitors += 1;
+                    class.stats.add(&monitor.stats);
+                }
+                None => classes.push(ContendedClass {
+                    class_name: monitor.monitor.class_name.clone(),
+                    monitors: 1,
+                    stats: monitor.stats,
+                }),
+            }
+        }
+
+        let mut resolver = FrameResolver::new(self.state.jvmti(), jni);
+        let mut stacks: Vec<ContendedStack> = stacks
+            .into_iter()
+            .map(|((class_name, frames), stats)| ContendedStack {
+                class_name,
+                frames: frames
+                    .into_iter()
+                    .filter_map(|frame| resolver.resolve(frame))
+                    .collect(),
+                stats,
+            })
+            .collect();
+
+        monitors.sort_by_key(|monitor| monitor.stats.sort_key());
+        classes.sort_by_key(|class| class.stats.sort_key());
+        stacks.sort_by_key(|stack| stack.stats.sort_key());
+        Ok(ContentionProfile {
+            monitors,
+            classes,
+            stacks,
+        })
+    }
+}
+
+impl Drop for LockProfiler<'_> {
+    fn drop(&mut self) {
+        for event in EVENTS {
+            if let Err(err) = self.state.jvmti().disable_event(event, EventScope::Global) {
+                error!("failed to disable {:?}: {}", event, err);
+            }
+        }
+    }
+}
+
+impl ContentionStats {
+    fn add(&mut self, other: &ContentionStats) {
+        self.contended += other.contended;
+        self.blocked += other.blocked;
+        self.waits += other.waits;
+        self.waited += other.waited;
+    }
+
+    fn sort_key(&self) -> (Reverse<Duration>, Reverse<Duration>) {
+        (Reverse(self.blocked), Reverse(self.waited))
+    }
+}
+
+impl Contention {
+    /// Returns the index of the new monitor
+    fn add_monitor(&mut self, monitor: MonitorObject) -> usize {
+        self.monitors.push(MonitorRecord {
+            monitor,
+            stats: ContentionStats::default(),
+            owners: Vec::new(),
+        });
+        self.monitors.len() - 1
+    }
+
+    /// Called on the blocking or waiting thread
+    fn begin(
+        &mut self,
+        waiting: bool,
+        monitor: usize,
+        frames: Vec<RawFrame>,
+        owner: Option<String>,
+        start: Instant,
+    ) {
+        let class_name = self.monitors[monitor].monitor.class_name.clone();
+        let stack_key = (class_name, frames);
+        let stack = match self.stack_indices.get(&stack_key) {
+            Some(&stack) => stack,
+            None => {
+                self.stacks
+                    .push((stack_key.clone(), ContentionStats::default()));
+                self.stack_indices.insert(stack_key, self.stacks.len() - 1);
+                self.stacks.len() - 1
+            }
+        };
+
+        let pending = Pending {
+            monitor,
+            stack,
+            owner,
+            start,
+        };
+        let thread = std::thread::current().id();
+        if waiting {
+            self.waiting.insert(thread, pending);
+        } else {
+            self.entering.insert(thread, pending);
+        }
+    }
+
+    /// Called on the thread that entered the monitor
+    fn entered(&mut self, end: Instant) {
+        let pending = match self.entering.remove(&std::thread::current().id()) {
+            Some(pending) => pending,
+            None => return,
+        };
+        let blocked = end.saturating_duration_since(pending.start);
+
+        let record = &mut self.monitors[pending.monitor];
+        for stats in [&mut record.stats, &mut self.stacks[pending.stack].1] {
+            stats.contended += 1;
+            stats.blocked += blocked;
+        }
+
+        if let Some(name) = pending.owner {
+            match record.owners.iter_mut().find(|owner| owner.name == name) {
+                Some(owner) => {
+                    owner.contended += 1;
+                    owner.blocked += blocked;
+                }
+                None => record.owners.push(MonitorOwner {
+                    name,
+                    contended: 1,
+                    blocked,
+                }),
+            }
+        }
+    }
+
+    /// Called on the thread that waited
+    fn waited(&mut self, end: Instant) {
+        let pending = match self.waiting.remove(&std::thread::current().id()) {
+            Some(pending) => pending,
+            None => return,
+        };
+        let waited = end.saturating_duration_since(pending.start);
+
+        let record = &mut self.monitors[pending.monitor];
+        for stats in [&mut record.stats, &mut self.stacks[pending.stack].1] {
+            stats.waits += 1;
+            stats.waited += waited;
+        }
+    }
+}
+
+/// Name of the thread owning the monitor, if any
+fn owner_name(jvmti: &JvmtiEnv, jni: JNIEnv, object: JObject) -> JvmtiResult<Option<String>> {
+    let usage = jvmti.get_object_monitor_usage(jni, object)?;
+    let name = usage.owner.map(|owner| -> JvmtiResult<_> {
+        let info = jvmti.get_thread_info(jni, owner)?;
+        info.delete_local_refs(jni)?;
+        Ok(info.name)
+    });
+    usage.delete_local_refs(jni)?;
+    name.transpose()
+}
+
+/// Records the monitor, stack and owner of a thread about to block or wait
+fn begin(
+    state: &ProfilerState,
+    jvmti: &JvmtiEnv,
+    jni: JNIEnv,
+    thread: Thread,
+    object: JObject,
+    waiting: bool,
+) {
+    let start = Instant::now();
+
+    // monitors are only described the first time they are seen, then tagged with their index + 1
+    let tag = match jvmti.get_tag(object) {
+        Ok(tag) => tag,
+        Err(err) => {
+            warn!("failed to get contended monitor tag: {}", err);
+            return;
+        }
+    };
+    let described = match tag {
+        Some(_) => None,
+        None => match MonitorObject::describe(jvmti, jni, object) {
+            Ok(monitor) => Some(monitor),
+            Err(err) => {
+                warn!("failed to describe contended monitor: {}", err);
+                return;
+            }
+        },
+    };
+    let frames = match jvmti.get_stack_trace(thread, 0, state.max_frames) {
+        Ok(frames) => frames
+            .iter()
+            .map(|&frame| stack::raw_frame(frame))
+            .collect(),
+        Err(err) => {
+            warn!("failed to get contended monitor stack: {}", err);
+            Vec::new()
+        }
+    };
+    let owner = if state.owners && !waiting {
+        owner_name(jvmti, jni, object).unwrap_or_else(|err| {
+            debug!("failed to get contended monitor owner: {}", err);
+            None
+        })
+    } else {
+        None
+    };
+
+    let mut contention = match state.contention.lock() {
+        Ok(contention) => contention,
+        Err(err) => {
+            error!("failed to record monitor contention: {}", err);
+            return;
+        }
+    };
+    // another thread may have added the new monitor meanwhile
+    let tag = match described {
+        Some(_) => jvmti.get_tag(object),
+        None => Ok(tag),
+    };
+    let monitor = match (tag, described) {
+        (Ok(Some(tag)), _) => tag.get() as usize - 1,
+        (Ok(None), Some(monitor)) => {
+            let index = contention.add_monitor(monitor);
+            if let Err(err) = jvmti.set_tag(object, NonZeroJlong::new(index as jlong + 1)) {
+                warn!("failed to tag contended monitor: {}", err);
+            }
+            index
+        }
+        // only undescribed if already tagged
+        (Ok(None), None) => return,
+        (Err(err), _) => {
+            warn!("failed to get contended monitor tag: {}", err);
+            return;
+        }
+    };
+    contention.begin(waiting, monitor, frames, owner, start);
+}
+
+/// Ends the contention or wait of the current thread
+fn end(state: &ProfilerState, end: Instant, waiting: bool) {
+    match state.contention.lock() {
+        Ok(mut contention) => {
+            if waiting {
+                contention.waited(end);
+            } else {
+                contention.entered(end);
+            }
+        }
+        Err(err) => error!("failed to record monitor contention: {}", err),
+    }
+}
+
+unsafe extern "C" fn monitor_contended_enter(
+    jvmti_env: JvmtiEnv,
+    jni_env: JNIEnv,
+    thread: Thread,
+    object: JObject,
+) {
+    CallbackState::<ProfilerState>::with(&jvmti_env, |state| {
+        begin(state, &jvmti_env, jni_env, thread, object, false)
+    });
+}
+
+unsafe extern "C" fn monitor_contended_entered(
+    jvmti_env: JvmtiEnv,
+    _jni_env: JNIEnv,
+    _thread: Thread,
+    _object: JObject,
+) {
+    let now = Instant::now();
+    CallbackState::<ProfilerState>::with(&jvmti_env, |state| end(state, now, false));
+}
+
+unsafe extern "C" fn monitor_wait(
+    jvmti_env: JvmtiEnv,
+    jni_env: JNIEnv,
+    thread: Thread,
+    object: JObject,
+    _timeout: jlong,
+) {
+    CallbackState::<ProfilerState>::with(&jvmti_env, |state| {
+        begin(state, &jvmti_env, jni_env, thread, object, true)
+    });
+}
+
+unsafe extern "C" fn monitor_waited(
+    jvmti_env: JvmtiEnv,
+    _jni_env: JNIEnv,
+    _thread: Thread,
+    _object: JObject,
+    _timed_out: jboolean,
+) {
+    let now = Instant::now();
+    CallbackState::<ProfilerState>::with(&jvmti_env, |state| end(state, now, true));
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn write_stats(f: &mut Formatter<'_>, stats: &ContentionStats) -> std::fmt::Result {
+    write!(
+        f,
+        "{:9} {:12.3} {:8} {:12.3}",
+        stats.contended,
+        millis(stats.blocked),
+        stats.waits,
+        millis(stats.waited)
+    )
+}
+
+/// Tables of the contended monitors with their owners, the classes and the acquiring stacks
+impl Display for ContentionProfile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        const HEADER: &str = " #blocked  blocked(ms)   #waits   waited(ms)";
+
+        writeln!(f, "Contended monitors:")?;
+        writeln!(f, "{}  monitor", HEADER)?;
+        for monitor in &self.monitors {
+            write_stats(f, &monitor.stats)?;
+            writeln!(f, "  {}", monitor.monitor)?;
+            for owner in &monitor.owners {
+                writeln!(
+                    f,
+                    "\towned by \"{}\" for {} blocked threads, {:.3}ms",
+                    owner.name,
+                    owner.contended,
+                    millis(owner.blocked)
+                )?;
+            }
+        }
+
+        writeln!(f)?;
+        writeln!(f, "Contended classes:")?;
+        writeln!(f, "{}  #monitors  class", HEADER)?;
+        for class in &self.classes {
+            write_stats(f, &class.stats)?;
+            writeln!(f, "  {:9}  {}", class.monitors, class.class_name)?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "Contended stacks:")?;
+        writeln!(f, "{}  class", HEADER)?;
+        for stack in &self.stacks {
+            write_stats(f, &stack.stats)?;
+            writeln!(f, "  {}", stack.class_name)?;
+            for frame in &stack.frames {
+                writeln!(f, "\tat {}", frame)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,11 +1,14 @@
+use crate::handles::{Class, Thread};
+use crate::heap::NonZeroJlong;
 use crate::util::*;
 use crate::JvmtiEnv;
 use jni::objects::JObject;
-use jni::sys::jobject;
+use jni::sys::{jlong, jobject};
 use jni::JNIEnv;
 
 use mutf8::mstr;
 use std::ffi::CStr;
+use std::marker::PhantomData;
 
 use std::ops::Deref;
 use std::os::raw::c_char;
@@ -17,6 +20,8 @@ pub trait Allocation: Sized {
 }
 
 pub struct AllocatedArray<'a, T: Allocation> {
+    /// Null if the JVM returned no allocation for an empty array
+    ptr: *mut T::Element,
     array: &'a mut [T::Element],
     jni: JNIEnv<'a>,
     jvmti: JvmtiEnv<'a>,
@@ -30,6 +35,21 @@ pub struct AllocatedMutf8<'a> {
 /// jobject
 pub struct LocalRef;
 
+/// jlong tags, which need no releasing
+pub struct Tag;
+
+/// Result of `GetObjectsWithTags` with the tag each object matched, owning both allocations
+pub struct TaggedObjects<'a> {
+    objects: AllocatedArray<'a, LocalRef>,
+    tags: AllocatedArray<'a, Tag>,
+}
+
+/// jthread
+pub struct LocalThread<'a>(PhantomData<&'a ()>);
+
+/// jclass
+pub struct LocalClass<'a>(PhantomData<&'a ()>);
+
 impl<'a, T: Allocation> AllocatedArray<'a, T> {
     pub unsafe fn new(
         ptr: *mut T::Element,
@@ -37,8 +57,14 @@ impl<'a, T: Allocation> AllocatedArray<'a, T> {
         jni: JNIEnv<'a>,
         jvmti: JvmtiEnv<'a>,
     ) -> Self {
-        let slice = std::slice::from_raw_parts_mut(ptr, len);
+        let slice = if ptr.is_null() {
+            debug_assert_eq!(len, 0);
+            &mut []
+        } else {
+            std::slice::from_raw_parts_mut(ptr, len)
+        };
         Self {
+            ptr,
             array: slice,
             jni,
             jvmti,
@@ -46,6 +72,41 @@ impl<'a, T: Allocation> AllocatedArray<'a, T> {
     }
 }
 
+impl<'a> TaggedObjects<'a> {
+    pub(crate) fn new(
+        objects: AllocatedArray<'a, LocalRef>,
+        tags: AllocatedArray<'a, Tag>,
+    ) -> Self {
+        debug_assert_eq!(objects.len(), tags.len());
+        Self { objects, tags }
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    pub fn objects(&self) -> &[jobject] {
+        &self.objects
+    }
+
+    /// Each object with the tag it matched
+    pub fn iter(&self) -> impl Iterator<Item = (JObject<'a>, NonZeroJlong)> + '_ {
+        self.objects
+            .iter()
+            .zip(self.tags.iter())
+            .map(|(&obj, &tag)| {
+                (
+                    JObject::from(obj),
+                    NonZeroJlong::new(tag).expect("matched objects are tagged"),
+                )
+            })
+    }
+}
+
 impl<'a> AllocatedMutf8<'a> {
     pub unsafe fn new(nul_terminated_ptr: *mut c_char, jvmti: JvmtiEnv<'a>) -> Self {
         let cstr = CStr::from_ptr(nul_terminated_ptr);
@@ -59,9 +120,13 @@ impl<'a, T: Allocation> Drop for AllocatedArray<'a, T> {
         // free elements
         T::release_multiple(self.jni, self.array);
 
+        if self.ptr.is_null() {
+            return;
+        }
+
         // free allocation
         unsafe {
-            if let Err(err) = self.jvmti.deallocate(self.array.as_mut_ptr() as *mut ()) {
+            if let Err(err) = self.jvmti.deallocate(self.ptr as *mut ()) {
                 error!(
                     "failed to deallocate array of {} {:?}: {}",
                     self.array.len(),
@@ -117,3 +182,38 @@ impl Allocation for LocalRef {
         }
     }
 }
+
+impl Allocation for Tag {
+    const WHAT: &'static str = "tags";
+    type Element = jlong;
+
+    fn release_multiple(_: JNIEnv, _: &[Self::Element]) {}
+}
+
+impl<'a> Allocation for LocalThread<'a> {
+    const WHAT: &'static str = "thread local refs";
+    type Element = Thread<'a>;
+
+    fn release_multiple(jni: JNIEnv, array: &[Self::Element]) {
+        trace!("releasing {} thread local refs", array.len());
+        for thread in array {
+            if let Err(err) = jni.delete_local_ref(**thread) {
+                error!("failed to delete local ref: {}", err);
+            }
+        }
+    }
+}
+
+impl<'a> Allocation for LocalClass<'a> {
+    const WHAT: &'static str = "class local refs";
+    type Element = Class<'a>;
+
+    fn release_multiple(jni: JNIEnv, array: &[Self::Element]) {
+        trace!("releasing {} class local refs", array.len());
+        for class in array {
+            if let Err(err) = jni.delete_local_ref(**class) {
+                error!("failed to delete local ref: {}", err);
+            }
+        }
+    }
+}
@@ -0,0 +1,510 @@
+use crate::callback_state::CallbackState;
+use crate::capability::Capability;
+use crate::event::{EventCallbacksBuilder, EventScope, EventType};
+use crate::handles::{Method, Thread};
+use crate::raw_monitor::{RawMonitor, RawMonitorGuard};
+use crate::util::*;
+use crate::JvmtiEnv;
+use jni::sys::{jboolean, jvalue, JNI_TRUE};
+use jni::{JNIEnv, JavaVM};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+use std::thread::ThreadId;
+use std::time::{Duration, Instant};
+
+const DEFAULT_MAX_CALLS: usize = 1_000_000;
+
+pub struct MethodTracerBuilder {
+    patterns: Vec<String>,
+    max_calls: usize,
+}
+
+/// Records calls to matching methods from `MethodEntry` and `MethodExit` events in a dedicated
+/// JVMTI environment, as a call tree per thread with timings.
+///
+/// Nothing is traced until events are enabled with [enable](Self::enable), typically for a single
+/// thread as every method call of a traced thread is slowed down, matching or not.
+pub struct MethodTracer<'a> {
+    state: CallbackState<'a, TracerState<'a>>,
+}
+
+struct TracerState<'a> {
+    patterns: Vec<String>,
+    max_calls: usize,
+    started: Instant,
+    calls: RawMonitor<'a, Calls>,
+}
+
+#[derive(Default)]
+struct Calls {
+    /// Index into `methods`, or None if filtered out or unresolvable
+    method_indices: HashMap<usize, Option<usize>>,
+    methods: Vec<TracedMethod>,
+    thread_indices: HashMap<ThreadId, usize>,
+    threads: Vec<ThreadCalls>,
+    recorded: usize,
+    dropped: u64,
+}
+
+struct ThreadCalls {
+    name: String,
+    calls: Vec<TracedCall>,
+    /// Method and call index of the calls in progress, where the call is None if dropped
+    open: Vec<(usize, Option<usize>)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TracedMethod {
+    /// e.g. `java.util.ArrayList`
+    pub class_name: String,
+    pub method_name: String,
+    /// e.g. `(Ljava/lang/Object;)Z`
+    pub signature: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct TracedCall {
+    /// Index into [MethodTrace::methods]
+    pub method: usize,
+    /// Number of traced calls in progress on the thread when this one was made
+    pub depth: usize,
+    /// Since the tracer was installed
+    pub start: Duration,
+    /// None if still in progress
+    pub duration: Option<Duration>,
+    /// Whether the method exited by throwing
+    pub exception: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ThreadTrace {
+    pub name: String,
+    /// In the order they were made, i.e. a pre-order walk of the call tree
+    pub calls: Vec<TracedCall>,
+}
+
+/// The calls traced so far
+#[derive(Debug, Clone)]
+pub struct MethodTrace {
+    /// Since the tracer was installed
+    pub duration: Duration,
+    pub methods: Vec<TracedMethod>,
+    pub threads: Vec<ThreadTrace>,
+    /// Calls not recorded after reaching the limit
+    pub dropped_calls: u64,
+}
+
+impl Default for MethodTracerBuilder {
+    fn default() -> Self {
+        Self {
+            patterns: Vec::new(),
+            max_calls: DEFAULT_MAX_CALLS,
+        }
+    }
+}
+
+impl MethodTracerBuilder {
+    /// Traces methods of classes matching the pattern, which is either an exact class name or
+    /// ends in `*` to match by prefix, e.g. `java.util.*` for a package and its subpackages.
+    /// Every method is traced if no patterns are given
+    pub fn with_pattern(mut self, pattern: impl Into<String>) -> Self {
+        self.patterns.push(pattern.into());
+        self
+    }
+
+    /// Calls after this many are counted but not recorded, defaults to a million
+    pub fn with_max_calls(mut self, max_calls: usize) -> Self {
+        self.max_calls = max_calls;
+        self
+    }
+
+    /// Creates a new JVMTI environment and installs the event callbacks, requesting
+    /// `can_generate_method_entry_events` and `can_generate_method_exit_events`, which are only
+    /// available in the live phase if another agent acquired them during startup
+    pub fn install<'a>(self, jvm: &JavaVM) -> JvmtiResult<MethodTracer<'a>> {
+        let jvmti = JvmtiEnv::from_jvm(jvm)?;
+        let state = TracerState {
+            patterns: self.patterns,
+            max_calls: self.max_calls,
+            started: Instant::now(),
+            calls: RawMonitor::new(&jvmti, "method tracer", Calls::default())?,
+        };
+        let tracer = MethodTracer {
+            state: CallbackState::new(jvmti, state)?,
+        };
+
+        let jvmti = tracer.state.jvmti();
+        jvmti.require_capabilities(&[
+            Capability::GenerateMethodEntryEvents,
+            Capability::GenerateMethodExitEvents,
+        ])?;
+
+        let callbacks = EventCallbacksBuilder::default()
+            .with_method_entry(Some(method_entry))
+            .with_method_exit(Some(method_exit))
+            .build();
+        jvmti.install_event_callbacks(&callbacks)?;
+
+        debug!("installed method tracer for {:?}", tracer.state.patterns);
+        Ok(tracer)
+    }
+}
+
+impl<'a> MethodTracer<'a> {
+    pub fn builder() -> MethodTracerBuilder {
+        MethodTracerBuilder::default()
+    }
+
+    /// Starts tracing the thread, or all threads if global
+    pub fn enable(&self, scope: EventScope) -> JvmtiResult<()> {
+        let jvmti = self.state.jvmti();
+        jvmti.enable_event(EventType::MethodEntry, scope)?;
+        jvmti.enable_event(EventType::MethodExit, scope)
+    }
+
+    /// Stops tracing the thread. Disabling globally does not stop threads enabled individually
+    pub fn disable(&self, scope: EventScope) -> JvmtiResult<()> {
+        let jvmti = self.state.jvmti();
+        jvmti.disable_event(EventType::MethodEntry, scope)?;
+        jvmti.disable_event(EventType::MethodExit, scope)
+    }
+
+    /// The calls traced so far
+    pub fn trace(&self) -> JvmtiResult<MethodTrace> {
+        let duration = self.state.started.elapsed();
+        let calls = self.state.calls.lock()?;
+        Ok(MethodTrace {
+            duration,
+            methods: calls.methods.clone(),
+            threads: calls
+                .threads
+                .iter()
+                .map(|thread| ThreadTrace {
+                    name: thread.name.clone(),
+                    calls: thread.calls.clone(),
+                })
+                .collect(),
+            dropped_calls: calls.dropped,
+        })
+    }
+
+    /// Forgets the calls traced so far, e.g. between requests. Calls in progress are not
+    /// recorded when they end
+    pub fn clear(&self) -> JvmtiResult<()> {
+        let mut calls = self.state.calls.lock()?;
+        for thread in &mut calls.threads {
+            thread.calls.clear();
+            for (_, call) in &mut thread.open {
+                *call = None;
+            }
+        }
+        calls.recorded = 0;
+        calls.dropped = 0;
+        Ok(())
+    }
+}
+
+impl Drop for MethodTracer<'_> {
+    fn drop(&mut self) {
+        // events may be enabled for any number of threads, so stop them all at once
+        if let Err(err) = self
+            .state
+            .jvmti()
+            .install_event_callbacks(&EventCallbacksBuilder::default().build())
+        {
+            error!("failed to remove method tracer callbacks: {}", err);
+        }
+    }
+}
+
+impl<'a> TracerState<'a> {
+    /// Locks the calls with the method and thread indices, or returns None if the method is
+    /// filtered out. Either is resolved the first time it is seen without holding the lock, as
+    /// that calls JVMTI and JNI
+    fn lock_calls(
+        &self,
+        jvmti: &JvmtiEnv,
+        jni: JNIEnv,
+        thread: Thread,
+        method: Method,
+    ) -> JvmtiResult<Option<(RawMonitorGuard<'_, 'a, Calls>, usize, usize)>> {
+        let method_id = method.into_inner() as usize;
+        let thread_id = std::thread::current().id();
+        let calls = self.calls.lock()?;
+        let known_method = calls.method_indices.get(&method_id).copied();
+        let known_thread = calls.thread_indices.get(&thread_id).copied();
+        match (known_method, known_thread) {
+            (Some(None), _) => return Ok(None),
+            (Some(Some(method)), Some(thread)) => return Ok(Some((calls, method, thread))),
+            _ => drop(calls),
+        }
+
+        let resolved = match known_method {
+            None => TracedMethod::resolve(self, jvmti, jni, method).unwrap_or_else(|err| {
+                debug!("failed to resolve traced method: {}", err);
+                None
+            }),
+            Some(_) => None,
+        };
+        let name = match known_thread {
+            None => Some(thread_name(jvmti, jni, thread)),
+            Some(_) => None,
+        };
+
+        // another thread may have resolved the method meanwhile, which is then kept
+        let mut calls = self.calls.lock()?;
+        let Calls {
+            method_indices,
+            methods,
+            thread_indices,
+            threads,
+            ..
+        } = &mut *calls;
+        let method = *method_indices.entry(method_id).or_insert_with(|| {
+            resolved.map(|method| {
+                methods.push(method);
+                methods.len() - 1
+            })
+        });
+        let thread = *thread_indices.entry(thread_id).or_insert_with(|| {
+            threads.push(ThreadCalls {
+                name: name.unwrap_or_default(),
+                calls: Vec::new(),
+                open: Vec::new(),
+            });
+            threads.len() - 1
+        });
+        Ok(method.map(|method| (calls, method, thread)))
+    }
+
+    fn matches(&self, class_name: &str) -> bool {
+        self.patterns.is_empty()
+            || self
+                .patterns
+                .iter()
+                .any(|pattern| matches_class_pattern(pattern, class_name))
+    }
+}
+
+/// Called on the thread itself
+fn thread_name(jvmti: &JvmtiEnv, jni: JNIEnv, thread: Thread) -> String {
+    match jvmti.get_thread_info(jni, thread) {
+        Ok(info) => {
+            let _ = info.delete_local_refs(jni);
+            info.name
+        }
+        Err(err) => {
+            debug!("failed to get traced thread info: {}", err);
+            String::from("unknown")
+        }
+    }
+}
+
+impl TracedMethod {
+    /// None if filtered out
+    fn resolve(
+        state: &TracerState<'_>,
+        jvmti: &JvmtiEnv,
+        jni: JNIEnv,
+        method: Method,
+    ) -> JvmtiResult<Option<Self>> {
+        let class = jvmti.get_method_declaring_class(jni, method)?;
+        let signature = jvmti.get_class_signature(class);
+        jni.delete_local_ref(*class)?;
+        let class_name = java_class_name(&mutf8_to_string(signature?.as_bytes()));
+        if !state.matches(&class_name) {
+            return Ok(None);
+        }
+
+        let (name, signature) = jvmti.get_method_name(method)?;
+        Ok(Some(TracedMethod {
+            class_name,
+            method_name: mutf8_to_string(name.as_bytes()),
+            signature: mutf8_to_string(signature.as_bytes()),
+        }))
+    }
+}
+
+unsafe extern "C" fn method_entry(
+    jvmti_env: JvmtiEnv,
+    jni_env: JNIEnv,
+    thread: Thread,
+    method: Method,
+) {
+    CallbackState::<TracerState>::with(&jvmti_env, |state| {
+        let start = state.started.elapsed();
+
+        let (mut calls, method, thread) =
+            match state.lock_calls(&jvmti_env, jni_env, thread, method) {
+                Ok(Some(locked)) => locked,
+                Ok(None) => return,
+                Err(err) => {
+                    error!("failed to lock traced calls: {}", err);
+                    return;
+                }
+            };
+
+        let record = calls.recorded < state.max_calls;
+        if record {
+            calls.recorded += 1;
+        } else {
+            calls.dropped += 1;
+        }
+
+        let thread = &mut calls.threads[thread];
+        let call = record.then(|| {
+            thread.calls.push(TracedCall {
+                method,
+                depth: thread.open.len(),
+                start,
+                duration: None,
+                exception: false,
+            });
+            thread.calls.len() - 1
+        });
+        thread.open.push((method, call));
+    });
+}
+
+unsafe extern "C" fn method_exit(
+    jvmti_env: JvmtiEnv,
+    jni_env: JNIEnv,
+    thread: Thread,
+    method: Method,
+    was_popped_by_exception: jboolean,
+    _return_value: jvalue,
+) {
+    CallbackState::<TracerState>::with(&jvmti_env, |state| {
+        let end = state.started.elapsed();
+
+        let (mut calls, method, thread) =
+            match state.lock_calls(&jvmti_env, jni_env, thread, method) {
+                Ok(Some(locked)) => locked,
+                Ok(None) => return,
+                Err(err) => {
+                    error!("failed to lock traced calls: {}", err);
+                    return;
+                }
+            };
+
+        // calls in progress when tracing started have no entry
+        let thread = &mut calls.threads[thread];
+        if thread.open.last().map(|&(open, _)| open) != Some(method) {
+            return;
+        }
+        if let Some((_, Some(call))) = thread.open.pop() {
+            let call = &mut thread.calls[call];
+            call.duration = Some(end.saturating_sub(call.start));
+            call.exception = was_popped_by_exception == JNI_TRUE;
+        }
+    });
+}
+
+/// e.g. `java.util.ArrayList.add(Ljava/lang/Object;)Z`
+impl Display for TracedMethod {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}.{}{}",
+            self.class_name, self.method_name, self.signature
+        )
+    }
+}
+
+impl MethodTrace {
+    /// Writes the Chrome trace event format, for `chrome://tracing` or Perfetto, with a complete
+    /// event per call. Calls still in progress last until the end of the trace
+    pub fn write_chrome_trace<W: Write>(&self, mut out: W) -> JvmtiResult<()> {
+        write!(out, "{{\"displayTimeUnit\":\"ms\",\"traceEvents\":[")?;
+        let mut first = true;
+        for (tid, thread) in self.threads.iter().enumerate() {
+            if !first {
+                write!(out, ",")?;
+            }
+            first = false;
+            write!(
+                out,
+                "\n{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":1,\"tid\":{},\"args\":{{\"name\":{}}}}}",
+                tid + 1,
+                json_string(&thread.name)
+            )?;
+
+            for call in &thread.calls {
+                let method = &self.methods[call.method];
+                let duration = call
+                    .duration
+                    .unwrap_or_else(|| self.duration.saturating_sub(call.start));
+                write!(
+                    out,
+                    ",\n{{\"name\":{},\"cat\":\"java\",\"ph\":\"X\",\"pid\":1,\"tid\":{},\"ts\":{:.3},\"dur\":{:.3},\"args\":{{\"signature\":{}",
+                    json_string(&format!("{}.{}", method.class_name, method.method_name)),
+                    tid + 1,
+                    micros(call.start),
+                    micros(duration),
+                    json_string(&method.signature)
+                )?;
+                if call.duration.is_none() {
+                    write!(out, ",\"unfinished\":true")?;
+                }
+                if call.exception {
+                    write!(out, ",\"exception\":true")?;
+                }
+                write!(out, "}}}}")?;
+            }
+        }
+        writeln!(out, "\n]}}")?;
+        Ok(())
+    }
+}
+
+fn micros(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1_000_000.0
+}
+
+fn json_string(string: &str) -> String {
+    let mut json = String::with_capacity(string.len() + 2);
+    json.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// An indented call tree per thread, each call prefixed with its start time and followed by its
+/// duration
+impl Display for MethodTrace {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for thread in &self.threads {
+            writeln!(f, "\"{}\"", thread.name)?;
+            for call in &thread.calls {
+                write!(
+                    f,
+                    "{:12.3}ms  {:indent$}{}",
+                    call.start.as_secs_f64() * 1000.0,
+                    "",
+                    self.methods[call.method],
+                    indent = call.depth * 2
+                )?;
+                match call.duration {
+                    Some(duration) => write!(f, " {:.3}ms", duration.as_secs_f64() * 1000.0)?,
+                    None => write!(f, " (unfinished)")?,
+                }
+                if call.exception {
+                    write!(f, " threw")?;
+                }
+                writeln!(f)?;
+            }
+        }
+        if self.dropped_calls > 0 {
+            writeln!(f, "{} calls dropped", self.dropped_calls)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+use crate::handles::Thread;
+use crate::memory::Allocation;
+use crate::util::*;
+use crate::JvmtiEnv;
+use jni::objects::JObject;
+use jni::sys::jint;
+use jni::JNIEnv;
+use jni_jvmti_sys::jvmtiMonitorStackDepthInfo;
+use std::fmt::{Display, Formatter};
+
+/// Owned result of `GetObjectMonitorUsage`. All threads are JNI local refs
+#[derive(Debug)]
+pub struct MonitorUsage<'a> {
+    /// None if the monitor is not owned
+    pub owner: Option<Thread<'a>>,
+    /// Number of times the owner has entered the monitor
+    pub entry_count: jint,
+    /// Threads waiting to own the monitor
+    pub waiters: Vec<Thread<'a>>,
+    /// Threads waiting to be notified by the monitor
+    pub notify_waiters: Vec<Thread<'a>>,
+}
+
+/// An object used as a monitor, identified as in thread dumps
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MonitorObject {
+    /// e.g. `java.lang.Object`
+    pub class_name: String,
+    /// Identity hash code, in place of the address that jstack prints
+    pub hash_code: jint,
+}
+
+/// jvmtiMonitorStackDepthInfo, whose monitor is a local ref
+pub struct MonitorStackDepth;
+
+impl Allocation for MonitorStackDepth {
+    const WHAT: &'static str = "monitor stack depth infos";
+    type Element = jvmtiMonitorStackDepthInfo;
+
+    fn release_multiple(jni: JNIEnv, array: &[Self::Element]) {
+        trace!("releasing {} monitor local refs", array.len());
+        for info in array {
+            if let Err(err) = jni.delete_local_ref(JObject::from(info.monitor)) {
+                error!("failed to delete local ref: {}", err);
+            }
+        }
+    }
+}
+
+impl MonitorUsage<'_> {
+    pub fn delete_local_refs(&self, jni: JNIEnv) -> jni::errors::Result<()> {
+        let threads = self
+            .owner
+            .iter()
+            .chain(self.waiters.iter())
+            .chain(self.notify_waiters.iter());
+        for thread in threads {
+            jni.delete_local_ref(**thread)?;
+        }
+        Ok(())
+    }
+}
+
+impl MonitorObject {
+    pub fn describe(jvmti: &JvmtiEnv, jni: JNIEnv, object: JObject) -> JvmtiResult<Self> {
+        let class = jni.get_object_class(object)?;
+        let signature = jvmti.get_class_signature(class.into());
+        jni.delete_local_ref(class.into())?;
+
+        Ok(MonitorObject {
+            class_name: java_class_name(&mutf8_to_string(signature?.as_bytes())),
+            hash_code: jvmti.get_object_hash_code(object)?,
+        })
+    }
+}
+
+/// e.g. `<0x000000001b6d3586> (a java.lang.Object)`
+impl Display for MonitorObject {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "<0x{:016x}> (a {})",
+            self.hash_code as u32, self.class_name
+        )
+    }
+}
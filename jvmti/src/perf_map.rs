@@ -0,0 +1,396 @@
+use crate::callback_state::CallbackState;
+use crate::capability::Capability;
+use crate::event::{EventCallbacksBuilder, EventScope, EventType};
+use crate::handles::Method;
+use crate::raw_monitor::RawMonitor;
+use crate::util::*;
+use crate::JvmtiEnv;
+use core::ffi::c_void;
+use jni::sys::{jint, jmethodID};
+use jni::{JNIEnv, JavaVM};
+use jni_jvmti_sys::{
+    jvmtiAddrLocationMap, jvmtiCMLRKind, jvmtiCompiledMethodLoadInlineRecord,
+    jvmtiCompiledMethodLoadRecordHeader,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+
+const EVENTS: [EventType; 3] = [
+    EventType::CompiledMethodLoad,
+    EventType::CompiledMethodUnload,
+    EventType::DynamicCodeGenerated,
+];
+
+#[derive(Default)]
+pub struct PerfMapBuilder {
+    path: Option<PathBuf>,
+    inlining: bool,
+    signatures: bool,
+}
+
+/// Writes a perf map of the code generated by the JIT and the VM, so Linux `perf` can symbolise
+/// frames in it as it does with perf-map-agent. Every `CompiledMethodLoad` and
+/// `DynamicCodeGenerated` event in a dedicated JVMTI environment appends a line per code range,
+/// and both are generated on start for code that already exists.
+///
+/// The file is only ever appended to, as `perf` reads it after the samples were taken, when code
+/// unloaded meanwhile still needs its symbols. [PerfMap::entries] only lists the code still live.
+pub struct PerfMap<'a> {
+    state: CallbackState<'a, MapState<'a>>,
+    path: PathBuf,
+}
+
+struct MapState<'a> {
+    jvm: JavaVM,
+    inlining: bool,
+    signatures: bool,
+    code: RawMonitor<'a, Code>,
+}
+
+struct Code {
+    out: BufWriter<File>,
+    /// Live code by start address, each split into ranges by inlined method if enabled
+    blobs: BTreeMap<usize, Vec<PerfMapEntry>>,
+    /// Whether writing has failed before, to only log it once
+    failed: bool,
+}
+
+/// A range of generated code, written as `<address> <size> <name>` in hex
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PerfMapEntry {
+    pub address: usize,
+    pub size: usize,
+    /// A method as `java.lang.Object.hashCode`, optionally followed by its signature, and with
+    /// inlining its inlined methods from the outermost, e.g.
+    /// `java.lang.Integer.toHexString->java.lang.Integer.toUnsignedString0`. Otherwise the VM's
+    /// name for the code, e.g. `Interpreter`
+    pub name: String,
+}
+
+impl PerfMapBuilder {
+    /// Defaults to `/tmp/perf-<pid>.map`, where `perf` looks for it
+    pub fn with_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    /// Whether to split compiled methods into a range per inlined method, from the `compile_info`
+    /// of `CompiledMethodLoad`. Defaults to false
+    pub fn with_inlining(mut self, inlining: bool) -> Self {
+        self.inlining = inlining;
+        self
+    }
+
+    /// Whether to follow method names with their signature to tell overloads apart, e.g.
+    /// `java.lang.Integer.toHexString(I)Ljava/lang/String;`. Defaults to false
+    pub fn with_signatures(mut self, signatures: bool) -> Self {
+        self.signatures = signatures;
+        self
+    }
+
+    /// Creates the map file, truncating any existing one, and a new JVMTI environment to fill
+    /// it from
+    pub fn start<'a>(self, jvm: &JavaVM) -> JvmtiResult<PerfMap<'a>> {
+        let path = self
+            .path
+            .unwrap_or_else(|| PathBuf::from(format!("/tmp/perf-{}.map", std::process::id())));
+        let out = BufWriter::new(File::create(&path)?);
+
+        let jvmti = JvmtiEnv::from_jvm(jvm)?;
+        jvmti.require_capabilities(&[Capability::GenerateCompiledMethodLoadEvents])?;
+
+        let code = Code {
+            out,
+            blobs: BTreeMap::new(),
+            failed: false,
+        };
+        let state = MapState {
+            jvm: unsafe { JavaVM::from_raw(jvm.get_java_vm_pointer())? },
+            inlining: self.inlining,
+            signatures: self.signatures,
+            code: RawMonitor::new(&jvmti, "perf map", code)?,
+        };
+        let map = PerfMap {
+            state: CallbackState::new(jvmti, state)?,
+            path,
+        };
+
+        let jvmti = map.state.jvmti();
+
+        let callbacks = EventCallbacksBuilder::default()
+            .with_compiled_method_load(Some(compiled_method_load))
+            .with_compiled_method_unload(Some(compiled_method_unload))
+            .with_dynamic_code_generated(Some(dynamic_code_generated))
+            .build();
+        jvmti.install_event_callbacks(&callbacks)?;
+        for event in EVENTS {
+            jvmti.enable_event(event, EventScope::Global)?;
+        }
+        jvmti.generate_events(EventType::DynamicCodeGenerated)?;
+        jvmti.generate_events(EventType::CompiledMethodLoad)?;
+
+        debug!("started perf map at {}", map.path.display());
+        Ok(map)
+    }
+}
+
+impl<'a> PerfMap<'a> {
+    pub fn builder() -> PerfMapBuilder {
+        PerfMapBuilder::default()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The ranges of code not yet unloaded, by address
+    pub fn entries(&self) -> JvmtiResult<Vec<PerfMapEntry>> {
+        let code = self.state.code.lock()?;
+        Ok(code.blobs.values().flatten().cloned().collect())
+    }
+
+    /// The live code range containing the address, e.g. a native stack frame's
+    pub fn lookup(&self, address: usize) -> JvmtiResult<Option<PerfMapEntry>> {
+        let code = self.state.code.lock()?;
+        let blob = code.blobs.range(..=address).next_back();
+        Ok(blob.and_then(|(_, entries)| {
+            entries
+                .iter()
+                .find(|entry| address < entry.address + entry.size && address >= entry.address)
+                .cloned()
+        }))
+    }
+}
+
+impl Drop for PerfMap<'_> {
+    fn drop(&mut self) {
+        for event in EVENTS {
+            if let Err(err) = self.state.jvmti().disable_event(event, EventScope::Global) {
+                error!("failed to disable {:?}: {}", event, err);
+            }
+        }
+    }
+}
+
+impl Code {
+    /// Replaces any code previously at the same address
+    fn add(&mut self, entries: Vec<PerfMapEntry>) {
+        let address = match entries.first() {
+            Some(entry) => entry.address,
+            None => return,
+        };
+
+        let result = (|| {
+            for entry in &entries {
+                writeln!(
+                    self.out,
+                    "{:x} {:x} {}",
+                    entry.address, entry.size, entry.name
+                )?;
+            }
+            // perf may read the map while the VM is still running
+            self.out.flush()
+        })();
+        if let Err(err) = result {
+            if !self.failed {
+                error!("failed to write perf map: {}", err);
+                self.failed = true;
+            }
+        }
+
+        self.blobs.insert(address, entries);
+    }
+}
+
+/// Names methods for a single event, resolving each once
+struct MethodNames<'a, 'b> {
+    jvmti: &'a JvmtiEnv<'b>,
+    jni: JNIEnv<'a>,
+    signatures: bool,
+    names: HashMap<jmethodID, String>,
+}
+
+impl<'a, 'b> MethodNames<'a, 'b> {
+    fn name(&mut self, method: jmethodID) -> String {
+        let (jvmti, jni, signatures) = (self.jvmti, self.jni, self.signatures);
+        self.names
+            .entry(method)
+            .or_insert_with(|| {
+                let method = Method::from(method);
+                match method_name(jvmti, jni, method, signatures) {
+                    Ok(name) => name,
+                    Err(err) => {
+                        debug!("failed to name compiled method: {}", err);
+                        format!("{:?}", method.into_inner())
+                    }
+                }
+            })
+            .clone()
+    }
+}
+
+fn method_name(
+    jvmti: &JvmtiEnv,
+    jni: JNIEnv,
+    method: Method,
+    signatures: bool,
+) -> JvmtiResult<String> {
+    let class = jvmti.get_method_declaring_class(jni, method)?;
+    let class_signature = jvmti.get_class_signature(class);
+    jni.delete_local_ref(*class)?;
+    let class_name = java_class_name(&mutf8_to_string(class_signature?.as_bytes()));
+
+    let (name, signature) = jvmti.get_method_name(method)?;
+    let mut qualified = format!("{}.{}", class_name, mutf8_to_string(name.as_bytes()));
+    if signatures {
+        qualified.push_str(&mutf8_to_string(signature.as_bytes()));
+    }
+    Ok(qualified)
+}
+
+/// The methods on the compile time stack at each pc with inlining info, innermost first
+unsafe fn inlined_stacks(compile_info: *const c_void) -> Vec<(usize, Vec<jmethodID>)> {
+    let mut stacks = Vec::new();
+    let mut record = compile_info as *const jvmtiCompiledMethodLoadRecordHeader;
+    while !record.is_null() {
+        if (*record).kind == jvmtiCMLRKind::JVMTI_CMLR_INLINE_INFO {
+            let inline = &*(record as *const jvmtiCompiledMethodLoadInlineRecord);
+            if !inline.pcinfo.is_null() {
+                let pcs = std::slice::from_raw_parts(inline.pcinfo, inline.numpcs as usize);
+                for pc in pcs {
+                    if pc.numstackframes <= 0 || pc.methods.is_null() {
+                        continue;
+                    }
+                    let methods =
+                        std::slice::from_raw_parts(pc.methods, pc.numstackframes as usize);
+                    stacks.push((pc.pc as usize, methods.to_vec()));
+                }
+            }
+        }
+        record = (*record).next;
+    }
+    stacks
+}
+
+/// Splits the method's code where the inlined methods at a pc change, each range named after the
+/// stack at its start
+fn inlined_entries(
+    names: &mut MethodNames,
+    method: jmethodID,
+    address: usize,
+    size: usize,
+    stacks: Vec<(usize, Vec<jmethodID>)>,
+) -> Vec<PerfMapEntry> {
+    let end = address + size;
+    let mut entries = Vec::new();
+    let mut start = address;
+    let mut current = vec![method];
+    for (pc, stack) in stacks {
+        if pc <= start || pc >= end || stack == current {
+            continue;
+        }
+        entries.push(PerfMapEntry {
+            address: start,
+            size: pc - start,
+            name: stack_name(names, &current),
+        });
+        start = pc;
+        current = stack;
+    }
+    entries.push(PerfMapEntry {
+        address: start,
+        size: end - start,
+        name: stack_name(names, &current),
+    });
+    entries
+}
+
+fn stack_name(names: &mut MethodNames, stack: &[jmethodID]) -> String {
+    let names: Vec<String> = stack
+        .iter()
+        .rev()
+        .map(|&method| names.name(method))
+        .collect();
+    names.join("->")
+}
+
+unsafe extern "C" fn compiled_method_load(
+    jvmti_env: JvmtiEnv,
+    method: Method,
+    code_size: jint,
+    code_addr: *const c_void,
+    _map_length: jint,
+    _map: *const jvmtiAddrLocationMap,
+    compile_info: *const c_void,
+) {
+    CallbackState::<MapState>::with(&jvmti_env, |state| {
+        // compiler threads are java threads, so attached
+        let jni = match state.jvm.get_env() {
+            Ok(jni) => jni,
+            Err(err) => {
+                warn!("failed to get jni env to name compiled method: {}", err);
+                return;
+            }
+        };
+        let mut names = MethodNames {
+            jvmti: &jvmti_env,
+            jni,
+            signatures: state.signatures,
+            names: HashMap::new(),
+        };
+        let method = method.into_inner();
+        let (address, size) = (code_addr as usize, code_size as usize);
+        let entries = if state.inlining && !compile_info.is_null() {
+            inlined_entries(
+                &mut names,
+                method,
+                address,
+                size,
+                inlined_stacks(compile_info),
+            )
+        } else {
+            vec![PerfMapEntry {
+                address,
+                size,
+                name: names.name(method),
+            }]
+        };
+
+        match state.code.lock() {
+            Ok(mut code) => code.add(entries),
+            Err(err) => error!("failed to record compiled method: {}", err),
+        }
+    });
+}
+
+unsafe extern "C" fn compiled_method_unload(
+    jvmti_env: JvmtiEnv,
+    _method: Method,
+    code_addr: *const c_void,
+) {
+    CallbackState::<MapState>::with(&jvmti_env, |state| match state.code.lock() {
+        Ok(mut code) => drop(code.blobs.remove(&(code_addr as usize))),
+        Err(err) => error!("failed to record unloaded method: {}", err),
+    });
+}
+
+unsafe extern "C" fn dynamic_code_generated(
+    jvmti_env: JvmtiEnv,
+    name: *const c_char,
+    address: *const c_void,
+    length: jint,
+) {
+    let entry = PerfMapEntry {
+        address: address as usize,
+        size: length as usize,
+        name: CStr::from_ptr(name).to_string_lossy().into_owned(),
+    };
+    CallbackState::<MapState>::with(&jvmti_env, |state| match state.code.lock() {
+        Ok(mut code) => code.add(vec![entry]),
+        Err(err) => error!("failed to record generated code: {}", err),
+    });
+}
@@ -0,0 +1,141 @@
+use crate::util::*;
+use crate::JvmtiEnv;
+use jni::sys::jlong;
+use jni_jvmti_sys::jrawMonitorID;
+use std::cell::UnsafeCell;
+use std::convert::TryFrom;
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// A `Mutex`-like lock around a JVMTI raw monitor, which unlike `std::sync::Mutex` is safe to use
+/// in callbacks such as `GarbageCollectionStart` and in all phases.
+///
+/// Raw monitors are reentrant, but locking one again from the thread that already owns it would
+/// alias the guarded value, so this fails instead. It must not panic, as it is mostly locked from
+/// JVMTI callbacks.
+pub struct RawMonitor<'a, T> {
+    jvmti: JvmtiEnv<'a>,
+    id: jrawMonitorID,
+    /// Only accessed by the owning thread, so the monitor itself provides ordering
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+/// Exits the monitor on drop
+pub struct RawMonitorGuard<'m, 'a, T> {
+    monitor: &'m RawMonitor<'a, T>,
+}
+
+unsafe impl<T: Send> Send for RawMonitor<'_, T> {}
+unsafe impl<T: Send> Sync for RawMonitor<'_, T> {}
+
+impl<'a, T> RawMonitor<'a, T> {
+    pub fn new(jvmti: &JvmtiEnv<'a>, name: &str, value: T) -> JvmtiResult<Self> {
+        let id = jvmti.create_raw_monitor(name)?;
+        Ok(Self {
+            jvmti: jvmti.clone(),
+            id,
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        })
+    }
+
+    /// Blocks until the monitor is entered. Fails with [Error::AlreadyLocked] if this thread
+    /// already holds it
+    pub fn lock(&self) -> JvmtiResult<RawMonitorGuard<'_, 'a, T>> {
+        self.jvmti.raw_monitor_enter(self.id)?;
+        if self.locked.swap(true, Ordering::Relaxed) {
+            // already owned by this thread, undo the reentrant enter
+            self.jvmti.raw_monitor_exit(self.id)?;
+            return Err(Error::AlreadyLocked);
+        }
+
+        Ok(RawMonitorGuard { monitor: self })
+    }
+
+    pub fn into_inner(self) -> T {
+        let this = ManuallyDrop::new(self);
+        this.destroy();
+
+        // moved out exactly once, as drop is skipped
+        unsafe { std::ptr::read(this.value.get()) }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    pub(crate) fn id(&self) -> jrawMonitorID {
+        self.id
+    }
+
+    fn destroy(&self) {
+        if let Err(err) = self.jvmti.destroy_raw_monitor(self.id) {
+            error!("failed to destroy raw monitor {:?}: {}", self.id, err);
+        }
+    }
+}
+
+impl<T> RawMonitorGuard<'_, '_, T> {
+    /// Releases the monitor and waits to be notified, or for the timeout to elapse if given, then
+    /// reacquires it. Fails with [JvmtiError::Interrupted] if the thread is interrupted
+    pub fn wait(&mut self, timeout: Option<Duration>) -> JvmtiResult<()> {
+        let millis = match timeout {
+            // 0 means forever, so round up
+            Some(timeout) => jlong::try_from(timeout.as_millis())
+                .unwrap_or(jlong::MAX)
+                .max(1),
+            None => 0,
+        };
+
+        let monitor = self.monitor;
+        monitor.locked.store(false, Ordering::Relaxed);
+        let result = monitor.jvmti.raw_monitor_wait(monitor.id, millis);
+
+        // reacquired regardless of result
+        monitor.locked.store(true, Ordering::Relaxed);
+        result
+    }
+
+    /// Wakes a single thread waiting on this monitor
+    pub fn notify(&self) -> JvmtiResult<()> {
+        self.monitor.jvmti.raw_monitor_notify(self.monitor.id)
+    }
+
+    /// Wakes all threads waiting on this monitor
+    pub fn notify_all(&self) -> JvmtiResult<()> {
+        self.monitor.jvmti.raw_monitor_notify_all(self.monitor.id)
+    }
+}
+
+impl<T> Deref for RawMonitorGuard<'_, '_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.monitor.value.get() }
+    }
+}
+
+impl<T> DerefMut for RawMonitorGuard<'_, '_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.monitor.value.get() }
+    }
+}
+
+impl<T> Drop for RawMonitorGuard<'_, '_, T> {
+    fn drop(&mut self) {
+        let monitor = self.monitor;
+        monitor.locked.store(false, Ordering::Relaxed);
+        if let Err(err) = monitor.jvmti.raw_monitor_exit(monitor.id) {
+            error!("failed to exit raw monitor {:?}: {}", monitor.id, err);
+        }
+    }
+}
+
+impl<T> Drop for RawMonitor<'_, T> {
+    fn drop(&mut self) {
+        self.destroy();
+    }
+}
@@ -0,0 +1,59 @@
+use crate::handles::Class;
+use crate::util::*;
+use crate::JvmtiEnv;
+use jni::sys::jint;
+use jni_jvmti_sys::jvmtiClassDefinition;
+use std::convert::TryFrom;
+use std::path::Path;
+
+/// New class file bytes for an existing class, for `RedefineClasses`
+#[derive(Debug, Clone)]
+pub struct ClassDefinition<'a> {
+    pub class: Class<'a>,
+    pub class_bytes: Vec<u8>,
+}
+
+impl<'a> ClassDefinition<'a> {
+    pub fn new(class: Class<'a>, class_bytes: Vec<u8>) -> Self {
+        Self { class, class_bytes }
+    }
+
+    pub(crate) fn as_raw(&self) -> jvmtiClassDefinition {
+        jvmtiClassDefinition {
+            klass: self.class.into_inner(),
+            class_byte_count: jint::try_from(self.class_bytes.len()).expect("class file too large"),
+            class_bytes: self.class_bytes.as_ptr(),
+        }
+    }
+}
+
+impl<'a> From<(Class<'a>, Vec<u8>)> for ClassDefinition<'a> {
+    fn from((class, class_bytes): (Class<'a>, Vec<u8>)) -> Self {
+        Self::new(class, class_bytes)
+    }
+}
+
+/// Reloads the given class from a `.class` file on disk, i.e. a hot swap.
+///
+/// `can_redefine_classes` is requested if not already possessed. Unsupported changes such as
+/// adding a method are reported as [JvmtiError::UnsupportedRedefinition].
+pub fn hot_swap(jvmti: &JvmtiEnv, class: Class, class_file: impl AsRef<Path>) -> JvmtiResult<()> {
+    let class_file = class_file.as_ref();
+    let class_bytes = std::fs::read(class_file)?;
+    debug!(
+        "hot swapping class from {} ({} bytes)",
+        class_file.display(),
+        class_bytes.len()
+    );
+
+    jvmti
+        .redefine_classes(&[ClassDefinition::new(class, class_bytes)])
+        .map_err(|err| {
+            warn!(
+                "failed to hot swap class from {}: {}",
+                class_file.display(),
+                err
+            );
+            err
+        })
+}
@@ -0,0 +1,324 @@
+use crate::callback_state::CallbackState;
+use crate::event::{EventCallbacksBuilder, EventScope, EventType};
+use crate::handles::Class;
+use crate::util::*;
+use crate::JvmtiEnv;
+use jni::objects::JObject;
+use jni::sys::jint;
+use jni::{JNIEnv, JavaVM};
+use std::convert::TryFrom;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_uchar};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const DEFAULT_BATCH_SIZE: usize = 64;
+
+/// A class passed to a transformer from `ClassFileLoadHook`
+#[derive(Debug)]
+pub struct ClassFileLoad<'a> {
+    /// Internal form, e.g. `java/lang/String`
+    pub name: &'a str,
+    /// None when the class is being loaded for the first time
+    pub class_being_redefined: Option<Class<'a>>,
+    pub loader: JObject<'a>,
+    pub class_data: &'a [u8],
+}
+
+/// Returns the new class bytes, or None to leave the class untouched
+pub type TransformerFn = dyn Fn(&ClassFileLoad) -> Option<Vec<u8>> + Send + Sync;
+
+pub type ClassFilterFn = dyn Fn(&str) -> bool + Send + Sync;
+
+pub struct RetransformerBuilder {
+    filter: Box<ClassFilterFn>,
+    batch_size: usize,
+    restore_on_unload: bool,
+}
+
+/// Runs a transformer from a dedicated JVMTI environment's `ClassFileLoadHook`, over both newly
+/// loaded classes and classes that were already loaded before it was installed.
+///
+/// The transformer can be switched off and on at runtime, which retransforms the matching classes
+/// back to their original definitions and back again.
+pub struct Retransformer<'a> {
+    state: CallbackState<'a, TransformerState>,
+    batch_size: usize,
+    restore_on_unload: bool,
+}
+
+struct TransformerState {
+    filter: Box<ClassFilterFn>,
+    transformer: Box<TransformerFn>,
+    active: AtomicBool,
+}
+
+#[derive(Debug)]
+pub struct RetransformFailure {
+    pub class_name: String,
+    pub error: Error,
+}
+
+/// Outcome of retransforming all loaded classes that match the filter
+#[derive(Debug, Default)]
+pub struct RetransformReport {
+    pub retransformed: Vec<String>,
+    pub unmodifiable: Vec<String>,
+    pub failures: Vec<RetransformFailure>,
+}
+
+impl Default for RetransformerBuilder {
+    fn default() -> Self {
+        Self {
+            filter: Box::new(|_| true),
+            batch_size: DEFAULT_BATCH_SIZE,
+            restore_on_unload: false,
+        }
+    }
+}
+
+impl RetransformerBuilder {
+    /// Filter on the internal class name, e.g. `java/lang/String`. Defaults to all classes
+    pub fn with_class_filter(
+        mut self,
+        filter: impl Fn(&str) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.filter = Box::new(filter);
+        self
+    }
+
+    /// Number of classes passed to each `RetransformClasses` call
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        assert_ne!(batch_size, 0, "batch size must be non-zero");
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Retransform all matching classes back to their original definitions in
+    /// [Retransformer::unload]
+    pub fn with_restore_on_unload(mut self, restore: bool) -> Self {
+        self.restore_on_unload = restore;
+        self
+    }
+
+    /// Creates a new JVMTI environment and installs the transformer. Only classes loaded from now
+    /// on are transformed until [Retransformer::retransform_loaded] is called
+    pub fn install<'a>(
+        self,
+        jvm: &JavaVM,
+        transformer: impl Fn(&ClassFileLoad) -> Option<Vec<u8>> + Send + Sync + 'static,
+    ) -> JvmtiResult<Retransformer<'a>> {
+        let jvmti = JvmtiEnv::from_jvm(jvm)?;
+        let state = TransformerState {
+            filter: self.filter,
+            transformer: Box::new(transformer),
+            active: AtomicBool::new(true),
+        };
+
+        let retransformer = Retransformer {
+            state: CallbackState::new(jvmti, state)?,
+            batch_size: self.batch_size,
+            restore_on_unload: self.restore_on_unload,
+        };
+
+        // must be possessed before the hook is enabled to receive retransformations
+        let jvmti = retransformer.state.jvmti();
+        jvmti.require_capabilities(&[crate::Capability::RetransformClasses])?;
+
+        let callbacks = EventCallbacksBuilder::default()
+            .with_class_file_load_hook(Some(class_file_load_hook))
+            .build();
+        jvmti.install_event_callbacks(&callbacks)?;
+        jvmti.enable_event(EventType::ClassFileLoadHook, EventScope::Global)?;
+
+        debug!("installed retransformer");
+        Ok(retransformer)
+    }
+}
+
+impl<'a> Retransformer<'a> {
+    pub fn builder() -> RetransformerBuilder {
+        RetransformerBuilder::default()
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.state.active.load(Ordering::Acquire)
+    }
+
+    /// Runs the transformer over every loaded class that matches the filter. A class whose
+    /// signature cannot be read is reported as a failure named `<unknown>`
+    pub fn retransform_loaded(&self, jni: JNIEnv) -> JvmtiResult<RetransformReport> {
+        let jvmti = self.state.jvmti();
+        let mut report = RetransformReport::default();
+        let loaded_classes = jvmti.get_loaded_classes(jni)?;
+
+        let mut matching = Vec::new();
+        for &class in loaded_classes.iter() {
+            let signature = match jvmti.get_class_signature(class) {
+                Ok(signature) => signature,
+                Err(error) => {
+                    report.failed(String::from("<unknown>"), error);
+                    continue;
+                }
+            };
+            let name = match class_name_from_signature(&mutf8_to_string(signature.as_bytes())) {
+                Some(name) => name,
+                None => continue, // array or primitive
+            };
+
+            if !(self.state.filter)(&name) {
+                continue;
+            }
+
+            match jvmti.is_modifiable_class(class) {
+                Ok(true) => matching.push((class, name)),
+                Ok(false) => report.unmodifiable.push(name),
+                Err(error) => report.failed(name, error),
+            }
+        }
+
+        debug!(
+            "retransforming {} matching classes in batches of {}",
+            matching.len(),
+            self.batch_size
+        );
+        for batch in matching.chunks(self.batch_size) {
+            let classes: Vec<Class> = batch.iter().map(|(class, _)| *class).collect();
+            if jvmti.retransform_classes(&classes).is_ok() {
+                report
+                    .retransformed
+                    .extend(batch.iter().map(|(_, name)| name.clone()));
+                continue;
+            }
+
+            // retry individually to find the culprits
+            for (class, name) in batch {
+                match jvmti.retransform_classes(&[*class]) {
+                    Ok(_) => report.retransformed.push(name.clone()),
+                    Err(error) => report.failed(name.clone(), error),
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Stops transforming and restores all matching classes to their original definitions
+    pub fn disable(&self, jni: JNIEnv) -> JvmtiResult<RetransformReport> {
+        self.state.active.store(false, Ordering::Release);
+        debug!("disabled retransformer");
+        self.retransform_loaded(jni)
+    }
+
+    /// Resumes transforming, including all matching classes that are already loaded
+    pub fn enable(&self, jni: JNIEnv) -> JvmtiResult<RetransformReport> {
+        self.state.active.store(true, Ordering::Release);
+        debug!("enabled retransformer");
+        self.retransform_loaded(jni)
+    }
+
+    /// Restores original class definitions if configured to, and disposes the environment
+    pub fn unload(self, jni: JNIEnv) -> JvmtiResult<Option<RetransformReport>> {
+        if self.restore_on_unload {
+            self.disable(jni).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl Drop for Retransformer<'_> {
+    fn drop(&mut self) {
+        if let Err(err) = self
+            .state
+            .jvmti()
+            .disable_event(EventType::ClassFileLoadHook, EventScope::Global)
+        {
+            error!("failed to disable retransformer hook: {}", err);
+        }
+    }
+}
+
+impl RetransformReport {
+    fn failed(&mut self, class_name: String, error: Error) {
+        warn!("failed to retransform {}: {}", class_name, error);
+        self.failures.push(RetransformFailure { class_name, error });
+    }
+}
+
+/// `Ljava/lang/String;` to `java/lang/String`, None for arrays and primitives
+pub fn class_name_from_signature(signature: &str) -> Option<String> {
+    signature
+        .strip_prefix('L')
+        .and_then(|s| s.strip_suffix(';'))
+        .map(str::to_owned)
+}
+
+unsafe extern "C" fn class_file_load_hook(
+    jvmti_env: JvmtiEnv,
+    _jni_env: JNIEnv,
+    class_being_redefined: Class,
+    loader: JObject,
+    name: *const c_char,
+    _protection_domain: JObject,
+    class_data_len: jint,
+    class_data: *const c_uchar,
+    new_class_data_len: *mut jint,
+    new_class_data: *mut *mut c_uchar,
+) {
+    CallbackState::<TransformerState>::with(&jvmti_env, |state| {
+        if !state.active.load(Ordering::Acquire) || name.is_null() {
+            return;
+        }
+
+        let name = mutf8_to_string(CStr::from_ptr(name).to_bytes());
+        let class = ClassFileLoad {
+            name: &name,
+            class_being_redefined: if class_being_redefined.is_null() {
+                None
+            } else {
+                Some(class_being_redefined)
+            },
+            loader,
+            class_data: std::slice::from_raw_parts(class_data, class_data_len as usize),
+        };
+
+        // unwinding into the jvm would abort the whole process
+        let transform = || {
+            if (state.filter)(&name) {
+                (state.transformer)(&class)
+            } else {
+                None
+            }
+        };
+        let new_bytes = match catch_unwind(AssertUnwindSafe(transform)) {
+            Ok(Some(bytes)) => bytes,
+            Ok(None) => return,
+            Err(_) => {
+                error!("transformer or filter panicked on class {}", name);
+                return;
+            }
+        };
+        let new_len = match jint::try_from(new_bytes.len()) {
+            Ok(len) => len,
+            Err(_) => {
+                error!("transformed class {} is too large", name);
+                return;
+            }
+        };
+
+        // must be a jvmti allocation, which the vm takes ownership of
+        let alloc = match jvmti_env.allocate(new_bytes.len()) {
+            Ok(ptr) => ptr,
+            Err(err) => {
+                error!("failed to allocate transformed class {}: {}", name, err);
+                return;
+            }
+        };
+
+        std::ptr::copy_nonoverlapping(new_bytes.as_ptr(), alloc, new_bytes.len());
+        *new_class_data = alloc;
+        *new_class_data_len = new_len;
+        trace!("transformed class {} ({} bytes)", name, new_bytes.len());
+    });
+}
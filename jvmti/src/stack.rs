@@ -0,0 +1,159 @@
+use crate::handles::{Location, Method, Thread};
+use crate::thread::ThreadState;
+use crate::util::*;
+use crate::JvmtiEnv;
+use jni::sys::{jint, jlong, jmethodID};
+use jni::JNIEnv;
+use jni_jvmti_sys::jvmtiLineNumberEntry;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+/// A frame of a thread's stack with its method resolved to names
+#[derive(Debug, Clone)]
+pub struct StackFrame {
+    /// e.g. `java.lang.Object`
+    pub class_name: String,
+    pub method_name: String,
+    /// e.g. `(J)V`
+    pub method_signature: String,
+    /// None if the class has no `SourceFile` attribute
+    pub source_file: Option<String>,
+    /// None for native methods or if the class has no line number table
+    pub line_number: Option<jint>,
+    /// Bytecode index, -1 for native methods
+    pub location: jlong,
+}
+
+/// A thread's stack as sampled by `GetAllStackTraces` or `GetThreadListStackTraces`
+pub struct StackInfo<'a> {
+    pub thread: Thread<'a>,
+    /// At the time of sampling
+    pub state: ThreadState,
+    /// From the top of the stack
+    pub frames: Vec<Location<'a>>,
+}
+
+/// Resolves up to `max_frames` frames of the given thread from the top of its stack
+pub fn get_stack_trace(
+    jvmti: &JvmtiEnv,
+    jni: JNIEnv,
+    thread: Thread,
+    max_frames: usize,
+) -> JvmtiResult<Vec<StackFrame>> {
+    jvmti
+        .get_stack_trace(thread, 0, max_frames)?
+        .iter()
+        .map(|&frame| StackFrame::resolve(jvmti, jni, frame))
+        .collect()
+}
+
+impl StackFrame {
+    pub fn resolve(jvmti: &JvmtiEnv, jni: JNIEnv, frame: Location) -> JvmtiResult<Self> {
+        let (method_name, method_signature) = {
+            let (name, signature) = jvmti.get_method_name(frame.method)?;
+            (
+                mutf8_to_string(name.as_bytes()),
+                mutf8_to_string(signature.as_bytes()),
+            )
+        };
+
+        let class = jvmti.get_method_declaring_class(jni, frame.method)?;
+        let class_info = (|| -> JvmtiResult<_> {
+            let signature = jvmti.get_class_signature(class)?;
+            let source_file = jvmti
+                .get_source_file_name(class)?
+                .map(|name| mutf8_to_string(name.as_bytes()));
+            Ok((
+                java_class_name(&mutf8_to_string(signature.as_bytes())),
+                source_file,
+            ))
+        })();
+        jni.delete_local_ref(*class)?;
+        let (class_name, source_file) = class_info?;
+
+        let line_number = if frame.is_native() {
+            None
+        } else {
+            absent_as_none(jvmti.get_line_number_table(frame.method))?
+                .and_then(|table| line_number(&table, frame.bci))
+        };
+
+        Ok(StackFrame {
+            class_name,
+            method_name,
+            method_signature,
+            source_file,
+            line_number,
+            location: frame.bci,
+        })
+    }
+
+    pub fn is_native(&self) -> bool {
+        self.location == -1
+    }
+}
+
+/// A frame recorded for later resolution, as the `jmethodID` address with the bytecode index
+pub(crate) type RawFrame = (usize, jlong);
+
+pub(crate) fn raw_frame(location: Location) -> RawFrame {
+    (location.method.into_inner() as usize, location.bci)
+}
+
+/// Resolves raw frames, each only once
+pub(crate) struct FrameResolver<'a, 'b> {
+    jvmti: &'a JvmtiEnv<'b>,
+    jni: JNIEnv<'a>,
+    resolved: HashMap<RawFrame, Option<StackFrame>>,
+}
+
+impl<'a, 'b> FrameResolver<'a, 'b> {
+    pub(crate) fn new(jvmti: &'a JvmtiEnv<'b>, jni: JNIEnv<'a>) -> Self {
+        Self {
+            jvmti,
+            jni,
+            resolved: HashMap::new(),
+        }
+    }
+
+    /// None if the method has been unloaded
+    pub(crate) fn resolve(&mut self, (method, bci): RawFrame) -> Option<StackFrame> {
+        let (jvmti, jni) = (self.jvmti, self.jni);
+        self.resolved
+            .entry((method, bci))
+            .or_insert_with(|| {
+                let location = Location::new(Method::from(method as jmethodID), bci);
+                match StackFrame::resolve(jvmti, jni, location) {
+                    Ok(frame) => Some(frame),
+                    Err(err) => {
+                        debug!("failed to resolve frame: {}", err);
+                        None
+                    }
+                }
+            })
+            .clone()
+    }
+}
+
+/// The line of the entry covering the bytecode index
+pub(crate) fn line_number(table: &[jvmtiLineNumberEntry], bci: jlong) -> Option<jint> {
+    table
+        .iter()
+        .filter(|entry| entry.start_location <= bci)
+        .max_by_key(|entry| entry.start_location)
+        .map(|entry| entry.line_number)
+}
+
+/// As in a Java stack trace, e.g. `java.lang.Thread.sleep(Thread.java:337)`
+impl Display for StackFrame {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}(", self.class_name, self.method_name)?;
+        match (&self.source_file, self.line_number) {
+            _ if self.is_native() => write!(f, "Native Method")?,
+            (Some(file), Some(line)) => write!(f, "{}:{}", file, line)?,
+            (Some(file), None) => write!(f, "{}", file)?,
+            (None, _) => write!(f, "Unknown Source")?,
+        }
+        write!(f, ")")
+    }
+}
@@ -0,0 +1,125 @@
+use crate::heap::NonZeroJlong;
+use crate::util::*;
+use crate::JvmtiEnv;
+use jni::objects::JObject;
+use jni::sys::jlong;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const VALUE_BITS: u32 = 47;
+const VALUE_MASK: u64 = (1 << VALUE_BITS) - 1;
+/// Set for tags carrying a payload, clear for unique tags
+const PAYLOAD_FLAG: u64 = 1 << VALUE_BITS;
+const OWNER_SHIFT: u32 = VALUE_BITS + 1;
+/// The sign bit is left clear so tags are always positive
+const MAX_OWNER: u64 = (1 << (63 - OWNER_SHIFT)) - 1;
+
+static NEXT_OWNER: AtomicU64 = AtomicU64::new(1);
+
+/// Hands out object tags that no other allocator will, so subsystems tagging objects in the same
+/// environment can tell their own tags apart.
+///
+/// A tag holds the allocator's owner id in its top bits, and below that either a unique sequence
+/// number or a [TagPayload] of up to 47 bits.
+#[derive(Debug)]
+pub struct TagAllocator {
+    owner: u64,
+    next: AtomicU64,
+}
+
+/// A value small enough to be stored in a tag, such as an index into a side table
+pub trait TagPayload: Sized {
+    fn into_payload(self) -> u64;
+
+    /// None if the payload is not a valid value of this type
+    fn from_payload(payload: u64) -> Option<Self>;
+}
+
+impl TagAllocator {
+    /// Claims a new owner id. Panics if all 2^15 ids have been claimed
+    pub fn new() -> Self {
+        let owner = NEXT_OWNER.fetch_add(1, Ordering::Relaxed);
+        assert!(owner <= MAX_OWNER, "too many tag allocators");
+        debug!("created tag allocator with owner id {}", owner);
+        Self {
+            owner,
+            next: AtomicU64::new(0),
+        }
+    }
+
+    /// A tag never returned before by this allocator
+    pub fn allocate(&self) -> NonZeroJlong {
+        let sequence = self.next.fetch_add(1, Ordering::Relaxed);
+        assert!(sequence <= VALUE_MASK, "tag allocator exhausted");
+        self.make_tag(sequence)
+    }
+
+    /// None if the payload does not fit in 47 bits
+    pub fn encode(&self, payload: impl TagPayload) -> Option<NonZeroJlong> {
+        let payload = payload.into_payload();
+        if payload > VALUE_MASK {
+            return None;
+        }
+        Some(self.make_tag(PAYLOAD_FLAG | payload))
+    }
+
+    /// None if the tag was not made by [encode](Self::encode) on this allocator
+    pub fn decode<T: TagPayload>(&self, tag: NonZeroJlong) -> Option<T> {
+        let bits = tag.get() as u64;
+        if !self.owns(tag.get()) || bits & PAYLOAD_FLAG == 0 {
+            return None;
+        }
+        T::from_payload(bits & VALUE_MASK)
+    }
+
+    /// Whether the tag was handed out by this allocator
+    pub fn owns(&self, tag: jlong) -> bool {
+        tag > 0 && (tag as u64) >> OWNER_SHIFT == self.owner
+    }
+
+    /// Tags the object with a new unique tag, replacing any existing tag. Needs `can_tag_objects`
+    pub fn tag(&self, jvmti: &JvmtiEnv, object: JObject) -> JvmtiResult<NonZeroJlong> {
+        let tag = self.allocate();
+        jvmti.set_tag(object, Some(tag))?;
+        Ok(tag)
+    }
+
+    /// The object's payload, or None if it is untagged or tagged by someone else. Needs
+    /// `can_tag_objects`
+    pub fn payload_of<T: TagPayload>(
+        &self,
+        jvmti: &JvmtiEnv,
+        object: JObject,
+    ) -> JvmtiResult<Option<T>> {
+        Ok(jvmti.get_tag(object)?.and_then(|tag| self.decode(tag)))
+    }
+
+    fn make_tag(&self, value: u64) -> NonZeroJlong {
+        let tag = (self.owner << OWNER_SHIFT) | value;
+        NonZeroJlong::new(tag as jlong).expect("owner id is non zero")
+    }
+}
+
+impl Default for TagAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+macro_rules! unsigned_payload {
+    ($($ty:ty),*) => {
+        $(
+            impl TagPayload for $ty {
+                fn into_payload(self) -> u64 {
+                    self as u64
+                }
+
+                fn from_payload(payload: u64) -> Option<Self> {
+                    <$ty>::try_from(payload).ok()
+                }
+            }
+        )*
+    };
+}
+
+unsigned_payload!(u8, u16, u32, u64, usize);
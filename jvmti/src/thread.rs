@@ -0,0 +1,186 @@
+use crate::util::*;
+use crate::JvmtiEnv;
+use jni::objects::{GlobalRef, JObject};
+use jni::sys::jint;
+use jni::JNIEnv;
+use jni_jvmti_sys::*;
+use std::fmt::{Display, Formatter};
+
+bitflags::bitflags! {
+    /// Result of `GetThreadState`
+    pub struct ThreadState : jint {
+        const ALIVE = JVMTI_THREAD_STATE_ALIVE as _;
+        const TERMINATED = JVMTI_THREAD_STATE_TERMINATED as _;
+        const RUNNABLE = JVMTI_THREAD_STATE_RUNNABLE as _;
+        const BLOCKED_ON_MONITOR_ENTER = JVMTI_THREAD_STATE_BLOCKED_ON_MONITOR_ENTER as _;
+        const WAITING = JVMTI_THREAD_STATE_WAITING as _;
+        const WAITING_INDEFINITELY = JVMTI_THREAD_STATE_WAITING_INDEFINITELY as _;
+        const WAITING_WITH_TIMEOUT = JVMTI_THREAD_STATE_WAITING_WITH_TIMEOUT as _;
+        const SLEEPING = JVMTI_THREAD_STATE_SLEEPING as _;
+        const IN_OBJECT_WAIT = JVMTI_THREAD_STATE_IN_OBJECT_WAIT as _;
+        const PARKED = JVMTI_THREAD_STATE_PARKED as _;
+        const SUSPENDED = JVMTI_THREAD_STATE_SUSPENDED as _;
+        const INTERRUPTED = JVMTI_THREAD_STATE_INTERRUPTED as _;
+        const IN_NATIVE = JVMTI_THREAD_STATE_IN_NATIVE as _;
+    }
+}
+
+/// Owned result of `GetThreadInfo`. The object references are JNI local refs
+#[derive(Debug)]
+pub struct ThreadInfo<'a> {
+    pub name: String,
+    pub priority: jint,
+    pub is_daemon: bool,
+    pub thread_group: JObject<'a>,
+    pub context_class_loader: JObject<'a>,
+}
+
+/// Owned result of `GetThreadGroupInfo`. The parent is a JNI local ref, null for top level groups
+#[derive(Debug)]
+pub struct ThreadGroupInfo<'a> {
+    pub parent: JObject<'a>,
+    pub name: String,
+    pub max_priority: jint,
+    pub is_daemon: bool,
+}
+
+/// A thread group and everything under it, with no JVMTI allocations or local refs left behind
+pub struct ThreadGroup {
+    pub group: GlobalRef,
+    pub name: String,
+    pub max_priority: jint,
+    pub is_daemon: bool,
+    pub threads: Vec<ThreadGroupMember>,
+    pub groups: Vec<ThreadGroup>,
+}
+
+/// A live thread directly within a [ThreadGroup]
+pub struct ThreadGroupMember {
+    pub thread: GlobalRef,
+    pub name: String,
+    pub priority: jint,
+    pub is_daemon: bool,
+}
+
+/// Walks the whole thread group hierarchy from the top level groups down
+pub fn thread_group_tree(jvmti: &JvmtiEnv, jni: JNIEnv) -> JvmtiResult<Vec<ThreadGroup>> {
+    let top_groups = jvmti.get_top_thread_groups(jni)?;
+    top_groups
+        .iter()
+        .map(|group| ThreadGroup::collect(jvmti, jni, JObject::from(*group)))
+        .collect()
+}
+
+impl ThreadGroup {
+    fn collect(jvmti: &JvmtiEnv, jni: JNIEnv, group: JObject) -> JvmtiResult<Self> {
+        let info = jvmti.get_thread_group_info(jni, group)?;
+        if !info.parent.is_null() {
+            jni.delete_local_ref(info.parent)?;
+        }
+
+        let (child_threads, child_groups) = jvmti.get_thread_group_children(jni, group)?;
+
+        let mut threads = Vec::with_capacity(child_threads.len());
+        for thread in child_threads.iter() {
+            let thread_info = jvmti.get_thread_info(jni, *thread)?;
+            thread_info.delete_local_refs(jni)?;
+
+            threads.push(ThreadGroupMember {
+                thread: jni.new_global_ref(**thread)?,
+                name: thread_info.name,
+                priority: thread_info.priority,
+                is_daemon: thread_info.is_daemon,
+            });
+        }
+
+        let groups = child_groups
+            .iter()
+            .map(|child| ThreadGroup::collect(jvmti, jni, JObject::from(*child)))
+            .collect::<JvmtiResult<_>>()?;
+
+        Ok(ThreadGroup {
+            group: jni.new_global_ref(group)?,
+            name: info.name,
+            max_priority: info.max_priority,
+            is_daemon: info.is_daemon,
+            threads,
+            groups,
+        })
+    }
+
+    /// All threads in this group and its descendants, depth first
+    pub fn all_threads(&self) -> Vec<&ThreadGroupMember> {
+        let mut threads: Vec<_> = self.threads.iter().collect();
+        for group in &self.groups {
+            threads.extend(group.all_threads());
+        }
+        threads
+    }
+
+    fn fmt_indented(&self, f: &mut Formatter<'_>, depth: usize) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{:indent$}\"{}\" max_priority={}{}",
+            "",
+            self.name,
+            self.max_priority,
+            if self.is_daemon { " daemon" } else { "" },
+            indent = depth * 2
+        )?;
+
+        for thread in &self.threads {
+            writeln!(
+                f,
+                "{:indent$}\"{}\" prio={}{}",
+                "",
+                thread.name,
+                thread.priority,
+                if thread.is_daemon { " daemon" } else { "" },
+                indent = (depth + 1) * 2
+            )?;
+        }
+
+        for group in &self.groups {
+            group.fmt_indented(f, depth + 1)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl ThreadState {
+    /// The corresponding `java.lang.Thread.State` name, e.g. `TIMED_WAITING`
+    pub fn java_lang_state(self) -> &'static str {
+        if self.contains(Self::TERMINATED) {
+            "TERMINATED"
+        } else if !self.contains(Self::ALIVE) {
+            "NEW"
+        } else if self.contains(Self::BLOCKED_ON_MONITOR_ENTER) {
+            "BLOCKED"
+        } else if self.contains(Self::WAITING_INDEFINITELY) {
+            "WAITING"
+        } else if self.contains(Self::WAITING_WITH_TIMEOUT) {
+            "TIMED_WAITING"
+        } else {
+            "RUNNABLE"
+        }
+    }
+}
+
+impl ThreadInfo<'_> {
+    pub fn delete_local_refs(&self, jni: JNIEnv) -> JvmtiResult<()> {
+        for obj in [self.thread_group, self.context_class_loader].iter() {
+            if !obj.is_null() {
+                jni.delete_local_ref(*obj)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Indented tree of groups and their threads
+impl Display for ThreadGroup {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
@@ -0,0 +1,623 @@
+use crate::callback_state::CallbackState;
+use crate::capability::Capability;
+use crate::deadlock::{find_deadlocks, Deadlock};
+use crate::event::{EventCallbacksBuilder, EventScope, EventType};
+use crate::handles::Thread;
+use crate::monitor::MonitorObject;
+use crate::raw_monitor::RawMonitor;
+use crate::stack::StackFrame;
+use crate::thread::ThreadState;
+use crate::util::*;
+use crate::JvmtiEnv;
+use jni::objects::{JObject, JString, JValue};
+use jni::sys::{jint, jlong};
+use jni::{JNIEnv, JavaVM};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAX_FRAMES: usize = 1024;
+
+/// A snapshot of all live Java threads, printed in the format of `jstack` and `Thread.print`
+pub struct ThreadDump {
+    /// e.g. `OpenJDK 64-Bit Server VM (17.0.9+9 mixed mode, sharing)`
+    pub vm_description: String,
+    pub timestamp: SystemTime,
+    /// Of the VM's default time zone at the timestamp, which is printed in local time
+    pub utc_offset_secs: i64,
+    pub threads: Vec<DumpedThread>,
+    /// Always empty if `can_get_current_contended_monitor` is unavailable
+    pub deadlocks: Vec<Deadlock>,
+}
+
+pub struct DumpedThread {
+    pub name: String,
+    /// `Thread.getId()`
+    pub id: jlong,
+    pub is_daemon: bool,
+    pub priority: jint,
+    pub state: ThreadState,
+    /// Identity hash code of the `java.lang.Thread`
+    pub hash_code: jint,
+    /// None if `can_get_thread_cpu_time` is unavailable
+    pub cpu_time: Option<Duration>,
+    /// None if the VM's `Thread.print` is unavailable or did not list the thread
+    pub native: Option<NativeThreadInfo>,
+    pub frames: Vec<DumpedFrame>,
+}
+
+/// Header values JVMTI has no access to, taken from the VM's own `Thread.print` through the
+/// `DiagnosticCommand` MBean during the dump
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct NativeThreadInfo {
+    pub os_priority: jint,
+    /// Since the thread started
+    pub elapsed: Duration,
+    /// Address of the VM's thread
+    pub tid: u64,
+    /// The OS thread id
+    pub nid: u64,
+    /// Stack page of the last Java frame, or 0 if there is none
+    pub last_java_sp: u64,
+}
+
+/// A stack frame and the monitors to annotate it with
+pub struct DumpedFrame {
+    pub frame: StackFrame,
+    pub monitors: Vec<FrameMonitor>,
+}
+
+/// Which annotations are available depends on the capabilities the environment can possess
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum FrameMonitor {
+    /// Blocked entering the monitor, needs `can_get_current_contended_monitor`
+    WaitingToLock(MonitorObject),
+    /// In `Object.wait()`, needs `can_get_current_contended_monitor`
+    WaitingOn(MonitorObject),
+    /// Parked on the blocker object given to `LockSupport.park`
+    ParkingToWaitFor(MonitorObject),
+    /// Entered by this frame, needs `can_get_owned_monitor_stack_depth_info`
+    Locked(MonitorObject),
+}
+
+/// Prints a thread dump on each `DataDumpRequest` event, i.e. on `SIGQUIT` or `ctrl-break`, from
+/// a dedicated JVMTI environment. The VM still prints its own thread dump to stdout.
+pub struct ThreadDumpOnSignal<'a> {
+    state: CallbackState<'a, SignalState<'a>>,
+}
+
+pub type ThreadDumpSinkFn = dyn Fn(&ThreadDump) + Send + Sync;
+
+struct SignalState<'a> {
+    jvm: JavaVM,
+    /// Held for the whole dump, so dumps for concurrent requests are not interleaved
+    sink: RawMonitor<'a, Box<ThreadDumpSinkFn>>,
+}
+
+/// Collects a dump of all live threads
+pub fn thread_dump(jvmti: &JvmtiEnv, jni: JNIEnv) -> JvmtiResult<ThreadDump> {
+    let timestamp = SystemTime::now();
+    let utc_offset_secs = utc_offset_secs(jni, timestamp)?;
+    let vm_description = vm_description(jvmti)?;
+    let cpu_time = optional(jvmti.require_capabilities(&[Capability::GetThreadCpuTime]))?.is_some();
+    let native = match vm_thread_print(jni) {
+        Ok(text) => parse_thread_print(&text),
+        Err(err) => {
+            debug!("no native thread info from Thread.print: {}", err);
+            HashMap::new()
+        }
+    };
+
+    let all_threads = jvmti.get_all_threads(jni)?;
+    let threads = all_threads
+        .iter()
+        .map(|&thread| DumpedThread::collect(jvmti, jni, thread, cpu_time, &native))
+        .filter_map(|result| match result {
+            Err(Error::Jvmti(JvmtiError::ThreadNotAlive)) => None,
+            result => Some(result),
+        })
+        .collect::<JvmtiResult<Vec<_>>>()?;
+
+    let deadlocks = optional(find_deadlocks(jvmti, jni))?.unwrap_or_default();
+
+    Ok(ThreadDump {
+        vm_description,
+        timestamp,
+        utc_offset_secs,
+        threads,
+        deadlocks,
+    })
+}
+
+/// `TimeZone.getDefault().getOffset(timestamp)`, as `jstack` prints the VM's local time
+fn utc_offset_secs(jni: JNIEnv, timestamp: SystemTime) -> JvmtiResult<i64> {
+    let millis = timestamp
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as jlong)
+        .unwrap_or(0);
+    let zone = jni
+        .call_static_method(
+            "java/util/TimeZone",
+            "getDefault",
+            "()Ljava/util/TimeZone;",
+            &[],
+        )?
+        .l()?;
+    let offset = jni.call_method(zone, "getOffset", "(J)I", &[JValue::Long(millis)]);
+    jni.delete_local_ref(zone)?;
+    Ok(i64::from(offset?.i()?) / 1000)
+}
+
+/// The text of the `Thread.print` diagnostic command, i.e. what `jstack` prints
+fn vm_thread_print(jni: JNIEnv) -> JvmtiResult<String> {
+    jni.push_local_frame(16)?;
+    let text = invoke_thread_print(jni);
+    if text.is_err() && jni.exception_check()? {
+        jni.exception_clear()?;
+    }
+    jni.pop_local_frame(JObject::null())?;
+    text
+}
+
+fn invoke_thread_print(jni: JNIEnv) -> JvmtiResult<String> {
+    let server = jni
+        .call_static_method(
+            "java/lang/management/ManagementFactory",
+            "getPlatformMBeanServer",
+            "()Ljavax/management/MBeanServer;",
+            &[],
+        )?
+        .l()?;
+    let name = jni.new_string("com.sun.management:type=DiagnosticCommand")?;
+    let name = jni.new_object(
+        "javax/management/ObjectName",
+        "(Ljava/lang/String;)V",
+        &[JValue::Object(name.into())],
+    )?;
+
+    // threadPrint(String[] args) with no args
+    let args = jni.new_object_array(0, "java/lang/String", JObject::null())?;
+    let params = jni.new_object_array(1, "java/lang/Object", args)?;
+    let signature = jni.new_string("[Ljava.lang.String;")?;
+    let signature = jni.new_object_array(1, "java/lang/String", signature)?;
+    let operation = jni.new_string("threadPrint")?;
+    let text = jni
+        .call_method(
+            server,
+            "invoke",
+            "(Ljavax/management/ObjectName;Ljava/lang/String;[Ljava/lang/Object;[Ljava/lang/String;)Ljava/lang/Object;",
+            &[
+                JValue::Object(name),
+                JValue::Object(operation.into()),
+                JValue::Object(params.into()),
+                JValue::Object(signature.into()),
+            ],
+        )?
+        .l()?;
+    Ok(jni.get_string(JString::from(text))?.into())
+}
+
+/// The native info of each Java thread in `Thread.print` text, by `Thread.getId()`. VM internal
+/// threads have no id and are skipped
+fn parse_thread_print(text: &str) -> HashMap<jlong, NativeThreadInfo> {
+    text.lines()
+        .filter(|line| line.starts_with('"'))
+        .filter_map(parse_thread_header)
+        .collect()
+}
+
+/// e.g. `"main" #1 prio=5 os_prio=0 cpu=9.87ms elapsed=0.27s tid=0x00007f0e5c028000 nid=0x4d2
+/// runnable  [0x00007f0e63d3c000]`
+fn parse_thread_header(line: &str) -> Option<(jlong, NativeThreadInfo)> {
+    // the name may contain anything but a line break, while the rest has no quotes
+    let rest = &line[line.rfind("\" #")? + 3..];
+    let mut fields = rest.split_whitespace();
+    let id = fields.next()?.parse().ok()?;
+
+    let hex = |value: &str| u64::from_str_radix(value.strip_prefix("0x")?, 16).ok();
+    let (mut os_priority, mut elapsed, mut tid, mut nid, mut last_java_sp) =
+        (None, None, None, None, None);
+    for field in fields {
+        match field.split_once('=') {
+            Some(("os_prio", value)) => os_priority = value.parse().ok(),
+            Some(("elapsed", value)) => {
+                elapsed = value
+                    .strip_suffix('s')
+                    .and_then(|secs| secs.parse().ok())
+                    .map(Duration::from_secs_f64)
+            }
+            Some(("tid", value)) => tid = hex(value),
+            Some(("nid", value)) => nid = hex(value),
+            Some(_) => {}
+            None => {
+                if let Some(address) = field.strip_prefix('[').and_then(|f| f.strip_suffix(']')) {
+                    last_java_sp = hex(address);
+                }
+            }
+        }
+    }
+
+    Some((
+        id,
+        NativeThreadInfo {
+            os_priority: os_priority?,
+            elapsed: elapsed?,
+            tid: tid?,
+            nid: nid?,
+            last_java_sp: last_java_sp?,
+        },
+    ))
+}
+
+fn vm_description(jvmti: &JvmtiEnv) -> JvmtiResult<String> {
+    let property =
+        |name| -> JvmtiResult<String> { Ok(jvmti.get_system_property(name)?.unwrap_or_default()) };
+
+    Ok(format!(
+        "{} ({} {})",
+        property("java.vm.name")?,
+        property("java.vm.version")?,
+        property("java.vm.info")?
+    ))
+}
+
+/// Maps an unavailable capability to None, for annotations that are best effort
+fn optional<T>(result: JvmtiResult<T>) -> JvmtiResult<Option<T>> {
+    match result {
+        Ok(val) => Ok(Some(val)),
+        Err(Error::UnavailableCapability(_)) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+impl DumpedThread {
+    fn collect(
+        jvmti: &JvmtiEnv,
+        jni: JNIEnv,
+        thread: Thread,
+        cpu_time: bool,
+        native: &HashMap<jlong, NativeThreadInfo>,
+    ) -> JvmtiResult<Self> {
+        let info = jvmti.get_thread_info(jni, thread)?;
+        info.delete_local_refs(jni)?;
+
+        let state = jvmti.get_thread_state(thread)?;
+        let id = jni.call_method(*thread, "getId", "()J", &[])?.j()?;
+
+        let frames = jvmti.get_stack_trace(thread, 0, MAX_FRAMES)?;
+        let mut dumped_frames = frames
+            .iter()
+            .map(|&frame| {
+                Ok(DumpedFrame {
+                    frame: StackFrame::resolve(jvmti, jni, frame)?,
+                    monitors: Vec::new(),
+                })
+            })
+            .collect::<JvmtiResult<Vec<_>>>()?;
+
+        if let Some(top) = dumped_frames.first_mut() {
+            if let Some(monitor) = Self::blocked_on(jvmti, jni, thread, state)? {
+                top.monitors.push(monitor);
+            }
+        }
+
+        if let Some(owned) = optional(jvmti.get_owned_monitor_stack_depth_info(jni, thread))? {
+            for info in owned.iter() {
+                // entered through jni if negative
+                let frame = usize::try_from(info.stack_depth)
+                    .ok()
+                    .and_then(|depth| dumped_frames.get_mut(depth));
+                if let Some(frame) = frame {
+                    let monitor = MonitorObject::describe(jvmti, jni, JObject::from(info.monitor))?;
+                    frame.monitors.push(FrameMonitor::Locked(monitor));
+                }
+            }
+        }
+
+        Ok(DumpedThread {
+            name: info.name,
+            id,
+            is_daemon: info.is_daemon,
+            priority: info.priority,
+            state,
+            hash_code: jvmti.get_object_hash_code(*thread)?,
+            cpu_time: if cpu_time {
+                Some(jvmti.get_thread_cpu_time(thread)?)
+            } else {
+                None
+            },
+            native: native.get(&id).copied(),
+            frames: dumped_frames,
+        })
+    }
+
+    /// What the top frame is blocked or waiting on, if anything
+    fn blocked_on(
+        jvmti: &JvmtiEnv,
+        jni: JNIEnv,
+        thread: Thread,
+        state: ThreadState,
+    ) -> JvmtiResult<Option<FrameMonitor>> {
+        if state.contains(ThreadState::PARKED) {
+            let blocker = jni
+                .call_static_method(
+                    "java/util/concurrent/locks/LockSupport",
+                    "getBlocker",
+                    "(Ljava/lang/Thread;)Ljava/lang/Object;",
+                    &[JValue::Object(*thread)],
+                )?
+                .l()?;
+            if blocker.is_null() {
+                return Ok(None);
+            }
+
+            let monitor = MonitorObject::describe(jvmti, jni, blocker);
+            jni.delete_local_ref(blocker)?;
+            return Ok(Some(FrameMonitor::ParkingToWaitFor(monitor?)));
+        }
+
+        let blocked = state.contains(ThreadState::BLOCKED_ON_MONITOR_ENTER);
+        if !blocked && !state.contains(ThreadState::IN_OBJECT_WAIT) {
+            return Ok(None);
+        }
+
+        let monitor = match optional(jvmti.get_current_contended_monitor(jni, thread))? {
+            Some(Some(monitor)) => monitor,
+            _ => return Ok(None),
+        };
+
+        let described = MonitorObject::describe(jvmti, jni, monitor);
+        jni.delete_local_ref(monitor)?;
+        Ok(Some(if blocked {
+            FrameMonitor::WaitingToLock(described?)
+        } else {
+            FrameMonitor::WaitingOn(described?)
+        }))
+    }
+
+    /// As printed by HotSpot after the thread's id
+    fn status(&self) -> &'static str {
+        let state = self.state;
+        if state.contains(ThreadState::BLOCKED_ON_MONITOR_ENTER) {
+            "waiting for monitor entry"
+        } else if state.contains(ThreadState::IN_OBJECT_WAIT) {
+            "in Object.wait()"
+        } else if state.intersects(ThreadState::SLEEPING | ThreadState::PARKED) {
+            "waiting on condition"
+        } else {
+            "runnable"
+        }
+    }
+
+    /// The parenthesised detail after `java.lang.Thread.State`
+    fn state_detail(&self) -> Option<&'static str> {
+        let state = self.state;
+        if state.intersects(ThreadState::BLOCKED_ON_MONITOR_ENTER | ThreadState::IN_OBJECT_WAIT) {
+            Some("on object monitor")
+        } else if state.contains(ThreadState::SLEEPING) {
+            Some("sleeping")
+        } else if state.contains(ThreadState::PARKED) {
+            Some("parking")
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a> ThreadDumpOnSignal<'a> {
+    /// Creates a new JVMTI environment and passes a thread dump to `sink` on each request
+    pub fn install(
+        jvm: &JavaVM,
+        sink: impl Fn(&ThreadDump) + Send + Sync + 'static,
+    ) -> JvmtiResult<Self> {
+        let jvmti = JvmtiEnv::from_jvm(jvm)?;
+        let sink: Box<ThreadDumpSinkFn> = Box::new(sink);
+        let state = SignalState {
+            jvm: unsafe { JavaVM::from_raw(jvm.get_java_vm_pointer()) }?,
+            sink: RawMonitor::new(&jvmti, "thread dump sink", sink)?,
+        };
+
+        let installed = ThreadDumpOnSignal {
+            state: CallbackState::new(jvmti, state)?,
+        };
+        let jvmti = installed.state.jvmti();
+
+        let callbacks = EventCallbacksBuilder::default()
+            .with_data_dump_request(Some(data_dump_request))
+            .build();
+        jvmti.install_event_callbacks(&callbacks)?;
+        jvmti.enable_event(EventType::DataDumpRequest, EventScope::Global)?;
+
+        debug!("installed thread dump on data dump request");
+        Ok(installed)
+    }
+}
+
+impl Drop for ThreadDumpOnSignal<'_> {
+    fn drop(&mut self) {
+        if let Err(err) = self
+            .state
+            .jvmti()
+            .disable_event(EventType::DataDumpRequest, EventScope::Global)
+        {
+            error!("failed to disable data dump request: {}", err);
+        }
+    }
+}
+
+unsafe extern "C" fn data_dump_request(jvmti_env: JvmtiEnv) {
+    CallbackState::<SignalState>::with(&jvmti_env, |state| {
+        // posted on a java thread, which is already attached
+        let jni = match state.jvm.get_env() {
+            Ok(jni) => jni,
+            Err(err) => {
+                error!("no JNIEnv for data dump request: {}", err);
+                return;
+            }
+        };
+
+        let sink = match state.sink.lock() {
+            Ok(sink) => sink,
+            Err(err) => {
+                error!("failed to lock thread dump sink: {}", err);
+                return;
+            }
+        };
+
+        if let Err(err) = jni.push_local_frame(256) {
+            error!("failed to push local frame: {}", err);
+            return;
+        }
+
+        match thread_dump(&jvmti_env, jni) {
+            Ok(dump) => {
+                // unwinding into the jvm would abort the whole process
+                if catch_unwind(AssertUnwindSafe(|| sink(&dump))).is_err() {
+                    error!("thread dump sink panicked");
+                }
+            }
+            Err(err) => error!("failed to dump threads: {}", err),
+        }
+
+        let _ = jni.pop_local_frame(JObject::null());
+    });
+}
+
+impl Display for ThreadDump {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{}",
+            LocalTimestamp(self.timestamp, self.utc_offset_secs)
+        )?;
+        writeln!(f, "Full thread dump {}:", self.vm_description)?;
+        writeln!(f)?;
+
+        for thread in &self.threads {
+            writeln!(f, "{}", thread)?;
+        }
+
+        for deadlock in &self.deadlocks {
+            writeln!(f)?;
+            write!(f, "{}", deadlock)?;
+        }
+
+        match self.deadlocks.len() {
+            0 => Ok(()),
+            1 => writeln!(f, "\nFound 1 deadlock."),
+            n => writeln!(f, "\nFound {} deadlocks.", n),
+        }
+    }
+}
+
+/// The thread's section of a dump, ending in a blank line, with the header as printed by JDK 17.
+/// Without [NativeThreadInfo], `os_prio`, `nid` and the address in brackets are 0, `elapsed=` is
+/// left out and `tid` is the identity hash code. `cpu=` needs `can_get_thread_cpu_time`
+impl Display for DumpedThread {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let native = self.native.unwrap_or(NativeThreadInfo {
+            os_priority: 0,
+            elapsed: Duration::ZERO,
+            tid: self.hash_code as u32 as u64,
+            nid: 0,
+            last_java_sp: 0,
+        });
+        write!(
+            f,
+            "\"{}\" #{} {}prio={} os_prio={} ",
+            self.name,
+            self.id,
+            if self.is_daemon { "daemon " } else { "" },
+            self.priority,
+            native.os_priority
+        )?;
+        if let Some(cpu_time) = self.cpu_time {
+            write!(f, "cpu={:.2}ms ", cpu_time.as_secs_f64() * 1000.0)?;
+        }
+        if self.native.is_some() {
+            write!(f, "elapsed={:.2}s ", native.elapsed.as_secs_f64())?;
+        }
+        writeln!(
+            f,
+            "tid=0x{:016x} nid=0x{:x} {}  [0x{:016x}]",
+            native.tid,
+            native.nid,
+            self.status(),
+            native.last_java_sp
+        )?;
+
+        match self.state_detail() {
+            Some(detail) => writeln!(
+                f,
+                "   java.lang.Thread.State: {} ({})",
+                self.state.java_lang_state(),
+                detail
+            )?,
+            None => writeln!(
+                f,
+                "   java.lang.Thread.State: {}",
+                self.state.java_lang_state()
+            )?,
+        }
+
+        for frame in &self.frames {
+            writeln!(f, "\tat {}", frame.frame)?;
+            for monitor in &frame.monitors {
+                writeln!(f, "\t- {}", monitor)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for FrameMonitor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameMonitor::WaitingToLock(monitor) => write!(f, "waiting to lock {}", monitor),
+            FrameMonitor::WaitingOn(monitor) => write!(f, "waiting on {}", monitor),
+            FrameMonitor::ParkingToWaitFor(monitor) => {
+                write!(f, "parking to wait for  {}", monitor)
+            }
+            FrameMonitor::Locked(monitor) => write!(f, "locked {}", monitor),
+        }
+    }
+}
+
+/// `yyyy-MM-dd HH:mm:ss` in local time, given the offset from UTC in seconds
+struct LocalTimestamp(SystemTime, i64);
+
+impl Display for LocalTimestamp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let secs = self
+            .0
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0)
+            + self.1;
+        let (days, time) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+        // civil from days, from http://howardhinnant.github.io/date_algorithms.html
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            year,
+            month,
+            day,
+            time / 3600,
+            time % 3600 / 60,
+            time % 60
+        )
+    }
+}
@@ -0,0 +1,295 @@
+use crate::agent_thread::{self, AgentThread, AgentThreadBuilder, Stoppable};
+use crate::capability::Capability;
+use crate::handles::Thread;
+use crate::raw_monitor::RawMonitor;
+use crate::stack::{get_stack_trace, StackFrame};
+use crate::thread::ThreadState;
+use crate::util::*;
+use crate::JvmtiEnv;
+use jni::objects::JObject;
+use jni::sys::{jint, jlong};
+use jni::JNIEnv;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+const MAX_FRAMES: usize = 32;
+
+/// A thread's share of the CPU over a window
+pub struct ThreadCpuUsage {
+    pub name: String,
+    /// `Thread.getId()`
+    pub id: jlong,
+    pub state: ThreadState,
+    /// Used during the window
+    pub cpu_time: Duration,
+    /// Used since the thread started
+    pub total_cpu_time: Duration,
+    /// Of a single processor as in `top`, so up to 100 times the number of processors
+    pub cpu_percent: f64,
+    /// Up to 32 frames from the top at the end of the window
+    pub stack: Vec<StackFrame>,
+}
+
+/// The hottest Java threads over a window
+pub struct ThreadTop {
+    pub window: Duration,
+    pub processors: jint,
+    /// Of all live threads, including those not listed
+    pub total_cpu_percent: f64,
+    /// By descending CPU usage
+    pub threads: Vec<ThreadCpuUsage>,
+}
+
+/// Runs [thread_top] periodically on an agent thread, measuring back to back windows
+pub struct ThreadTopMonitor<'a> {
+    thread: AgentThread,
+    state: Box<RawMonitor<'a, Stoppable<()>>>,
+}
+
+/// CPU time of each live thread by `Thread.getId()`, at a time of the `GetTime` timer
+struct CpuSnapshot {
+    time: Duration,
+    threads: HashMap<jlong, Duration>,
+}
+
+/// Measures the CPU time used by each live thread over `window`, blocking the current thread
+/// meanwhile, and reports up to `limit` of the hottest. Requests `can_get_thread_cpu_time` if
+/// not already possessed
+pub fn thread_top(
+    jvmti: &JvmtiEnv,
+    jni: JNIEnv,
+    window: Duration,
+    limit: usize,
+) -> JvmtiResult<ThreadTop> {
+    jvmti.require_capabilities(&[Capability::GetThreadCpuTime])?;
+
+    let start = CpuSnapshot::take(jvmti, jni)?;
+    std::thread::sleep(window);
+    let (top, _) = ThreadTop::since(jvmti, jni, &start, limit)?;
+    Ok(top)
+}
+
+impl CpuSnapshot {
+    fn take(jvmti: &JvmtiEnv, jni: JNIEnv) -> JvmtiResult<Self> {
+        let time = jvmti.get_time()?;
+        let all_threads = jvmti.get_all_threads(jni)?;
+        let mut threads = HashMap::with_capacity(all_threads.len());
+        for &thread in all_threads.iter() {
+            if let Some((id, cpu_time)) = thread_cpu_time(jvmti, jni, thread)? {
+                threads.insert(id, cpu_time);
+            }
+        }
+        Ok(CpuSnapshot { time, threads })
+    }
+}
+
+/// None if the thread has terminated
+fn thread_cpu_time(
+    jvmti: &JvmtiEnv,
+    jni: JNIEnv,
+    thread: Thread,
+) -> JvmtiResult<Option<(jlong, Duration)>> {
+    let cpu_time = match jvmti.get_thread_cpu_time(thread) {
+        Ok(cpu_time) => cpu_time,
+        Err(Error::Jvmti(JvmtiError::ThreadNotAlive)) => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let id = jni.call_method(*thread, "getId", "()J", &[])?.j()?;
+    Ok(Some((id, cpu_time)))
+}
+
+impl ThreadTop {
+    /// The usage since `start`, and a snapshot to measure the next window from
+    fn since(
+        jvmti: &JvmtiEnv,
+        jni: JNIEnv,
+        start: &CpuSnapshot,
+        limit: usize,
+    ) -> JvmtiResult<(Self, CpuSnapshot)> {
+        let end = CpuSnapshot::take(jvmti, jni)?;
+        let window = end.time.saturating_sub(start.time);
+        let percent = |cpu_time: Duration| match window.as_secs_f64() {
+            secs if secs > 0.0 => cpu_time.as_secs_f64() / secs * 100.0,
+            _ => 0.0,
+        };
+
+        // threads started during the window used all of their time in it
+        let used = |id: jlong, total: Duration| {
+            total.saturating_sub(start.threads.get(&id).copied().unwrap_or_default())
+        };
+        let total_cpu_time: Duration = end.threads.iter().map(|(&id, &t)| used(id, t)).sum();
+
+        let mut hottest: Vec<(jlong, Duration)> = end
+            .threads
+            .iter()
+            .map(|(&id, &total)| (id, used(id, total)))
+            .collect();
+        hottest.sort_by_key(|&(id, cpu_time)| (Reverse(cpu_time), id));
+        hottest.truncate(limit);
+
+        let all_threads = jvmti.get_all_threads(jni)?;
+        let mut threads = Vec::with_capacity(hottest.len());
+        for &thread in all_threads.iter() {
+            let id = jni.call_method(*thread, "getId", "()J", &[])?.j()?;
+            if let Some(&(_, cpu_time)) = hottest.iter().find(|(hot, _)| *hot == id) {
+                match ThreadCpuUsage::collect(jvmti, jni, thread, id, cpu_time, end.threads[&id]) {
+                    Ok(mut usage) => {
+                        usage.cpu_percent = percent(cpu_time);
+                        threads.push(usage);
+                    }
+                    Err(Error::Jvmti(JvmtiError::ThreadNotAlive)) => {}
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        threads.sort_by_key(|usage| (Reverse(usage.cpu_time), usage.id));
+
+        let top = ThreadTop {
+            window,
+            processors: jvmti.get_available_processors()?,
+            total_cpu_percent: percent(total_cpu_time),
+            threads,
+        };
+        Ok((top, end))
+    }
+}
+
+impl ThreadCpuUsage {
+    fn collect(
+        jvmti: &JvmtiEnv,
+        jni: JNIEnv,
+        thread: Thread,
+        id: jlong,
+        cpu_time: Duration,
+        total_cpu_time: Duration,
+    ) -> JvmtiResult<Self> {
+        let info = jvmti.get_thread_info(jni, thread)?;
+        info.delete_local_refs(jni)?;
+
+        Ok(ThreadCpuUsage {
+            name: info.name,
+            id,
+            state: jvmti.get_thread_state(thread)?,
+            cpu_time,
+            total_cpu_time,
+            cpu_percent: 0.0,
+            stack: get_stack_trace(jvmti, jni, thread, MAX_FRAMES)?,
+        })
+    }
+}
+
+impl<'a> ThreadTopMonitor<'a> {
+    /// Measures back to back windows of `window`, passing each report of up to `limit` threads
+    /// to `on_report`. Requests `can_get_thread_cpu_time` if not already possessed
+    pub fn spawn(
+        jvmti: &JvmtiEnv<'a>,
+        jni: JNIEnv,
+        window: Duration,
+        limit: usize,
+        mut on_report: impl FnMut(&ThreadTop) + Send + 'static,
+    ) -> JvmtiResult<Self> {
+        let state = Box::new(RawMonitor::new(
+            jvmti,
+            "thread top monitor",
+            Stoppable::default(),
+        )?);
+
+        let run = move |jvmti: JvmtiEnv, jni: JNIEnv, state: &RawMonitor<Stoppable<()>>| {
+            let mut start = None;
+            loop {
+                if let Err(err) = jni.push_local_frame(64) {
+                    error!("thread top monitor failed to push local frame: {}", err);
+                    break;
+                }
+
+                let result = match &start {
+                    None => CpuSnapshot::take(&jvmti, jni).map(|end| (None, end)),
+                    Some(start) => ThreadTop::since(&jvmti, jni, start, limit)
+                        .map(|(top, end)| (Some(top), end)),
+                };
+                match result {
+                    Ok((top, end)) => {
+                        if let Some(top) = top {
+                            on_report(&top);
+                        }
+                        start = Some(end);
+                    }
+                    Err(err) => {
+                        warn!("failed to measure thread cpu usage: {}", err);
+                        start = None;
+                    }
+                }
+
+                let _ = jni.pop_local_frame(JObject::null());
+
+                if !agent_thread::wait_unless_stopped(state, window) {
+                    break;
+                }
+            }
+            debug!("thread top monitor stopped");
+        };
+
+        // boxed and stopped on drop, so outlives the thread
+        let thread = unsafe {
+            AgentThreadBuilder::new("thread top monitor").spawn_stoppable(
+                jvmti,
+                jni,
+                &[Capability::GetThreadCpuTime],
+                &state,
+                run,
+            )?
+        };
+
+        Ok(Self { thread, state })
+    }
+
+    pub fn thread(&self) -> &AgentThread {
+        &self.thread
+    }
+
+    /// Wakes the monitor and waits for its thread to end. Also called on drop, and must not be
+    /// called from the `on_report` callback
+    pub fn stop(&self) -> JvmtiResult<()> {
+        agent_thread::stop(&self.state)
+    }
+}
+
+impl Drop for ThreadTopMonitor<'_> {
+    fn drop(&mut self) {
+        if let Err(err) = self.stop() {
+            error!("failed to stop thread top monitor: {}", err);
+        }
+    }
+}
+
+/// A table of the threads like `top -H`, each followed by its stack
+impl Display for ThreadTop {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Threads by CPU over {:.3}s on {} processors, {:.1}% CPU in total:",
+            self.window.as_secs_f64(),
+            self.processors,
+            self.total_cpu_percent
+        )?;
+        writeln!(f, "   %CPU     CPU(s)   TOTAL(s)  STATE          TID  NAME")?;
+        for thread in &self.threads {
+            writeln!(
+                f,
+                "{:7.1} {:10.3} {:10.3}  {:13} {:4}  \"{}\"",
+                thread.cpu_percent,
+                thread.cpu_time.as_secs_f64(),
+                thread.total_cpu_time.as_secs_f64(),
+                thread.state.java_lang_state(),
+                thread.id,
+                thread.name
+            )?;
+            for frame in &thread.stack {
+                writeln!(f, "\tat {}", frame)?;
+            }
+        }
+        Ok(())
+    }
+}
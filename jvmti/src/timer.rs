@@ -0,0 +1,52 @@
+use jni::sys::{jlong, JNI_TRUE};
+use jni_jvmti_sys::{jvmtiTimerInfo, jvmtiTimerKind};
+use std::time::Duration;
+
+/// What a timer measures
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TimerKind {
+    /// Time the thread spent in user mode
+    UserCpu,
+    /// Time the thread spent in user or system mode
+    TotalCpu,
+    /// Wall clock time
+    Elapsed,
+}
+
+/// Characteristics of a JVMTI timer, e.g. from `GetTimerInfo`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TimerInfo {
+    /// The timer wraps back to zero after reaching this
+    pub max_value: Duration,
+    /// Whether the timer can be externally adjusted and so skip forward
+    pub may_skip_forward: bool,
+    /// Whether the timer can be externally adjusted and so skip back
+    pub may_skip_backward: bool,
+    pub kind: TimerKind,
+}
+
+/// Timer values are unsigned, even though they are passed as `jlong`
+pub(crate) fn nanos_to_duration(nanos: jlong) -> Duration {
+    Duration::from_nanos(nanos as u64)
+}
+
+impl From<jvmtiTimerKind> for TimerKind {
+    fn from(kind: jvmtiTimerKind) -> Self {
+        match kind {
+            jvmtiTimerKind::JVMTI_TIMER_USER_CPU => TimerKind::UserCpu,
+            jvmtiTimerKind::JVMTI_TIMER_TOTAL_CPU => TimerKind::TotalCpu,
+            jvmtiTimerKind::JVMTI_TIMER_ELAPSED => TimerKind::Elapsed,
+        }
+    }
+}
+
+impl From<jvmtiTimerInfo> for TimerInfo {
+    fn from(info: jvmtiTimerInfo) -> Self {
+        TimerInfo {
+            max_value: nanos_to_duration(info.max_value),
+            may_skip_forward: info.may_skip_forward == JNI_TRUE,
+            may_skip_backward: info.may_skip_backward == JNI_TRUE,
+            kind: info.kind.into(),
+        }
+    }
+}
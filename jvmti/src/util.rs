@@ -1,6 +1,7 @@
 pub use displaydoc::Display;
 pub use jni::errors::Error as GeneralJniError;
 
+use crate::capability::Capability;
 use jni_jvmti_sys::jvmtiError;
 
 pub use log::*;
@@ -16,6 +17,15 @@ pub enum Error {
 
     /// JVMTI function {0:?} is null
     NullFunction(&'static str),
+
+    /// Capability {0:?} is not available in this environment
+    UnavailableCapability(Capability),
+
+    /// Raw monitor is already locked by this thread
+    AlreadyLocked,
+
+    /// IO error: {0}
+    Io(#[from] std::io::Error),
 }
 
 /// Maps to JVMTI_ERROR_*
@@ -25,10 +35,83 @@ pub enum JvmtiError {
     /// The capability being used is false in this environment.
     MissingCapability,
 
+    /// Invalid priority.
+    InvalidPriority,
+
+    /// The monitor is invalid.
+    InvalidMonitor,
+
+    /// The monitor is not owned by this thread.
+    NotMonitorOwner,
+
+    /// The wait was interrupted.
+    Interrupted,
+
+    /// The information requested is not available.
+    AbsentInformation,
+
+    /// This operation requires the thread to be alive.
+    ThreadNotAlive,
+
+    /// The class has been loaded but not yet prepared.
+    ClassNotPrepared,
+
+    /// The method is native and cannot be used in this way.
+    NativeMethod,
+
+    /// The desired functionality has not been implemented in this virtual machine.
+    NotAvailable,
+
+    /// The class cannot be modified.
+    UnmodifiableClass,
+
+    /// The class bytes are malformed.
+    InvalidClassFormat,
+
+    /// The new class file definitions would lead to a circular definition.
+    CircularClassDefinition,
+
+    /// The class bytes fail verification.
+    FailsVerification,
+
+    /// The class name defined in the new class file is different from the name in the old class.
+    NamesDontMatch,
+
+    /// The new class file has a version number not supported by this VM.
+    UnsupportedVersion,
+
+    /// Unsupported redefinition: {0}
+    UnsupportedRedefinition(UnsupportedRedefinition),
+
     /// {0:?}
     Other(jvmtiError),
 }
 
+/// Maps to JVMTI_ERROR_UNSUPPORTED_REDEFINITION_*
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Display)]
+pub enum UnsupportedRedefinition {
+    /// method added
+    MethodAdded,
+
+    /// method deleted
+    MethodDeleted,
+
+    /// schema changed (fields added, removed or changed)
+    SchemaChanged,
+
+    /// class hierarchy changed (superclass or interfaces)
+    HierarchyChanged,
+
+    /// class modifiers changed
+    ClassModifiersChanged,
+
+    /// method modifiers changed
+    MethodModifiersChanged,
+
+    /// class attribute changed
+    ClassAttributeChanged,
+}
+
 pub type JvmtiResult<T> = Result<T, Error>;
 
 pub fn jvmti_err_to_result(err: jvmtiError) -> Result<(), JvmtiError> {
@@ -37,10 +120,100 @@ pub fn jvmti_err_to_result(err: jvmtiError) -> Result<(), JvmtiError> {
     Err(match err {
         JVMTI_ERROR_NONE => return Ok(()),
         JVMTI_ERROR_MUST_POSSESS_CAPABILITY => MissingCapability,
+        JVMTI_ERROR_INVALID_PRIORITY => InvalidPriority,
+        JVMTI_ERROR_INVALID_MONITOR => InvalidMonitor,
+        JVMTI_ERROR_NOT_MONITOR_OWNER => NotMonitorOwner,
+        JVMTI_ERROR_INTERRUPT => Interrupted,
+        JVMTI_ERROR_ABSENT_INFORMATION => AbsentInformation,
+        JVMTI_ERROR_THREAD_NOT_ALIVE => ThreadNotAlive,
+        JVMTI_ERROR_CLASS_NOT_PREPARED => ClassNotPrepared,
+        JVMTI_ERROR_NATIVE_METHOD => NativeMethod,
+        JVMTI_ERROR_NOT_AVAILABLE => NotAvailable,
+        JVMTI_ERROR_UNMODIFIABLE_CLASS => UnmodifiableClass,
+        JVMTI_ERROR_INVALID_CLASS_FORMAT => InvalidClassFormat,
+        JVMTI_ERROR_CIRCULAR_CLASS_DEFINITION => CircularClassDefinition,
+        JVMTI_ERROR_FAILS_VERIFICATION => FailsVerification,
+        JVMTI_ERROR_NAMES_DONT_MATCH => NamesDontMatch,
+        JVMTI_ERROR_UNSUPPORTED_VERSION => UnsupportedVersion,
+        JVMTI_ERROR_UNSUPPORTED_REDEFINITION_METHOD_ADDED => {
+            UnsupportedRedefinition(self::UnsupportedRedefinition::MethodAdded)
+        }
+        JVMTI_ERROR_UNSUPPORTED_REDEFINITION_METHOD_DELETED => {
+            UnsupportedRedefinition(self::UnsupportedRedefinition::MethodDeleted)
+        }
+        JVMTI_ERROR_UNSUPPORTED_REDEFINITION_SCHEMA_CHANGED => {
+            UnsupportedRedefinition(self::UnsupportedRedefinition::SchemaChanged)
+        }
+        JVMTI_ERROR_UNSUPPORTED_REDEFINITION_HIERARCHY_CHANGED => {
+            UnsupportedRedefinition(self::UnsupportedRedefinition::HierarchyChanged)
+        }
+        JVMTI_ERROR_UNSUPPORTED_REDEFINITION_CLASS_MODIFIERS_CHANGED => {
+            UnsupportedRedefinition(self::UnsupportedRedefinition::ClassModifiersChanged)
+        }
+        JVMTI_ERROR_UNSUPPORTED_REDEFINITION_METHOD_MODIFIERS_CHANGED => {
+            UnsupportedRedefinition(self::UnsupportedRedefinition::MethodModifiersChanged)
+        }
+        JVMTI_ERROR_UNSUPPORTED_REDEFINITION_CLASS_ATTRIBUTE_CHANGED => {
+            UnsupportedRedefinition(self::UnsupportedRedefinition::ClassAttributeChanged)
+        }
         err => Other(err),
     })
 }
 
+/// Falls back to a lossy utf8 conversion for invalid modified utf8
+pub fn mutf8_to_string(mutf8: &[u8]) -> String {
+    match mutf8::mstr::from_mutf8(mutf8).to_str() {
+        Ok(str) => str.into_owned(),
+        Err(_) => String::from_utf8_lossy(mutf8).into_owned(),
+    }
+}
+
+/// Type signature to the name Java source uses, e.g. `Ljava/lang/String;` to `java.lang.String`
+/// and `[I` to `int[]`
+pub fn java_class_name(signature: &str) -> String {
+    let dimensions = signature.bytes().take_while(|b| *b == b'[').count();
+    let element = &signature[dimensions..];
+    let mut name = match element {
+        "Z" => "boolean".to_owned(),
+        "B" => "byte".to_owned(),
+        "C" => "char".to_owned(),
+        "S" => "short".to_owned(),
+        "I" => "int".to_owned(),
+        "J" => "long".to_owned(),
+        "F" => "float".to_owned(),
+        "D" => "double".to_owned(),
+        "V" => "void".to_owned(),
+        _ => element
+            .strip_prefix('L')
+            .and_then(|s| s.strip_suffix(';'))
+            .unwrap_or(element)
+            .replace('/', "."),
+    };
+
+    for _ in 0..dimensions {
+        name.push_str("[]");
+    }
+    name
+}
+
+/// Whether a Java class name matches the pattern, which is either an exact class name or ends in
+/// `*` to match by prefix, e.g. `java.util.*` for a package and its subpackages
+pub fn matches_class_pattern(pattern: &str, class_name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => class_name.starts_with(prefix),
+        None => pattern == class_name,
+    }
+}
+
+/// Maps `JVMTI_ERROR_ABSENT_INFORMATION` to None, for optional class file attributes
+pub fn absent_as_none<T>(result: JvmtiResult<T>) -> JvmtiResult<Option<T>> {
+    match result {
+        Ok(val) => Ok(Some(val)),
+        Err(Error::Jvmti(JvmtiError::AbsentInformation)) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
 macro_rules! jvmti_method {
             ($jvmti:expr, $name:ident $(, $args:expr )* ) => {{
                 let fn_ptr = $jvmti.as_ref()
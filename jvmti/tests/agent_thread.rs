@@ -0,0 +1,85 @@
+use jni::objects::JString;
+use jni_jvmti_sys::JVMTI_THREAD_MAX_PRIORITY;
+use jvmti::{AgentThreadBuilder, JvmtiEnv, RawMonitor};
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+
+mod common;
+
+#[derive(Default)]
+struct Shared {
+    /// Thread name as seen from java
+    name: Option<String>,
+    finish: bool,
+}
+
+#[test]
+fn agent_thread() {
+    let jvm = common::new_jvm();
+    let jni = jvm.attach_current_thread().unwrap();
+
+    let jvmti = JvmtiEnv::from_jvm(&jvm).expect("failed");
+
+    let shared = Arc::new(RawMonitor::new(&jvmti, "shared", Shared::default()).expect("failed"));
+
+    let (done_tx, done_rx) = channel();
+    let thread = {
+        let shared = shared.clone();
+        AgentThreadBuilder::new("rust-agent-thread")
+            .with_priority(JVMTI_THREAD_MAX_PRIORITY as _)
+            .spawn(&jvmti, *jni, move |_jvmti, jni| {
+                let thread = jni
+                    .call_static_method(
+                        "java/lang/Thread",
+                        "currentThread",
+                        "()Ljava/lang/Thread;",
+                        &[],
+                    )
+                    .and_then(|t| t.l())
+                    .expect("no current thread");
+                let name = jni
+                    .call_method(thread, "getName", "()Ljava/lang/String;", &[])
+                    .and_then(|n| n.l())
+                    .expect("no name");
+                let name: String = jni.get_string(JString::from(name)).unwrap().into();
+
+                let mut guard = shared.lock().expect("lock failed");
+                guard.name = Some(name);
+                guard.notify_all().expect("notify failed");
+
+                // stay alive until checked
+                while !guard.finish {
+                    guard.wait(None).expect("wait failed");
+                }
+                drop(guard);
+                drop(shared);
+                done_tx.send(()).expect("send failed");
+            })
+            .expect("spawn failed")
+    };
+
+    let mut guard = shared.lock().expect("lock failed");
+    while guard.name.is_none() {
+        guard.wait(None).expect("wait failed");
+    }
+    assert_eq!(guard.name.as_deref(), Some("rust-agent-thread"));
+    assert_eq!(thread.name(), "rust-agent-thread");
+
+    {
+        let threads = jvmti.get_all_threads(*jni).expect("failed");
+        let found = threads.iter().any(|t| {
+            jni.is_same_object(*t, thread.thread().as_obj())
+                .expect("comparison failed")
+        });
+        assert!(found, "agent thread not in GetAllThreads");
+    }
+
+    guard.finish = true;
+    guard.notify_all().expect("notify failed");
+    drop(guard);
+
+    // the thread must be done with the env before it is disposed
+    done_rx.recv().expect("agent thread failed");
+    drop(shared);
+    jvmti.dispose().expect("dispose failed");
+}
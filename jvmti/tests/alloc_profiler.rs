@@ -0,0 +1,91 @@
+use jni::objects::{JObject, JValue};
+use jni::JNIEnv;
+use jvmti::{AllocationProfile, AllocationProfiler, AllocationSite};
+use log::*;
+use std::time::Duration;
+
+mod common;
+
+const COUNT: i32 = 100;
+const ARRAY_SIZE: i32 = 4096;
+
+#[test]
+fn sampled_allocations() {
+    let jvm = common::new_jvm();
+    let jni = jvm.attach_current_thread().unwrap();
+
+    let profiler = AllocationProfiler::builder()
+        .with_sampling_interval(0)
+        .start(&jvm)
+        .expect("failed to start");
+
+    // the interval only applies from the thread's next TLAB, so allocate something else until
+    // sampling starts
+    let int_source = jni.new_int_array(1).unwrap();
+    for attempt in 0.. {
+        assert!(attempt < 100_000, "nothing sampled");
+        let array = copy_of(&jni, "([II)[I", int_source.into(), 64);
+        jni.delete_local_ref(array).unwrap();
+        if copy_site(&profiler.profile(*jni).unwrap(), "int[]").is_some() {
+            break;
+        }
+    }
+
+    // allocated from java so the sample has a frame, keeping every other array
+    let source = jni.new_byte_array(1).unwrap();
+    let kept = jni
+        .new_object_array(COUNT / 2, "java/lang/Object", JObject::null())
+        .unwrap();
+    for i in 0..COUNT {
+        let array = copy_of(&jni, "([BI)[B", source.into(), ARRAY_SIZE);
+        if i % 2 == 0 {
+            jni.set_object_array_element(kept, i / 2, array).unwrap();
+        }
+        jni.delete_local_ref(array).unwrap();
+    }
+
+    let mut attempts = 0;
+    let site = loop {
+        jni.call_static_method("java/lang/System", "gc", "()V", &[])
+            .unwrap();
+        let profile = profiler.profile(*jni).expect("failed");
+        let site = copy_site(&profile, "byte[]")
+            .expect("site not found")
+            .clone();
+
+        attempts += 1;
+        if site.live_samples < site.samples || attempts == 20 {
+            info!("{}", profile);
+            break site;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    };
+
+    assert_eq!(site.samples, COUNT as u64);
+    assert!(site.sampled_bytes >= (COUNT * ARRAY_SIZE) as u64);
+    assert!(site.live_samples < site.samples, "no samples freed");
+    // the kept half can't have been collected
+    assert!(site.live_samples >= (COUNT / 2) as u64);
+    assert!(site.live_bytes >= (COUNT / 2 * ARRAY_SIZE) as u64);
+}
+
+fn copy_of<'a>(jni: &JNIEnv<'a>, signature: &str, source: JObject, length: i32) -> JObject<'a> {
+    jni.call_static_method(
+        "java/util/Arrays",
+        "copyOf",
+        signature,
+        &[JValue::from(source), JValue::Int(length)],
+    )
+    .unwrap()
+    .l()
+    .unwrap()
+}
+
+fn copy_site<'p>(profile: &'p AllocationProfile, class_name: &str) -> Option<&'p AllocationSite> {
+    profile.sites.iter().find(|site| {
+        site.class_name == class_name
+            && site.frames.first().is_some_and(|frame| {
+                frame.class_name == "java.util.Arrays" && frame.method_name == "copyOf"
+            })
+    })
+}
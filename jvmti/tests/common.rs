@@ -1,20 +1,43 @@
 use jni::{InitArgsBuilder, JNIVersion, JavaVM};
 use log::LevelFilter;
 
+const INTERPRETED: [&str; 2] = ["-Djava.compiler=NONE", "-Xint"];
+
 /// Current thread is unattached
+#[allow(dead_code)]
 pub fn new_jvm() -> JavaVM {
+    create_jvm(&INTERPRETED)
+}
+
+/// As [new_jvm], but with the JIT compiler enabled
+#[allow(dead_code)]
+pub fn new_jvm_with_jit() -> JavaVM {
+    create_jvm(&[])
+}
+
+/// As [new_jvm], but loads jdwp during startup, after which the capabilities it acquired in the
+/// OnLoad phase (e.g. `can_get_current_contended_monitor`) remain potentially available
+#[allow(dead_code)]
+pub fn new_jvm_with_onload_capabilities() -> JavaVM {
+    create_jvm(&[
+        INTERPRETED[0],
+        INTERPRETED[1],
+        "-agentlib:jdwp=transport=dt_socket,server=y,suspend=n,address=127.0.0.1:0",
+    ])
+}
+
+fn create_jvm(options: &[&str]) -> JavaVM {
     let _ = env_logger::builder()
         .filter_level(LevelFilter::Trace)
         .filter_module("jni", LevelFilter::Info)
         .is_test(true)
         .try_init();
 
-    let jvm_args = InitArgsBuilder::new()
-        .version(JNIVersion::V8)
-        .option("-Djava.compiler=NONE")
-        .option("-Xint")
-        .build()
-        .expect("failed to create jvm args");
+    let mut jvm_args = InitArgsBuilder::new().version(JNIVersion::V8);
+    for option in options {
+        jvm_args = jvm_args.option(option);
+    }
 
+    let jvm_args = jvm_args.build().expect("failed to create jvm args");
     JavaVM::new(jvm_args).expect("failed to create jvm")
 }
@@ -0,0 +1,183 @@
+use jni::objects::JValue;
+use jvmti::{CpuProfile, CpuProfiler, JvmtiEnv};
+use log::*;
+use std::time::{Duration, Instant};
+
+mod common;
+
+const MIN_SAMPLES: u64 = 20;
+
+#[test]
+fn cpu_profiler() {
+    let jvm = common::new_jvm();
+    let jni = jvm.attach_current_thread().unwrap();
+    let jvmti = JvmtiEnv::from_jvm(&jvm).expect("failed");
+
+    let profiler = CpuProfiler::builder()
+        .with_interval(Duration::from_millis(5))
+        .spawn(&jvmti, *jni)
+        .expect("failed to spawn profiler");
+
+    // keep this thread busy in java until it has been sampled enough
+    let base = jni
+        .call_static_method(
+            "java/math/BigInteger",
+            "valueOf",
+            "(J)Ljava/math/BigInteger;",
+            &[JValue::Long(7)],
+        )
+        .unwrap()
+        .l()
+        .unwrap();
+    let start = Instant::now();
+    let profile = loop {
+        let power = jni
+            .call_method(
+                base,
+                "pow",
+                "(I)Ljava/math/BigInteger;",
+                &[JValue::Int(5000)],
+            )
+            .unwrap()
+            .l()
+            .unwrap();
+        jni.delete_local_ref(power).unwrap();
+
+        let profile = profiler.profile().expect("failed");
+        if pow_samples(&profile) >= MIN_SAMPLES {
+            break profile;
+        }
+        assert!(start.elapsed() < Duration::from_secs(30), "not sampled");
+    };
+    profiler.stop().expect("failed to stop");
+
+    assert!(profile.ticks > 0);
+    assert!(profile.samples() >= MIN_SAMPLES);
+
+    let mut collapsed = Vec::new();
+    profile.write_collapsed(&mut collapsed).unwrap();
+    let collapsed = String::from_utf8(collapsed).unwrap();
+    info!("collapsed stacks:\n{}", collapsed);
+    let pow_line = collapsed
+        .lines()
+        // called from native code, so pow is the bottom frame
+        .find(|line| line.starts_with("java.math.BigInteger.pow;"))
+        .expect("no pow stack");
+    let (_, count) = pow_line.rsplit_once(' ').unwrap();
+    assert!(count.parse::<u64>().unwrap() > 0);
+    let total: u64 = collapsed
+        .lines()
+        .map(|line| line.rsplit_once(' ').unwrap().1.parse::<u64>().unwrap())
+        .sum();
+    assert_eq!(total, profile.samples());
+
+    let mut pprof = Vec::new();
+    profile.write_pprof(&mut pprof).unwrap();
+    let fields = parse_message(&pprof);
+    let strings: Vec<String> = fields
+        .iter()
+        .filter(|(field, _)| *field == 6)
+        .map(|(_, value)| String::from_utf8(value.bytes().to_vec()).unwrap())
+        .collect();
+    assert_eq!(strings[0], "");
+    assert!(strings.iter().any(|s| s == "java.math.BigInteger.pow"));
+    assert!(strings.iter().any(|s| s == "BigInteger.java"));
+
+    let samples: Vec<&Value> = fields
+        .iter()
+        .filter(|(field, _)| *field == 2)
+        .map(|(_, value)| value)
+        .collect();
+    assert_eq!(samples.len(), profile.stacks.len());
+    let pprof_total: u64 = samples
+        .iter()
+        .map(|sample| {
+            let fields = parse_message(sample.bytes());
+            let values = fields.iter().find(|(field, _)| *field == 2).unwrap();
+            let mut values = Reader(values.1.bytes());
+            let count = values.varint();
+            assert_eq!(values.varint(), count * 5_000_000);
+            count
+        })
+        .sum();
+    assert_eq!(pprof_total, profile.samples());
+    let period = fields.iter().find(|(field, _)| *field == 12).unwrap();
+    assert_eq!(period.1.varint(), 5_000_000);
+    let locations = fields.iter().filter(|(field, _)| *field == 4).count();
+    let functions = fields.iter().filter(|(field, _)| *field == 5).count();
+    assert!(locations > 0);
+    assert_eq!(functions, profile.methods.len());
+}
+
+fn pow_samples(profile: &CpuProfile) -> u64 {
+    profile
+        .stacks
+        .iter()
+        .filter(|stack| {
+            stack.frames.iter().any(|frame| {
+                let method = &profile.methods[frame.method];
+                method.class_name == "java.math.BigInteger" && method.method_name == "pow"
+            })
+        })
+        .map(|stack| stack.samples)
+        .sum()
+}
+
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+impl<'a> Value<'a> {
+    fn varint(&self) -> u64 {
+        match self {
+            Value::Varint(value) => *value,
+            Value::Bytes(_) => panic!("not a varint"),
+        }
+    }
+
+    fn bytes(&self) -> &'a [u8] {
+        match self {
+            Value::Bytes(bytes) => bytes,
+            Value::Varint(_) => panic!("not length delimited"),
+        }
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn varint(&mut self) -> u64 {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.0[0];
+            self.0 = &self.0[1..];
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte < 0x80 {
+                break;
+            }
+        }
+        value
+    }
+}
+
+/// Just enough of a protobuf decoder to check the structure of the profile
+fn parse_message(bytes: &[u8]) -> Vec<(u64, Value<'_>)> {
+    let mut reader = Reader(bytes);
+    let mut fields = Vec::new();
+    while !reader.0.is_empty() {
+        let key = reader.varint();
+        let value = match key & 7 {
+            0 => Value::Varint(reader.varint()),
+            2 => {
+                let length = reader.varint() as usize;
+                let (value, rest) = reader.0.split_at(length);
+                reader.0 = rest;
+                Value::Bytes(value)
+            }
+            ty => panic!("unexpected wire type {}", ty),
+        };
+        fields.push((key >> 3, value));
+    }
+    fields
+}
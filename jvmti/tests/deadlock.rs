@@ -0,0 +1,77 @@
+use jni::objects::JObject;
+use jvmti::{find_deadlocks, DeadlockWatcher, JvmtiEnv};
+use log::*;
+use std::sync::mpsc;
+use std::time::Duration;
+
+mod common;
+
+const CLASS_FILE: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/resources/deadlock/DeadlockTarget.class"
+);
+
+#[test]
+fn deadlock() {
+    let jvm = common::new_jvm_with_onload_capabilities();
+    let jni = jvm.attach_current_thread().unwrap();
+
+    let jvmti = JvmtiEnv::from_jvm(&jvm).expect("failed");
+
+    assert!(find_deadlocks(&jvmti, *jni).expect("failed").is_empty());
+
+    let (tx, rx) = mpsc::channel();
+    let watcher =
+        DeadlockWatcher::spawn(&jvmti, *jni, Duration::from_millis(50), move |deadlocks| {
+            let names: Vec<Vec<String>> = deadlocks
+                .iter()
+                .map(|d| d.threads.iter().map(|t| t.name.clone()).collect())
+                .collect();
+            tx.send(names).unwrap();
+        })
+        .expect("failed to spawn watcher");
+
+    let bytes = std::fs::read(CLASS_FILE).unwrap();
+    let cls = jni
+        .define_class("DeadlockTarget", JObject::null(), &bytes)
+        .expect("failed to define class");
+    jni.call_static_method(cls, "start", "()V", &[])
+        .expect("call failed");
+
+    let deadlocks = find_deadlocks(&jvmti, *jni).expect("failed");
+    assert_eq!(deadlocks.len(), 1);
+    let deadlock = &deadlocks[0];
+    info!("{}", deadlock);
+
+    let mut names: Vec<_> = deadlock.threads.iter().map(|t| t.name.as_str()).collect();
+    names.sort_unstable();
+    assert_eq!(names, ["deadlock-a", "deadlock-b"]);
+
+    for thread in &deadlock.threads {
+        assert_eq!(thread.monitor.class_name, "java.lang.Object");
+        assert_ne!(thread.owner_name, thread.name);
+
+        let top = &thread.stack[0];
+        assert_eq!(top.class_name, "DeadlockTarget");
+        assert_eq!(top.source_file.as_deref(), Some("DeadlockTarget.java"));
+        assert_eq!(top.line_number, Some(22));
+    }
+
+    let text = deadlock.to_string();
+    assert!(text.contains("which is held by \"deadlock-"));
+    assert!(text.contains("\tat DeadlockTarget.lambda$locker$0(DeadlockTarget.java:22)"));
+
+    // reported once only
+    let reported = rx
+        .recv_timeout(Duration::from_secs(10))
+        .expect("not reported");
+    assert_eq!(reported.len(), 1);
+    assert_eq!(reported[0].len(), 2);
+    assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+
+    watcher.stop().expect("failed to stop");
+    drop(watcher);
+    drop(deadlocks);
+
+    jvmti.dispose().expect("dispose failed");
+}
@@ -0,0 +1,75 @@
+use jni::objects::JValue;
+use jvmti::{dominator_tree, RootKind};
+use log::*;
+
+mod common;
+
+const COUNT: i32 = 1000;
+const ARRAY_SIZE: i32 = 1024;
+
+#[test]
+fn retained_sizes() {
+    let jvm = common::new_jvm();
+    let jni = jvm.attach_current_thread().unwrap();
+
+    // only retained by the list, which only this thread's local ref retains
+    let list = jni.new_object("java/util/ArrayList", "()V", &[]).unwrap();
+    for _ in 0..COUNT {
+        let array = jni.new_byte_array(ARRAY_SIZE).unwrap();
+        jni.call_method(list, "add", "(Ljava/lang/Object;)Z", &[JValue::from(array)])
+            .unwrap();
+        jni.delete_local_ref(array.into()).unwrap();
+    }
+
+    let tree = dominator_tree(*jni).expect("failed");
+    let report = tree.leak_report(10);
+    info!("{}", report);
+
+    let retained = (COUNT * ARRAY_SIZE) as u64;
+    assert!(tree.object_count() > COUNT as usize);
+    assert!(tree.total_size() > retained);
+    assert!(tree
+        .objects()
+        .all(|obj| tree.retained_size(obj) >= tree.shallow_size(obj)
+            && tree.retained_size(obj) <= tree.total_size()));
+
+    let list_entry = report
+        .objects
+        .iter()
+        .find(|obj| obj.description == "java.util.ArrayList")
+        .expect("list not found");
+    assert!(list_entry.retained_size > retained);
+    assert!(tree.immediate_dominator(list_entry.id).is_none());
+    assert_eq!(list_entry.path_to_root.root, RootKind::JniLocal);
+    assert_eq!(list_entry.path_to_root.objects, ["java.util.ArrayList"]);
+
+    // the elements are dominated by the list's backing array
+    let elements = tree
+        .objects()
+        .find(|&obj| tree.immediate_dominator(obj) == Some(list_entry.id))
+        .expect("no dominated objects");
+    assert_eq!(tree.describe(elements), "java.lang.Object[]");
+    assert!(tree.retained_size(elements) > retained);
+    let array = tree
+        .objects()
+        .find(|&obj| tree.immediate_dominator(obj) == Some(elements))
+        .expect("no dominated arrays");
+    assert_eq!(tree.describe(array), "byte[]");
+    assert_eq!(
+        tree.path_to_root(array).to_string(),
+        "JNI local -> java.util.ArrayList -> java.lang.Object[] -> byte[]"
+    );
+
+    let bytes = tree
+        .top_classes(usize::MAX)
+        .into_iter()
+        .find(|class| class.class_name == "byte[]")
+        .expect("no byte arrays");
+    assert!(bytes.instances >= COUNT as u64);
+    assert!(bytes.retained_size >= retained);
+    assert_eq!(bytes.retained_size, bytes.shallow_size);
+
+    let text = report.to_string();
+    assert!(text.contains("\nBiggest objects by retained size:\n"));
+    assert!(text.contains("\nBiggest classes by retained size:\n"));
+}
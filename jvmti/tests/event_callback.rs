@@ -1,6 +1,4 @@
-use jni::sys::jclass;
-use jni_jvmti_sys::jthread;
-use jvmti::{EventCallbacksBuilder, EventScope, EventType, JvmtiEnv};
+use jvmti::{Class, EventCallbacksBuilder, EventScope, EventType, JvmtiEnv, Thread};
 
 mod common;
 
@@ -18,8 +16,8 @@ fn event_callback() {
     unsafe extern "C" fn classload_callback(
         _jvmti_env: JvmtiEnv,
         _jni_env: jni::JNIEnv,
-        _thread: jthread,
-        _klass: jclass,
+        _thread: Thread,
+        _klass: Class,
     ) {
         log::info!("class load!!");
         CALLBACK_HIT = true;
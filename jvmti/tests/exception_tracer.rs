@@ -0,0 +1,135 @@
+use jni::objects::JValue;
+use jvmti::ExceptionTracer;
+use log::*;
+
+mod common;
+
+const NUMBER_FORMAT_EXCEPTION: &str = "java.lang.NumberFormatException";
+
+#[test]
+fn exception_tracer() {
+    let jvm = common::new_jvm_with_onload_capabilities();
+    let jni = jvm.attach_current_thread().unwrap();
+
+    let property = jni.new_string("exception.tracer.test").unwrap();
+    let value = jni.new_string("abc").unwrap();
+    jni.call_static_method(
+        "java/lang/System",
+        "setProperty",
+        "(Ljava/lang/String;Ljava/lang/String;)Ljava/lang/String;",
+        &[JValue::Object(*property), JValue::Object(*value)],
+    )
+    .expect("failed");
+
+    let tracer = ExceptionTracer::builder()
+        .with_include("java.lang.*")
+        .with_exclude("java.lang.ClassNotFoundException")
+        .start(&jvm)
+        .expect("failed to start");
+
+    // swallows the NumberFormatException and returns null
+    for _ in 0..10 {
+        let parsed = jni
+            .call_static_method(
+                "java/lang/Integer",
+                "getInteger",
+                "(Ljava/lang/String;)Ljava/lang/Integer;",
+                &[JValue::Object(*property)],
+            )
+            .expect("failed")
+            .l()
+            .unwrap();
+        assert!(parsed.is_null());
+    }
+
+    // not caught by any Java method
+    let parsed = jni.call_static_method(
+        "java/lang/Integer",
+        "parseInt",
+        "(Ljava/lang/String;)I",
+        &[JValue::Object(*value)],
+    );
+    assert!(parsed.is_err());
+    jni.exception_clear().unwrap();
+
+    let report = tracer.report(*jni).expect("failed");
+    info!("{}", report);
+    assert!(report
+        .sites
+        .iter()
+        .all(|site| site.class_name.starts_with("java.lang.")
+            && site.class_name != "java.lang.ClassNotFoundException"));
+
+    let sites: Vec<_> = report
+        .sites
+        .iter()
+        .filter(|site| site.class_name == NUMBER_FORMAT_EXCEPTION)
+        .collect();
+    // decode catches the first and parses again, throwing another one
+    assert_eq!(sites.iter().map(|site| site.thrown).sum::<u64>(), 21);
+    assert_eq!(sites.iter().map(|site| site.uncaught).sum::<u64>(), 1);
+    assert!(sites.iter().all(|site| site
+        .throw_frame
+        .as_ref()
+        .is_some_and(|frame| frame.class_name == "java.lang.Integer")));
+
+    let mut catches: Vec<(String, u64)> = sites
+        .iter()
+        .flat_map(|site| &site.catches)
+        .map(|catch| {
+            (
+                catch.frame.as_ref().unwrap().method_name.clone(),
+                catch.caught,
+            )
+        })
+        .collect();
+    catches.sort();
+    assert_eq!(
+        catches,
+        [("decode".to_owned(), 10), ("getInteger".to_owned(), 10)]
+    );
+
+    let exceptions: Vec<_> = report
+        .exceptions
+        .iter()
+        .filter(|exception| exception.class_name == NUMBER_FORMAT_EXCEPTION)
+        .collect();
+    assert_eq!(exceptions.len(), 21);
+    for exception in &exceptions {
+        assert!(exception
+            .message
+            .as_deref()
+            .is_some_and(|message| message.starts_with("For input string: ")));
+        assert!(exception
+            .stack
+            .iter()
+            .any(|frame| frame.method_name == "parseInt"));
+        assert_eq!(
+            exception.throw_frame.as_ref().map(ToString::to_string),
+            exception.stack.first().map(ToString::to_string)
+        );
+    }
+    for pair in exceptions[..20].chunks(2) {
+        assert!(pair.iter().all(|exception| !exception.uncaught));
+        assert_eq!(pair[0].catch_frame.as_ref().unwrap().method_name, "decode");
+        assert_eq!(
+            pair[1].catch_frame.as_ref().unwrap().method_name,
+            "getInteger"
+        );
+    }
+    assert!(exceptions[20].uncaught);
+    assert!(exceptions[20].catch_frame.is_none());
+    assert_eq!(
+        exceptions[20].message.as_deref(),
+        Some("For input string: \"abc\"")
+    );
+
+    let uncaught: Vec<_> = report
+        .uncaught()
+        .filter(|exception| exception.class_name == NUMBER_FORMAT_EXCEPTION)
+        .collect();
+    assert_eq!(uncaught.len(), 1);
+    assert!(report
+        .to_string()
+        .contains("caught 10 at java.lang.Integer.getInteger("));
+}
@@ -0,0 +1,75 @@
+use jni::objects::JObject;
+use jvmti::{
+    Capability, HeapFilterFlags, HeapReference, HeapVisitControlFlags, JvmtiEnv, TagAllocator,
+};
+use log::*;
+
+mod common;
+
+#[test]
+fn follow_references() {
+    let jvm = common::new_jvm();
+    let jni = jvm.attach_current_thread().unwrap();
+
+    let jvmti = JvmtiEnv::from_jvm(&jvm).expect("failed");
+    jvmti
+        .require_capabilities(&[Capability::TagObjects])
+        .expect("no tagging");
+    let tags = TagAllocator::new();
+
+    let string = jni.new_string("referree").expect("failed");
+    let array = jni
+        .new_object_array(3, "java/lang/Object", JObject::null())
+        .expect("failed");
+    jni.set_object_array_element(array, 1, string).unwrap();
+    jni.set_object_array_element(array, 2, array).unwrap();
+
+    let array_tag = tags.tag(&jvmti, JObject::from(array)).expect("failed");
+    let string_tag = tags.allocate();
+
+    let mut elements = Vec::new();
+    let mut saw_class = false;
+    jvmti
+        .follow_references(
+            HeapFilterFlags::empty(),
+            None,
+            Some(JObject::from(array)),
+            |mut reference| {
+                debug!("{:?}", reference);
+                assert!(!reference.is_root());
+                match &mut reference {
+                    // to itself
+                    HeapReference::ArrayElement { index: 2, tags } => {
+                        elements.push(2);
+                        assert!(tags.referrer_tag.is_none());
+                        assert_eq!(*tags.tag, array_tag.get());
+                    }
+                    HeapReference::ArrayElement { index, tags } => {
+                        elements.push(*index);
+                        assert_eq!(tags.referrer_tag.as_deref(), Some(&array_tag.get()));
+                        assert_eq!(tags.array_length, None);
+                        *tags.tag = string_tag.get();
+                    }
+                    HeapReference::Class(tags) => {
+                        assert_eq!(tags.referrer_tag.as_deref(), Some(&array_tag.get()));
+                        saw_class = true;
+                    }
+                    _ => {}
+                }
+
+                // only the array's own references
+                HeapVisitControlFlags::empty()
+            },
+        )
+        .expect("failed");
+
+    elements.sort_unstable();
+    assert_eq!(elements, vec![1, 2]);
+    assert!(saw_class);
+    assert_eq!(
+        jvmti.get_tag(JObject::from(string)).expect("failed"),
+        Some(string_tag)
+    );
+
+    jvmti.dispose().expect("dispose failed");
+}
@@ -0,0 +1,68 @@
+use jvmti::{histogram, GcMonitor};
+use log::*;
+use std::sync::mpsc;
+use std::time::Duration;
+
+mod common;
+
+const COLLECTIONS: u64 = 3;
+
+#[test]
+fn gc_monitor() {
+    let jvm = common::new_jvm();
+    let jni = jvm.attach_current_thread().unwrap();
+
+    let (tx, rx) = mpsc::channel();
+    let monitor = GcMonitor::builder()
+        .with_history(2)
+        .with_on_gc(move |pause, jvmti, jni| {
+            let histogram = histogram(jvmti, jni).expect("failed to take histogram");
+            let _ = tx.send((*pause, histogram));
+        })
+        .start(&jvm, *jni)
+        .expect("failed to start");
+
+    for _ in 0..COLLECTIONS {
+        jni.call_static_method("java/lang/System", "gc", "()V", &[])
+            .expect("failed");
+    }
+
+    let mut reported = Vec::new();
+    while reported.len() < COLLECTIONS as usize {
+        let (pause, histogram) = rx
+            .recv_timeout(Duration::from_secs(10))
+            .expect("pause not reported");
+        assert!(histogram
+            .entries
+            .iter()
+            .any(|entry| entry.class_name == "java.lang.String"));
+        reported.push(pause);
+    }
+    assert!(reported
+        .windows(2)
+        .all(|pair| pair[0].number < pair[1].number && pair[0].end <= pair[1].start));
+    assert!(reported
+        .iter()
+        .all(|pause| pause.duration == pause.end - pause.start));
+
+    let stats = monitor.stats().expect("failed");
+    info!("{}", stats);
+    assert!(stats.pauses >= COLLECTIONS);
+    assert_eq!(stats.dropped, 0);
+    assert!(stats.min <= stats.max);
+    assert!(stats.total >= stats.max);
+    assert_eq!(stats.recent.len(), 2);
+    assert_eq!(
+        stats.percentile(100.0),
+        Some(stats.recent[0].duration.max(stats.recent[1].duration))
+    );
+    assert!(stats.mean().is_some());
+    assert!(stats
+        .to_string()
+        .starts_with(&format!("{} pauses, total ", stats.pauses)));
+
+    monitor.stop().expect("failed to stop");
+    jni.call_static_method("java/lang/System", "gc", "()V", &[])
+        .expect("failed");
+    assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+}
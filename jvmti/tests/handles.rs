@@ -0,0 +1,72 @@
+use jni::objects::{JClass, JValue};
+use jni::sys::jmethodID;
+use jvmti::{Class, Field, JvmtiEnv, Location, Method};
+
+mod common;
+
+#[test]
+fn handles() {
+    let jvm = common::new_jvm();
+    let jni = jvm.attach_current_thread().unwrap();
+
+    let jvmti = JvmtiEnv::from_jvm(&jvm).expect("failed");
+
+    let math_cls = Class::from(jni.find_class("java/lang/Math").expect("no Math"));
+    assert_eq!(
+        math_cls.describe(&jvmti, *jni).expect("failed"),
+        "java.lang.Math"
+    );
+    assert_eq!(format!("{:?}", math_cls), "java.lang.Math");
+
+    let abs = Method::from(
+        jni.get_static_method_id(JClass::from(math_cls), "abs", "(I)I")
+            .expect("no abs"),
+    );
+    assert_eq!(
+        abs.describe(&jvmti, *jni).expect("failed"),
+        "java.lang.Math.abs(I)I"
+    );
+    let location = Location::new(abs, 3);
+    assert_eq!(
+        location.describe(&jvmti, *jni).expect("failed"),
+        "java.lang.Math.abs(I)I@3"
+    );
+    assert_eq!(format!("{:?}", abs), "java.lang.Math.abs(I)I");
+    assert_eq!(format!("{:?}", location), "java.lang.Math.abs(I)I@3");
+
+    // falls back to the pointer on a detached thread
+    let raw = abs.into_inner() as usize;
+    let detached = std::thread::spawn(move || format!("{:?}", Method::from(raw as jmethodID)))
+        .join()
+        .unwrap();
+    assert_eq!(detached, format!("Method({:?})", abs.into_inner()));
+
+    let declaring = jvmti.get_method_declaring_class(*jni, abs).expect("failed");
+    assert!(jni.is_same_object(*declaring, *math_cls).unwrap());
+
+    let string_cls = Class::from(jni.find_class("java/lang/String").expect("no String"));
+    let hash = Field::new(
+        string_cls,
+        jni.get_field_id(JClass::from(string_cls), "hash", "I")
+            .expect("no hash"),
+    );
+    assert_eq!(
+        hash.describe(&jvmti, *jni).expect("failed"),
+        "java.lang.String.hash:I"
+    );
+    assert_eq!(format!("{:?}", hash), "java.lang.String.hash:I");
+
+    let thread = jvmti.get_current_thread(*jni).expect("failed");
+    let name = jni
+        .call_method(*thread, "getName", "()Ljava/lang/String;", &[])
+        .and_then(JValue::l)
+        .and_then(|name| jni.get_string(name.into()).map(String::from))
+        .expect("failed");
+    assert_eq!(thread.describe(&jvmti, *jni).expect("failed"), name);
+    assert_eq!(format!("{:?}", thread), format!("Thread({:?})", name));
+
+    let frames = jvmti.get_stack_trace(thread, 0, 8).expect("failed");
+    assert!(frames.is_empty(), "attached thread has no java frames");
+
+    jvmti.dispose().expect("dispose failed");
+}
@@ -0,0 +1,90 @@
+use jni::objects::JObject;
+use jvmti::{histogram, Capability, JvmtiEnv, NonZeroJlong};
+use log::*;
+
+mod common;
+
+const COUNT: i32 = 1000;
+
+#[test]
+fn histogram_counts_instances() {
+    let jvm = common::new_jvm();
+    let jni = jvm.attach_current_thread().unwrap();
+
+    let jvmti = JvmtiEnv::from_jvm(&jvm).expect("failed");
+    jvmti
+        .require_capabilities(&[Capability::TagObjects])
+        .expect("no tagging");
+
+    let class = jni
+        .find_class("java/util/concurrent/atomic/AtomicLong")
+        .expect("failed");
+    let array = jni
+        .new_object_array(COUNT, class, JObject::null())
+        .expect("failed");
+    for i in 0..COUNT {
+        let obj = jni.new_object(class, "()V", &[]).expect("failed");
+        jni.set_object_array_element(array, i, obj).unwrap();
+        jni.delete_local_ref(obj).unwrap();
+    }
+
+    // the original tag is restored
+    let class_tag = NonZeroJlong::new(77).unwrap();
+    jvmti.set_tag(*class, Some(class_tag)).expect("failed");
+
+    let histo = histogram(&jvmti, *jni).expect("failed");
+    info!("{}", histo);
+
+    let entry = histo
+        .get("java.util.concurrent.atomic.AtomicLong")
+        .expect("no entry");
+    assert!(entry.instances >= COUNT as u64);
+    assert!(entry.bytes >= entry.instances * 16);
+
+    // the running vm is JDK 17
+    assert!(histo.modules);
+    assert_eq!(
+        entry
+            .module
+            .as_deref()
+            .and_then(|module| module.split('@').next()),
+        Some("java.base")
+    );
+
+    let array_entry = histo
+        .get("[Ljava.util.concurrent.atomic.AtomicLong;")
+        .expect("no array entry");
+    assert_eq!(array_entry.instances, 1);
+    assert!(histo.get("java.lang.String").is_some());
+    assert!(histo
+        .entries
+        .windows(2)
+        .all(|pair| pair[0].bytes >= pair[1].bytes));
+
+    assert_eq!(jvmti.get_tag(*class).expect("failed"), Some(class_tag));
+
+    let text = histo.to_string();
+    let mut lines = text.lines();
+    assert_eq!(
+        lines.next(),
+        Some(" num     #instances         #bytes  class name (module)")
+    );
+    assert_eq!(
+        lines.next(),
+        Some("-------------------------------------------------------")
+    );
+    let first = lines.next().unwrap();
+    assert!(first.starts_with("   1: "));
+    assert!(first.ends_with(&format!(" ({})", histo.entries[0].module.as_ref().unwrap())));
+    let total = text.lines().last().unwrap();
+    assert_eq!(
+        total,
+        format!(
+            "Total {:13} {:13}",
+            histo.total_instances(),
+            histo.total_bytes()
+        )
+    );
+
+    jvmti.dispose().expect("dispose failed");
+}
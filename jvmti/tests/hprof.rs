@@ -0,0 +1,253 @@
+use jni::objects::{JObject, JValue};
+use jvmti::dump_heap;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+
+mod common;
+
+const VALUE: i64 = 0x1234_5678_9abc;
+const MARKER: &str = "hprof-marker";
+
+#[test]
+fn dump_heap_to_hprof() {
+    let jvm = common::new_jvm();
+    let jni = jvm.attach_current_thread().unwrap();
+
+    let atomic = jni
+        .new_object(
+            "java/util/concurrent/atomic/AtomicLong",
+            "(J)V",
+            &[JValue::Long(VALUE)],
+        )
+        .unwrap();
+    let marker = jni.new_string(MARKER).unwrap();
+    let reference = jni
+        .new_object(
+            "java/util/concurrent/atomic/AtomicReference",
+            "(Ljava/lang/Object;)V",
+            &[JValue::Object(*marker)],
+        )
+        .unwrap();
+    let array = jni
+        .new_object_array(3, "java/lang/Object", JObject::null())
+        .unwrap();
+    jni.set_object_array_element(array, 0, atomic).unwrap();
+    jni.set_object_array_element(array, 1, marker).unwrap();
+    jni.set_object_array_element(array, 2, reference).unwrap();
+
+    let path = std::env::temp_dir().join(format!("jvmti-test-{}.hprof", std::process::id()));
+    let summary = dump_heap(*jni, &path).expect("dump failed");
+    let bytes = std::fs::read(&path).expect("no dump");
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(bytes.len() as u64, summary.bytes);
+    assert!(summary.classes > 0 && summary.roots > 0);
+    assert!(summary.instances > 0 && summary.object_arrays > 0 && summary.primitive_arrays > 0);
+
+    let dump = Hprof::parse(&bytes);
+    assert_eq!(dump.instances.len() as u64, summary.instances);
+
+    // every class, instance and array has its own id
+    let unique: HashSet<&u64> = dump.ids.iter().collect();
+    assert_eq!(unique.len(), dump.ids.len());
+    assert_eq!(
+        dump.ids.len(),
+        dump.class_dumps.len()
+            + dump.instances.len()
+            + dump.object_arrays.len()
+            + summary.primitive_arrays as usize
+    );
+
+    let atomic_class = dump.class_id("java/util/concurrent/atomic/AtomicLong");
+    let array_class = dump.class_id("[Ljava/lang/Object;");
+    assert!(dump.class_dumps.contains(&atomic_class));
+
+    // the array references the atomic, whose only field holds the value
+    let (atomic_id, elements) = dump
+        .object_arrays
+        .iter()
+        .filter(|(_, (class, _))| *class == array_class)
+        .find_map(|(_, (_, elements))| {
+            let atomic_id = elements.first()?;
+            let fields = dump.instances.get(atomic_id)?;
+            (fields.0 == atomic_class).then_some((*atomic_id, elements))
+        })
+        .expect("array not found");
+    assert_eq!(elements.len(), 3);
+    assert_eq!(dump.instances[&atomic_id].1, VALUE.to_be_bytes());
+
+    // the reference's only field holds the string's id
+    let reference_class = dump.class_id("java/util/concurrent/atomic/AtomicReference");
+    let (class, fields) = &dump.instances[&elements[2]];
+    assert_eq!(*class, reference_class);
+    assert_eq!(*fields, elements[1].to_be_bytes());
+    assert!(dump.instances.contains_key(&elements[1]));
+
+    // the string's latin1 value
+    assert!(dump
+        .primitive_arrays
+        .iter()
+        .any(|array| array == MARKER.as_bytes()));
+    assert!(dump.roots > 0);
+}
+
+/// Just enough of a parser to check the structure of the dump
+#[derive(Default)]
+struct Hprof {
+    strings: HashMap<u64, String>,
+    /// Name to class id
+    classes: HashMap<String, u64>,
+    class_dumps: Vec<u64>,
+    /// Id to class id and field values
+    instances: HashMap<u64, (u64, Vec<u8>)>,
+    /// Id to class id and elements
+    object_arrays: HashMap<u64, (u64, Vec<u64>)>,
+    /// Elements of byte arrays
+    primitive_arrays: Vec<Vec<u8>>,
+    roots: usize,
+    /// Ids of all class dumps, instances and arrays in order
+    ids: Vec<u64>,
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> &'a [u8] {
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        taken
+    }
+
+    fn u1(&mut self) -> u8 {
+        self.take(1)[0]
+    }
+
+    fn u2(&mut self) -> u16 {
+        u16::from_be_bytes(self.take(2).try_into().unwrap())
+    }
+
+    fn u4(&mut self) -> u32 {
+        u32::from_be_bytes(self.take(4).try_into().unwrap())
+    }
+
+    fn id(&mut self) -> u64 {
+        u64::from_be_bytes(self.take(8).try_into().unwrap())
+    }
+}
+
+fn type_size(ty: u8) -> usize {
+    match ty {
+        2 => 8,
+        4 | 8 => 1,
+        5 | 9 => 2,
+        6 | 10 => 4,
+        7 | 11 => 8,
+        _ => panic!("bad type {}", ty),
+    }
+}
+
+impl Hprof {
+    fn parse(bytes: &[u8]) -> Self {
+        let mut reader = Reader(bytes);
+        assert_eq!(reader.take(19), b"JAVA PROFILE 1.0.2\0");
+        assert_eq!(reader.u4(), 8);
+        reader.take(8);
+
+        let mut dump = Self::default();
+        let mut ended = false;
+        while !reader.0.is_empty() {
+            assert!(!ended, "records after heap dump end");
+            let tag = reader.u1();
+            reader.u4();
+            let length = reader.u4() as usize;
+            let mut body = Reader(reader.take(length));
+            match tag {
+                0x01 => {
+                    let id = body.id();
+                    let string = String::from_utf8(body.0.to_vec()).unwrap();
+                    dump.strings.insert(id, string);
+                }
+                0x02 => {
+                    body.u4();
+                    let id = body.id();
+                    body.u4();
+                    let name = dump.strings[&body.id()].clone();
+                    dump.classes.insert(name, id);
+                }
+                0x04 | 0x05 => {}
+                0x1c => {
+                    while !body.0.is_empty() {
+                        dump.parse_sub_record(&mut body);
+                    }
+                }
+                0x2c => ended = true,
+                _ => panic!("unexpected record {:#x}", tag),
+            }
+        }
+        assert!(ended);
+        dump
+    }
+
+    fn parse_sub_record(&mut self, body: &mut Reader) {
+        let tag = body.u1();
+        match tag {
+            0xff | 0x05 | 0x07 => {
+                body.id();
+                self.roots += 1;
+            }
+            0x01 | 0x02 | 0x03 | 0x08 => {
+                body.take(16);
+                self.roots += 1;
+            }
+            0x20 => {
+                let id = body.id();
+                body.take(4 + 8 * 6 + 4);
+                assert_eq!(body.u2(), 0);
+                for _ in 0..body.u2() {
+                    body.id();
+                    let ty = body.u1();
+                    body.take(type_size(ty));
+                }
+                for _ in 0..body.u2() {
+                    body.id();
+                    type_size(body.u1());
+                }
+                self.class_dumps.push(id);
+                self.ids.push(id);
+            }
+            0x21 => {
+                let id = body.id();
+                body.u4();
+                let class = body.id();
+                let length = body.u4() as usize;
+                let fields = body.take(length).to_vec();
+                self.instances.insert(id, (class, fields));
+                self.ids.push(id);
+            }
+            0x22 => {
+                let id = body.id();
+                body.u4();
+                let length = body.u4();
+                let class = body.id();
+                let elements = (0..length).map(|_| body.id()).collect();
+                self.object_arrays.insert(id, (class, elements));
+                self.ids.push(id);
+            }
+            0x23 => {
+                self.ids.push(body.id());
+                body.u4();
+                let length = body.u4() as usize;
+                let ty = body.u1();
+                let elements = body.take(length * type_size(ty));
+                if ty == 8 {
+                    self.primitive_arrays.push(elements.to_vec());
+                }
+            }
+            _ => panic!("unexpected sub-record {:#x}", tag),
+        }
+    }
+
+    fn class_id(&self, name: &str) -> u64 {
+        *self.classes.get(name).expect("class not loaded")
+    }
+}
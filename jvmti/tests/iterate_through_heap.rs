@@ -51,5 +51,3 @@ fn iterate_through_heap_and_tag() {
 
     jvmti.dispose().expect("dispose failed");
 }
-
-// TODO do the same but with followreferences
@@ -0,0 +1,134 @@
+use jni::objects::{JObject, JValue};
+use jni::JNIEnv;
+use jvmti::{LifetimeReport, LifetimeStack, LifetimeTracker};
+use log::*;
+use std::time::Duration;
+
+mod common;
+
+const COUNT: i32 = 100;
+const ARRAY_SIZE: i32 = 4096;
+const THRESHOLD: Duration = Duration::from_millis(100);
+
+#[test]
+fn object_lifetimes() {
+    let jvm = common::new_jvm();
+    let jni = jvm.attach_current_thread().unwrap();
+
+    let old = jni.new_byte_array(1).unwrap();
+    let tracker = LifetimeTracker::builder()
+        .with_class("byte[]")
+        .with_class("int[]")
+        .with_sampling_interval(Some(0))
+        .start(&jvm)
+        .expect("failed to start");
+
+    // allocated before tracking started
+    let tagged = tracker.tag_live_objects(*jni).expect("failed to tag");
+    assert!(tagged >= 1);
+
+    // the interval only applies from the thread's next TLAB, so allocate something else until
+    // sampling starts
+    let int_source = jni.new_int_array(1).unwrap();
+    for attempt in 0.. {
+        assert!(attempt < 100_000, "nothing sampled");
+        let array = copy_of(&jni, "([II)[I", int_source.into(), 64);
+        jni.delete_local_ref(array).unwrap();
+        let report = tracker.report(*jni, THRESHOLD).unwrap();
+        if copy_stack(&report, "int[]").is_some() {
+            break;
+        }
+    }
+
+    // allocated from java so the stack has a frame, keeping every other array
+    let source = jni.new_byte_array(1).unwrap();
+    let kept = jni
+        .new_object_array(COUNT / 2, "java/lang/Object", JObject::null())
+        .unwrap();
+    for i in 0..COUNT {
+        let array = copy_of(&jni, "([BI)[B", source.into(), ARRAY_SIZE);
+        if i % 2 == 0 {
+            jni.set_object_array_element(kept, i / 2, array).unwrap();
+        }
+        jni.delete_local_ref(array).unwrap();
+    }
+    std::thread::sleep(THRESHOLD);
+
+    let mut attempts = 0;
+    let report = loop {
+        jni.call_static_method("java/lang/System", "gc", "()V", &[])
+            .unwrap();
+        let report = tracker.report(*jni, THRESHOLD).expect("failed");
+        let stack = copy_stack(&report, "byte[]").expect("stack not found");
+
+        attempts += 1;
+        if stack.freed > 0 || attempts == 20 {
+            info!("{}", report);
+            break report;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    };
+
+    let (index, stack) = report
+        .stacks
+        .iter()
+        .enumerate()
+        .find(|(_, stack)| is_copy_stack(stack, "byte[]"))
+        .unwrap();
+    assert_eq!(stack.tagged, COUNT as u64);
+    assert!(stack.freed > 0, "nothing freed");
+    // the kept half can't have been collected, and has outlived the threshold
+    assert!(stack.live() >= (COUNT / 2) as u64);
+    assert_eq!(stack.survivors, stack.live());
+    assert!(stack.surviving_bytes >= (COUNT / 2 * ARRAY_SIZE) as u64);
+    assert!(stack.max_lifetime >= THRESHOLD);
+    assert!(stack.mean_lifetime().unwrap() <= stack.max_lifetime);
+
+    let survivors: Vec<_> = report
+        .survivors
+        .iter()
+        .filter(|survivor| survivor.stack == index)
+        .collect();
+    assert_eq!(survivors.len() as u64, stack.survivors);
+    assert!(survivors.iter().all(|survivor| survivor.age > THRESHOLD));
+    assert!(report
+        .survivors
+        .windows(2)
+        .all(|pair| pair[0].age >= pair[1].age));
+
+    let heap_stack = report
+        .stacks
+        .iter()
+        .find(|stack| stack.class_name == "byte[]" && stack.frames.is_empty())
+        .expect("live objects not tagged");
+    assert!(heap_stack.survivors >= 1);
+    assert!(report.to_string().contains("(tagged on the heap)"));
+
+    jni.delete_local_ref(old.into()).unwrap();
+}
+
+fn copy_of<'a>(jni: &JNIEnv<'a>, signature: &str, source: JObject, length: i32) -> JObject<'a> {
+    jni.call_static_method(
+        "java/util/Arrays",
+        "copyOf",
+        signature,
+        &[JValue::from(source), JValue::Int(length)],
+    )
+    .unwrap()
+    .l()
+    .unwrap()
+}
+
+fn is_copy_stack(stack: &LifetimeStack, class_name: &str) -> bool {
+    stack.class_name == class_name
+        && stack.frames.first().is_some_and(|frame| {
+            frame.class_name == "java.util.Arrays" && frame.method_name == "copyOf"
+        })
+}
+
+fn copy_stack<'r>(report: &'r LifetimeReport, class_name: &str) -> Option<&'r LifetimeStack> {
+    report
+        .stacks
+        .iter()
+        .find(|stack| is_copy_stack(stack, class_name))
+}
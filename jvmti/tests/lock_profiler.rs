@@ -0,0 +1,120 @@
+use jni::objects::JValue;
+use jvmti::{AgentThreadBuilder, JvmtiEnv, LockProfiler, Thread, ThreadState};
+use log::*;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+mod common;
+
+const CONTENDER: &str = "contender";
+const HOLD: Duration = Duration::from_millis(200);
+const WAIT: Duration = Duration::from_millis(50);
+
+#[test]
+fn lock_profiler() {
+    let jvm = common::new_jvm_with_onload_capabilities();
+    let jni = jvm.attach_current_thread().unwrap();
+    let jvmti = JvmtiEnv::from_jvm(&jvm).expect("failed");
+    let profiler = LockProfiler::builder()
+        .with_owners(true)
+        .start(&jvm)
+        .expect("failed to start");
+
+    let buffer = jni
+        .new_object("java/lang/StringBuffer", "()V", &[])
+        .expect("failed");
+    let shared = jni.new_global_ref(buffer).unwrap();
+
+    // blocks in the synchronized append while this thread holds the buffer's monitor
+    let guard = jni.lock_obj(buffer).expect("failed to lock");
+    let (tx, rx) = mpsc::channel();
+    let contender = AgentThreadBuilder::new(CONTENDER)
+        .spawn(&jvmti, *jni, move |_, jni| {
+            let text = jni.new_string("contended").unwrap();
+            jni.call_method(
+                shared.as_obj(),
+                "append",
+                "(Ljava/lang/String;)Ljava/lang/StringBuffer;",
+                &[JValue::Object(*text)],
+            )
+            .expect("failed");
+            tx.send(()).unwrap();
+        })
+        .expect("failed to spawn");
+
+    let contender_thread = Thread::from(contender.thread().as_obj());
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !jvmti
+        .get_thread_state(contender_thread)
+        .expect("failed")
+        .contains(ThreadState::BLOCKED_ON_MONITOR_ENTER)
+    {
+        assert!(Instant::now() < deadline, "contender never blocked");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    std::thread::sleep(HOLD);
+    drop(guard);
+    rx.recv_timeout(Duration::from_secs(10))
+        .expect("contender never appended");
+
+    let guard = jni.lock_obj(buffer).expect("failed to lock");
+    jni.call_method(
+        buffer,
+        "wait",
+        "(J)V",
+        &[JValue::Long(WAIT.as_millis() as i64)],
+    )
+    .expect("failed to wait");
+    drop(guard);
+
+    let profile = profiler.profile(*jni).expect("failed");
+    info!("{}", profile);
+
+    let monitor = profile
+        .monitors
+        .iter()
+        .find(|monitor| monitor.monitor.class_name == "java.lang.StringBuffer")
+        .expect("buffer not contended");
+    assert_eq!(monitor.stats.contended, 1);
+    assert!(monitor.stats.blocked >= HOLD, "{:?}", monitor.stats);
+    assert_eq!(monitor.stats.waits, 1);
+    assert!(monitor.stats.waited >= WAIT, "{:?}", monitor.stats);
+
+    let main = jvmti
+        .get_thread_info(*jni, jvmti.get_current_thread(*jni).unwrap())
+        .expect("failed");
+    assert_eq!(monitor.owners.len(), 1);
+    assert_eq!(monitor.owners[0].name, main.name);
+    assert_eq!(monitor.owners[0].contended, 1);
+    assert_eq!(monitor.owners[0].blocked, monitor.stats.blocked);
+
+    let class = profile
+        .classes
+        .iter()
+        .find(|class| class.class_name == "java.lang.StringBuffer")
+        .expect("class not contended");
+    assert_eq!(class.monitors, 1);
+    assert_eq!(class.stats, monitor.stats);
+
+    let blocked_stack = profile
+        .stacks
+        .iter()
+        .find(|stack| stack.class_name == "java.lang.StringBuffer" && stack.stats.contended > 0)
+        .expect("no acquiring stack");
+    assert_eq!(blocked_stack.frames[0].method_name, "append");
+    assert_eq!(blocked_stack.stats.blocked, monitor.stats.blocked);
+
+    let waiting_stack = profile
+        .stacks
+        .iter()
+        .find(|stack| stack.class_name == "java.lang.StringBuffer" && stack.stats.waits > 0)
+        .expect("no waiting stack");
+    assert!(waiting_stack
+        .frames
+        .iter()
+        .any(|frame| frame.method_name == "wait"));
+
+    let text = profile.to_string();
+    assert!(text.contains("(a java.lang.StringBuffer)"));
+    assert!(text.contains(&format!("owned by \"{}\"", main.name)));
+}
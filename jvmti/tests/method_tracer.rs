@@ -0,0 +1,100 @@
+use jni::objects::JValue;
+use jvmti::{EventScope, JvmtiEnv, MethodTrace, MethodTracer};
+use log::*;
+
+mod common;
+
+#[test]
+fn method_tracer() {
+    let jvm = common::new_jvm_with_onload_capabilities();
+    let jni = jvm.attach_current_thread().unwrap();
+    let jvmti = JvmtiEnv::from_jvm(&jvm).expect("failed");
+    let thread = jvmti.get_current_thread(*jni).expect("failed");
+
+    let tracer = MethodTracer::builder()
+        .with_pattern("java.util.ArrayList")
+        .install(&jvm)
+        .expect("failed to install");
+    tracer
+        .enable(EventScope::Thread(thread))
+        .expect("failed to enable");
+
+    let list = jni
+        .new_object("java/util/ArrayList", "()V", &[])
+        .expect("failed");
+    jni.call_method(
+        list,
+        "add",
+        "(Ljava/lang/Object;)Z",
+        &[JValue::Object(list)],
+    )
+    .expect("failed");
+    let out_of_bounds = jni.call_method(list, "get", "(I)Ljava/lang/Object;", &[JValue::Int(5)]);
+    assert!(out_of_bounds.is_err());
+    jni.exception_clear().unwrap();
+
+    tracer
+        .disable(EventScope::Thread(thread))
+        .expect("failed to disable");
+    // not traced
+    jni.call_method(list, "size", "()I", &[]).expect("failed");
+
+    let trace = tracer.trace().expect("failed");
+    info!("{}", trace);
+    assert_eq!(trace.threads.len(), 1);
+    let info = jvmti.get_thread_info(*jni, thread).expect("failed");
+    assert_eq!(trace.threads[0].name, info.name);
+    assert_eq!(trace.dropped_calls, 0);
+    assert!(trace
+        .methods
+        .iter()
+        .all(|method| method.class_name == "java.util.ArrayList"));
+
+    let calls = &trace.threads[0].calls;
+    let roots: Vec<String> = calls
+        .iter()
+        .filter(|call| call.depth == 0)
+        .map(|call| trace.methods[call.method].to_string())
+        .collect();
+    assert_eq!(
+        roots,
+        [
+            "java.util.ArrayList.<init>()V",
+            "java.util.ArrayList.add(Ljava/lang/Object;)Z",
+            "java.util.ArrayList.get(I)Ljava/lang/Object;",
+        ]
+    );
+    assert!(calls.iter().all(|call| call.duration.is_some()));
+
+    // add delegates to a private helper
+    let add = position(&trace, "add", "(Ljava/lang/Object;)Z");
+    assert_eq!(calls[add + 1].depth, 1);
+    assert_eq!(trace.methods[calls[add + 1].method].method_name, "add");
+
+    let get = position(&trace, "get", "(I)Ljava/lang/Object;");
+    assert!(calls[get].exception);
+    assert!(!calls[add].exception);
+
+    let mut json = Vec::new();
+    trace.write_chrome_trace(&mut json).expect("failed");
+    let json = String::from_utf8(json).unwrap();
+    assert!(json.starts_with('{'));
+    assert!(json.contains(r#""ph":"M","pid":1,"tid":1,"args":{"name":"#));
+    assert!(json.contains(r#""name":"java.util.ArrayList.get""#));
+    assert!(json.contains(r#""exception":true"#));
+    assert_eq!(json.matches(r#""ph":"X""#).count(), calls.len());
+
+    tracer.clear().expect("failed");
+    assert!(tracer.trace().expect("failed").threads[0].calls.is_empty());
+}
+
+fn position(trace: &MethodTrace, name: &str, signature: &str) -> usize {
+    trace.threads[0]
+        .calls
+        .iter()
+        .position(|call| {
+            let method = &trace.methods[call.method];
+            method.method_name == name && method.signature == signature
+        })
+        .expect("not traced")
+}
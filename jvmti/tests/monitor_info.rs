@@ -0,0 +1,100 @@
+use jvmti::JvmtiEnv;
+use std::sync::mpsc::channel;
+use std::time::{Duration, Instant};
+
+mod common;
+
+#[test]
+fn monitor_info() {
+    let jvm = common::new_jvm_with_onload_capabilities();
+    let jni = jvm.attach_current_thread().unwrap();
+
+    let jvmti = JvmtiEnv::from_jvm(&jvm).expect("failed");
+
+    let thread = jvmti.get_current_thread(*jni).expect("failed");
+    let lock = jni.new_object("java/lang/Object", "()V", &[]).unwrap();
+
+    {
+        let usage = jvmti.get_object_monitor_usage(*jni, lock).expect("failed");
+        assert!(usage.owner.is_none());
+        assert_eq!(usage.entry_count, 0);
+    }
+
+    {
+        let guard = jni.lock_obj(lock).unwrap();
+
+        let owned = jvmti.get_owned_monitor_info(*jni, thread).expect("failed");
+        assert!(owned.iter().any(|m| jni.is_same_object(lock, *m).unwrap()));
+
+        let depths = jvmti
+            .get_owned_monitor_stack_depth_info(*jni, thread)
+            .expect("failed");
+        let info = depths
+            .iter()
+            .find(|info| jni.is_same_object(lock, info.monitor).unwrap())
+            .expect("monitor not found");
+        // entered through jni
+        assert_eq!(info.stack_depth, -1);
+
+        let usage = jvmti.get_object_monitor_usage(*jni, lock).expect("failed");
+        let owner = usage.owner.expect("no owner");
+        assert!(jni.is_same_object(owner, thread).unwrap());
+        // entry_count only counts entries from java frames in hotspot, so is not checked
+        assert!(usage.waiters.is_empty());
+        usage.delete_local_refs(*jni).unwrap();
+
+        let contended = jvmti
+            .get_current_contended_monitor(*jni, thread)
+            .expect("failed");
+        assert!(contended.is_none());
+
+        // another thread blocks on the held lock
+        let global_lock = jni.new_global_ref(lock).unwrap();
+        let (thread_tx, thread_rx) = channel();
+        let (done_tx, done_rx) = channel::<()>();
+        let vm = jni.get_java_vm().unwrap();
+        let blocked = std::thread::spawn(move || {
+            let jni = vm.attach_current_thread().unwrap();
+            let jvmti = JvmtiEnv::from_jvm(&vm).expect("failed");
+            let thread = jvmti.get_current_thread(*jni).expect("failed");
+            thread_tx.send(jni.new_global_ref(thread).unwrap()).unwrap();
+            drop(jni.lock_obj(global_lock.as_obj()).unwrap());
+            let _ = done_rx.recv();
+            jvmti.dispose().expect("dispose failed");
+        });
+        let blocked_thread = thread_rx.recv().unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let contended = loop {
+            let contended = jvmti
+                .get_current_contended_monitor(*jni, blocked_thread.as_obj().into())
+                .expect("failed");
+            if let Some(contended) = contended {
+                break contended;
+            }
+            assert!(Instant::now() < deadline, "thread never blocked");
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        assert!(jni.is_same_object(lock, contended).unwrap());
+        jni.delete_local_ref(contended).unwrap();
+
+        drop(guard);
+        done_tx.send(()).unwrap();
+        blocked.join().expect("blocked thread failed");
+    }
+
+    let hash = jvmti.get_object_hash_code(lock).expect("failed");
+    let java_hash = jni
+        .call_static_method(
+            "java/lang/System",
+            "identityHashCode",
+            "(Ljava/lang/Object;)I",
+            &[lock.into()],
+        )
+        .unwrap()
+        .i()
+        .unwrap();
+    assert_eq!(hash, java_hash);
+
+    jvmti.dispose().expect("dispose failed");
+}
@@ -0,0 +1,109 @@
+use jni::objects::JValue;
+use jni::JNIEnv;
+use jvmti::{PerfMap, PerfMapEntry};
+use log::*;
+use std::time::{Duration, Instant};
+
+mod common;
+
+const HOT_METHOD: &str = "java.lang.Integer.toHexString";
+
+#[test]
+fn perf_map() {
+    let jvm = common::new_jvm_with_jit();
+    let jni = jvm.attach_current_thread().unwrap();
+    let dir = std::env::temp_dir();
+    let path = dir.join(format!("perf-map-test-{}.map", std::process::id()));
+    let inlined_path = dir.join(format!("perf-map-test-{}-inlined.map", std::process::id()));
+
+    let map = PerfMap::builder()
+        .with_path(&path)
+        .start(&jvm)
+        .expect("failed to start");
+    assert_eq!(map.path(), path);
+
+    // generated for code that existed before starting
+    let entries = map.entries().expect("failed");
+    let interpreter = entries
+        .iter()
+        .find(|entry| entry.name == "Interpreter")
+        .expect("no interpreter");
+    let middle = interpreter.address + interpreter.size / 2;
+    assert_eq!(
+        map.lookup(middle).expect("failed").as_ref(),
+        Some(interpreter)
+    );
+
+    // compiled in the background once hot
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while !has_method(&map.entries().expect("failed"), HOT_METHOD) {
+        assert!(Instant::now() < deadline, "{} never compiled", HOT_METHOD);
+        call_hot_method(&jni, 1000);
+    }
+
+    let text = std::fs::read_to_string(&path).expect("failed to read map");
+    for line in text.lines() {
+        let mut fields = line.splitn(3, ' ');
+        let address = fields.next().unwrap();
+        let size = fields.next().expect("no size");
+        assert!(usize::from_str_radix(address, 16).is_ok(), "{}", line);
+        assert!(usize::from_str_radix(size, 16).is_ok(), "{}", line);
+        assert!(!fields.next().expect("no name").is_empty(), "{}", line);
+    }
+    assert!(text.lines().any(|line| line.ends_with(" Interpreter")));
+    assert!(text
+        .lines()
+        .any(|line| line.ends_with(&format!(" {}", HOT_METHOD))));
+
+    // sees the already compiled method
+    let inlined = PerfMap::builder()
+        .with_path(&inlined_path)
+        .with_inlining(true)
+        .with_signatures(true)
+        .start(&jvm)
+        .expect("failed to start");
+    let entries = inlined.entries().expect("failed");
+    let hot = format!("{}(I)Ljava/lang/String;", HOT_METHOD);
+    assert!(entries.iter().any(|entry| entry.name.starts_with(&hot)));
+    let inlining: Vec<&PerfMapEntry> = entries
+        .iter()
+        .filter(|entry| entry.name.contains("->"))
+        .collect();
+    info!(
+        "{} entries, {} with inlined methods, e.g. {:?}",
+        entries.len(),
+        inlining.len(),
+        inlining.first()
+    );
+    assert!(!inlining.is_empty());
+    assert!(entries
+        .windows(2)
+        .all(|pair| pair[0].address <= pair[1].address));
+
+    drop(inlined);
+    drop(map);
+    let text = std::fs::read_to_string(&inlined_path).expect("failed to read map");
+    assert!(text.contains(&hot));
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(&inlined_path);
+}
+
+fn has_method(entries: &[PerfMapEntry], name: &str) -> bool {
+    entries.iter().any(|entry| entry.name == name)
+}
+
+fn call_hot_method(jni: &JNIEnv, times: i32) {
+    for i in 0..times {
+        let string = jni
+            .call_static_method(
+                "java/lang/Integer",
+                "toHexString",
+                "(I)Ljava/lang/String;",
+                &[JValue::Int(i)],
+            )
+            .unwrap()
+            .l()
+            .unwrap();
+        jni.delete_local_ref(string).unwrap();
+    }
+}
@@ -0,0 +1,52 @@
+use jvmti::{Error, JvmtiEnv, RawMonitor};
+use std::sync::Arc;
+use std::time::Duration;
+
+mod common;
+
+#[test]
+fn raw_monitor() {
+    let jvm = common::new_jvm();
+    let _env = jvm.attach_current_thread().unwrap();
+
+    let jvmti = JvmtiEnv::from_jvm(&jvm).expect("failed");
+
+    let monitor = Arc::new(RawMonitor::new(&jvmti, "test monitor", 0u32).expect("failed"));
+
+    // times out without a notify
+    {
+        let mut guard = monitor.lock().expect("lock failed");
+        guard
+            .wait(Some(Duration::from_millis(10)))
+            .expect("wait failed");
+        assert_eq!(*guard, 0);
+
+        // would alias the guarded value
+        assert!(matches!(monitor.lock(), Err(Error::AlreadyLocked)));
+    }
+
+    let producer = {
+        let monitor = monitor.clone();
+        std::thread::spawn(move || {
+            for _ in 0..5 {
+                let mut guard = monitor.lock().expect("lock failed");
+                *guard += 1;
+                guard.notify_all().expect("notify failed");
+            }
+        })
+    };
+
+    {
+        let mut guard = monitor.lock().expect("lock failed");
+        while *guard < 5 {
+            guard.wait(None).expect("wait failed");
+        }
+    }
+
+    producer.join().unwrap();
+
+    let monitor = Arc::try_unwrap(monitor).unwrap_or_else(|_| panic!("monitor still shared"));
+    assert_eq!(monitor.into_inner(), 5);
+
+    jvmti.dispose().expect("dispose failed");
+}
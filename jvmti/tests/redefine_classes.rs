@@ -0,0 +1,67 @@
+use jni::objects::JObject;
+use jvmti::{hot_swap, Capability, Error, JvmtiEnv, JvmtiError, UnsupportedRedefinition};
+
+mod common;
+
+const CLASS_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/resources/hotswap");
+
+#[test]
+fn redefine_classes() {
+    let jvm = common::new_jvm();
+    let jni = jvm.attach_current_thread().unwrap();
+
+    let jvmti = JvmtiEnv::from_jvm(&jvm).expect("failed");
+
+    let original = std::fs::read(format!("{}/v1/HotSwapTarget.class", CLASS_DIR)).unwrap();
+    let cls = jni
+        .define_class("HotSwapTarget", JObject::null(), &original)
+        .expect("failed to define class");
+
+    let call_value = || {
+        jni.call_static_method(cls, "value", "()I", &[])
+            .expect("call failed")
+            .i()
+            .expect("not an int")
+    };
+
+    assert_eq!(call_value(), 1);
+    assert!(jvmti.is_modifiable_class(cls.into()).expect("failed"));
+
+    // method body changed
+    hot_swap(
+        &jvmti,
+        cls.into(),
+        format!("{}/v2/HotSwapTarget.class", CLASS_DIR),
+    )
+    .expect("hot swap failed");
+    assert_eq!(call_value(), 2);
+
+    // method added
+    let err = hot_swap(
+        &jvmti,
+        cls.into(),
+        format!("{}/v3/HotSwapTarget.class", CLASS_DIR),
+    )
+    .expect_err("hot swap should have failed");
+    assert!(matches!(
+        err,
+        Error::Jvmti(JvmtiError::UnsupportedRedefinition(
+            UnsupportedRedefinition::MethodAdded
+        ))
+    ));
+    assert!(err.to_string().contains("method added"), "{}", err);
+    assert_eq!(call_value(), 2);
+
+    // only potentially available during OnLoad
+    let extension = jvmti.get_source_debug_extension(cls.into());
+    assert!(matches!(
+        extension,
+        Ok(None)
+            | Err(Error::UnavailableCapability(
+                Capability::GetSourceDebugExtension
+            ))
+    ));
+    drop(extension);
+
+    jvmti.dispose().expect("dispose failed");
+}
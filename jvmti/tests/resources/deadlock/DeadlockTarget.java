@@ -0,0 +1,37 @@
+public class DeadlockTarget {
+    static final Object first = new Object();
+    static final Object second = new Object();
+
+    /** Deadlocks two new daemon threads, returning once both are blocked */
+    public static void start() throws InterruptedException {
+        Thread a = locker("deadlock-a", first, second);
+        Thread b = locker("deadlock-b", second, first);
+        a.start();
+        b.start();
+
+        while (a.getState() != Thread.State.BLOCKED || b.getState() != Thread.State.BLOCKED) {
+            Thread.sleep(10);
+        }
+    }
+
+    private static Thread locker(String name, Object outer, Object inner) {
+        Thread thread = new Thread(() -> {
+            synchronized (outer) {
+                pause();
+                synchronized (inner) {
+                    System.out.println("unreachable");
+                }
+            }
+        }, name);
+        thread.setDaemon(true);
+        return thread;
+    }
+
+    private static void pause() {
+        try {
+            Thread.sleep(200);
+        } catch (InterruptedException e) {
+            throw new RuntimeException(e);
+        }
+    }
+}
@@ -0,0 +1,5 @@
+public class HotSwapTarget {
+    public static int value() {
+        return 2;
+    }
+}
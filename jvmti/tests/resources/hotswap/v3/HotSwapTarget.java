@@ -0,0 +1,9 @@
+public class HotSwapTarget {
+    public static int value() {
+        return 3;
+    }
+
+    public static int added() {
+        return 4;
+    }
+}
@@ -0,0 +1,47 @@
+import java.util.concurrent.locks.LockSupport;
+
+public class ThreadDumpTarget {
+    static final Object sleepLock = new Object();
+    static final Object waitLock = new Object();
+    static final Object parkBlocker = new Object();
+
+    /** Starts daemon threads that sleep holding a lock, wait on a monitor and park */
+    public static void start() throws InterruptedException {
+        Thread sleeper = daemon("dump-sleeper", () -> {
+            synchronized (sleepLock) {
+                pause();
+            }
+        });
+        Thread waiter = daemon("dump-waiter", () -> {
+            synchronized (waitLock) {
+                try {
+                    waitLock.wait();
+                } catch (InterruptedException e) {
+                    throw new RuntimeException(e);
+                }
+            }
+        });
+        Thread parker = daemon("dump-parker", () -> LockSupport.park(parkBlocker));
+
+        while (sleeper.getState() != Thread.State.TIMED_WAITING
+                || waiter.getState() != Thread.State.WAITING
+                || parker.getState() != Thread.State.WAITING) {
+            Thread.sleep(10);
+        }
+    }
+
+    private static Thread daemon(String name, Runnable runnable) {
+        Thread thread = new Thread(runnable, name);
+        thread.setDaemon(true);
+        thread.start();
+        return thread;
+    }
+
+    private static void pause() {
+        try {
+            Thread.sleep(60_000);
+        } catch (InterruptedException e) {
+            throw new RuntimeException(e);
+        }
+    }
+}
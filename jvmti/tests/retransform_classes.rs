@@ -0,0 +1,51 @@
+use jni::objects::JObject;
+use jvmti::Retransformer;
+
+mod common;
+
+const CLASS_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/resources/hotswap");
+
+#[test]
+fn retransform_classes() {
+    let jvm = common::new_jvm();
+    let jni = jvm.attach_current_thread().unwrap();
+
+    // loaded before the transformer is installed
+    let original = std::fs::read(format!("{}/v1/HotSwapTarget.class", CLASS_DIR)).unwrap();
+    let cls = jni
+        .define_class("HotSwapTarget", JObject::null(), &original)
+        .expect("failed to define class");
+
+    let call_value = || {
+        jni.call_static_method(cls, "value", "()I", &[])
+            .expect("call failed")
+            .i()
+            .expect("not an int")
+    };
+    assert_eq!(call_value(), 1);
+
+    let patched = std::fs::read(format!("{}/v2/HotSwapTarget.class", CLASS_DIR)).unwrap();
+    let retransformer = Retransformer::builder()
+        .with_class_filter(|name| name == "HotSwapTarget")
+        .with_restore_on_unload(true)
+        .install(&jvm, move |class| {
+            assert_eq!(class.name, "HotSwapTarget");
+            Some(patched.clone())
+        })
+        .expect("failed to install");
+
+    let report = retransformer.retransform_loaded(*jni).expect("failed");
+    assert_eq!(report.retransformed, vec!["HotSwapTarget".to_owned()]);
+    assert!(report.failures.is_empty());
+    assert_eq!(call_value(), 2);
+
+    // switch off and on again
+    retransformer.disable(*jni).expect("failed");
+    assert_eq!(call_value(), 1);
+    retransformer.enable(*jni).expect("failed");
+    assert_eq!(call_value(), 2);
+
+    let report = retransformer.unload(*jni).expect("failed").unwrap();
+    assert_eq!(report.retransformed, vec!["HotSwapTarget".to_owned()]);
+    assert_eq!(call_value(), 1);
+}
@@ -0,0 +1,83 @@
+use jvmti::{Capability, JvmtiEnv, NonZeroJlong, TagAllocator};
+
+mod common;
+
+#[test]
+fn tags() {
+    let jvm = common::new_jvm();
+    let jni = jvm.attach_current_thread().unwrap();
+
+    let jvmti = JvmtiEnv::from_jvm(&jvm).expect("failed");
+    jvmti
+        .require_capabilities(&[Capability::TagObjects])
+        .expect("no tagging");
+
+    let obj = jni.new_object("java/lang/Object", "()V", &[]).unwrap();
+    let other = jni.new_object("java/lang/Object", "()V", &[]).unwrap();
+    assert_eq!(jvmti.get_tag(obj).expect("failed"), None);
+
+    let tag = NonZeroJlong::new(1234).unwrap();
+    jvmti.set_tag(obj, Some(tag)).expect("failed");
+    assert_eq!(jvmti.get_tag(obj).expect("failed"), Some(tag));
+    jvmti.set_tag(obj, None).expect("failed");
+    assert_eq!(jvmti.get_tag(obj).expect("failed"), None);
+
+    let heap_tags = TagAllocator::new();
+    let side_table = TagAllocator::new();
+
+    let first = heap_tags.tag(&jvmti, obj).expect("failed");
+    let second = heap_tags.allocate();
+    assert_ne!(first, second);
+    assert!(first.get() > 0 && heap_tags.owns(first.get()));
+    assert!(!side_table.owns(first.get()));
+    assert!(!heap_tags.owns(tag.get()));
+    assert_eq!(heap_tags.decode::<u32>(first), None, "not a payload tag");
+
+    let index = side_table.encode(42_usize).expect("payload fits");
+    jvmti.set_tag(other, Some(index)).expect("failed");
+    assert_eq!(
+        side_table
+            .payload_of::<usize>(&jvmti, other)
+            .expect("failed"),
+        Some(42)
+    );
+    assert_eq!(
+        heap_tags
+            .payload_of::<usize>(&jvmti, other)
+            .expect("failed"),
+        None
+    );
+    assert_eq!(
+        side_table.payload_of::<usize>(&jvmti, obj).expect("failed"),
+        None
+    );
+    assert_eq!(side_table.encode(u64::MAX), None);
+    assert_eq!(
+        side_table.decode::<u8>(side_table.encode(300_u32).unwrap()),
+        None
+    );
+
+    let tagged = jvmti
+        .get_objects_with_tag(first.get(), *jni)
+        .expect("failed");
+    assert_eq!(tagged.len(), 1);
+    assert!(jni.is_same_object(tagged[0], obj).unwrap());
+    drop(tagged);
+
+    let queried = [first.get(), index.get(), second.get()];
+    let matched = jvmti
+        .get_objects_with_tag_results(&queried, *jni)
+        .expect("failed");
+    assert_eq!(matched.len(), 2);
+    for (object, tag) in matched.iter() {
+        let expected = if tag == first { obj } else { other };
+        assert!(tag == first || tag == index);
+        assert!(jni.is_same_object(object, expected).unwrap());
+    }
+    drop(matched);
+
+    jni.delete_local_ref(obj).unwrap();
+    jni.delete_local_ref(other).unwrap();
+
+    jvmti.dispose().expect("dispose failed");
+}
@@ -0,0 +1,100 @@
+use jni::objects::JObject;
+use jvmti::{thread_dump, DumpedThread, FrameMonitor, JvmtiEnv, ThreadDump, ThreadDumpOnSignal};
+use log::*;
+use std::sync::{mpsc, Mutex};
+use std::time::Duration;
+
+mod common;
+
+const CLASS_FILE: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/resources/thread_dump/ThreadDumpTarget.class"
+);
+
+fn find<'a>(dump: &'a ThreadDump, name: &str) -> &'a DumpedThread {
+    dump.threads
+        .iter()
+        .find(|t| t.name == name)
+        .unwrap_or_else(|| panic!("no thread {:?}", name))
+}
+
+#[test]
+fn thread_dump_text() {
+    let jvm = common::new_jvm_with_onload_capabilities();
+    let jni = jvm.attach_current_thread().unwrap();
+
+    let jvmti = JvmtiEnv::from_jvm(&jvm).expect("failed");
+
+    let bytes = std::fs::read(CLASS_FILE).unwrap();
+    let cls = jni
+        .define_class("ThreadDumpTarget", JObject::null(), &bytes)
+        .expect("failed to define class");
+    jni.call_static_method(cls, "start", "()V", &[])
+        .expect("call failed");
+
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+    let on_signal = ThreadDumpOnSignal::install(&jvm, move |dump| {
+        tx.lock().unwrap().send(dump.to_string()).unwrap();
+    })
+    .expect("failed to install");
+
+    let dump = thread_dump(&jvmti, *jni).expect("failed");
+    let text = dump.to_string();
+    info!("{}", text);
+
+    assert!(text.contains("\nFull thread dump "));
+    assert!(dump.deadlocks.is_empty());
+
+    let sleeper = find(&dump, "dump-sleeper");
+    assert!(sleeper.is_daemon);
+    assert_eq!(sleeper.frames[0].frame.method_name, "sleep");
+    assert!(sleeper.frames[0].frame.is_native());
+    assert!(sleeper
+        .frames
+        .iter()
+        .flat_map(|f| &f.monitors)
+        .any(|m| matches!(m, FrameMonitor::Locked(obj) if obj.class_name == "java.lang.Object")));
+    let sleeper_text = sleeper.to_string();
+    assert!(sleeper_text.starts_with("\"dump-sleeper\" #"));
+    assert!(sleeper_text.contains(" daemon prio=5 os_prio="));
+    assert!(sleeper_text.contains(" cpu="));
+    assert!(sleeper_text.contains("s elapsed="));
+
+    // from the vm's own thread print
+    let native = sleeper.native.expect("no native thread info");
+    assert_ne!(native.tid, 0);
+    assert_ne!(native.nid, 0);
+    assert!(sleeper_text.contains(&format!(
+        "s tid=0x{:016x} nid=0x{:x} waiting on condition  [0x",
+        native.tid, native.nid
+    )));
+    assert!(sleeper_text.contains("\n   java.lang.Thread.State: TIMED_WAITING (sleeping)\n"));
+    assert!(sleeper_text.contains("\n\tat java.lang.Thread.sleep(Native Method)\n"));
+    assert!(sleeper_text.contains("\n\t- locked <0x"));
+
+    let waiter = find(&dump, "dump-waiter").to_string();
+    assert!(waiter.contains(" in Object.wait()  [0x"));
+    assert!(waiter.contains("\n   java.lang.Thread.State: WAITING (on object monitor)\n"));
+    assert!(waiter.contains("\n\t- waiting on <0x"));
+
+    let parker = find(&dump, "dump-parker").to_string();
+    assert!(parker.contains("\n   java.lang.Thread.State: WAITING (parking)\n"));
+    assert!(parker.contains("\n\t- parking to wait for  <0x"));
+
+    // the data dump request is posted on sigquit
+    let pid = std::process::id().to_string();
+    let status = std::process::Command::new("kill")
+        .args(["-QUIT", &pid])
+        .status()
+        .expect("failed to run kill");
+    assert!(status.success());
+
+    let signalled = rx
+        .recv_timeout(Duration::from_secs(10))
+        .expect("no dump on sigquit");
+    assert!(signalled.contains("\"dump-parker\" #"));
+
+    drop(on_signal);
+    jvmti.dispose().expect("dispose failed");
+}
@@ -0,0 +1,37 @@
+use jvmti::{thread_group_tree, JvmtiEnv, ThreadGroup};
+use log::*;
+
+mod common;
+
+fn find_group<'a>(groups: &'a [ThreadGroup], name: &str) -> Option<&'a ThreadGroup> {
+    groups.iter().find_map(|group| {
+        if group.name == name {
+            Some(group)
+        } else {
+            find_group(&group.groups, name)
+        }
+    })
+}
+
+#[test]
+fn thread_groups() {
+    let jvm = common::new_jvm();
+    let jni = jvm.attach_current_thread().unwrap();
+
+    let jvmti = JvmtiEnv::from_jvm(&jvm).expect("failed");
+
+    let tree = thread_group_tree(&jvmti, *jni).expect("failed");
+    for group in &tree {
+        debug!("thread groups:\n{}", group);
+    }
+
+    let system = find_group(&tree, "system").expect("no system group");
+    assert!(system.threads.iter().any(|t| t.name == "Reference Handler"));
+    assert!(find_group(&system.groups, "main").is_some());
+
+    let all_threads = jvmti.get_all_threads(*jni).expect("failed").len();
+    let tree_threads: usize = tree.iter().map(|g| g.all_threads().len()).sum();
+    assert_eq!(all_threads, tree_threads);
+
+    jvmti.dispose().expect("dispose failed");
+}
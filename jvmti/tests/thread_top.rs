@@ -0,0 +1,92 @@
+use jni::objects::JValue;
+use jvmti::{thread_top, AgentThreadBuilder, JvmtiEnv, ThreadTop, ThreadTopMonitor};
+use log::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+mod common;
+
+const BUSY_THREAD: &str = "busy thread";
+
+#[test]
+fn hottest_threads() {
+    let jvm = common::new_jvm();
+    let jni = jvm.attach_current_thread().unwrap();
+    let jvmti = JvmtiEnv::from_jvm(&jvm).expect("failed");
+
+    // burns cpu in java until told to stop
+    let stop = Arc::new(AtomicBool::new(false));
+    let busy_stop = stop.clone();
+    AgentThreadBuilder::new(BUSY_THREAD)
+        .spawn(&jvmti, *jni, move |_, jni| {
+            let base = jni
+                .call_static_method(
+                    "java/math/BigInteger",
+                    "valueOf",
+                    "(J)Ljava/math/BigInteger;",
+                    &[JValue::Long(7)],
+                )
+                .unwrap()
+                .l()
+                .unwrap();
+            while !busy_stop.load(Ordering::Relaxed) {
+                let power = jni
+                    .call_method(
+                        base,
+                        "pow",
+                        "(I)Ljava/math/BigInteger;",
+                        &[JValue::Int(2000)],
+                    )
+                    .unwrap()
+                    .l()
+                    .unwrap();
+                jni.delete_local_ref(power).unwrap();
+            }
+        })
+        .expect("failed to spawn");
+
+    let top = thread_top(&jvmti, *jni, Duration::from_millis(500), 3).expect("failed");
+    info!("{}", top);
+    assert_busy_thread_is_hottest(&top);
+    assert!(top.threads.len() <= 3);
+    assert!(top.window >= Duration::from_millis(400));
+    assert!(top.processors >= 1);
+    assert!(top.total_cpu_percent >= top.threads[0].cpu_percent);
+    assert!(top.to_string().contains(&format!("\"{}\"", BUSY_THREAD)));
+
+    let (tx, rx) = mpsc::channel();
+    let monitor =
+        ThreadTopMonitor::spawn(&jvmti, *jni, Duration::from_millis(200), 1, move |top| {
+            let _ = tx.send(
+                top.threads
+                    .iter()
+                    .map(|t| t.name.clone())
+                    .collect::<Vec<_>>(),
+            );
+        })
+        .expect("failed to spawn monitor");
+    let hottest = rx.recv_timeout(Duration::from_secs(10)).expect("no report");
+    assert_eq!(hottest, [BUSY_THREAD]);
+    monitor.stop().expect("failed to stop");
+
+    stop.store(true, Ordering::Relaxed);
+}
+
+fn assert_busy_thread_is_hottest(top: &ThreadTop) {
+    let busy = &top.threads[0];
+    assert_eq!(busy.name, BUSY_THREAD);
+    assert!(busy.cpu_percent > 20.0, "only {}%", busy.cpu_percent);
+    assert!(busy.total_cpu_time >= busy.cpu_time);
+    assert!(busy.state.contains(jvmti::ThreadState::RUNNABLE));
+    // no java frames if sampled between calls into java
+    assert!(
+        busy.stack.is_empty()
+            || busy
+                .stack
+                .iter()
+                .any(|frame| frame.class_name == "java.math.BigInteger"),
+        "{:?}",
+        busy.stack
+    );
+}
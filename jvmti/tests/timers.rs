@@ -0,0 +1,70 @@
+use jni::objects::JValue;
+use jvmti::{Capability, JvmtiEnv, TimerKind};
+use log::*;
+use std::time::Duration;
+
+mod common;
+
+#[test]
+fn timers() {
+    let jvm = common::new_jvm();
+    let jni = jvm.attach_current_thread().unwrap();
+    let jvmti = JvmtiEnv::from_jvm(&jvm).expect("failed");
+    jvmti
+        .require_capabilities(&[
+            Capability::GetCurrentThreadCpuTime,
+            Capability::GetThreadCpuTime,
+        ])
+        .expect("failed");
+
+    assert!(jvmti.get_available_processors().expect("failed") >= 1);
+
+    let timer = jvmti.get_timer_info().expect("failed");
+    info!("system timer: {:?}", timer);
+    assert_eq!(timer.kind, TimerKind::Elapsed);
+    assert!(timer.max_value > Duration::ZERO);
+
+    let thread_timer = jvmti.get_thread_cpu_timer_info().expect("failed");
+    let current_thread_timer = jvmti.get_current_thread_cpu_timer_info().expect("failed");
+    info!("thread cpu timer: {:?}", thread_timer);
+    assert_ne!(thread_timer.kind, TimerKind::Elapsed);
+    assert_eq!(thread_timer, current_thread_timer);
+
+    let thread = jvmti.get_current_thread(*jni).unwrap();
+    let cpu_before = jvmti.get_current_thread_cpu_time().expect("failed");
+    let time_before = jvmti.get_time().expect("failed");
+
+    // burn some cpu in java
+    let base = jni
+        .call_static_method(
+            "java/math/BigInteger",
+            "valueOf",
+            "(J)Ljava/math/BigInteger;",
+            &[JValue::Long(7)],
+        )
+        .unwrap()
+        .l()
+        .unwrap();
+    for _ in 0..10 {
+        let power = jni
+            .call_method(
+                base,
+                "pow",
+                "(I)Ljava/math/BigInteger;",
+                &[JValue::Int(5000)],
+            )
+            .unwrap()
+            .l()
+            .unwrap();
+        jni.delete_local_ref(power).unwrap();
+    }
+
+    let cpu_after = jvmti.get_current_thread_cpu_time().expect("failed");
+    let thread_cpu = jvmti.get_thread_cpu_time(thread).expect("failed");
+    let elapsed = jvmti.get_time().expect("failed") - time_before;
+    info!("used {:?} cpu in {:?}", cpu_after - cpu_before, elapsed);
+    assert!(cpu_after > cpu_before);
+    assert!(thread_cpu >= cpu_after);
+    // leeway for timer granularity
+    assert!(cpu_after - cpu_before <= elapsed + Duration::from_millis(20));
+}